@@ -0,0 +1,114 @@
+//! Build script helpers (`build` feature)
+//!
+//! Compile a Piet image to Rust at build time, so a crate can run the
+//! program without shipping the image:
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     canvas_codegen::build::compile_image("programs/hello.png", "hello").unwrap();
+//! }
+//!
+//! // src/lib.rs
+//! include!(concat!(env!("OUT_DIR"), "/hello.rs"));
+//!
+//! fn greet(io: &mut impl hello::PietIo) -> Result<hello::ExitStatus, hello::Error> {
+//!     hello::run(io)
+//! }
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use canvas_vm::{Compiler, Grid, VmError};
+
+use crate::rust::RustCodegen;
+use crate::wasm::{CodegenError, CodegenOptions};
+
+/// Why an image could not be compiled
+#[derive(Debug)]
+pub enum BuildError {
+    /// Reading the image or writing the module failed
+    Io(std::io::Error),
+    /// The image could not be decoded
+    Image(String),
+    /// `OUT_DIR` is not set (not running from a build script)
+    NoOutDir,
+    /// The image is not a valid Piet program
+    Vm(VmError),
+    /// The program could not be translated
+    Codegen(CodegenError),
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::Io(e) => write!(f, "I/O error: {}", e),
+            BuildError::Image(msg) => write!(f, "Image error: {}", msg),
+            BuildError::NoOutDir => write!(f, "OUT_DIR is not set; call this from build.rs"),
+            BuildError::Vm(e) => write!(f, "Compile error: {}", e),
+            BuildError::Codegen(e) => write!(f, "Codegen error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Compile `image` into `$OUT_DIR/<module_name>.rs`, defining
+/// `pub mod <module_name>`, and return the path written
+///
+/// Also tells Cargo to rebuild when the image changes.
+pub fn compile_image(image: impl AsRef<Path>, module_name: &str) -> Result<PathBuf, BuildError> {
+    compile_image_with_options(image, module_name, CodegenOptions::default())
+}
+
+/// [`compile_image`] with custom options
+pub fn compile_image_with_options(
+    image: impl AsRef<Path>,
+    module_name: &str,
+    options: CodegenOptions,
+) -> Result<PathBuf, BuildError> {
+    let out_dir = std::env::var_os("OUT_DIR").ok_or(BuildError::NoOutDir)?;
+    println!("cargo:rerun-if-changed={}", image.as_ref().display());
+    write_module(image.as_ref(), module_name, Path::new(&out_dir), options)
+}
+
+/// Compile `image` into `<out_dir>/<module_name>.rs`
+pub(crate) fn write_module(
+    image: &Path,
+    module_name: &str,
+    out_dir: &Path,
+    options: CodegenOptions,
+) -> Result<PathBuf, BuildError> {
+    let img = image::open(image).map_err(|e| BuildError::Image(e.to_string()))?.to_rgba8();
+    let (width, height) = img.dimensions();
+    let grid = Grid::from_rgba(width as usize, height as usize, img.as_raw()).map_err(BuildError::Vm)?;
+    let program = Compiler::new(grid).compile().map_err(BuildError::Vm)?;
+    let source = RustCodegen::with_options(options)
+        .module_name(module_name)
+        .generate(&program)
+        .map_err(BuildError::Codegen)?;
+
+    let path = out_dir.join(format!("{}.rs", module_name));
+    std::fs::write(&path, source).map_err(BuildError::Io)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_module() {
+        let image = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tools/fixtures/samples/HelloWorld.png");
+        let out_dir = std::env::temp_dir().join(format!("canvas_codegen_build_{}", std::process::id()));
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let path = write_module(&image, "hello", &out_dir, CodegenOptions::default()).unwrap();
+        assert_eq!(path, out_dir.join("hello.rs"));
+        let source = std::fs::read_to_string(&path).unwrap();
+        assert!(source.contains("pub mod hello {") && source.contains("pub fn run(io: &mut impl PietIo)"));
+
+        let missing = write_module(&out_dir.join("missing.png"), "missing", &out_dir, CodegenOptions::default());
+        assert!(matches!(missing, Err(BuildError::Image(_))));
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+}
@@ -0,0 +1,531 @@
+//! C99 source generator
+//!
+//! Translates a compiled `Program` into a single self-contained C file: a
+//! `switch` state machine over the program states, a growable `int64_t`
+//! stack and stdin/stdout I/O. Values stay in `i32` range and wrap like
+//! `BytecodeVm` does.
+
+use std::fmt::Write;
+
+use canvas_vm::{Instruction, Program};
+
+use crate::wasm::{successor_table, CodegenError, CodegenOptions, ErrorCode, ErrorPolicy, ExitStatus};
+
+/// Helpers a program needs, so the output compiles warning-free
+#[derive(Debug, Default)]
+struct Uses {
+    push: bool,
+    pop: bool,
+    peek: bool,
+    wrap: bool,
+    roll: bool,
+    read_char: bool,
+    read_number: bool,
+    write_char: bool,
+    branch: bool,
+}
+
+impl Uses {
+    fn scan(instructions: &[Instruction]) -> Self {
+        let mut uses = Uses::default();
+        for instruction in instructions {
+            for part in instruction.unfused().map_or_else(|| vec![instruction.clone()], |parts| parts.to_vec()) {
+                match part {
+                    Instruction::Push(_) => uses.push = true,
+                    Instruction::Pop | Instruction::Pointer | Instruction::Switch => uses.pop = true,
+                    Instruction::Add | Instruction::Subtract | Instruction::Multiply => {
+                        uses.push = true;
+                        uses.pop = true;
+                        uses.wrap = true;
+                    }
+                    Instruction::Divide | Instruction::Mod | Instruction::Duplicate => {
+                        uses.push = true;
+                        uses.pop = true;
+                        uses.peek = true;
+                    }
+                    Instruction::Not | Instruction::Greater => {
+                        uses.push = true;
+                        uses.pop = true;
+                    }
+                    Instruction::Roll => {
+                        uses.pop = true;
+                        uses.roll = true;
+                    }
+                    Instruction::InNumber => {
+                        uses.push = true;
+                        uses.wrap = true;
+                        uses.read_number = true;
+                    }
+                    Instruction::InChar => {
+                        uses.push = true;
+                        uses.read_char = true;
+                    }
+                    Instruction::OutNumber => uses.pop = true,
+                    Instruction::OutChar => {
+                        uses.pop = true;
+                        uses.write_char = true;
+                    }
+                    _ => {}
+                }
+                uses.branch |= matches!(part, Instruction::Pointer | Instruction::Switch);
+            }
+        }
+        uses
+    }
+
+    fn stack(&self) -> bool {
+        self.push || self.pop || self.peek || self.roll
+    }
+
+    fn input(&self) -> bool {
+        self.read_char || self.read_number
+    }
+}
+
+/// Integer literal that is valid C for every `i32`
+fn c_int(n: i32) -> String {
+    if n == i32::MIN {
+        "(-2147483647 - 1)".to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+const STACK: &str = r#"
+static int64_t *stack;
+static size_t sp, cap;
+"#;
+
+const PUSH: &str = r#"
+static void push(int64_t value) {
+    if (sp == cap) {
+        size_t grown = cap ? cap * 2 : 1024;
+        int64_t *bigger;
+        if (grown > STACK_LIMIT) grown = STACK_LIMIT;
+        bigger = grown > cap ? realloc(stack, grown * sizeof *stack) : NULL;
+        if (!bigger) {
+            error(ERR_STACK_OVERFLOW);
+            fflush(stdout);
+            exit(STATUS_STACK_OVERFLOW);
+        }
+        stack = bigger;
+        cap = grown;
+    }
+    stack[sp++] = value;
+}
+"#;
+
+const POP: &str = r#"
+static int64_t pop(void) {
+    return stack[--sp];
+}
+"#;
+
+const PEEK: &str = r#"
+static int64_t peek(void) {
+    return stack[sp - 1];
+}
+"#;
+
+const WRAP: &str = r#"
+/* Two's complement i32 wrap-around, like the interpreter */
+static int64_t wrap(uint32_t value) {
+    return value < 0x80000000u ? (int64_t)value : (int64_t)value - 0x100000000;
+}
+"#;
+
+const ROLL: &str = r#"
+static void reverse(size_t lo, size_t hi) {
+    while (lo < hi) {
+        int64_t tmp = stack[lo];
+        stack[lo++] = stack[hi];
+        stack[hi--] = tmp;
+    }
+}
+
+/* Rolls the top `depth` elements `times` times; returns 1 on underflow */
+static int roll(void) {
+    int64_t times, depth;
+    size_t lo;
+    if (sp < 2) return 1;
+    times = pop();
+    depth = pop();
+    if (depth < 0) return 0;
+    if ((uint64_t)depth > sp) return 1;
+    if (depth == 0) return 0;
+    times %= depth;
+    if (times < 0) times += depth;
+    /* rotate right by `times` with three reversals */
+    lo = sp - (size_t)depth;
+    reverse(lo, sp - 1);
+    if (times > 0) reverse(lo, lo + (size_t)times - 1);
+    reverse(lo + (size_t)times, sp - 1);
+    return 0;
+}
+"#;
+
+const INPUT: &str = r#"
+static int lookahead = -1;
+
+/* Next stdin byte, or -1 at end of input */
+static int read_byte(void) {
+    int b = lookahead;
+    if (b >= 0) {
+        lookahead = -1;
+        return b;
+    }
+    fflush(stdout);
+    b = getchar();
+    return b == EOF ? -1 : b;
+}
+
+/* The interpreter stops when it runs out of input */
+static void end_of_input(void) {
+    fflush(stdout);
+    exit(STATUS_HALTED);
+}
+"#;
+
+const READ_CHAR: &str = r#"
+/* One UTF-8 code point; malformed sequences read as U+FFFD */
+static int64_t read_char(void) {
+    int b = read_byte(), n;
+    int32_t cp;
+    if (b < 0) end_of_input();
+    if (b < 0x80) return b;
+    if (b >= 0xF0) { n = 3; cp = b & 0x07; }
+    else if (b >= 0xE0) { n = 2; cp = b & 0x0F; }
+    else if (b >= 0xC0) { n = 1; cp = b & 0x1F; }
+    else return 0xFFFD;
+    while (n-- > 0) {
+        b = read_byte();
+        if ((b & 0xC0) != 0x80) {
+            if (b >= 0) lookahead = b;
+            return 0xFFFD;
+        }
+        cp = cp << 6 | (b & 0x3F);
+    }
+    return cp;
+}
+"#;
+
+const READ_NUMBER: &str = r#"
+/* A decimal integer; overflow wraps and a non-number reads as 0 */
+static int64_t read_number(void) {
+    int b, negative, digits = 0;
+    uint32_t value = 0;
+    do {
+        b = read_byte();
+        if (b < 0) end_of_input();
+    } while (b == ' ' || b == '\t' || b == '\n' || b == '\r');
+    negative = b == '-';
+    if (b == '-' || b == '+') b = read_byte();
+    while (b >= '0' && b <= '9') {
+        value = value * 10u + (uint32_t)(b - '0');
+        digits++;
+        b = read_byte();
+    }
+    if (digits > 0) {
+        if (b >= 0) lookahead = b;
+    } else if (b < 0) {
+        end_of_input();
+    }
+    return wrap(negative ? 0u - value : value);
+}
+"#;
+
+const WRITE_CHAR: &str = r#"
+/* UTF-8; values that are not Unicode scalar values write nothing */
+static void write_char(int64_t c) {
+    if (c < 0 || c > 0x10FFFF || (c >= 0xD800 && c <= 0xDFFF)) return;
+    if (c < 0x80) {
+        putchar((int)c);
+    } else if (c < 0x800) {
+        putchar((int)(0xC0 | c >> 6));
+        putchar((int)(0x80 | (c & 0x3F)));
+    } else if (c < 0x10000) {
+        putchar((int)(0xE0 | c >> 12));
+        putchar((int)(0x80 | (c >> 6 & 0x3F)));
+        putchar((int)(0x80 | (c & 0x3F)));
+    } else {
+        putchar((int)(0xF0 | c >> 18));
+        putchar((int)(0x80 | (c >> 12 & 0x3F)));
+        putchar((int)(0x80 | (c >> 6 & 0x3F)));
+        putchar((int)(0x80 | (c & 0x3F)));
+    }
+}
+"#;
+
+/// C source code generator
+///
+/// Uses the same [`CodegenOptions`] as the WASM backend: `on_error`,
+/// `max_steps` and `max_memory_pages` (as a stack size limit of 16384
+/// values per page, like the WASM stack); the WASM-specific options are
+/// ignored.
+///
+/// The program's exit code is its [`ExitStatus`].
+pub struct CCodegen {
+    options: CodegenOptions,
+}
+
+impl CCodegen {
+    /// Create a new C code generator with default options
+    pub fn new() -> Self {
+        Self::with_options(CodegenOptions::default())
+    }
+
+    /// Create a C code generator with custom options
+    pub fn with_options(options: CodegenOptions) -> Self {
+        Self { options }
+    }
+
+    /// Generate a C99 translation unit for a program
+    pub fn generate(&self, program: &Program) -> Result<String, CodegenError> {
+        let successors = successor_table(program)?;
+        let uses = Uses::scan(&program.instructions);
+        let mut out = String::new();
+
+        self.write_prelude(&mut out, program, &uses);
+
+        out.push_str("\nint main(void) {\n");
+        if uses.branch {
+            out.push_str("    int branch = 0;\n");
+        }
+        out.push_str("    for (;;) {\n        switch (state) {\n");
+        for (k, instruction) in program.instructions.iter().enumerate() {
+            let position = program
+                .rich_instructions
+                .get(k)
+                .and_then(|rich| rich.debug.as_ref())
+                .map(|debug| format!(" @ ({}, {})", debug.from_pos.0, debug.from_pos.1))
+                .unwrap_or_default();
+            let _ = writeln!(out, "        case {}: {{ /* {}{} */", k, instruction, position);
+            if self.options.max_steps.is_some() {
+                out.push_str("            STEP();\n");
+            }
+            self.write_instruction(&mut out, instruction, 3);
+            if *instruction != Instruction::Halt {
+                Self::write_transition(&mut out, instruction, &successors[k]);
+            }
+            out.push_str("        }\n");
+        }
+        out.push_str("        default:\n            goto done;\n        }\n    }\n");
+        out.push_str("done:\n");
+        out.push_str("    fflush(stdout);\n    return status;\n}\n");
+        Ok(out)
+    }
+
+    fn write_prelude(&self, out: &mut String, program: &Program, uses: &Uses) {
+        let _ = writeln!(out, "/*");
+        let _ = writeln!(out, " * Generated by canvas_codegen from a Piet program ({} states).", program.instructions.len());
+        let _ = writeln!(out, " * Build: cc -std=c99 -O2 -o program program.c");
+        let _ = writeln!(
+            out,
+            " * Exit code: {} halted, {} step limit reached, {} stack overflow.",
+            ExitStatus::Halted as i32,
+            ExitStatus::OutOfFuel as i32,
+            ExitStatus::StackOverflow as i32
+        );
+        let _ = writeln!(out, " */");
+        out.push_str("#include <inttypes.h>\n#include <stdint.h>\n#include <stdio.h>\n#include <stdlib.h>\n\n");
+
+        for (name, status) in [
+            ("HALTED", ExitStatus::Halted),
+            ("OUT_OF_FUEL", ExitStatus::OutOfFuel),
+            ("STACK_OVERFLOW", ExitStatus::StackOverflow),
+        ] {
+            let _ = writeln!(out, "#define STATUS_{} {}", name, status as i32);
+        }
+        for (name, code) in [
+            ("STACK_UNDERFLOW", ErrorCode::StackUnderflow),
+            ("DIVISION_BY_ZERO", ErrorCode::DivisionByZero),
+            ("OVERFLOW", ErrorCode::Overflow),
+            ("STACK_OVERFLOW", ErrorCode::StackOverflow),
+        ] {
+            let _ = writeln!(out, "#define ERR_{} {}", name, code as i32);
+        }
+        // Values are stored as int64_t, but counted as 32-bit like the WASM stack
+        let limit = match self.options.max_memory_pages {
+            Some(pages) => format!("((size_t){} * 65536 / 4)", pages),
+            None => "((size_t)-1 / sizeof(int64_t))".to_string(),
+        };
+        let _ = writeln!(out, "#define STACK_LIMIT {}", limit);
+
+        out.push_str("\nstatic int state;\nstatic int status = STATUS_HALTED;\n");
+        if let Some(max_steps) = self.options.max_steps {
+            let _ = writeln!(out, "static uint64_t fuel = UINT64_C({});", max_steps);
+            out.push_str(
+                "#define STEP() do { if (fuel == 0) { status = STATUS_OUT_OF_FUEL; goto done; } fuel--; } while (0)\n",
+            );
+        }
+
+        if uses.stack() {
+            out.push_str("\nstatic void error(int code) {\n");
+            match self.options.on_error {
+                ErrorPolicy::Ignore => out.push_str("    (void)code;\n"),
+                ErrorPolicy::Report => {
+                    out.push_str("    fprintf(stderr, \"piet: error %d at state %d\\n\", code, state);\n")
+                }
+                ErrorPolicy::Trap => {
+                    out.push_str("    fflush(stdout);\n");
+                    out.push_str("    fprintf(stderr, \"piet: error %d at state %d\\n\", code, state);\n");
+                    out.push_str("    abort();\n");
+                }
+            }
+            out.push_str("}\n");
+            out.push_str(STACK);
+        }
+        for (used, helper) in [
+            (uses.push, PUSH),
+            (uses.pop, POP),
+            (uses.peek, PEEK),
+            (uses.wrap, WRAP),
+            (uses.roll, ROLL),
+            (uses.input(), INPUT),
+            (uses.read_char, READ_CHAR),
+            (uses.read_number, READ_NUMBER),
+            (uses.write_char, WRITE_CHAR),
+        ] {
+            if used {
+                out.push_str(helper);
+            }
+        }
+    }
+
+    /// Write the statements of one instruction at `depth` levels of indentation
+    fn write_instruction(&self, out: &mut String, instruction: &Instruction, depth: usize) {
+        let pad = "    ".repeat(depth);
+        let needed = match instruction {
+            Instruction::Pop
+            | Instruction::Not
+            | Instruction::Duplicate
+            | Instruction::OutNumber
+            | Instruction::OutChar
+            | Instruction::Pointer
+            | Instruction::Switch => 1,
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Mod
+            | Instruction::Greater => 2,
+            // roll() checks the stack itself
+            _ => 0,
+        };
+        if matches!(instruction, Instruction::Pointer | Instruction::Switch) {
+            // An ignored Pointer/Switch keeps DP/CC: branch 0
+            let _ = writeln!(out, "{}branch = 0;", pad);
+        }
+        let pad = if needed > 0 {
+            let _ = writeln!(out, "{}if (sp < {}) {{", pad, needed);
+            let _ = writeln!(out, "{}    error(ERR_STACK_UNDERFLOW);", pad);
+            let _ = writeln!(out, "{}}} else {{", pad);
+            format!("{}    ", pad)
+        } else {
+            pad
+        };
+
+        let binary = |out: &mut String, op: &str| {
+            let _ = writeln!(out, "{}int64_t a = pop(), b = pop();", pad);
+            let _ = writeln!(out, "{}push({});", pad, op);
+        };
+        match instruction {
+            Instruction::Push(n) => {
+                let _ = writeln!(out, "{}push({});", pad, c_int(*n));
+            }
+            Instruction::Pop => {
+                let _ = writeln!(out, "{}(void)pop();", pad);
+            }
+            Instruction::Add => binary(out, "wrap((uint32_t)b + (uint32_t)a)"),
+            Instruction::Subtract => binary(out, "wrap((uint32_t)b - (uint32_t)a)"),
+            Instruction::Multiply => binary(out, "wrap((uint32_t)b * (uint32_t)a)"),
+            Instruction::Greater => binary(out, "b > a"),
+            Instruction::Divide | Instruction::Mod => {
+                // A zero divisor leaves the stack untouched; i32::MIN by -1
+                // pushes the wrapped result
+                let (result, wrapped) = if *instruction == Instruction::Divide {
+                    ("b / a", "b")
+                } else {
+                    ("(b % a + (a < 0 ? -a : a)) % (a < 0 ? -a : a)", "0")
+                };
+                let _ = writeln!(out, "{}if (peek() == 0) {{", pad);
+                let _ = writeln!(out, "{}    error(ERR_DIVISION_BY_ZERO);", pad);
+                let _ = writeln!(out, "{}}} else {{", pad);
+                let _ = writeln!(out, "{}    int64_t a = pop(), b = pop();", pad);
+                let _ = writeln!(out, "{}    if (b == INT32_MIN && a == -1) {{", pad);
+                let _ = writeln!(out, "{}        error(ERR_OVERFLOW);", pad);
+                let _ = writeln!(out, "{}        push({});", pad, wrapped);
+                let _ = writeln!(out, "{}    }} else {{", pad);
+                let _ = writeln!(out, "{}        push({});", pad, result);
+                let _ = writeln!(out, "{}    }}", pad);
+                let _ = writeln!(out, "{}}}", pad);
+            }
+            Instruction::Not => {
+                let _ = writeln!(out, "{}push(pop() == 0);", pad);
+            }
+            Instruction::Duplicate => {
+                let _ = writeln!(out, "{}push(peek());", pad);
+            }
+            Instruction::Roll => {
+                let _ = writeln!(out, "{}if (roll()) error(ERR_STACK_UNDERFLOW);", pad);
+            }
+            Instruction::InNumber => {
+                let _ = writeln!(out, "{}push(read_number());", pad);
+            }
+            Instruction::InChar => {
+                let _ = writeln!(out, "{}push(read_char());", pad);
+            }
+            Instruction::OutNumber => {
+                let _ = writeln!(out, "{}printf(\"%\" PRId64, pop());", pad);
+            }
+            Instruction::OutChar => {
+                let _ = writeln!(out, "{}write_char(pop());", pad);
+            }
+            Instruction::Pointer => {
+                let _ = writeln!(out, "{}int64_t n = pop();", pad);
+                let _ = writeln!(out, "{}branch = (int)((n % 4 + 4) % 4);", pad);
+            }
+            Instruction::Switch => {
+                let _ = writeln!(out, "{}branch = pop() % 2 != 0;", pad);
+            }
+            Instruction::Nop => {}
+            Instruction::Halt => {
+                let _ = writeln!(out, "{}goto done;", pad);
+            }
+            Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => {
+                // Superinstructions: the push and the operation
+                if let Some(parts) = instruction.unfused() {
+                    for part in &parts {
+                        self.write_instruction(out, part, pad.len() / 4);
+                    }
+                }
+            }
+        }
+
+        if needed > 0 {
+            let _ = writeln!(out, "{}}}", &pad[4..]);
+        }
+    }
+
+    /// Jump to the successor state; -1 (no successor) ends the program
+    fn write_transition(out: &mut String, instruction: &Instruction, successors: &[Option<usize>]) {
+        let target = |j: &Option<usize>| j.map_or(-1, |j| j as i64);
+        let next = match instruction {
+            Instruction::Pointer | Instruction::Switch if successors.len() > 1 => {
+                let mut next = target(&successors[successors.len() - 1]).to_string();
+                for (branch, j) in successors.iter().enumerate().rev().skip(1) {
+                    next = format!("branch == {} ? {} : {}", branch, target(j), next);
+                }
+                next
+            }
+            _ => target(&successors.first().copied().flatten()).to_string(),
+        };
+        let _ = writeln!(out, "            state = {};\n            break;", next);
+    }
+}
+
+impl Default for CCodegen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
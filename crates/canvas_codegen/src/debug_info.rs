@@ -0,0 +1,158 @@
+//! Debug sections for generated modules (see `CodegenOptions::debug_info`).
+//!
+//! - `name`: function names, the locals of `main` and its block labels
+//!   (`dispatch`, `exit`, `state_<k>`), picked up by disassemblers and
+//!   browser devtools.
+//! - [`STATE_MAP_SECTION`]: where each Piet state's code starts, so a
+//!   trap or profiler offset can be traced back to the image.
+//!
+//! The state map is a count followed by one entry per state, every field
+//! an unsigned LEB128:
+//!
+//! ```text
+//! offset  module offset of the first instruction of the state
+//! state   instruction index in the program
+//! x + 1   codel of the instruction (0 = unknown)
+//! y + 1
+//! ```
+//!
+//! The codel is where the instruction starts when the program was
+//! compiled with `CompileMode::Debug`. Otherwise it is the first codel, in
+//! reading order, that `Program::position_map` assigns to the instruction.
+
+use canvas_vm::Program;
+use wasm_encoder::{CustomSection, IndirectNameMap, NameMap, NameSection};
+
+use crate::wasm::{Target, WasmCodegen};
+
+/// Name of the custom section mapping code offsets to Piet states
+pub const STATE_MAP_SECTION: &str = "piet.states";
+
+fn leb128(mut value: u64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn leb128_len(value: u64) -> usize {
+    let mut buf = Vec::new();
+    leb128(value, &mut buf);
+    buf.len()
+}
+
+impl WasmCodegen {
+    /// `name` section for a module with `states` states
+    pub(crate) fn name_section(&self, states: usize) -> NameSection {
+        let f = &self.funcs;
+        let mut functions: Vec<(u32, &str)> = vec![
+            (f.main, "main"),
+            (f.stack_push, "stack_push"),
+            (f.stack_pop, "stack_pop"),
+            (f.stack_peek, "stack_peek"),
+            (f.stack_size, "stack_size"),
+            (f.stack_roll, "stack_roll"),
+            (f.stack_reverse, "stack_reverse"),
+            (f.stack_pointer, "stack_pointer"),
+            (f.read_char, "read_char"),
+            (f.read_number, "read_number"),
+            (f.write_char, "write_char"),
+            (f.write_number, "write_number"),
+        ];
+        match self.options.target {
+            Target::Wasi => functions.extend([
+                (f.fd_read, "fd_read"),
+                (f.fd_write, "fd_write"),
+                (f.proc_exit, "proc_exit"),
+                (f.read_byte, "read_byte"),
+                (f.write_bytes, "write_bytes"),
+            ]),
+            Target::Host if f.main > f.on_error => functions.push((f.on_error, "on_error")),
+            Target::Host => {}
+        }
+        functions.sort_unstable();
+
+        let mut function_names = NameMap::new();
+        for (index, name) in functions {
+            function_names.append(index, name);
+        }
+
+        let mut main_locals = NameMap::new();
+        for (index, name) in ["state", "branch", "operand", "result"].into_iter().enumerate() {
+            main_locals.append(index as u32, name);
+        }
+        let mut locals = IndirectNameMap::new();
+        locals.append(f.main, &main_locals);
+
+        // Labels in order of appearance: the dispatch loop, $exit, then
+        // the state blocks from the outermost ($s(n-1)) inwards
+        let mut main_labels = NameMap::new();
+        if states > 0 {
+            main_labels.append(0, "dispatch");
+            main_labels.append(1, "exit");
+            for k in (0..states).rev() {
+                main_labels.append(2 + (states - 1 - k) as u32, &format!("state_{}", k));
+            }
+        }
+        let mut labels = IndirectNameMap::new();
+        labels.append(f.main, &main_labels);
+
+        let mut names = NameSection::new();
+        names.module("piet");
+        names.functions(&function_names);
+        names.locals(&locals);
+        names.labels(&labels);
+        names
+    }
+
+    /// [`STATE_MAP_SECTION`] for a module whose code section starts at
+    /// `code_section` and holds `code_size` bytes of `functions` bodies,
+    /// `main` (of `main_size` bytes) first
+    pub(crate) fn state_map_section(
+        &self,
+        program: &Program,
+        state_offsets: &[usize],
+        code_section: usize,
+        code_size: usize,
+        functions: u32,
+        main_size: usize,
+    ) -> CustomSection<'static> {
+        // section id, section size, function count, main's body size
+        let main_start = code_section
+            + 1
+            + leb128_len(code_size as u64)
+            + leb128_len(functions as u64)
+            + leb128_len(main_size as u64);
+
+        // Release programs have no debug info, only the codels of each block
+        let mut mapped = vec![None; state_offsets.len()];
+        for (y, row) in program.position_map.iter().enumerate() {
+            for (x, idx) in row.iter().enumerate() {
+                if let Some(slot) = idx.and_then(|idx| mapped.get_mut(idx)) {
+                    slot.get_or_insert((x, y));
+                }
+            }
+        }
+
+        let mut data = Vec::new();
+        leb128(state_offsets.len() as u64, &mut data);
+        for (state, offset) in state_offsets.iter().enumerate() {
+            let position = program
+                .rich_instructions
+                .get(state)
+                .and_then(|rich| rich.debug.as_ref())
+                .map(|debug| debug.from_pos)
+                .or(mapped[state]);
+            leb128((main_start + offset) as u64, &mut data);
+            leb128(state as u64, &mut data);
+            leb128(position.map_or(0, |(x, _)| x as u64 + 1), &mut data);
+            leb128(position.map_or(0, |(_, y)| y as u64 + 1), &mut data);
+        }
+        CustomSection { name: STATE_MAP_SECTION.into(), data: data.into() }
+    }
+}
@@ -0,0 +1,321 @@
+//! JavaScript source generator
+//!
+//! Translates a compiled `Program` into one plain JavaScript function: a
+//! `switch` state machine over the program states with an `Int32Array`
+//! stack, wrapping like `BytecodeVm`. I/O goes through callbacks, so the
+//! function runs in any JS engine without instantiating a WASM module.
+
+use std::fmt::Write;
+
+use canvas_vm::{Instruction, Program};
+
+use crate::wasm::{grows_stack, successor_table, CodegenError, CodegenOptions, ErrorCode, ErrorPolicy};
+
+/// Stack helpers; `roll` is only emitted when the program rolls
+const PUSH_HELPER: &str = "
+  function push(value) {
+    if (sp === stack.length) {
+      const grown = new Int32Array(stack.length * 2);
+      grown.set(stack);
+      stack = grown;
+    }
+    stack[sp++] = value;
+  }
+";
+
+const ROLL_HELPER: &str = "
+  // Rolls the top `depth` values `times` times; false on underflow
+  function roll() {
+    if (sp < 2) return false;
+    const times = stack[--sp];
+    const depth = stack[--sp];
+    if (depth < 0) return true;
+    if (depth > sp) return false;
+    if (depth === 0) return true;
+    const turns = ((times % depth) + depth) % depth;
+    if (turns !== 0) {
+      const base = sp - depth;
+      const moved = stack.slice(sp - turns, sp);
+      stack.copyWithin(base + turns, base, sp - turns);
+      stack.set(moved, base);
+    }
+    return true;
+  }
+";
+
+/// JavaScript source code generator
+///
+/// Emits `function <name>(io)`, where `io` provides `readNumber()` and
+/// `readChar()` (a number, or `null` to end the program), `writeNumber(n)`,
+/// `writeChar(codePoint)` and, with [`ErrorPolicy::Report`],
+/// `onError(code, state)`. The function returns an [`ExitStatus`] value;
+/// with [`ErrorPolicy::Trap`] it throws an `Error` carrying `code` and
+/// `state`.
+///
+/// Uses the same [`CodegenOptions`] as the WASM backend: `on_error`,
+/// `max_steps` and `max_memory_pages` (as a stack size limit, 64 KiB per
+/// page); the WASM-specific options are ignored.
+///
+/// [`ExitStatus`]: crate::ExitStatus
+pub struct JsCodegen {
+    options: CodegenOptions,
+    function_name: String,
+    export: bool,
+}
+
+impl JsCodegen {
+    /// Create a new JavaScript code generator with default options
+    pub fn new() -> Self {
+        Self::with_options(CodegenOptions::default())
+    }
+
+    /// Create a JavaScript code generator with custom options
+    pub fn with_options(options: CodegenOptions) -> Self {
+        Self { options, function_name: "runPiet".to_string(), export: false }
+    }
+
+    /// Name of the generated function (default `runPiet`)
+    pub fn function_name(mut self, name: &str) -> Self {
+        self.function_name = name.to_string();
+        self
+    }
+
+    /// Emit an ES module exporting the function, instead of a script
+    pub fn export(mut self, export: bool) -> Self {
+        self.export = export;
+        self
+    }
+
+    /// Generate the function for a program
+    pub fn generate(&self, program: &Program) -> Result<String, CodegenError> {
+        let successors = successor_table(program)?;
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "// Generated by canvas_codegen from a Piet program ({} states). Do not edit.\n",
+            program.instructions.len()
+        );
+        let _ = writeln!(out, "{}function {}(io) {{", if self.export { "export " } else { "" }, self.function_name);
+        out.push_str("  \"use strict\";\n");
+        out.push_str("  let stack = new Int32Array(64);\n");
+        out.push_str("  let sp = 0;\n");
+        out.push_str("  let state = 0;\n");
+        out.push_str("  let branch = 0;\n");
+        out.push_str("  let a = 0;\n");
+        out.push_str("  let b = 0;\n");
+        if let Some(max_steps) = self.options.max_steps {
+            let _ = writeln!(out, "  let fuel = {};", max_steps);
+        }
+        if let Some(pages) = self.options.max_memory_pages {
+            let _ = writeln!(out, "  const STACK_LIMIT = {} * 65536 / 4;", pages);
+        }
+        if program.instructions.is_empty() {
+            out.push_str("  return 0;\n}\n");
+            return Ok(out);
+        }
+        out.push_str(PUSH_HELPER);
+        if program.instructions.contains(&Instruction::Roll) {
+            out.push_str(ROLL_HELPER);
+        }
+
+        out.push_str("\n  for (;;) {\n    switch (state) {\n");
+        for (k, instruction) in program.instructions.iter().enumerate() {
+            let position = program
+                .rich_instructions
+                .get(k)
+                .and_then(|rich| rich.debug.as_ref())
+                .map(|debug| format!(" @ ({}, {})", debug.from_pos.0, debug.from_pos.1))
+                .unwrap_or_default();
+            let _ = writeln!(out, "      case {}: {{ // {}{}", k, instruction, position);
+            if self.options.max_steps.is_some() {
+                out.push_str("        if (fuel === 0) return 1;\n");
+                out.push_str("        fuel--;\n");
+            }
+            self.write_instruction(&mut out, instruction, k, 4);
+            if self.options.max_memory_pages.is_some() && grows_stack(instruction) {
+                out.push_str("        if (sp > STACK_LIMIT) {\n");
+                self.write_error(&mut out, ErrorCode::StackOverflow, k, 5);
+                out.push_str("          return 2;\n");
+                out.push_str("        }\n");
+            }
+            if *instruction != Instruction::Halt {
+                Self::write_transition(&mut out, instruction, &successors[k]);
+            }
+            out.push_str("      }\n");
+        }
+        out.push_str("      default:\n        return 0;\n    }\n  }\n}\n");
+        Ok(out)
+    }
+
+    /// Handle a runtime error as the error policy says
+    fn write_error(&self, out: &mut String, code: ErrorCode, state: usize, depth: usize) {
+        let pad = "  ".repeat(depth);
+        let code = code as i32;
+        match self.options.on_error {
+            ErrorPolicy::Ignore => {}
+            ErrorPolicy::Report => {
+                let _ = writeln!(out, "{}if (io.onError) io.onError({}, {});", pad, code, state);
+            }
+            ErrorPolicy::Trap => {
+                let _ = writeln!(
+                    out,
+                    "{}throw Object.assign(new Error(\"Piet error {} at state {}\"), {{ code: {}, state: {} }});",
+                    pad, code, state, code, state
+                );
+            }
+        }
+    }
+
+    /// Write the statements of one instruction at `depth` levels of indentation
+    fn write_instruction(&self, out: &mut String, instruction: &Instruction, state: usize, depth: usize) {
+        let mut pad = "  ".repeat(depth);
+        let needed = match instruction {
+            Instruction::Pop
+            | Instruction::Not
+            | Instruction::Duplicate
+            | Instruction::OutNumber
+            | Instruction::OutChar
+            | Instruction::Pointer
+            | Instruction::Switch => 1,
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Mod
+            | Instruction::Greater => 2,
+            // roll() checks the stack itself
+            _ => 0,
+        };
+        if matches!(instruction, Instruction::Pointer | Instruction::Switch) {
+            // An ignored Pointer/Switch keeps DP/CC: branch 0
+            let _ = writeln!(out, "{}branch = 0;", pad);
+        }
+        if needed > 0 {
+            if self.options.on_error == ErrorPolicy::Ignore {
+                let _ = writeln!(out, "{}if (sp >= {}) {{", pad, needed);
+            } else {
+                let _ = writeln!(out, "{}if (sp < {}) {{", pad, needed);
+                self.write_error(out, ErrorCode::StackUnderflow, state, depth + 1);
+                let _ = writeln!(out, "{}}} else {{", pad);
+            }
+            pad += "  ";
+        }
+
+        // Popping two values leaves room for the result
+        let binary = |out: &mut String, op: &str| {
+            let _ = writeln!(out, "{}a = stack[--sp];", pad);
+            let _ = writeln!(out, "{}b = stack[--sp];", pad);
+            let _ = writeln!(out, "{}stack[sp++] = {};", pad, op);
+        };
+        match instruction {
+            Instruction::Push(n) => {
+                let _ = writeln!(out, "{}push({});", pad, n);
+            }
+            Instruction::Pop => {
+                let _ = writeln!(out, "{}sp--;", pad);
+            }
+            Instruction::Add => binary(out, "(b + a) | 0"),
+            Instruction::Subtract => binary(out, "(b - a) | 0"),
+            Instruction::Multiply => binary(out, "Math.imul(b, a)"),
+            Instruction::Greater => binary(out, "b > a ? 1 : 0"),
+            Instruction::Divide | Instruction::Mod => {
+                // A zero divisor leaves the stack untouched; -2147483648 by -1
+                // stores the wrapped result
+                let _ = writeln!(out, "{}if (stack[sp - 1] === 0) {{", pad);
+                self.write_error(out, ErrorCode::DivisionByZero, state, depth + 2);
+                let _ = writeln!(out, "{}}} else {{", pad);
+                let _ = writeln!(out, "{}  a = stack[--sp];", pad);
+                let _ = writeln!(out, "{}  b = stack[--sp];", pad);
+                if self.options.on_error != ErrorPolicy::Ignore {
+                    let _ = writeln!(out, "{}  if (b === -2147483648 && a === -1) {{", pad);
+                    self.write_error(out, ErrorCode::Overflow, state, depth + 3);
+                    let _ = writeln!(out, "{}  }}", pad);
+                }
+                if *instruction == Instruction::Divide {
+                    let _ = writeln!(out, "{}  stack[sp++] = (b / a) | 0;", pad);
+                } else {
+                    // Euclidean: the result takes the sign of neither operand
+                    let _ = writeln!(out, "{}  b %= a;", pad);
+                    let _ = writeln!(out, "{}  stack[sp++] = b < 0 ? b + Math.abs(a) : b;", pad);
+                }
+                let _ = writeln!(out, "{}}}", pad);
+            }
+            Instruction::Not => {
+                let _ = writeln!(out, "{}stack[sp - 1] = stack[sp - 1] === 0 ? 1 : 0;", pad);
+            }
+            Instruction::Duplicate => {
+                let _ = writeln!(out, "{}push(stack[sp - 1]);", pad);
+            }
+            Instruction::Roll => {
+                if self.options.on_error == ErrorPolicy::Ignore {
+                    let _ = writeln!(out, "{}roll();", pad);
+                } else {
+                    let _ = writeln!(out, "{}if (!roll()) {{", pad);
+                    self.write_error(out, ErrorCode::StackUnderflow, state, depth + 1);
+                    let _ = writeln!(out, "{}}}", pad);
+                }
+            }
+            Instruction::InNumber | Instruction::InChar => {
+                // The interpreter stops when it runs out of input
+                let read = if *instruction == Instruction::InNumber { "readNumber" } else { "readChar" };
+                let _ = writeln!(out, "{}a = io.{}();", pad, read);
+                let _ = writeln!(out, "{}if (a === null || a === undefined) return 0;", pad);
+                let _ = writeln!(out, "{}push(a);", pad);
+            }
+            Instruction::OutNumber => {
+                let _ = writeln!(out, "{}io.writeNumber(stack[--sp]);", pad);
+            }
+            Instruction::OutChar => {
+                let _ = writeln!(out, "{}io.writeChar(stack[--sp]);", pad);
+            }
+            Instruction::Pointer => {
+                let _ = writeln!(out, "{}branch = ((stack[--sp] % 4) + 4) % 4;", pad);
+            }
+            Instruction::Switch => {
+                let _ = writeln!(out, "{}branch = stack[--sp] % 2 !== 0 ? 1 : 0;", pad);
+            }
+            Instruction::Nop => {}
+            Instruction::Halt => {
+                let _ = writeln!(out, "{}return 0;", pad);
+            }
+            Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => {
+                // Superinstructions: the push and the operation
+                if let Some(parts) = instruction.unfused() {
+                    for part in &parts {
+                        self.write_instruction(out, part, state, pad.len() / 2);
+                    }
+                }
+            }
+        }
+
+        if needed > 0 {
+            let _ = writeln!(out, "{}}}", &pad[2..]);
+        }
+    }
+
+    /// Set the next state and leave the `case`
+    fn write_transition(out: &mut String, instruction: &Instruction, successors: &[Option<usize>]) {
+        let target = |j: &Option<usize>| j.map_or("return 0;".to_string(), |j| format!("state = {}; break;", j));
+        match instruction {
+            Instruction::Pointer | Instruction::Switch if successors.len() > 1 => {
+                out.push_str("        switch (branch) {\n");
+                for (branch, j) in successors.iter().enumerate() {
+                    let label = if branch + 1 == successors.len() { "default".to_string() } else { format!("case {}", branch) };
+                    let _ = writeln!(out, "          {}: {}", label, target(j));
+                }
+                out.push_str("        }\n        break;\n");
+            }
+            _ => {
+                let target = target(&successors.first().copied().flatten());
+                let _ = writeln!(out, "        {}", target);
+            }
+        }
+    }
+}
+
+impl Default for JsCodegen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
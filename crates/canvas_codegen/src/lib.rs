@@ -29,6 +29,13 @@
 //! └────────────────────────────────────────────────────────┘
 //! ```
 //!
+//! Memory grows a page at a time as the stack does, up to
+//! `CodegenOptions::max_memory_pages`; a push past that stops the run
+//! with [`ExitStatus::StackOverflow`].
+//!
+//! With [`Target::Wasi`] the stack starts at 0x0030, after a scratch area
+//! used by the generated I/O helpers.
+//!
 //! ## Imports
 //!
 //! The generated module imports I/O functions from the host:
@@ -37,10 +44,51 @@
 //! - `env.read_number() -> i32` - Read an integer
 //! - `env.write_char(i32)` - Write a character
 //! - `env.write_number(i32)` - Write an integer
+//! - `env.on_error(code: i32, state: i32)` - Runtime error report, only
+//!   imported with [`ErrorPolicy::Report`]
+//!
+//! With [`Target::Wasi`] it imports `wasi_snapshot_preview1.fd_read`,
+//! `fd_write` and `proc_exit` instead, and runs as a WASI command.
+//!
+//! ## Exports
+//!
+//! - `main()` - Run the program (named by `CodegenOptions::main_function_name`,
+//!   `_start` with [`Target::Wasi`])
+//! - `memory` - The linear memory holding the stack
+//! - `status: i32` - [`ExitStatus`] of the last run
+//! - `fuel: i64` - Steps left, with [`CodegenOptions::max_steps`]
+//! - `stack_pointer() -> i32` - Address one past the top of the stack, with
+//!   [`CodegenOptions::export_stack_pointer`]
+//!
+//! ## Debugging
+//!
+//! With [`CodegenOptions::debug_info`] the module carries a `name` section
+//! and a [`STATE_MAP_SECTION`] custom section locating each Piet state's
+//! code. [`compile_to_wat`] prints the module as WAT text.
+//!
+//! ## Other backends
+//!
+//! [`CCodegen`] produces a self-contained C99 file,
+//! to build Piet programs as native binaries. [`RustCodegen`] emits a Rust
+//! module instead; with the `build` feature, `build::compile_image` does
+//! that from a build script. [`JsCodegen`] emits a plain JavaScript
+//! function with callback I/O, for engines where instantiating WASM costs
+//! more than the program runs.
 
+#[cfg(feature = "build")]
+pub mod build;
+mod c;
+mod debug_info;
+mod js;
+mod rust;
+mod wasi;
 mod wasm;
 
-pub use wasm::{WasmCodegen, CodegenError, CodegenOptions};
+pub use c::CCodegen;
+pub use debug_info::STATE_MAP_SECTION;
+pub use js::JsCodegen;
+pub use rust::RustCodegen;
+pub use wasm::{WasmCodegen, CodegenError, CodegenOptions, ErrorCode, ErrorPolicy, ExitStatus, Target};
 
 use canvas_vm::Program;
 
@@ -73,6 +121,30 @@ pub fn compile_to_wasm_with_options(
     codegen.generate(program)
 }
 
+/// Compile to WAT text, e.g. to review or diff generated code.
+pub fn compile_to_wat(program: &Program, options: CodegenOptions) -> Result<String, CodegenError> {
+    let codegen = WasmCodegen::with_options(options);
+    codegen.generate_wat(program)
+}
+
+/// Compile to a self-contained C99 source file (see [`CCodegen`]).
+pub fn compile_to_c(program: &Program, options: CodegenOptions) -> Result<String, CodegenError> {
+    let codegen = CCodegen::with_options(options);
+    codegen.generate(program)
+}
+
+/// Compile to Rust source defining `pub mod piet` (see [`RustCodegen`]).
+pub fn compile_to_rust(program: &Program, options: CodegenOptions) -> Result<String, CodegenError> {
+    let codegen = RustCodegen::with_options(options);
+    codegen.generate(program)
+}
+
+/// Compile to a JavaScript `function runPiet(io)` (see [`JsCodegen`]).
+pub fn compile_to_js(program: &Program, options: CodegenOptions) -> Result<String, CodegenError> {
+    let codegen = JsCodegen::with_options(options);
+    codegen.generate(program)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -90,6 +162,7 @@ mod tests {
             next_position: vec![],
             width: 0,
             height: 0,
+            successors: vec![],
         }
     }
 
@@ -185,4 +258,106 @@ mod tests {
         println!("WASM size for 'Hi': {} bytes", wasm.len());
         assert!(wasm.len() < 1000, "WASM should be compact");
     }
+
+    /// State map entry: (offset, state, x + 1, y + 1)
+    type StateEntry = (u32, u32, u32, u32);
+
+    /// Offsets right after each `end` in `main`'s body, and the state map
+    fn state_map_and_block_ends(wasm: &[u8]) -> (Vec<StateEntry>, Vec<usize>) {
+        let mut map = Vec::new();
+        let mut ends = Vec::new();
+        let mut main_seen = false;
+        for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+            match payload.unwrap() {
+                wasmparser::Payload::CustomSection(section) if section.name() == STATE_MAP_SECTION => {
+                    let mut reader = wasmparser::BinaryReader::new(section.data(), 0);
+                    for _ in 0..reader.read_var_u32().unwrap() {
+                        let entry: Vec<u32> = (0..4).map(|_| reader.read_var_u32().unwrap()).collect();
+                        map.push((entry[0], entry[1], entry[2], entry[3]));
+                    }
+                    assert!(reader.eof());
+                }
+                wasmparser::Payload::CodeSectionEntry(body) if !main_seen => {
+                    main_seen = true;
+                    let mut ops = body.get_operators_reader().unwrap();
+                    while !ops.eof() {
+                        if let wasmparser::Operator::End = ops.read().unwrap() {
+                            ends.push(ops.original_position());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        (map, ends)
+    }
+
+    #[test]
+    fn test_state_map_points_at_state_code() {
+        let program = make_program(vec![
+            Instruction::Push(3),
+            Instruction::Duplicate,
+            Instruction::Multiply,
+            Instruction::OutNumber,
+            Instruction::Halt,
+        ]);
+        let options = CodegenOptions { debug_info: true, ..CodegenOptions::default() };
+        let wasm = compile_to_wasm_with_options(&program, options).unwrap();
+        wasmparser::validate(&wasm).unwrap();
+
+        // Every state starts right after the `end` of its block
+        let (map, ends) = state_map_and_block_ends(&wasm);
+        assert_eq!(map.len(), 5);
+        for (k, &(offset, state, x, y)) in map.iter().enumerate() {
+            assert_eq!((state, x, y), (k as u32, 0, 0));
+            assert!(ends.contains(&(offset as usize)), "state {}", k);
+        }
+        assert!(map.windows(2).all(|w| w[0].0 < w[1].0));
+
+        // No debug sections by default
+        let (map, _) = state_map_and_block_ends(&compile_to_wasm(&program).unwrap());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_state_map_codel_positions() {
+        use canvas_vm::InstructionDebugInfo;
+        let at = |x, y| InstructionDebugInfo { from_pos: (x, y), ..InstructionDebugInfo::default() };
+        let mut program = Program::new(4, 2);
+        program.add_rich_instruction(Instruction::Push(1), at(0, 0));
+        program.add_instruction(Instruction::OutNumber);
+        program.add_rich_instruction(Instruction::Halt, at(3, 1));
+        program.successors.clear();
+        let options = CodegenOptions { debug_info: true, ..CodegenOptions::default() };
+        let (map, _) = state_map_and_block_ends(&compile_to_wasm_with_options(&program, options.clone()).unwrap());
+        let codels: Vec<_> = map.iter().map(|&(_, state, x, y)| (state, x, y)).collect();
+        assert_eq!(codels, vec![(0, 1, 1), (1, 0, 0), (2, 4, 2)]);
+
+        // Without debug info, the first codel of the instruction's block
+        program.map_position(2, 1, 1);
+        program.map_position(1, 1, 1);
+        program.map_position(3, 0, 0);
+        let (map, _) = state_map_and_block_ends(&compile_to_wasm_with_options(&program, options).unwrap());
+        let codels: Vec<_> = map.iter().map(|&(_, state, x, y)| (state, x, y)).collect();
+        assert_eq!(codels, vec![(0, 1, 1), (1, 2, 2), (2, 4, 2)]);
+    }
+
+    #[test]
+    fn test_wat_output() {
+        let program = make_program(vec![
+            Instruction::Push(72),
+            Instruction::OutChar,
+            Instruction::Halt,
+        ]);
+        let options = CodegenOptions { debug_info: true, ..CodegenOptions::default() };
+        let wat = compile_to_wat(&program, options.clone()).unwrap();
+        for expected in ["(func $main", "(func $stack_push", "(import \"env\" \"write_char\" (func $write_char", "$state_2", "$exit", "(local $state i32)"] {
+            assert!(wat.contains(expected), "missing {:?}", expected);
+        }
+        // Stable output, so it can be diffed
+        assert_eq!(wat, compile_to_wat(&program, options).unwrap());
+
+        let plain = compile_to_wat(&program, CodegenOptions::default()).unwrap();
+        assert!(plain.contains("(func (;4;)") && !plain.contains("$stack_push"));
+    }
 }
@@ -0,0 +1,344 @@
+//! Rust source generator
+//!
+//! Translates a compiled `Program` into a self-contained Rust module with
+//! a `run(io: &mut impl PietIo)` entry point: a `match` state machine over
+//! the program states and a `Vec<i32>` stack, wrapping like `BytecodeVm`.
+//! Meant to be `include!`d, e.g. from a build script (see `build`).
+
+use std::fmt::Write;
+
+use canvas_vm::{Instruction, Program};
+
+use crate::wasm::{grows_stack, successor_table, CodegenError, CodegenOptions, ErrorCode, ErrorPolicy};
+
+/// Items shared by every generated module
+const PRELUDE: &str = r#"
+    /// Input and output of the program
+    pub trait PietIo {
+        /// Next value for `in(number)`; `None` ends the program
+        fn read_number(&mut self) -> Option<i32>;
+        /// Next code point for `in(char)`; `None` ends the program
+        fn read_char(&mut self) -> Option<i32>;
+        /// `out(number)`
+        fn write_number(&mut self, value: i32);
+        /// `out(char)`, as a code point
+        fn write_char(&mut self, value: i32);
+        /// A runtime error, with the `Report` error policy (see `ERR_*`)
+        fn on_error(&mut self, code: i32, state: usize) {
+            let _ = (code, state);
+        }
+    }
+
+    pub const ERR_STACK_UNDERFLOW: i32 = 1;
+    pub const ERR_DIVISION_BY_ZERO: i32 = 2;
+    pub const ERR_OVERFLOW: i32 = 3;
+    pub const ERR_STACK_OVERFLOW: i32 = 4;
+
+    /// How a run ended
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ExitStatus {
+        /// The program halted or ran out of input
+        Halted,
+        /// The step limit was reached
+        OutOfFuel,
+        /// The stack outgrew its limit
+        StackOverflow,
+    }
+
+    /// A runtime error, with the `Trap` error policy
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Error {
+        /// One of `ERR_*`
+        pub code: i32,
+        /// State (instruction index) that failed
+        pub state: usize,
+    }
+
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Piet error {} at state {}", self.code, self.state)
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    /// Rolls the top `depth` elements `times` times; false on underflow
+    fn roll(stack: &mut Vec<i32>) -> bool {
+        if stack.len() < 2 {
+            return false;
+        }
+        let times = stack.pop().unwrap();
+        let depth = stack.pop().unwrap();
+        if depth < 0 {
+            return true;
+        }
+        let depth = depth as usize;
+        if depth > stack.len() {
+            return false;
+        }
+        if depth == 0 {
+            return true;
+        }
+        let times = times.rem_euclid(depth as i32) as usize;
+        let len = stack.len();
+        stack[len - depth..].rotate_right(times);
+        true
+    }
+"#;
+
+/// Rust source code generator
+///
+/// Uses the same [`CodegenOptions`] as the WASM backend: `on_error`,
+/// `max_steps` and `max_memory_pages` (as a stack size limit, 64 KiB per
+/// page); the WASM-specific options are ignored.
+pub struct RustCodegen {
+    options: CodegenOptions,
+    module_name: String,
+}
+
+impl RustCodegen {
+    /// Create a new Rust code generator with default options
+    pub fn new() -> Self {
+        Self::with_options(CodegenOptions::default())
+    }
+
+    /// Create a Rust code generator with custom options
+    pub fn with_options(options: CodegenOptions) -> Self {
+        Self { options, module_name: "piet".to_string() }
+    }
+
+    /// Name of the generated module (default `piet`)
+    pub fn module_name(mut self, name: &str) -> Self {
+        self.module_name = name.to_string();
+        self
+    }
+
+    /// Generate `pub mod <module_name> { ... }` for a program
+    pub fn generate(&self, program: &Program) -> Result<String, CodegenError> {
+        let successors = successor_table(program)?;
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "// Generated by canvas_codegen from a Piet program ({} states). Do not edit.\n",
+            program.instructions.len()
+        );
+        let _ = writeln!(out, "pub mod {} {{", self.module_name);
+        out.push_str("    #![allow(dead_code, unused_assignments, unused_mut, unused_variables, unreachable_code, clippy::all)]\n");
+        out.push_str(PRELUDE);
+        if let Some(pages) = self.options.max_memory_pages {
+            out.push_str("\n    /// Most values the stack may hold\n");
+            let _ = writeln!(out, "    pub const STACK_LIMIT: usize = {} * 65536 / 4;", pages);
+        }
+
+        out.push_str("\n    /// Run the program to the end\n");
+        out.push_str("    pub fn run(io: &mut impl PietIo) -> Result<ExitStatus, Error> {\n");
+        out.push_str("        let mut stack: Vec<i32> = Vec::new();\n");
+        out.push_str("        let mut state: usize = 0;\n");
+        out.push_str("        let mut branch: usize = 0;\n");
+        if let Some(max_steps) = self.options.max_steps {
+            let _ = writeln!(out, "        let mut fuel: u64 = {};", max_steps);
+        }
+        if program.instructions.is_empty() {
+            out.push_str("        Ok(ExitStatus::Halted)\n    }\n}\n");
+            return Ok(out);
+        }
+        out.push_str("        loop {\n            state = match state {\n");
+        for (k, instruction) in program.instructions.iter().enumerate() {
+            let position = program
+                .rich_instructions
+                .get(k)
+                .and_then(|rich| rich.debug.as_ref())
+                .map(|debug| format!(" @ ({}, {})", debug.from_pos.0, debug.from_pos.1))
+                .unwrap_or_default();
+            let _ = writeln!(out, "                {} => {{ // {}{}", k, instruction, position);
+            if self.options.max_steps.is_some() {
+                out.push_str("                    if fuel == 0 {\n");
+                out.push_str("                        return Ok(ExitStatus::OutOfFuel);\n");
+                out.push_str("                    }\n");
+                out.push_str("                    fuel -= 1;\n");
+            }
+            self.write_instruction(&mut out, instruction, k, 5);
+            if self.options.max_memory_pages.is_some() && grows_stack(instruction) {
+                out.push_str("                    if stack.len() > STACK_LIMIT {\n");
+                self.write_error(&mut out, ErrorCode::StackOverflow, k, 6);
+                out.push_str("                        return Ok(ExitStatus::StackOverflow);\n");
+                out.push_str("                    }\n");
+            }
+            if *instruction != Instruction::Halt {
+                Self::write_transition(&mut out, instruction, &successors[k]);
+            }
+            out.push_str("                }\n");
+        }
+        out.push_str("                _ => unreachable!(),\n            };\n        }\n    }\n}\n");
+        Ok(out)
+    }
+
+    /// Handle a runtime error as the error policy says
+    fn write_error(&self, out: &mut String, code: ErrorCode, state: usize, depth: usize) {
+        let pad = "    ".repeat(depth);
+        let name = match code {
+            ErrorCode::StackUnderflow => "ERR_STACK_UNDERFLOW",
+            ErrorCode::DivisionByZero => "ERR_DIVISION_BY_ZERO",
+            ErrorCode::Overflow => "ERR_OVERFLOW",
+            ErrorCode::StackOverflow => "ERR_STACK_OVERFLOW",
+        };
+        match self.options.on_error {
+            ErrorPolicy::Ignore => {}
+            ErrorPolicy::Report => {
+                let _ = writeln!(out, "{}io.on_error({}, {});", pad, name, state);
+            }
+            ErrorPolicy::Trap => {
+                let _ = writeln!(out, "{}return Err(Error {{ code: {}, state: {} }});", pad, name, state);
+            }
+        }
+    }
+
+    /// Write the statements of one instruction at `depth` levels of indentation
+    fn write_instruction(&self, out: &mut String, instruction: &Instruction, state: usize, depth: usize) {
+        let mut pad = "    ".repeat(depth);
+        let needed = match instruction {
+            Instruction::Pop
+            | Instruction::Not
+            | Instruction::Duplicate
+            | Instruction::OutNumber
+            | Instruction::OutChar
+            | Instruction::Pointer
+            | Instruction::Switch => 1,
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Mod
+            | Instruction::Greater => 2,
+            // roll() checks the stack itself
+            _ => 0,
+        };
+        if matches!(instruction, Instruction::Pointer | Instruction::Switch) {
+            // An ignored Pointer/Switch keeps DP/CC: branch 0
+            let _ = writeln!(out, "{}branch = 0;", pad);
+        }
+        if needed > 0 {
+            if self.options.on_error == ErrorPolicy::Ignore {
+                let _ = writeln!(out, "{}if stack.len() >= {} {{", pad, needed);
+            } else {
+                let _ = writeln!(out, "{}if stack.len() < {} {{", pad, needed);
+                self.write_error(out, ErrorCode::StackUnderflow, state, depth + 1);
+                let _ = writeln!(out, "{}}} else {{", pad);
+            }
+            pad += "    ";
+        }
+
+        let binary = |out: &mut String, op: &str| {
+            let _ = writeln!(out, "{}let a = stack.pop().unwrap();", pad);
+            let _ = writeln!(out, "{}let b = stack.pop().unwrap();", pad);
+            let _ = writeln!(out, "{}stack.push({});", pad, op);
+        };
+        match instruction {
+            Instruction::Push(n) => {
+                let _ = writeln!(out, "{}stack.push({});", pad, if *n == i32::MIN { "i32::MIN".to_string() } else { n.to_string() });
+            }
+            Instruction::Pop => {
+                let _ = writeln!(out, "{}stack.pop();", pad);
+            }
+            Instruction::Add => binary(out, "b.wrapping_add(a)"),
+            Instruction::Subtract => binary(out, "b.wrapping_sub(a)"),
+            Instruction::Multiply => binary(out, "b.wrapping_mul(a)"),
+            Instruction::Greater => binary(out, "(b > a) as i32"),
+            Instruction::Divide | Instruction::Mod => {
+                // A zero divisor leaves the stack untouched; i32::MIN by -1
+                // pushes the wrapped result
+                let op = if *instruction == Instruction::Divide { "wrapping_div" } else { "wrapping_rem_euclid" };
+                let _ = writeln!(out, "{}if stack[stack.len() - 1] == 0 {{", pad);
+                self.write_error(out, ErrorCode::DivisionByZero, state, depth + 2);
+                let _ = writeln!(out, "{}}} else {{", pad);
+                let _ = writeln!(out, "{}    let a = stack.pop().unwrap();", pad);
+                let _ = writeln!(out, "{}    let b = stack.pop().unwrap();", pad);
+                if self.options.on_error != ErrorPolicy::Ignore {
+                    let _ = writeln!(out, "{}    if b == i32::MIN && a == -1 {{", pad);
+                    self.write_error(out, ErrorCode::Overflow, state, depth + 3);
+                    let _ = writeln!(out, "{}    }}", pad);
+                }
+                let _ = writeln!(out, "{}    stack.push(b.{}(a));", pad, op);
+                let _ = writeln!(out, "{}}}", pad);
+            }
+            Instruction::Not => {
+                let _ = writeln!(out, "{}let a = stack.pop().unwrap();", pad);
+                let _ = writeln!(out, "{}stack.push((a == 0) as i32);", pad);
+            }
+            Instruction::Duplicate => {
+                let _ = writeln!(out, "{}let a = stack[stack.len() - 1];", pad);
+                let _ = writeln!(out, "{}stack.push(a);", pad);
+            }
+            Instruction::Roll => {
+                if self.options.on_error == ErrorPolicy::Ignore {
+                    let _ = writeln!(out, "{}roll(&mut stack);", pad);
+                } else {
+                    let _ = writeln!(out, "{}if !roll(&mut stack) {{", pad);
+                    self.write_error(out, ErrorCode::StackUnderflow, state, depth + 1);
+                    let _ = writeln!(out, "{}}}", pad);
+                }
+            }
+            Instruction::InNumber | Instruction::InChar => {
+                // The interpreter stops when it runs out of input
+                let read = if *instruction == Instruction::InNumber { "read_number" } else { "read_char" };
+                let _ = writeln!(out, "{}match io.{}() {{", pad, read);
+                let _ = writeln!(out, "{}    Some(value) => stack.push(value),", pad);
+                let _ = writeln!(out, "{}    None => return Ok(ExitStatus::Halted),", pad);
+                let _ = writeln!(out, "{}}}", pad);
+            }
+            Instruction::OutNumber => {
+                let _ = writeln!(out, "{}io.write_number(stack.pop().unwrap());", pad);
+            }
+            Instruction::OutChar => {
+                let _ = writeln!(out, "{}io.write_char(stack.pop().unwrap());", pad);
+            }
+            Instruction::Pointer => {
+                let _ = writeln!(out, "{}branch = stack.pop().unwrap().rem_euclid(4) as usize;", pad);
+            }
+            Instruction::Switch => {
+                let _ = writeln!(out, "{}branch = (stack.pop().unwrap() % 2 != 0) as usize;", pad);
+            }
+            Instruction::Nop => {}
+            Instruction::Halt => {
+                let _ = writeln!(out, "{}return Ok(ExitStatus::Halted);", pad);
+            }
+            Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => {
+                // Superinstructions: the push and the operation
+                if let Some(parts) = instruction.unfused() {
+                    for part in &parts {
+                        self.write_instruction(out, part, state, pad.len() / 4);
+                    }
+                }
+            }
+        }
+
+        if needed > 0 {
+            let _ = writeln!(out, "{}}}", &pad[4..]);
+        }
+    }
+
+    /// The next state, as the value of the state's match arm
+    fn write_transition(out: &mut String, instruction: &Instruction, successors: &[Option<usize>]) {
+        let target = |j: &Option<usize>| j.map_or("return Ok(ExitStatus::Halted)".to_string(), |j| j.to_string());
+        match instruction {
+            Instruction::Pointer | Instruction::Switch if successors.len() > 1 => {
+                out.push_str("                    match branch {\n");
+                for (branch, j) in successors.iter().enumerate() {
+                    let pattern = if branch + 1 == successors.len() { "_".to_string() } else { branch.to_string() };
+                    let _ = writeln!(out, "                        {} => {},", pattern, target(j));
+                }
+                out.push_str("                    }\n");
+            }
+            _ => {
+                let _ = writeln!(out, "                    {}", target(&successors.first().copied().flatten()));
+            }
+        }
+    }
+}
+
+impl Default for RustCodegen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,449 @@
+//! Generated I/O helpers for [`Target::Wasi`](crate::Target::Wasi).
+//!
+//! WASI only moves bytes, so UTF-8 decoding/encoding and decimal number
+//! parsing/formatting live in the module itself. They work on a small
+//! scratch area below the stack:
+//!
+//! ```text
+//! 0x0004: iovec { buf, len }
+//! 0x000C: nread / nwritten
+//! 0x0010: byte buffer (up to 4 bytes of UTF-8)
+//! 0x0018: lookahead byte (-1 if none)
+//! 0x0020: digit buffer (16 bytes, filled backwards)
+//! 0x0030: stack data
+//! ```
+
+use wasm_encoder::{BlockType, Function, Instruction as WasmInst, MemArg, ValType};
+
+use crate::wasm::WasmCodegen;
+
+const IOVEC: i32 = 0x04;
+const NIO: i32 = 0x0C;
+const BYTES: i32 = 0x10;
+const LOOKAHEAD: i32 = 0x18;
+const DIGITS_END: i32 = 0x30;
+/// First stack slot of a WASI module
+pub(crate) const STACK_BASE: i32 = 0x30;
+
+const STDIN: i32 = 0;
+const STDOUT: i32 = 1;
+/// Returned by `read_char` for malformed UTF-8
+const REPLACEMENT_CHAR: i32 = 0xFFFD;
+
+fn word(offset: u64) -> MemArg {
+    MemArg { offset, align: 2, memory_index: 0 }
+}
+
+fn byte(offset: u64) -> MemArg {
+    MemArg { offset, align: 0, memory_index: 0 }
+}
+
+/// Is the byte in `local` ASCII whitespace (space, tab, LF, CR)?
+fn emit_is_space(func: &mut Function, local: u32) {
+    func.instruction(&WasmInst::I32Const(0));
+    for c in [b' ', b'\t', b'\n', b'\r'] {
+        func.instruction(&WasmInst::LocalGet(local));
+        func.instruction(&WasmInst::I32Const(c as i32));
+        func.instruction(&WasmInst::I32Eq);
+        func.instruction(&WasmInst::I32Or);
+    }
+}
+
+impl WasmCodegen {
+    /// Clear the lookahead byte at the start of `_start`
+    pub(crate) fn emit_wasi_init(&self, func: &mut Function) {
+        func.instruction(&WasmInst::I32Const(LOOKAHEAD));
+        func.instruction(&WasmInst::I32Const(-1));
+        func.instruction(&WasmInst::I32Store(word(0)));
+    }
+
+    /// Input ran out: stop like `BytecodeVm` does, with a clean exit
+    fn emit_end_of_input(&self, func: &mut Function) {
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::Call(self.funcs.proc_exit));
+        func.instruction(&WasmInst::Unreachable);
+    }
+
+    /// `read_byte() -> i32`: next stdin byte, or -1 at end of input
+    pub(crate) fn generate_wasi_read_byte(&self) -> Function {
+        let mut func = Function::new(vec![(1, ValType::I32)]);
+        let b = 0;
+
+        // A byte pushed back by read_number/read_char comes first
+        func.instruction(&WasmInst::I32Const(LOOKAHEAD));
+        func.instruction(&WasmInst::I32Load(word(0)));
+        func.instruction(&WasmInst::LocalTee(b));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32GeS);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(LOOKAHEAD));
+        func.instruction(&WasmInst::I32Const(-1));
+        func.instruction(&WasmInst::I32Store(word(0)));
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+
+        // fd_read(stdin, [{BYTES, 1}], 1, NIO)
+        func.instruction(&WasmInst::I32Const(IOVEC));
+        func.instruction(&WasmInst::I32Const(BYTES));
+        func.instruction(&WasmInst::I32Store(word(0)));
+        func.instruction(&WasmInst::I32Const(IOVEC));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::I32Store(word(4)));
+        func.instruction(&WasmInst::I32Const(STDIN));
+        func.instruction(&WasmInst::I32Const(IOVEC));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::I32Const(NIO));
+        func.instruction(&WasmInst::Call(self.funcs.fd_read));
+
+        // An error or zero bytes read both mean end of input
+        func.instruction(&WasmInst::I32Const(NIO));
+        func.instruction(&WasmInst::I32Load(word(0)));
+        func.instruction(&WasmInst::I32Eqz);
+        func.instruction(&WasmInst::I32Or);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(-1));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+
+        func.instruction(&WasmInst::I32Const(BYTES));
+        func.instruction(&WasmInst::I32Load8U(byte(0)));
+        func.instruction(&WasmInst::End);
+        func
+    }
+
+    /// `write_bytes(ptr, len)`: write a buffer to stdout
+    pub(crate) fn generate_wasi_write_bytes(&self) -> Function {
+        let mut func = Function::new(vec![]);
+        let (ptr, len) = (0, 1);
+
+        func.instruction(&WasmInst::I32Const(IOVEC));
+        func.instruction(&WasmInst::LocalGet(ptr));
+        func.instruction(&WasmInst::I32Store(word(0)));
+        func.instruction(&WasmInst::I32Const(IOVEC));
+        func.instruction(&WasmInst::LocalGet(len));
+        func.instruction(&WasmInst::I32Store(word(4)));
+        func.instruction(&WasmInst::I32Const(STDOUT));
+        func.instruction(&WasmInst::I32Const(IOVEC));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::I32Const(NIO));
+        func.instruction(&WasmInst::Call(self.funcs.fd_write));
+        func.instruction(&WasmInst::Drop);
+        func.instruction(&WasmInst::End);
+        func
+    }
+
+    /// `read_char() -> i32`: decode one UTF-8 code point from stdin
+    ///
+    /// Malformed sequences read as U+FFFD; a byte that cannot continue the
+    /// sequence is kept for the next read.
+    pub(crate) fn generate_wasi_read_char(&self) -> Function {
+        let mut func = Function::new(vec![(3, ValType::I32)]);
+        let (b, cp, n) = (0, 1, 2);
+
+        func.instruction(&WasmInst::Call(self.funcs.read_byte));
+        func.instruction(&WasmInst::LocalTee(b));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32LtS);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        self.emit_end_of_input(&mut func);
+        func.instruction(&WasmInst::End);
+
+        // ASCII
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Const(0x80));
+        func.instruction(&WasmInst::I32LtU);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+
+        // Lead byte: number of continuation bytes and the payload bits
+        let leads = [(0xF0, 3, 0x07), (0xE0, 2, 0x0F), (0xC0, 1, 0x1F)];
+        for (lead, count, mask) in leads {
+            func.instruction(&WasmInst::LocalGet(b));
+            func.instruction(&WasmInst::I32Const(lead));
+            func.instruction(&WasmInst::I32GeU);
+            func.instruction(&WasmInst::If(BlockType::Empty));
+            func.instruction(&WasmInst::I32Const(count));
+            func.instruction(&WasmInst::LocalSet(n));
+            func.instruction(&WasmInst::LocalGet(b));
+            func.instruction(&WasmInst::I32Const(mask));
+            func.instruction(&WasmInst::I32And);
+            func.instruction(&WasmInst::LocalSet(cp));
+            func.instruction(&WasmInst::Else);
+        }
+        // A stray continuation byte
+        func.instruction(&WasmInst::I32Const(REPLACEMENT_CHAR));
+        func.instruction(&WasmInst::Return);
+        for _ in leads {
+            func.instruction(&WasmInst::End);
+        }
+
+        // Continuation bytes
+        func.instruction(&WasmInst::Loop(BlockType::Empty));
+        func.instruction(&WasmInst::LocalGet(n));
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::Call(self.funcs.read_byte));
+        func.instruction(&WasmInst::LocalTee(b));
+        func.instruction(&WasmInst::I32Const(0xC0));
+        func.instruction(&WasmInst::I32And);
+        func.instruction(&WasmInst::I32Const(0x80));
+        func.instruction(&WasmInst::I32Ne);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        // Truncated sequence: keep the byte (unless at end of input)
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32GeS);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(LOOKAHEAD));
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Store(word(0)));
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::I32Const(REPLACEMENT_CHAR));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+        // cp = cp << 6 | b & 0x3F
+        func.instruction(&WasmInst::LocalGet(cp));
+        func.instruction(&WasmInst::I32Const(6));
+        func.instruction(&WasmInst::I32Shl);
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Const(0x3F));
+        func.instruction(&WasmInst::I32And);
+        func.instruction(&WasmInst::I32Or);
+        func.instruction(&WasmInst::LocalSet(cp));
+        func.instruction(&WasmInst::LocalGet(n));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::LocalSet(n));
+        func.instruction(&WasmInst::Br(1)); // loop
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::End);
+
+        func.instruction(&WasmInst::LocalGet(cp));
+        func.instruction(&WasmInst::End);
+        func
+    }
+
+    /// `read_number() -> i32`: parse a decimal integer from stdin
+    ///
+    /// Skips leading whitespace and accepts an optional sign. Overflow
+    /// wraps; a token that is not a number is consumed and reads as 0.
+    pub(crate) fn generate_wasi_read_number(&self) -> Function {
+        let mut func = Function::new(vec![(4, ValType::I32)]);
+        let (b, negative, value, digits) = (0, 1, 2, 3);
+
+        // Skip whitespace
+        func.instruction(&WasmInst::Loop(BlockType::Empty));
+        func.instruction(&WasmInst::Call(self.funcs.read_byte));
+        func.instruction(&WasmInst::LocalTee(b));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32LtS);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        self.emit_end_of_input(&mut func);
+        func.instruction(&WasmInst::End);
+        emit_is_space(&mut func, b);
+        func.instruction(&WasmInst::BrIf(0));
+        func.instruction(&WasmInst::End);
+
+        // Sign
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Const(b'-' as i32));
+        func.instruction(&WasmInst::I32Eq);
+        func.instruction(&WasmInst::LocalTee(negative));
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Const(b'+' as i32));
+        func.instruction(&WasmInst::I32Eq);
+        func.instruction(&WasmInst::I32Or);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::Call(self.funcs.read_byte));
+        func.instruction(&WasmInst::LocalSet(b));
+        func.instruction(&WasmInst::End);
+
+        // Digits
+        func.instruction(&WasmInst::Block(BlockType::Empty));
+        func.instruction(&WasmInst::Loop(BlockType::Empty));
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Const(b'0' as i32));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::I32Const(10));
+        func.instruction(&WasmInst::I32GeU);
+        func.instruction(&WasmInst::BrIf(1)); // not a digit (or end of input)
+        func.instruction(&WasmInst::LocalGet(value));
+        func.instruction(&WasmInst::I32Const(10));
+        func.instruction(&WasmInst::I32Mul);
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Const(b'0' as i32));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::I32Add);
+        func.instruction(&WasmInst::LocalSet(value));
+        func.instruction(&WasmInst::LocalGet(digits));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::I32Add);
+        func.instruction(&WasmInst::LocalSet(digits));
+        func.instruction(&WasmInst::Call(self.funcs.read_byte));
+        func.instruction(&WasmInst::LocalSet(b));
+        func.instruction(&WasmInst::Br(0));
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::End);
+
+        func.instruction(&WasmInst::LocalGet(digits));
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        // Keep the byte that ended the number
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32GeS);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(LOOKAHEAD));
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Store(word(0)));
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::Else);
+        // A lone sign at the end of input
+        func.instruction(&WasmInst::LocalGet(b));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32LtS);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        self.emit_end_of_input(&mut func);
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::End);
+
+        // negative ? 0 - value : value
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::LocalGet(value));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::LocalGet(value));
+        func.instruction(&WasmInst::LocalGet(negative));
+        func.instruction(&WasmInst::Select);
+        func.instruction(&WasmInst::End);
+        func
+    }
+
+    /// `write_char(c)`: encode a code point as UTF-8 on stdout
+    ///
+    /// Values that are not Unicode scalar values write nothing, matching
+    /// `Output::read_string`.
+    pub(crate) fn generate_wasi_write_char(&self) -> Function {
+        let mut func = Function::new(vec![(1, ValType::I32)]);
+        let (c, len) = (0, 1);
+
+        // c > 0x10FFFF (unsigned, so negatives too) or a surrogate
+        func.instruction(&WasmInst::LocalGet(c));
+        func.instruction(&WasmInst::I32Const(0x10FFFF));
+        func.instruction(&WasmInst::I32GtU);
+        func.instruction(&WasmInst::LocalGet(c));
+        func.instruction(&WasmInst::I32Const(!0x7FF));
+        func.instruction(&WasmInst::I32And);
+        func.instruction(&WasmInst::I32Const(0xD800));
+        func.instruction(&WasmInst::I32Eq);
+        func.instruction(&WasmInst::I32Or);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+
+        // (upper bound, lead byte) per encoded length
+        let lengths = [(0x80, 0x00), (0x800, 0xC0), (0x10000, 0xE0)];
+        for (i, (bound, lead)) in lengths.iter().enumerate() {
+            func.instruction(&WasmInst::LocalGet(c));
+            func.instruction(&WasmInst::I32Const(*bound));
+            func.instruction(&WasmInst::I32LtU);
+            func.instruction(&WasmInst::If(BlockType::Empty));
+            self.emit_utf8_bytes(&mut func, c, i as u32 + 1, *lead);
+            func.instruction(&WasmInst::I32Const(i as i32 + 1));
+            func.instruction(&WasmInst::LocalSet(len));
+            func.instruction(&WasmInst::Else);
+        }
+        self.emit_utf8_bytes(&mut func, c, 4, 0xF0);
+        func.instruction(&WasmInst::I32Const(4));
+        func.instruction(&WasmInst::LocalSet(len));
+        for _ in lengths {
+            func.instruction(&WasmInst::End);
+        }
+
+        func.instruction(&WasmInst::I32Const(BYTES));
+        func.instruction(&WasmInst::LocalGet(len));
+        func.instruction(&WasmInst::Call(self.funcs.write_bytes));
+        func.instruction(&WasmInst::End);
+        func
+    }
+
+    /// Store the `len`-byte UTF-8 encoding of `c` at BYTES
+    fn emit_utf8_bytes(&self, func: &mut Function, c: u32, len: u32, lead: i32) {
+        for i in 0..len {
+            let shift = 6 * (len - 1 - i) as i32;
+            func.instruction(&WasmInst::I32Const(BYTES));
+            func.instruction(&WasmInst::LocalGet(c));
+            if shift > 0 {
+                func.instruction(&WasmInst::I32Const(shift));
+                func.instruction(&WasmInst::I32ShrU);
+            }
+            if i == 0 {
+                func.instruction(&WasmInst::I32Const(lead));
+            } else {
+                func.instruction(&WasmInst::I32Const(0x3F));
+                func.instruction(&WasmInst::I32And);
+                func.instruction(&WasmInst::I32Const(0x80));
+            }
+            func.instruction(&WasmInst::I32Or);
+            func.instruction(&WasmInst::I32Store8(byte(i as u64)));
+        }
+    }
+
+    /// `write_number(n)`: write `n` in decimal to stdout
+    pub(crate) fn generate_wasi_write_number(&self) -> Function {
+        let mut func = Function::new(vec![(2, ValType::I32)]);
+        let (n, magnitude, ptr) = (0, 1, 2);
+
+        // |n| as unsigned, so i32::MIN works too
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::LocalGet(n));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::LocalGet(n));
+        func.instruction(&WasmInst::LocalGet(n));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32LtS);
+        func.instruction(&WasmInst::Select);
+        func.instruction(&WasmInst::LocalSet(magnitude));
+        func.instruction(&WasmInst::I32Const(DIGITS_END));
+        func.instruction(&WasmInst::LocalSet(ptr));
+
+        // Digits, least significant first, filling the buffer backwards
+        func.instruction(&WasmInst::Loop(BlockType::Empty));
+        func.instruction(&WasmInst::LocalGet(ptr));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::LocalTee(ptr));
+        func.instruction(&WasmInst::LocalGet(magnitude));
+        func.instruction(&WasmInst::I32Const(10));
+        func.instruction(&WasmInst::I32RemU);
+        func.instruction(&WasmInst::I32Const(b'0' as i32));
+        func.instruction(&WasmInst::I32Add);
+        func.instruction(&WasmInst::I32Store8(byte(0)));
+        func.instruction(&WasmInst::LocalGet(magnitude));
+        func.instruction(&WasmInst::I32Const(10));
+        func.instruction(&WasmInst::I32DivU);
+        func.instruction(&WasmInst::LocalTee(magnitude));
+        func.instruction(&WasmInst::BrIf(0));
+        func.instruction(&WasmInst::End);
+
+        func.instruction(&WasmInst::LocalGet(n));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32LtS);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::LocalGet(ptr));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::LocalTee(ptr));
+        func.instruction(&WasmInst::I32Const(b'-' as i32));
+        func.instruction(&WasmInst::I32Store8(byte(0)));
+        func.instruction(&WasmInst::End);
+
+        func.instruction(&WasmInst::LocalGet(ptr));
+        func.instruction(&WasmInst::I32Const(DIGITS_END));
+        func.instruction(&WasmInst::LocalGet(ptr));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::Call(self.funcs.write_bytes));
+        func.instruction(&WasmInst::End);
+        func
+    }
+}
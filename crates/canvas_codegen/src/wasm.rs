@@ -4,11 +4,25 @@
 
 use canvas_vm::{Instruction, Program};
 use wasm_encoder::{
-    CodeSection, ExportKind, ExportSection, Function, FunctionSection,
-    ImportSection, Instruction as WasmInst, MemorySection, MemoryType,
+    BlockType, CodeSection, ConstExpr, ExportKind, ExportSection, Function, FunctionSection,
+    GlobalSection, GlobalType, ImportSection, Instruction as WasmInst, MemorySection, MemoryType,
     Module, TypeSection, ValType,
 };
 
+/// Local of `main` holding the current state (instruction index)
+const STATE_LOCAL: u32 = 0;
+/// Local of `main` holding the branch taken by `Pointer`/`Switch`
+const BRANCH_LOCAL: u32 = 1;
+/// Scratch locals of `main` for binary operations
+const OPERAND_LOCAL: u32 = 2;
+const RESULT_LOCAL: u32 = 3;
+
+/// Global holding the [`ExitStatus`] of the last run, exported as `status`
+const STATUS_GLOBAL: u32 = 0;
+/// Global holding the remaining steps, exported as `fuel` (only with
+/// [`CodegenOptions::max_steps`])
+const FUEL_GLOBAL: u32 = 1;
+
 /// Code generation error
 #[derive(Debug, Clone)]
 pub enum CodegenError {
@@ -18,6 +32,8 @@ pub enum CodegenError {
     StackUnderflow(String),
     /// Unsupported operation
     Unsupported(String),
+    /// The generated module could not be disassembled
+    InvalidModule(String),
 }
 
 impl std::fmt::Display for CodegenError {
@@ -26,6 +42,7 @@ impl std::fmt::Display for CodegenError {
             CodegenError::InvalidSequence(msg) => write!(f, "Invalid sequence: {}", msg),
             CodegenError::StackUnderflow(msg) => write!(f, "Stack underflow: {}", msg),
             CodegenError::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
+            CodegenError::InvalidModule(msg) => write!(f, "Invalid module: {}", msg),
         }
     }
 }
@@ -39,10 +56,81 @@ pub struct CodegenOptions {
     pub memory_pages: u32,
     /// Maximum memory pages (None = unlimited)
     pub max_memory_pages: Option<u32>,
-    /// Export the stack pointer for debugging
+    /// Export a `stack_pointer() -> i32` function returning the address
+    /// one past the top of the stack, so hosts can read the stack from
+    /// `memory` after a run
     pub export_stack_pointer: bool,
     /// Name of the exported main function
     pub main_function_name: String,
+    /// What generated code does on stack underflow, division by zero and
+    /// division overflow
+    pub on_error: ErrorPolicy,
+    /// Step limit: every executed state spends one unit of the exported
+    /// `fuel` global, and the program stops with
+    /// [`ExitStatus::OutOfFuel`] when it runs out (None = unbounded)
+    pub max_steps: Option<u64>,
+    /// Where I/O comes from (see [`Target`])
+    pub target: Target,
+    /// Emit a `name` section and a [`STATE_MAP_SECTION`](crate::STATE_MAP_SECTION)
+    /// mapping code offsets to Piet states and codels
+    pub debug_info: bool,
+}
+
+/// Runtime the generated module is meant for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+    /// Imports `env.read_char`/`read_number`/`write_char`/`write_number`
+    /// from the embedding host and exports the main function
+    #[default]
+    Host,
+    /// A WASI command: I/O goes through `wasi_snapshot_preview1`
+    /// `fd_read`/`fd_write` on stdin/stdout (UTF-8 text, decimal numbers),
+    /// the entry point is `_start`, and a run that does not halt exits
+    /// with its [`ExitStatus`] as the process exit code. Reading past the
+    /// end of stdin ends the program with exit code 0, as `BytecodeVm`
+    /// stops when it runs out of input.
+    Wasi,
+}
+
+/// Why the main function returned, read from the exported `status` global
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ExitStatus {
+    /// The program halted (or was never started)
+    Halted = 0,
+    /// The step limit was reached
+    OutOfFuel = 1,
+    /// A push needed more memory than `max_memory_pages` allows
+    StackOverflow = 2,
+}
+
+/// How generated code handles runtime errors
+///
+/// With [`ErrorPolicy::Ignore`] the instruction leaves the stack as
+/// `BytecodeVm` does: underflow and division by zero change nothing, and
+/// `i32::MIN / -1` wraps. `Report` behaves the same after calling the
+/// `env.on_error(code, state)` import with an [`ErrorCode`] and the index
+/// of the failing instruction; the host may throw to stop execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    #[default]
+    Ignore,
+    /// Execute `unreachable`
+    Trap,
+    /// Call `env.on_error(code, state)` and carry on as with `Ignore`
+    Report,
+}
+
+/// Error codes passed to `env.on_error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ErrorCode {
+    StackUnderflow = 1,
+    DivisionByZero = 2,
+    /// `i32::MIN / -1` or `i32::MIN % -1`
+    Overflow = 3,
+    /// The stack cannot grow any further (the run stops)
+    StackOverflow = 4,
 }
 
 impl Default for CodegenOptions {
@@ -52,6 +140,10 @@ impl Default for CodegenOptions {
             max_memory_pages: Some(16), // 1MB max
             export_stack_pointer: false,
             main_function_name: "main".to_string(),
+            on_error: ErrorPolicy::Ignore,
+            max_steps: None,
+            target: Target::Host,
+            debug_info: false,
         }
     }
 }
@@ -60,24 +152,90 @@ impl Default for CodegenOptions {
 ///
 /// Compiles Piet bytecode to WebAssembly binary format.
 pub struct WasmCodegen {
-    options: CodegenOptions,
+    pub(crate) options: CodegenOptions,
+    pub(crate) funcs: Funcs,
+}
+
+/// Function indices of the generated module (imports come first)
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Funcs {
+    pub(crate) read_char: u32,
+    pub(crate) read_number: u32,
+    pub(crate) write_char: u32,
+    pub(crate) write_number: u32,
+    /// Only imported with [`ErrorPolicy::Report`]
+    pub(crate) on_error: u32,
+    pub(crate) main: u32,
+    pub(crate) stack_push: u32,
+    pub(crate) stack_pop: u32,
+    pub(crate) stack_peek: u32,
+    pub(crate) stack_size: u32,
+    pub(crate) stack_roll: u32,
+    pub(crate) stack_reverse: u32,
+    pub(crate) stack_pointer: u32,
+    // Only with [`Target::Wasi`]
+    pub(crate) fd_read: u32,
+    pub(crate) fd_write: u32,
+    pub(crate) proc_exit: u32,
+    pub(crate) read_byte: u32,
+    pub(crate) write_bytes: u32,
+}
+
+impl Funcs {
+    fn new(options: &CodegenOptions) -> Self {
+        let wasi = options.target == Target::Wasi;
+        let imports = match (wasi, options.on_error) {
+            (true, _) => 3,
+            (false, ErrorPolicy::Report) => 5,
+            (false, _) => 4,
+        };
+        let main = imports;
+        // With WASI, the I/O functions are defined after the stack helpers
+        let io = main + 8;
+        let (read_char, read_number, write_char, write_number) = if wasi { (io, io + 1, io + 2, io + 3) } else { (0, 1, 2, 3) };
+        Self {
+            read_char,
+            read_number,
+            write_char,
+            write_number,
+            on_error: 4,
+            main,
+            stack_push: main + 1,
+            stack_pop: main + 2,
+            stack_peek: main + 3,
+            stack_size: main + 4,
+            stack_roll: main + 5,
+            stack_reverse: main + 6,
+            stack_pointer: main + 7,
+            fd_read: 0,
+            fd_write: 1,
+            proc_exit: 2,
+            read_byte: io + 4,
+            write_bytes: io + 5,
+        }
+    }
 }
 
 impl WasmCodegen {
     /// Create a new code generator with default options
     pub fn new() -> Self {
-        Self {
-            options: CodegenOptions::default(),
-        }
+        Self::with_options(CodegenOptions::default())
     }
 
     /// Create with custom options
     pub fn with_options(options: CodegenOptions) -> Self {
-        Self { options }
+        let funcs = Funcs::new(&options);
+        Self { options, funcs }
     }
 
     /// Generate WASM binary from a compiled Piet program
     pub fn generate(&self, program: &Program) -> Result<Vec<u8>, CodegenError> {
+        let wasi = self.options.target == Target::Wasi;
+        if wasi && self.options.on_error == ErrorPolicy::Report {
+            return Err(CodegenError::Unsupported(
+                "WASI modules cannot import env.on_error; use ErrorPolicy::Ignore or Trap".to_string(),
+            ));
+        }
         let mut module = Module::new();
 
         // === Type Section ===
@@ -93,45 +251,86 @@ impl WasmCodegen {
         // Type 2: (i32) -> () for write_char, write_number
         types.ty().function(vec![ValType::I32], vec![]);
 
+        // Type 3: (i32, i32) -> () for stack_reverse, on_error, write_bytes
+        types.ty().function(vec![ValType::I32, ValType::I32], vec![]);
+
+        // Type 4: (i32, i32, i32, i32) -> i32 for fd_read, fd_write
+        types.ty().function(vec![ValType::I32; 4], vec![ValType::I32]);
+
         module.section(&types);
 
         // === Import Section ===
         // Import I/O functions from host
         let mut imports = ImportSection::new();
-        
-        // env.read_char: () -> i32
-        imports.import("env", "read_char", wasm_encoder::EntityType::Function(1));
-        // env.read_number: () -> i32
-        imports.import("env", "read_number", wasm_encoder::EntityType::Function(1));
-        // env.write_char: (i32) -> ()
-        imports.import("env", "write_char", wasm_encoder::EntityType::Function(2));
-        // env.write_number: (i32) -> ()
-        imports.import("env", "write_number", wasm_encoder::EntityType::Function(2));
+
+        if wasi {
+            // fd_read(fd, iovs, iovs_len, nread) -> errno
+            imports.import(
+                "wasi_snapshot_preview1",
+                "fd_read",
+                wasm_encoder::EntityType::Function(4),
+            );
+            // fd_write(fd, iovs, iovs_len, nwritten) -> errno
+            imports.import(
+                "wasi_snapshot_preview1",
+                "fd_write",
+                wasm_encoder::EntityType::Function(4),
+            );
+            // proc_exit(code)
+            imports.import(
+                "wasi_snapshot_preview1",
+                "proc_exit",
+                wasm_encoder::EntityType::Function(2),
+            );
+        } else {
+            // env.read_char: () -> i32
+            imports.import("env", "read_char", wasm_encoder::EntityType::Function(1));
+            // env.read_number: () -> i32
+            imports.import("env", "read_number", wasm_encoder::EntityType::Function(1));
+            // env.write_char: (i32) -> ()
+            imports.import("env", "write_char", wasm_encoder::EntityType::Function(2));
+            // env.write_number: (i32) -> ()
+            imports.import("env", "write_number", wasm_encoder::EntityType::Function(2));
+            if self.options.on_error == ErrorPolicy::Report {
+                // env.on_error: (code: i32, state: i32) -> ()
+                imports.import("env", "on_error", wasm_encoder::EntityType::Function(3));
+            }
+        }
 
         module.section(&imports);
 
         // === Function Section ===
-        // Declare our functions (main + helpers)
+        // Declare our functions (main + helpers), in the order of `Funcs`
         let mut functions = FunctionSection::new();
         
-        // Function 4 (after 4 imports): main
-        functions.function(0); // Type 0: () -> ()
-        
-        // Function 5: stack_push (helper)
-        // Type: (i32) -> ()
+        // main: () -> ()
+        functions.function(0);
+        // stack_push: (i32) -> ()
         functions.function(2);
-        
-        // Function 6: stack_pop (helper)
-        // Type: () -> i32
+        // stack_pop: () -> i32
         functions.function(1);
-        
-        // Function 7: stack_peek (helper) - read top without popping
-        // Type: () -> i32
+        // stack_peek: () -> i32 (read top without popping)
         functions.function(1);
-        
-        // Function 8: stack_size (helper)
-        // Type: () -> i32
+        // stack_size: () -> i32
+        functions.function(1);
+        // stack_roll: () -> i32 (underflow flag)
         functions.function(1);
+        // stack_reverse: (i32, i32) -> ()
+        functions.function(3);
+        // stack_pointer: () -> i32
+        functions.function(1);
+        if wasi {
+            // read_char, read_number: () -> i32
+            functions.function(1);
+            functions.function(1);
+            // write_char, write_number: (i32) -> ()
+            functions.function(2);
+            functions.function(2);
+            // read_byte: () -> i32
+            functions.function(1);
+            // write_bytes: (i32, i32) -> ()
+            functions.function(3);
+        }
 
         module.section(&functions);
 
@@ -148,22 +347,42 @@ impl WasmCodegen {
 
         module.section(&memories);
 
+        // === Global Section ===
+        let mut globals = GlobalSection::new();
+        let mutable = |val_type| GlobalType { val_type, mutable: true, shared: false };
+        globals.global(mutable(ValType::I32), &ConstExpr::i32_const(ExitStatus::Halted as i32));
+        if let Some(max_steps) = self.options.max_steps {
+            globals.global(mutable(ValType::I64), &ConstExpr::i64_const(max_steps.min(i64::MAX as u64) as i64));
+        }
+
+        module.section(&globals);
+
         // === Export Section ===
         let mut exports = ExportSection::new();
         
-        // Export main function
-        exports.export(&self.options.main_function_name, ExportKind::Func, 4);
+        // Export main function (WASI runtimes look for `_start`)
+        let main_name = if wasi { "_start" } else { self.options.main_function_name.as_str() };
+        exports.export(main_name, ExportKind::Func, self.funcs.main);
         
         // Export memory for host inspection
         exports.export("memory", ExportKind::Memory, 0);
 
+        // Export the exit status and the remaining steps
+        exports.export("status", ExportKind::Global, STATUS_GLOBAL);
+        if self.options.max_steps.is_some() {
+            exports.export("fuel", ExportKind::Global, FUEL_GLOBAL);
+        }
+        if self.options.export_stack_pointer {
+            exports.export("stack_pointer", ExportKind::Func, self.funcs.stack_pointer);
+        }
+
         module.section(&exports);
 
         // === Code Section ===
         let mut codes = CodeSection::new();
 
         // Generate main function body
-        let main_func = self.generate_main(program)?;
+        let (main_func, state_offsets) = self.generate_main(program)?;
         codes.function(&main_func);
 
         // Generate helper functions
@@ -171,154 +390,378 @@ impl WasmCodegen {
         codes.function(&self.generate_stack_pop());
         codes.function(&self.generate_stack_peek());
         codes.function(&self.generate_stack_size());
+        codes.function(&self.generate_stack_roll());
+        codes.function(&self.generate_stack_reverse());
+        codes.function(&self.generate_stack_pointer());
+        if wasi {
+            codes.function(&self.generate_wasi_read_char());
+            codes.function(&self.generate_wasi_read_number());
+            codes.function(&self.generate_wasi_write_char());
+            codes.function(&self.generate_wasi_write_number());
+            codes.function(&self.generate_wasi_read_byte());
+            codes.function(&self.generate_wasi_write_bytes());
+        }
 
+        let code_section = module.len();
         module.section(&codes);
 
+        // === Debug Sections ===
+        if self.options.debug_info {
+            module.section(&self.state_map_section(
+                program,
+                &state_offsets,
+                code_section,
+                codes.byte_len(),
+                codes.len(),
+                main_func.byte_len(),
+            ));
+            module.section(&self.name_section(state_offsets.len()));
+        }
+
         Ok(module.finish())
     }
 
+    /// Generate the module as WAT text
+    ///
+    /// Combine with `debug_info` to get named functions, locals and labels.
+    pub fn generate_wat(&self, program: &Program) -> Result<String, CodegenError> {
+        let wasm = self.generate(program)?;
+        wasmprinter::print_bytes(&wasm).map_err(|e| CodegenError::InvalidModule(e.to_string()))
+    }
+
     /// Generate the main function that executes the Piet program
-    fn generate_main(&self, program: &Program) -> Result<Function, CodegenError> {
-        let mut func = Function::new(vec![]);
+    ///
+    /// The program's state graph becomes a dispatch loop: a `br_table` on
+    /// the current state jumps into the code of that state, which then
+    /// either falls through to the next state, stores its successor and
+    /// branches back to the loop, or leaves through the exit block.
+    ///
+    /// ```text
+    /// loop $dispatch
+    ///   block $exit
+    ///     block $s(n-1) ... block $s0
+    ///       br_table $s0 .. $s(n-1) $exit (local.get $state)
+    ///     end  ;; code of state 0, then its transition
+    ///     ...
+    ///     end  ;; code of state n-1, then its transition
+    ///   end
+    /// end
+    /// ```
+    ///
+    /// Also returns where the code of each state starts in the body.
+    fn generate_main(&self, program: &Program) -> Result<(Function, Vec<usize>), CodegenError> {
+        let mut func = Function::new(vec![(4, ValType::I32)]); // state, branch, operand, result
 
-        // Initialize stack pointer at memory[0] = 4 (skip the SP itself)
+        // Initialize stack pointer at memory[0] = stack base (skip the SP
+        // itself, and the I/O scratch area with WASI)
         func.instruction(&WasmInst::I32Const(0));  // address 0
-        func.instruction(&WasmInst::I32Const(4));  // initial SP value
+        func.instruction(&WasmInst::I32Const(self.stack_base()));  // initial SP value
         func.instruction(&WasmInst::I32Store(wasm_encoder::MemArg {
             offset: 0,
             align: 2,
             memory_index: 0,
         }));
+        if self.options.target == Target::Wasi {
+            self.emit_wasi_init(&mut func);
+        }
+
+        // Every run starts with a full tank
+        func.instruction(&WasmInst::I32Const(ExitStatus::Halted as i32));
+        func.instruction(&WasmInst::GlobalSet(STATUS_GLOBAL));
+        if let Some(max_steps) = self.options.max_steps {
+            func.instruction(&WasmInst::I64Const(max_steps.min(i64::MAX as u64) as i64));
+            func.instruction(&WasmInst::GlobalSet(FUEL_GLOBAL));
+        }
+
+        let successors = successor_table(program)?;
+        let n = successors.len() as u32;
+        if n == 0 {
+            func.instruction(&WasmInst::End);
+            return Ok((func, Vec::new()));
+        }
+
+        func.instruction(&WasmInst::Loop(BlockType::Empty));
+        func.instruction(&WasmInst::Block(BlockType::Empty)); // $exit
+        for _ in 0..n {
+            func.instruction(&WasmInst::Block(BlockType::Empty));
+        }
+        // Execution starts at state 0 (locals are zero-initialized)
+        func.instruction(&WasmInst::LocalGet(STATE_LOCAL));
+        func.instruction(&WasmInst::BrTable((0..n).collect(), n));
+
+        let mut state_offsets = Vec::with_capacity(n as usize);
+        for (k, instruction) in program.instructions.iter().enumerate() {
+            func.instruction(&WasmInst::End); // block $s{k}
+            state_offsets.push(func.byte_len());
+            // From here, blocks $s{k+1}..$s{n-1} are still open, then $exit
+            let exit_depth = n - 1 - k as u32;
+            if self.options.max_steps.is_some() {
+                self.emit_fuel_check(&mut func, exit_depth);
+            }
+            self.emit_instruction(&mut func, instruction, k)?;
+            if grows_stack(instruction) {
+                self.emit_overflow_check(&mut func, k, exit_depth);
+            }
+            if *instruction == Instruction::Halt {
+                func.instruction(&WasmInst::Br(exit_depth)); // $exit
+            } else {
+                self.emit_transition(&mut func, k, instruction, &successors[k], exit_depth, n);
+            }
+        }
 
-        // Generate code for each instruction
-        for instruction in &program.instructions {
-            self.emit_instruction(&mut func, instruction)?;
+        func.instruction(&WasmInst::End); // block $exit
+        func.instruction(&WasmInst::End); // loop $dispatch
+        if self.options.target == Target::Wasi {
+            // A run that did not halt exits with its status
+            func.instruction(&WasmInst::GlobalGet(STATUS_GLOBAL));
+            func.instruction(&WasmInst::If(BlockType::Empty));
+            func.instruction(&WasmInst::GlobalGet(STATUS_GLOBAL));
+            func.instruction(&WasmInst::Call(self.funcs.proc_exit));
+            func.instruction(&WasmInst::End);
         }
+        func.instruction(&WasmInst::End);
+        Ok((func, state_offsets))
+    }
 
+    /// Leave through `$exit` if the last push hit the memory limit
+    ///
+    /// `stack_push` only sets [`ExitStatus::StackOverflow`]; the error is
+    /// handled here, where the state is known.
+    fn emit_overflow_check(&self, func: &mut Function, state: usize, exit_depth: u32) {
+        func.instruction(&WasmInst::GlobalGet(STATUS_GLOBAL));
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        self.emit_error(func, ErrorCode::StackOverflow, state);
+        func.instruction(&WasmInst::Br(exit_depth + 1)); // $exit, from inside the if
         func.instruction(&WasmInst::End);
-        Ok(func)
     }
 
-    /// Emit WASM instructions for a single Piet instruction
+    /// Address of the first stack element
+    pub(crate) fn stack_base(&self) -> i32 {
+        match self.options.target {
+            Target::Host => 4,
+            Target::Wasi => crate::wasi::STACK_BASE,
+        }
+    }
+
+    /// Spend one step, leaving through `$exit` with
+    /// [`ExitStatus::OutOfFuel`] when there are none left
+    fn emit_fuel_check(&self, func: &mut Function, exit_depth: u32) {
+        func.instruction(&WasmInst::GlobalGet(FUEL_GLOBAL));
+        func.instruction(&WasmInst::I64Eqz);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(ExitStatus::OutOfFuel as i32));
+        func.instruction(&WasmInst::GlobalSet(STATUS_GLOBAL));
+        func.instruction(&WasmInst::Br(exit_depth + 1)); // $exit, from inside the if
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::GlobalGet(FUEL_GLOBAL));
+        func.instruction(&WasmInst::I64Const(1));
+        func.instruction(&WasmInst::I64Sub);
+        func.instruction(&WasmInst::GlobalSet(FUEL_GLOBAL));
+    }
+
+    /// Emit the jump from state `k` to its successor
+    ///
+    /// `Pointer` and `Switch` pick the successor with the branch local set
+    /// by `emit_instruction`; a missing successor ends the program.
+    fn emit_transition(
+        &self,
+        func: &mut Function,
+        k: usize,
+        instruction: &Instruction,
+        successors: &[Option<usize>],
+        exit_depth: u32,
+        n: u32,
+    ) {
+        let branches = match instruction {
+            Instruction::Pointer => 4,
+            Instruction::Switch => 2,
+            _ => 1,
+        };
+        let target = |branch: usize| successors.get(branch).copied().flatten();
+
+        // Every branch goes to the same place
+        if (1..branches).all(|b| target(b) == target(0)) {
+            match target(0) {
+                // The next state's code comes right after this one
+                Some(j) if j == k + 1 => {}
+                Some(j) => {
+                    func.instruction(&WasmInst::I32Const(j as i32));
+                    func.instruction(&WasmInst::LocalSet(STATE_LOCAL));
+                    func.instruction(&WasmInst::Br(exit_depth + 1)); // $dispatch
+                }
+                None => {
+                    func.instruction(&WasmInst::Br(exit_depth)); // $exit
+                }
+            }
+            return;
+        }
+
+        // state = successors[branch], with state n (the br_table default)
+        // standing for "stop"
+        let state = |branch: usize| target(branch).map_or(n as i32, |j| j as i32);
+        func.instruction(&WasmInst::I32Const(state(0)));
+        func.instruction(&WasmInst::LocalSet(STATE_LOCAL));
+        for branch in 1..branches {
+            func.instruction(&WasmInst::I32Const(state(branch)));
+            func.instruction(&WasmInst::LocalGet(STATE_LOCAL));
+            func.instruction(&WasmInst::LocalGet(BRANCH_LOCAL));
+            func.instruction(&WasmInst::I32Const(branch as i32));
+            func.instruction(&WasmInst::I32Eq);
+            func.instruction(&WasmInst::Select);
+            func.instruction(&WasmInst::LocalSet(STATE_LOCAL));
+        }
+        func.instruction(&WasmInst::Br(exit_depth + 1)); // $dispatch
+    }
+
+    /// Emit WASM instructions for a single Piet instruction of `state`
+    ///
+    /// Instructions that need more elements than the stack holds are
+    /// skipped, reporting the underflow as the error policy says.
     fn emit_instruction(
         &self,
         func: &mut Function,
         instruction: &Instruction,
+        state: usize,
     ) -> Result<(), CodegenError> {
+        let needed = match instruction {
+            Instruction::Pop
+            | Instruction::Not
+            | Instruction::Duplicate
+            | Instruction::OutNumber
+            | Instruction::OutChar
+            | Instruction::Pointer
+            | Instruction::Switch => 1,
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Mod
+            | Instruction::Greater => 2,
+            // Roll checks the stack in its helper
+            _ => 0,
+        };
+        if matches!(instruction, Instruction::Pointer | Instruction::Switch) {
+            // An ignored Pointer/Switch keeps DP/CC: branch 0
+            func.instruction(&WasmInst::I32Const(0));
+            func.instruction(&WasmInst::LocalSet(BRANCH_LOCAL));
+        }
+        if needed > 0 {
+            // if stack_size < needed { error } else { ... }
+            func.instruction(&WasmInst::Call(self.funcs.stack_size));
+            func.instruction(&WasmInst::I32Const(needed));
+            func.instruction(&WasmInst::I32LtU);
+            func.instruction(&WasmInst::If(BlockType::Empty));
+            self.emit_error(func, ErrorCode::StackUnderflow, state);
+            func.instruction(&WasmInst::Else);
+        }
+
         match instruction {
             Instruction::Push(n) => {
                 // call stack_push(n)
                 func.instruction(&WasmInst::I32Const(*n));
-                func.instruction(&WasmInst::Call(5)); // stack_push
+                func.instruction(&WasmInst::Call(self.funcs.stack_push));
             }
 
             Instruction::Pop => {
                 // call stack_pop() and discard
-                func.instruction(&WasmInst::Call(6)); // stack_pop
+                func.instruction(&WasmInst::Call(self.funcs.stack_pop));
                 func.instruction(&WasmInst::Drop);
             }
 
             Instruction::Add => {
                 // a = pop(), b = pop(), push(b + a)
-                func.instruction(&WasmInst::Call(6)); // a
-                func.instruction(&WasmInst::Call(6)); // b
+                self.emit_operands(func);
                 func.instruction(&WasmInst::I32Add);
-                func.instruction(&WasmInst::Call(5)); // push result
+                func.instruction(&WasmInst::Call(self.funcs.stack_push)); // push result
             }
 
             Instruction::Subtract => {
                 // a = pop(), b = pop(), push(b - a)
-                func.instruction(&WasmInst::Call(6)); // a
-                func.instruction(&WasmInst::Call(6)); // b
+                self.emit_operands(func);
                 func.instruction(&WasmInst::I32Sub);
-                func.instruction(&WasmInst::Call(5)); // push result
+                func.instruction(&WasmInst::Call(self.funcs.stack_push)); // push result
             }
 
             Instruction::Multiply => {
                 // a = pop(), b = pop(), push(b * a)
-                func.instruction(&WasmInst::Call(6)); // a
-                func.instruction(&WasmInst::Call(6)); // b
+                self.emit_operands(func);
                 func.instruction(&WasmInst::I32Mul);
-                func.instruction(&WasmInst::Call(5)); // push result
-            }
-
-            Instruction::Divide => {
-                // a = pop(), b = pop(), push(b / a)
-                func.instruction(&WasmInst::Call(6)); // a (divisor)
-                func.instruction(&WasmInst::Call(6)); // b (dividend)
-                // Handle division by zero: if a == 0, return 0
-                // For simplicity, use signed division (Piet spec)
-                func.instruction(&WasmInst::I32DivS);
-                func.instruction(&WasmInst::Call(5)); // push result
+                func.instruction(&WasmInst::Call(self.funcs.stack_push)); // push result
             }
 
-            Instruction::Mod => {
-                // a = pop(), b = pop(), push(b % a)
-                func.instruction(&WasmInst::Call(6)); // a
-                func.instruction(&WasmInst::Call(6)); // b
-                func.instruction(&WasmInst::I32RemS);
-                func.instruction(&WasmInst::Call(5)); // push result
+            Instruction::Divide | Instruction::Mod => {
+                self.emit_division(func, instruction, state);
             }
 
             Instruction::Not => {
                 // a = pop(), push(a == 0 ? 1 : 0)
-                func.instruction(&WasmInst::Call(6)); // a
+                func.instruction(&WasmInst::Call(self.funcs.stack_pop)); // a
                 func.instruction(&WasmInst::I32Eqz);
-                func.instruction(&WasmInst::Call(5)); // push result
+                func.instruction(&WasmInst::Call(self.funcs.stack_push)); // push result
             }
 
             Instruction::Greater => {
                 // a = pop(), b = pop(), push(b > a ? 1 : 0)
-                func.instruction(&WasmInst::Call(6)); // a
-                func.instruction(&WasmInst::Call(6)); // b
+                self.emit_operands(func);
                 func.instruction(&WasmInst::I32GtS);
-                func.instruction(&WasmInst::Call(5)); // push result
+                func.instruction(&WasmInst::Call(self.funcs.stack_push)); // push result
             }
 
             Instruction::Duplicate => {
                 // a = peek(), push(a)
-                func.instruction(&WasmInst::Call(7)); // stack_peek
-                func.instruction(&WasmInst::Call(5)); // stack_push
+                func.instruction(&WasmInst::Call(self.funcs.stack_peek));
+                func.instruction(&WasmInst::Call(self.funcs.stack_push));
             }
 
             Instruction::Roll => {
-                // This is complex - needs runtime helper
-                // For now, emit a simpler version
                 // times = pop(), depth = pop()
-                // Roll depth elements, times times
-                self.emit_roll(func)?;
+                // Roll depth elements, times times (runtime helper)
+                self.emit_roll(func, state)?;
             }
 
             Instruction::InNumber => {
                 // Read number from host and push
-                func.instruction(&WasmInst::Call(1)); // env.read_number
-                func.instruction(&WasmInst::Call(5)); // stack_push
+                func.instruction(&WasmInst::Call(self.funcs.read_number));
+                func.instruction(&WasmInst::Call(self.funcs.stack_push));
             }
 
             Instruction::InChar => {
                 // Read char from host and push
-                func.instruction(&WasmInst::Call(0)); // env.read_char
-                func.instruction(&WasmInst::Call(5)); // stack_push
+                func.instruction(&WasmInst::Call(self.funcs.read_char));
+                func.instruction(&WasmInst::Call(self.funcs.stack_push));
             }
 
             Instruction::OutNumber => {
                 // Pop and write number to host
-                func.instruction(&WasmInst::Call(6)); // stack_pop
-                func.instruction(&WasmInst::Call(3)); // env.write_number
+                func.instruction(&WasmInst::Call(self.funcs.stack_pop));
+                func.instruction(&WasmInst::Call(self.funcs.write_number));
             }
 
             Instruction::OutChar => {
                 // Pop and write char to host
-                func.instruction(&WasmInst::Call(6)); // stack_pop
-                func.instruction(&WasmInst::Call(2)); // env.write_char
+                func.instruction(&WasmInst::Call(self.funcs.stack_pop));
+                func.instruction(&WasmInst::Call(self.funcs.write_char));
             }
 
             Instruction::Pointer | Instruction::Switch => {
-                // These modify DP/CC which is already resolved at compile time
-                // In the linear bytecode, they become NOPs
-                // (control flow was determined during compilation)
-                func.instruction(&WasmInst::Call(6)); // pop the argument
-                func.instruction(&WasmInst::Drop);    // discard it
+                // DP/CC changes select the successor state:
+                // branch = n mod 4 (Pointer) or n odd (Switch)
+                func.instruction(&WasmInst::Call(self.funcs.stack_pop)); // n
+                if *instruction == Instruction::Pointer {
+                    // rem_euclid(n, 4)
+                    func.instruction(&WasmInst::I32Const(4));
+                    func.instruction(&WasmInst::I32RemS);
+                    func.instruction(&WasmInst::I32Const(4));
+                    func.instruction(&WasmInst::I32Add);
+                    func.instruction(&WasmInst::I32Const(4));
+                    func.instruction(&WasmInst::I32RemU);
+                } else {
+                    func.instruction(&WasmInst::I32Const(2));
+                    func.instruction(&WasmInst::I32RemS);
+                    func.instruction(&WasmInst::I32Const(0));
+                    func.instruction(&WasmInst::I32Ne);
+                }
+                func.instruction(&WasmInst::LocalSet(BRANCH_LOCAL));
             }
 
             Instruction::Nop => {
@@ -327,32 +770,128 @@ impl WasmCodegen {
             }
 
             Instruction::Halt => {
-                // Return from main
-                func.instruction(&WasmInst::Return);
+                // generate_main leaves the dispatch loop
+            }
+
+            Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => {
+                // Superinstructions: emit the push and the operation
+                if let Some(parts) = instruction.unfused() {
+                    for part in &parts {
+                        self.emit_instruction(func, part, state)?;
+                    }
+                }
             }
         }
 
+        if needed > 0 {
+            func.instruction(&WasmInst::End);
+        }
         Ok(())
     }
 
-    /// Emit roll operation (complex stack manipulation)
-    fn emit_roll(&self, func: &mut Function) -> Result<(), CodegenError> {
-        // Roll is the most complex Piet operation
-        // For MVP, we implement a simple version using memory operations
-        
-        // Pop times and depth
-        func.instruction(&WasmInst::Call(6)); // times
-        func.instruction(&WasmInst::Drop);    // TODO: implement proper roll
-        func.instruction(&WasmInst::Call(6)); // depth
-        func.instruction(&WasmInst::Drop);    // TODO: implement proper roll
-        
-        // TODO: Implement full roll semantics
-        // This requires manipulating the stack in memory
-        
+    /// Emit `Divide`/`Mod` once the stack is known to hold two elements
+    ///
+    /// A zero divisor leaves the stack untouched; `i32::MIN` by `-1`
+    /// pushes the wrapped result (`i32::MIN` or 0), both reported as the
+    /// error policy says.
+    fn emit_division(&self, func: &mut Function, instruction: &Instruction, state: usize) {
+        func.instruction(&WasmInst::Call(self.funcs.stack_peek));
+        func.instruction(&WasmInst::I32Eqz);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        self.emit_error(func, ErrorCode::DivisionByZero, state);
+        func.instruction(&WasmInst::Else);
+
+        // a = pop() (divisor), b = pop() (dividend)
+        func.instruction(&WasmInst::Call(self.funcs.stack_pop));
+        func.instruction(&WasmInst::LocalSet(OPERAND_LOCAL));
+        func.instruction(&WasmInst::Call(self.funcs.stack_pop));
+        func.instruction(&WasmInst::LocalSet(RESULT_LOCAL));
+
+        // b == i32::MIN && a == -1
+        func.instruction(&WasmInst::LocalGet(RESULT_LOCAL));
+        func.instruction(&WasmInst::I32Const(i32::MIN));
+        func.instruction(&WasmInst::I32Eq);
+        func.instruction(&WasmInst::LocalGet(OPERAND_LOCAL));
+        func.instruction(&WasmInst::I32Const(-1));
+        func.instruction(&WasmInst::I32Eq);
+        func.instruction(&WasmInst::I32And);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        self.emit_error(func, ErrorCode::Overflow, state);
+        let wrapped = if *instruction == Instruction::Divide { i32::MIN } else { 0 };
+        func.instruction(&WasmInst::I32Const(wrapped));
+        func.instruction(&WasmInst::Call(self.funcs.stack_push));
+        func.instruction(&WasmInst::Else);
+
+        func.instruction(&WasmInst::LocalGet(RESULT_LOCAL));
+        func.instruction(&WasmInst::LocalGet(OPERAND_LOCAL));
+        if *instruction == Instruction::Divide {
+            // b / a, truncating like BytecodeVm
+            func.instruction(&WasmInst::I32DivS);
+        } else {
+            // b mod a with the sign of a removed: r = b % a, plus |a|
+            // when r < 0
+            func.instruction(&WasmInst::I32RemS);
+            func.instruction(&WasmInst::LocalSet(RESULT_LOCAL));
+            func.instruction(&WasmInst::LocalGet(RESULT_LOCAL));
+            func.instruction(&WasmInst::I32Const(0));
+            func.instruction(&WasmInst::LocalGet(OPERAND_LOCAL));
+            func.instruction(&WasmInst::I32Sub);
+            func.instruction(&WasmInst::LocalGet(OPERAND_LOCAL));
+            func.instruction(&WasmInst::LocalGet(OPERAND_LOCAL));
+            func.instruction(&WasmInst::I32Const(0));
+            func.instruction(&WasmInst::I32LtS);
+            func.instruction(&WasmInst::Select); // |a|
+            func.instruction(&WasmInst::I32Const(0));
+            func.instruction(&WasmInst::LocalGet(RESULT_LOCAL));
+            func.instruction(&WasmInst::I32Const(0));
+            func.instruction(&WasmInst::I32LtS);
+            func.instruction(&WasmInst::Select);
+            func.instruction(&WasmInst::I32Add);
+        }
+        func.instruction(&WasmInst::Call(self.funcs.stack_push)); // push result
+        func.instruction(&WasmInst::End);
+
+        func.instruction(&WasmInst::End);
+    }
+
+    /// Emit the error policy's reaction to a runtime error in `state`
+    fn emit_error(&self, func: &mut Function, code: ErrorCode, state: usize) {
+        match self.options.on_error {
+            ErrorPolicy::Ignore => {}
+            ErrorPolicy::Trap => {
+                func.instruction(&WasmInst::Unreachable);
+            }
+            ErrorPolicy::Report => {
+                func.instruction(&WasmInst::I32Const(code as i32));
+                func.instruction(&WasmInst::I32Const(state as i32));
+                func.instruction(&WasmInst::Call(self.funcs.on_error));
+            }
+        }
+    }
+
+    /// Pop `a` (top) and `b`, leaving `b a` on the WASM stack and `a` in
+    /// the operand local
+    fn emit_operands(&self, func: &mut Function) {
+        func.instruction(&WasmInst::Call(self.funcs.stack_pop)); // a
+        func.instruction(&WasmInst::LocalSet(OPERAND_LOCAL));
+        func.instruction(&WasmInst::Call(self.funcs.stack_pop)); // b
+        func.instruction(&WasmInst::LocalGet(OPERAND_LOCAL));
+    }
+
+    /// Emit roll operation (see `generate_stack_roll`)
+    fn emit_roll(&self, func: &mut Function, state: usize) -> Result<(), CodegenError> {
+        func.instruction(&WasmInst::Call(self.funcs.stack_roll));
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        self.emit_error(func, ErrorCode::StackUnderflow, state);
+        func.instruction(&WasmInst::End);
         Ok(())
     }
 
     /// Generate stack_push helper: (value: i32) -> ()
+    ///
+    /// Grows memory a page at a time when the stack reaches its end. If
+    /// memory cannot grow (`max_memory_pages`), the value is dropped and
+    /// `status` becomes [`ExitStatus::StackOverflow`].
     fn generate_stack_push(&self) -> Function {
         // Stack layout in memory:
         // [0..4]: Stack pointer (points to next free slot)
@@ -365,6 +904,26 @@ impl WasmCodegen {
         func.instruction(&WasmInst::I32Load(wasm_encoder::MemArg {
             offset: 0, align: 2, memory_index: 0,
         }));
+
+        // SP is 4-aligned, so the slot fits iff SP's page is in memory:
+        // if (SP >> 16) >= memory.size && memory.grow(1) == -1 { overflow }
+        func.instruction(&WasmInst::LocalTee(1));
+        func.instruction(&WasmInst::I32Const(16));
+        func.instruction(&WasmInst::I32ShrU);
+        func.instruction(&WasmInst::MemorySize(0));
+        func.instruction(&WasmInst::I32GeU);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::MemoryGrow(0));
+        func.instruction(&WasmInst::I32Const(-1));
+        func.instruction(&WasmInst::I32Eq);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(ExitStatus::StackOverflow as i32));
+        func.instruction(&WasmInst::GlobalSet(STATUS_GLOBAL));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::LocalGet(1));
         
         // Store value at SP
         // local.get 0 (the parameter is the value)
@@ -441,12 +1000,12 @@ impl WasmCodegen {
     fn generate_stack_size(&self) -> Function {
         let mut func = Function::new(vec![]);
         
-        // (SP - 4) / 4 = number of elements
+        // (SP - base) / 4 = number of elements
         func.instruction(&WasmInst::I32Const(0));
         func.instruction(&WasmInst::I32Load(wasm_encoder::MemArg {
             offset: 0, align: 2, memory_index: 0,
         }));
-        func.instruction(&WasmInst::I32Const(4));
+        func.instruction(&WasmInst::I32Const(self.stack_base()));
         func.instruction(&WasmInst::I32Sub);
         func.instruction(&WasmInst::I32Const(4));
         func.instruction(&WasmInst::I32DivU);
@@ -454,6 +1013,177 @@ impl WasmCodegen {
         func.instruction(&WasmInst::End);
         func
     }
+
+    /// Generate stack_roll helper: () -> i32
+    ///
+    /// Pops `times` and `depth` and rotates the top `depth` elements
+    /// `times` positions towards the top, like `BytecodeVm`: with fewer
+    /// than two elements nothing happens, a negative depth or one deeper
+    /// than the remaining stack only consumes the arguments, and depth 0
+    /// is a no-op. The rotation is done in place with three reversals.
+    /// Returns 1 on underflow (the first and third cases), 0 otherwise.
+    fn generate_stack_roll(&self) -> Function {
+        const TIMES: u32 = 0;
+        const DEPTH: u32 = 1;
+        const BASE: u32 = 2;
+        const SHIFT: u32 = 3;
+        let mem = wasm_encoder::MemArg { offset: 0, align: 2, memory_index: 0 };
+        let mut func = Function::new(vec![(4, ValType::I32)]);
+
+        func.instruction(&WasmInst::Call(self.funcs.stack_size));
+        func.instruction(&WasmInst::I32Const(2));
+        func.instruction(&WasmInst::I32LtU);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+
+        func.instruction(&WasmInst::Call(self.funcs.stack_pop));
+        func.instruction(&WasmInst::LocalSet(TIMES));
+        func.instruction(&WasmInst::Call(self.funcs.stack_pop));
+        func.instruction(&WasmInst::LocalSet(DEPTH));
+
+        // depth <= 0: nothing to rotate
+        func.instruction(&WasmInst::LocalGet(DEPTH));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32LeS);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+
+        // depth > stack_size: underflow
+        func.instruction(&WasmInst::LocalGet(DEPTH));
+        func.instruction(&WasmInst::Call(self.funcs.stack_size));
+        func.instruction(&WasmInst::I32GtU);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(1));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+
+        // shift = rem_euclid(times, depth)
+        func.instruction(&WasmInst::LocalGet(TIMES));
+        func.instruction(&WasmInst::LocalGet(DEPTH));
+        func.instruction(&WasmInst::I32RemS);
+        func.instruction(&WasmInst::LocalTee(SHIFT));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32LtS);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::LocalGet(SHIFT));
+        func.instruction(&WasmInst::LocalGet(DEPTH));
+        func.instruction(&WasmInst::I32Add);
+        func.instruction(&WasmInst::LocalSet(SHIFT));
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::LocalGet(SHIFT));
+        func.instruction(&WasmInst::I32Eqz);
+        func.instruction(&WasmInst::If(BlockType::Empty));
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::Return);
+        func.instruction(&WasmInst::End);
+
+        // base = SP - 4 * depth, address of the deepest rolled element
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32Load(mem));
+        func.instruction(&WasmInst::LocalGet(DEPTH));
+        func.instruction(&WasmInst::I32Const(2));
+        func.instruction(&WasmInst::I32Shl);
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::LocalSet(BASE));
+
+        // Rotating right by shift: reverse [base, top], then the first
+        // shift elements and the rest separately
+        func.instruction(&WasmInst::LocalGet(BASE));
+        Self::emit_top_address(&mut func);
+        func.instruction(&WasmInst::Call(self.funcs.stack_reverse));
+
+        func.instruction(&WasmInst::LocalGet(BASE));
+        func.instruction(&WasmInst::LocalGet(BASE));
+        func.instruction(&WasmInst::LocalGet(SHIFT));
+        func.instruction(&WasmInst::I32Const(2));
+        func.instruction(&WasmInst::I32Shl);
+        func.instruction(&WasmInst::I32Add);
+        func.instruction(&WasmInst::I32Const(4));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::Call(self.funcs.stack_reverse));
+
+        func.instruction(&WasmInst::LocalGet(BASE));
+        func.instruction(&WasmInst::LocalGet(SHIFT));
+        func.instruction(&WasmInst::I32Const(2));
+        func.instruction(&WasmInst::I32Shl);
+        func.instruction(&WasmInst::I32Add);
+        Self::emit_top_address(&mut func);
+        func.instruction(&WasmInst::Call(self.funcs.stack_reverse));
+
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::End);
+        func
+    }
+
+    /// Push the address of the top of the stack (SP - 4)
+    fn emit_top_address(func: &mut Function) {
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32Load(wasm_encoder::MemArg {
+            offset: 0, align: 2, memory_index: 0,
+        }));
+        func.instruction(&WasmInst::I32Const(4));
+        func.instruction(&WasmInst::I32Sub);
+    }
+
+    /// Generate stack_pointer helper: () -> i32
+    fn generate_stack_pointer(&self) -> Function {
+        let mut func = Function::new(vec![]);
+        func.instruction(&WasmInst::I32Const(0));
+        func.instruction(&WasmInst::I32Load(wasm_encoder::MemArg {
+            offset: 0, align: 2, memory_index: 0,
+        }));
+        func.instruction(&WasmInst::End);
+        func
+    }
+
+    /// Generate stack_reverse helper: (lo: i32, hi: i32) -> ()
+    ///
+    /// Reverses the elements between two addresses, both inclusive.
+    fn generate_stack_reverse(&self) -> Function {
+        const LO: u32 = 0;
+        const HI: u32 = 1;
+        const TMP: u32 = 2;
+        let mem = wasm_encoder::MemArg { offset: 0, align: 2, memory_index: 0 };
+        let mut func = Function::new(vec![(1, ValType::I32)]);
+
+        func.instruction(&WasmInst::Block(BlockType::Empty));
+        func.instruction(&WasmInst::Loop(BlockType::Empty));
+        func.instruction(&WasmInst::LocalGet(LO));
+        func.instruction(&WasmInst::LocalGet(HI));
+        func.instruction(&WasmInst::I32GeS);
+        func.instruction(&WasmInst::BrIf(1));
+
+        // tmp = *lo; *lo = *hi; *hi = tmp
+        func.instruction(&WasmInst::LocalGet(LO));
+        func.instruction(&WasmInst::I32Load(mem));
+        func.instruction(&WasmInst::LocalSet(TMP));
+        func.instruction(&WasmInst::LocalGet(LO));
+        func.instruction(&WasmInst::LocalGet(HI));
+        func.instruction(&WasmInst::I32Load(mem));
+        func.instruction(&WasmInst::I32Store(mem));
+        func.instruction(&WasmInst::LocalGet(HI));
+        func.instruction(&WasmInst::LocalGet(TMP));
+        func.instruction(&WasmInst::I32Store(mem));
+
+        func.instruction(&WasmInst::LocalGet(LO));
+        func.instruction(&WasmInst::I32Const(4));
+        func.instruction(&WasmInst::I32Add);
+        func.instruction(&WasmInst::LocalSet(LO));
+        func.instruction(&WasmInst::LocalGet(HI));
+        func.instruction(&WasmInst::I32Const(4));
+        func.instruction(&WasmInst::I32Sub);
+        func.instruction(&WasmInst::LocalSet(HI));
+        func.instruction(&WasmInst::Br(0));
+        func.instruction(&WasmInst::End);
+        func.instruction(&WasmInst::End);
+
+        func.instruction(&WasmInst::End);
+        func
+    }
 }
 
 impl Default for WasmCodegen {
@@ -462,6 +1192,43 @@ impl Default for WasmCodegen {
     }
 }
 
+/// Successors of every state, validated against the program size
+///
+/// Programs without successor information run their instructions in
+/// sequence.
+pub(crate) fn successor_table(program: &Program) -> Result<Vec<Vec<Option<usize>>>, CodegenError> {
+    let n = program.instructions.len();
+    if program.successors.is_empty() {
+        return Ok((0..n).map(|k| vec![Some(k + 1).filter(|&j| j < n)]).collect());
+    }
+    (0..n)
+        .map(|k| {
+            let successors = program.get_successors(k);
+            match successors.iter().flatten().find(|&&j| j >= n) {
+                Some(j) => Err(CodegenError::InvalidSequence(format!(
+                    "state {} jumps to state {} but the program has {} states",
+                    k, j, n
+                ))),
+                None => Ok(successors.to_vec()),
+            }
+        })
+        .collect()
+}
+
+/// Can `instruction` leave the stack bigger than it found it?
+pub(crate) fn grows_stack(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Push(_)
+            | Instruction::Duplicate
+            | Instruction::InNumber
+            | Instruction::InChar
+            | Instruction::PushAdd(_)
+            | Instruction::PushSubtract(_)
+            | Instruction::PushMultiply(_)
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,181 @@
+//! Builds generated C with the system C compiler (`$CC`, or `cc`) and
+//! compares the binaries against `BytecodeVm`
+//!
+//! Every fixture becomes its own executable. Its stdout, the errors it
+//! reports on stderr and its exit code are read back as a `common::Ran`,
+//! so the checks are the same as for the other backends.
+
+mod common;
+
+use canvas_codegen::{compile_to_c, CodegenOptions, ErrorPolicy, ExitStatus};
+use canvas_vm::{Assembler, Compiler, Instruction};
+use common::{
+    check_arithmetic, check_error_policies, check_samples_where, check_step_limit_and_stack_overflow, fixtures, linear,
+    run_process, Outcome, Ran,
+};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// The C compiler, checked once; a missing one fails the tests
+fn cc() -> &'static str {
+    static CC: OnceLock<String> = OnceLock::new();
+    CC.get_or_init(|| {
+        let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+        let found = Command::new(&cc).arg("--version").output().is_ok_and(|o| o.status.success());
+        assert!(found, "C compiler `{}` not found: install one or point the CC environment variable at it", cc);
+        cc
+    })
+}
+
+/// Compile C source to an executable, failing on any warning
+fn build(name: &str, source: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("canvas_codegen_c_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let c_file = dir.join(format!("{}.c", name));
+    let exe = dir.join(name);
+    std::fs::write(&c_file, source).unwrap();
+    let result = Command::new(cc())
+        .args(["-std=c99", "-pedantic", "-Wall", "-Wextra", "-Werror", "-O1", "-o"])
+        .arg(&exe)
+        .arg(&c_file)
+        .output()
+        .unwrap();
+    assert!(
+        result.status.success(),
+        "{}: {} failed:\n{}\n{}",
+        name,
+        cc(),
+        String::from_utf8_lossy(&result.stderr),
+        source
+    );
+    exe
+}
+
+/// An executable per fixture, built once, and whether it reads characters
+fn binaries() -> &'static HashMap<String, (PathBuf, bool)> {
+    static BINARIES: OnceLock<HashMap<String, (PathBuf, bool)>> = OnceLock::new();
+    BINARIES.get_or_init(|| {
+        fixtures()
+            .into_iter()
+            .map(|(name, program, options)| {
+                let exe = build(&name, &compile_to_c(&program, options).unwrap());
+                let reads_chars = program.instructions.contains(&Instruction::InChar);
+                (name, (exe, reads_chars))
+            })
+            .collect()
+    })
+}
+
+/// `piet: error <code> at state <state>`
+fn parse_error(line: &str) -> (i32, usize) {
+    let rest = line.strip_prefix("piet: error ").unwrap_or_else(|| panic!("unexpected stderr line {:?}", line));
+    let (code, state) = rest.split_once(" at state ").unwrap();
+    (code.parse().unwrap(), state.parse().unwrap())
+}
+
+/// Runs a fixture, with the input as characters if it reads any and as
+/// whitespace-separated numbers otherwise
+fn run(name: &str, input: &[i32]) -> Ran {
+    let (exe, reads_chars) = &binaries()[name];
+    let stdin: String = if *reads_chars {
+        input.iter().filter_map(|&v| char::from_u32(v as u32)).collect()
+    } else {
+        input.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(" ")
+    };
+    let result = run_process(Command::new(exe), stdin.as_bytes());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    let mut errors: Vec<(i32, usize)> = stderr.lines().map(parse_error).collect();
+    // ErrorPolicy::Trap reports the error, then aborts
+    let outcome = match result.status.code() {
+        Some(status) => Outcome::Exit(status),
+        None => {
+            let (code, state) = errors.pop().expect("a trap reports its error");
+            Outcome::Trap(code, state)
+        }
+    };
+    Ran { output: String::from_utf8(result.stdout).expect("stdout should be UTF-8"), errors, outcome }
+}
+
+/// stdout, stderr and exit code (None if killed by a signal)
+fn run_bytes(exe: &PathBuf, stdin: &[u8]) -> (String, String, Option<i32>) {
+    let result = run_process(Command::new(exe), stdin);
+    (
+        String::from_utf8(result.stdout).expect("stdout should be UTF-8"),
+        String::from_utf8_lossy(&result.stderr).into_owned(),
+        result.status.code(),
+    )
+}
+
+#[test]
+fn test_c_samples_match_bytecode_vm() {
+    // stdin is one byte stream, so a program reading both characters and
+    // numbers cannot be given the same values as the VM
+    check_samples_where(run, |program| {
+        !(program.instructions.contains(&Instruction::InChar) && program.instructions.contains(&Instruction::InNumber))
+    });
+}
+
+#[test]
+fn test_c_arithmetic_and_roll_match_bytecode_vm() {
+    check_arithmetic(run);
+}
+
+#[test]
+fn test_c_error_policies() {
+    check_error_policies(run);
+}
+
+#[test]
+fn test_c_step_limit_and_stack_overflow() {
+    check_step_limit_and_stack_overflow(run);
+
+    // One 64 KiB page holds 16384 values, as in the other backends
+    use Instruction::*;
+    let mut pushes = linear(&[Push(1)]);
+    pushes.set_successors(0, vec![Some(0)]);
+    let options = CodegenOptions {
+        max_memory_pages: Some(1),
+        max_steps: Some(16_384),
+        on_error: ErrorPolicy::Report,
+        ..CodegenOptions::default()
+    };
+    let exe = build("stack_full", &compile_to_c(&pushes, options).unwrap());
+    assert_eq!(run_bytes(&exe, b""), (String::new(), String::new(), Some(ExitStatus::OutOfFuel as i32)));
+}
+
+#[test]
+fn test_c_text_and_numbers() {
+    let echo = "
+            in(char)
+            outc
+            in(char)
+            outc
+            in(char)
+            outc
+            in(number)
+            in(number)
+            add
+            out(number)
+            in(number)
+            out(number)
+            in(char)
+            outc
+    ";
+    let grid = Assembler::parse(echo).unwrap().assemble().unwrap();
+    let program = Compiler::new(grid).compile().unwrap();
+    let exe = build("text_and_numbers", &compile_to_c(&program, CodegenOptions::default()).unwrap());
+
+    // Multi-byte UTF-8, signs and whitespace, and the byte after a number
+    let (output, _, code) = run_bytes(&exe, "é€😀 -12\n+30 -2147483648!".as_bytes());
+    assert_eq!((output.as_str(), code), ("é€😀18-2147483648!", Some(0)));
+
+    // Not a number reads as 0; running out of input exits cleanly
+    let (output, _, code) = run_bytes(&exe, b"abcx");
+    assert_eq!((output.as_str(), code), ("abc", Some(0)));
+
+    // Malformed UTF-8 reads as U+FFFD
+    let (output, _, _) = run_bytes(&exe, b"\xE2\x82A\xFF");
+    assert_eq!(output, "\u{FFFD}A\u{FFFD}");
+}
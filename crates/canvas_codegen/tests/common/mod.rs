@@ -0,0 +1,284 @@
+//! Helpers shared by the backend execution tests
+
+// Each test crate uses its own subset
+#![allow(dead_code)]
+
+use canvas_codegen::{CodegenOptions, ErrorCode, ErrorPolicy, ExitStatus};
+use canvas_vm::{Assembler, BytecodeVm, Compiler, Grid, Instruction, Program, VmError};
+use image::ImageReader;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::sync::OnceLock;
+
+pub const SAMPLES: &[&str] = &[
+    "HelloWorld.png",
+    "HelloWorld2.png",
+    "HelloWorld3.png",
+    "PI.png",
+    "Piet.png",
+    "PrimeGenerator.png",
+    "echo1.bmp",
+    "echo4.bmp",
+    "echo4_linear.bmp",
+    "echo4_ordered.bmp",
+    "echo4_simple.bmp",
+    "echo4_terminating.bmp",
+    "echo_corridor.bmp",
+    "echo_linear.bmp",
+    "single_block.bmp",
+    "single_echo.bmp",
+];
+
+pub fn load_grid(name: &str) -> Grid {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../tools/fixtures/samples").join(name);
+    let img = ImageReader::open(&path)
+        .unwrap_or_else(|_| panic!("Failed to open image at {:?}", path))
+        .decode()
+        .expect("Failed to decode image")
+        .to_rgba8();
+    let (width, height) = img.dimensions();
+    Grid::from_rgba(width as usize, height as usize, img.as_raw()).expect("Failed to create grid")
+}
+
+/// Output of the interpreter and whether it stopped on its own (halt or
+/// missing input) rather than by running out of steps
+pub fn run_vm(grid: Grid, input: &[i32], max_steps: usize) -> (String, bool) {
+    let mut vm = BytecodeVm::from_grid(grid).expect("Failed to create VM");
+    vm.set_max_steps(Some(max_steps));
+    vm.load_input_number_vec(input);
+    let finished = loop {
+        match vm.stroke() {
+            Ok(()) => {}
+            Err(VmError::ExecutionTimeout(_)) => break false,
+            Err(_) => break true,
+        }
+    };
+    (vm.ink_string(), finished)
+}
+
+/// A program without successor information: the instructions run in sequence
+pub fn linear(instructions: &[Instruction]) -> Program {
+    let mut program = Program::new(0, 0);
+    for instr in instructions {
+        program.add_instruction(instr.clone());
+    }
+    program.successors.clear();
+    program
+}
+
+/// Hits every runtime error; see [`ERROR_PROGRAM_OUTPUT`] and
+/// [`ERROR_PROGRAM_ERRORS`]
+pub fn error_program() -> Program {
+    use Instruction::*;
+    linear(&[
+        Pop,                                         // 0: underflow
+        Push(7), Push(0), Divide,                    // 3: division by zero, stack [7, 0]
+        Pop, OutNumber,
+        Push(i32::MIN), Push(-1), Divide, OutNumber, // 8: overflow, wraps
+        Push(4), Push(1), Push(5), Roll,             // 13: depth 1 is fine
+        Push(9), Push(1), Roll,                      // 16: depth 9 underflows
+        OutNumber,
+        OutNumber,                                   // 18: underflow
+        Halt,
+    ])
+}
+
+/// What [`error_program`] prints when errors are not trapped
+pub const ERROR_PROGRAM_OUTPUT: &str = "7-21474836484";
+
+/// Errors [`error_program`] reports, as (code, state)
+pub const ERROR_PROGRAM_ERRORS: [(ErrorCode, usize); 5] = [
+    (ErrorCode::StackUnderflow, 0),
+    (ErrorCode::DivisionByZero, 3),
+    (ErrorCode::Overflow, 8),
+    (ErrorCode::StackUnderflow, 16),
+    (ErrorCode::StackUnderflow, 18),
+];
+
+/// Assembly covering wrapping, Euclidean mod, division by zero, and rolls
+/// of every kind
+pub fn arithmetic_source() -> String {
+    let mut source = String::from(
+        "push 7\npush 2\nsub\noutn\npush -7\npush 3\nmod\noutn\npush 7\npush -3\nmod\noutn\n\
+         push -7\npush 2\ndiv\noutn\npush 3\npush 0\ndiv\noutn\noutn\n\
+         push 65536\ndup\nmul\ndup\nmul\noutn\n",
+    );
+    let cases = [(vec![1, 2, 3, 4], 3, -4), (vec![5, 6], 9, 1), (vec![8, 9, 10], -1, 2), (vec![11, 12, 13], 3, 7)];
+    for (values, depth, times) in &cases {
+        for v in values {
+            source += &format!("push {}\n", v);
+        }
+        source += &format!("push {}\npush {}\nroll\n", depth, times);
+        for _ in values {
+            source += "push ' '\noutc\noutn\n";
+        }
+    }
+    source
+}
+
+/// Both outputs agree, up to where a run that was cut short stopped
+pub fn assert_same_output(name: &str, compiled: (&str, bool), interpreted: (&str, bool)) {
+    let ((output, finished), (expected, vm_finished)) = (compiled, interpreted);
+    if finished && vm_finished {
+        assert_eq!(output, expected, "{}", name);
+    } else {
+        let (short, long) = if output.len() <= expected.len() { (output, expected) } else { (expected, output) };
+        assert!(long.starts_with(short), "{}: compiled {:?} vs vm {:?}", name, output, expected);
+    }
+}
+
+/// How a source backend's program ended
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Returned an [`ExitStatus`]
+    Exit(i32),
+    /// Stopped with [`ErrorPolicy::Trap`], as (code, state)
+    Trap(i32, usize),
+}
+
+/// One run of a source backend's program
+#[derive(Debug, PartialEq, Eq)]
+pub struct Ran {
+    pub output: String,
+    /// Errors reported with [`ErrorPolicy::Report`], as (code, state)
+    pub errors: Vec<(i32, usize)>,
+    pub outcome: Outcome,
+}
+
+impl Ran {
+    fn new(output: &str, errors: Vec<(i32, usize)>, outcome: Outcome) -> Self {
+        Ran { output: output.to_string(), errors, outcome }
+    }
+}
+
+/// Module or function name of a sample in [`fixtures`]
+pub fn sample_name(file: &str) -> String {
+    format!("sample_{}", file.replace('.', "_").to_lowercase())
+}
+
+/// Named programs every source backend builds into one artifact and the
+/// `check_*` functions run
+pub fn fixtures() -> Vec<(String, Program, CodegenOptions)> {
+    use Instruction::*;
+    let mut fixtures = Vec::new();
+    let limited = CodegenOptions { max_steps: Some(200_000), ..CodegenOptions::default() };
+    for file in SAMPLES {
+        let program = Compiler::new(load_grid(file)).compile().unwrap();
+        fixtures.push((sample_name(file), program, limited.clone()));
+    }
+    for (name, on_error) in [("ignore", ErrorPolicy::Ignore), ("report", ErrorPolicy::Report), ("trap", ErrorPolicy::Trap)] {
+        fixtures.push((name.to_string(), error_program(), CodegenOptions { on_error, ..CodegenOptions::default() }));
+    }
+    let grid = Assembler::parse(&arithmetic_source()).unwrap().assemble().unwrap();
+    fixtures.push(("arithmetic".to_string(), Compiler::new(grid).compile().unwrap(), CodegenOptions::default()));
+
+    let mut endless = linear(&[Push(1), OutNumber]);
+    endless.set_successors(0, vec![Some(1)]);
+    endless.set_successors(1, vec![Some(0)]);
+    fixtures.push(("step_limit".to_string(), endless, CodegenOptions { max_steps: Some(7), ..CodegenOptions::default() }));
+    let mut pushes = linear(&[Push(1)]);
+    pushes.set_successors(0, vec![Some(0)]);
+    let overflow = CodegenOptions { max_memory_pages: Some(1), on_error: ErrorPolicy::Report, ..CodegenOptions::default() };
+    fixtures.push(("stack_overflow".to_string(), pushes, overflow));
+    fixtures.push(("empty".to_string(), linear(&[Nop, Halt]), CodegenOptions::default()));
+    fixtures
+}
+
+/// The node binary; panics rather than skipping when there is none
+pub fn node() -> &'static str {
+    static NODE: OnceLock<String> = OnceLock::new();
+    NODE.get_or_init(|| {
+        let node = std::env::var("NODE").unwrap_or_else(|_| "node".to_string());
+        let found = Command::new(&node).arg("--version").output().is_ok_and(|o| o.status.success());
+        assert!(found, "`{}` not found: install Node.js or set NODE to run the JavaScript and WASI tests", node);
+        node
+    })
+}
+
+/// Runs `command` with `stdin` and waits for it to exit
+pub fn run_process(mut command: Command, stdin: &[u8]) -> Output {
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    // A program may exit before reading all of its input
+    if let Err(e) = child.stdin.take().unwrap().write_all(stdin) {
+        assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+    child.wait_with_output().unwrap()
+}
+
+/// Runs a harness for the [`fixtures`] with whitespace-separated numbers
+/// on stdin. The harness prints the program's output to stdout, then two
+/// lines to stderr: the reported errors as `code:state` tokens, and
+/// `exit <status>` or `trap <code> <state>`.
+pub fn run_harness(command: Command, input: &[i32]) -> Ran {
+    let name = format!("{:?}", command);
+    let stdin: Vec<String> = input.iter().map(|v| v.to_string()).collect();
+    let result = run_process(command, stdin.join(" ").as_bytes());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(result.status.success(), "{}: {}", name, stderr);
+    let mut lines = stderr.lines();
+    let errors = lines
+        .next()
+        .unwrap()
+        .split_whitespace()
+        .map(|t| {
+            let (code, state) = t.split_once(':').unwrap();
+            (code.parse().unwrap(), state.parse().unwrap())
+        })
+        .collect();
+    let outcome: Vec<&str> = lines.next().unwrap().split(' ').collect();
+    let outcome = match outcome[..] {
+        ["exit", status] => Outcome::Exit(status.parse().unwrap()),
+        ["trap", code, state] => Outcome::Trap(code.parse().unwrap(), state.parse().unwrap()),
+        _ => panic!("{}: unexpected outcome {:?}", name, outcome),
+    };
+    Ran { output: String::from_utf8(result.stdout).unwrap(), errors, outcome }
+}
+
+/// The samples match `BytecodeVm` on a few inputs
+pub fn check_samples(run: impl Fn(&str, &[i32]) -> Ran) {
+    check_samples_where(run, |_| true);
+}
+
+/// [`check_samples`] for the samples whose program passes `filter`
+pub fn check_samples_where(run: impl Fn(&str, &[i32]) -> Ran, filter: impl Fn(&Program) -> bool) {
+    for file in SAMPLES {
+        if !filter(&Compiler::new(load_grid(file)).compile().unwrap()) {
+            continue;
+        }
+        for input in [&[][..], &[5, 72, 105, 33, 10], &[12, 3]] {
+            let ran = run(&sample_name(file), input);
+            let halted = ran.outcome == Outcome::Exit(ExitStatus::Halted as i32);
+            let (expected, finished) = run_vm(load_grid(file), input, 200_000);
+            assert_same_output(&format!("{} with input {:?}", file, input), (&ran.output, halted), (&expected, finished));
+        }
+    }
+}
+
+/// The `arithmetic` fixture matches `BytecodeVm`
+pub fn check_arithmetic(run: impl Fn(&str, &[i32]) -> Ran) {
+    let grid = Assembler::parse(&arithmetic_source()).unwrap().assemble().unwrap();
+    let (expected, finished) = run_vm(grid, &[], 100_000);
+    assert!(finished);
+    assert_eq!(run("arithmetic", &[]), Ran::new(&expected, Vec::new(), Outcome::Exit(ExitStatus::Halted as i32)));
+}
+
+/// [`error_program`] under each policy
+pub fn check_error_policies(run: impl Fn(&str, &[i32]) -> Ran) {
+    let halted = || Outcome::Exit(ExitStatus::Halted as i32);
+    assert_eq!(run("ignore", &[]), Ran::new(ERROR_PROGRAM_OUTPUT, Vec::new(), halted()));
+    let errors = ERROR_PROGRAM_ERRORS.iter().map(|&(code, state)| (code as i32, state)).collect();
+    assert_eq!(run("report", &[]), Ran::new(ERROR_PROGRAM_OUTPUT, errors, halted()));
+    let underflow = Outcome::Trap(ErrorCode::StackUnderflow as i32, 0);
+    assert_eq!(run("trap", &[]), Ran::new("", Vec::new(), underflow));
+}
+
+/// The `step_limit`, `stack_overflow` and `empty` fixtures
+pub fn check_step_limit_and_stack_overflow(run: impl Fn(&str, &[i32]) -> Ran) {
+    let out_of_fuel = Outcome::Exit(ExitStatus::OutOfFuel as i32);
+    assert_eq!(run("step_limit", &[]), Ran::new("111", Vec::new(), out_of_fuel));
+    let ran = run("stack_overflow", &[]);
+    assert_eq!(ran.errors, vec![(ErrorCode::StackOverflow as i32, 0)]);
+    assert_eq!(ran.outcome, Outcome::Exit(ExitStatus::StackOverflow as i32));
+    assert_eq!(run("empty", &[]), Ran::new("", Vec::new(), Outcome::Exit(ExitStatus::Halted as i32)));
+}
@@ -0,0 +1,166 @@
+//! Runs generated JavaScript with `node` and compares it against
+//! `BytecodeVm`. The tests fail when node is missing; set `NODE` to use a
+//! node binary that is not on the path.
+//!
+//! Every fixture becomes a function of one script, which runs the
+//! function named by its argument; see `common::run_harness`.
+
+mod common;
+
+use canvas_codegen::JsCodegen;
+use canvas_vm::Instruction;
+use common::{
+    check_arithmetic, check_error_policies, check_samples, check_step_limit_and_stack_overflow, fixtures, linear, node,
+    run_harness, Ran,
+};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::OnceLock;
+
+const HARNESS: &str = r#"
+const name = process.argv[2];
+const input = require("fs").readFileSync(0, "utf8").split(/\s+/).filter((t) => t !== "").map(Number);
+const errors = [];
+let output = "";
+const io = {
+  readNumber: () => (input.length > 0 ? input.shift() : null),
+  readChar: () => (input.length > 0 ? input.shift() : null),
+  writeNumber: (value) => {
+    output += value;
+  },
+  writeChar: (value) => {
+    if (value >= 0 && value <= 0x10ffff && (value < 0xd800 || value > 0xdfff)) {
+      output += String.fromCodePoint(value);
+    }
+  },
+  onError: (code, state) => errors.push(`${code}:${state}`),
+};
+let outcome;
+try {
+  outcome = `exit ${programs[name](io)}`;
+} catch (e) {
+  outcome = e.message === `Piet error ${e.code} at state ${e.state}` ? `trap ${e.code} ${e.state}` : String(e);
+}
+process.stdout.write(output);
+process.stderr.write(errors.join(" ") + "\n" + outcome + "\n");
+"#;
+
+/// Delimiters outside of string literals and comments pair up
+fn assert_balanced(name: &str, source: &str) {
+    let mut open = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' | '`' => {
+                while let Some(d) = chars.next() {
+                    match d {
+                        '\\' => {
+                            chars.next();
+                        }
+                        d if d == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for d in chars.by_ref() {
+                    if d == '\n' {
+                        break;
+                    }
+                }
+            }
+            '(' | '[' | '{' => open.push(c),
+            ')' | ']' | '}' => {
+                let expected = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                assert_eq!(open.pop(), Some(expected), "{}: unmatched {:?}", name, c);
+            }
+            _ => {}
+        }
+    }
+    assert!(open.is_empty(), "{}: unclosed {:?}", name, open);
+}
+
+/// The test script, written once
+fn script() -> &'static PathBuf {
+    static SCRIPT: OnceLock<PathBuf> = OnceLock::new();
+    SCRIPT.get_or_init(|| {
+        let functions = fixtures();
+        let mut source = String::new();
+        for (name, program, options) in &functions {
+            source += &JsCodegen::with_options(options.clone()).function_name(name).generate(program).unwrap();
+        }
+        let names: Vec<&str> = functions.iter().map(|(name, _, _)| name.as_str()).collect();
+        source += &format!("const programs = {{ {} }};\n", names.join(", "));
+        source += HARNESS;
+
+        let dir = std::env::temp_dir().join(format!("canvas_codegen_js_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("programs.js");
+        std::fs::write(&path, &source).unwrap();
+        path
+    })
+}
+
+fn run(program: &str, input: &[i32]) -> Ran {
+    let mut command = Command::new(node());
+    command.arg(script()).arg(program);
+    run_harness(command, input)
+}
+
+#[test]
+fn test_js_structure() {
+    // Checked without node: one function per fixture, one `case` per state,
+    // transitions to existing states, and balanced delimiters
+    for (name, program, options) in fixtures() {
+        let source = JsCodegen::with_options(options).function_name(&name).generate(&program).unwrap();
+        assert!(source.contains(&format!("\nfunction {}(io) {{\n  \"use strict\";\n", name)), "{}", name);
+        let helpers = ["function push(", "function roll("].iter().filter(|h| source.contains(*h)).count();
+        assert_eq!(source.matches("function ").count(), 1 + helpers, "{}", name);
+        for k in 0..program.instructions.len() {
+            assert!(source.contains(&format!("\n      case {}: {{ // ", k)), "{}: no case {}", name, k);
+        }
+        for target in source.split("state = ").skip(1) {
+            let target: usize = target[..target.find(';').unwrap()].parse().unwrap();
+            assert!(target < program.instructions.len(), "{}: jumps to {}", name, target);
+        }
+        assert_balanced(&name, &source);
+    }
+}
+
+#[test]
+fn test_js_samples_match_bytecode_vm() {
+    check_samples(run);
+}
+
+#[test]
+fn test_js_arithmetic_and_roll_match_bytecode_vm() {
+    check_arithmetic(run);
+}
+
+#[test]
+fn test_js_error_policies() {
+    check_error_policies(run);
+}
+
+#[test]
+fn test_js_step_limit_and_stack_overflow() {
+    check_step_limit_and_stack_overflow(run);
+}
+
+#[test]
+fn test_js_es_module() {
+    let program = linear(&[Instruction::Push(72), Instruction::OutChar, Instruction::Halt]);
+    let source = JsCodegen::new().export(true).generate(&program).unwrap();
+    assert!(source.contains("export function runPiet(io) {"));
+    assert_balanced("module", &source);
+    let dir = std::env::temp_dir().join(format!("canvas_codegen_js_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("module.mjs");
+    std::fs::write(&path, source).unwrap();
+    let result = Command::new(node()).arg("--check").arg(&path).output().unwrap();
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+}
@@ -0,0 +1,123 @@
+//! Builds generated Rust with `rustc` and compares it against `BytecodeVm`
+//!
+//! Every fixture becomes a module of one test binary, which runs the
+//! module named by its argument; see `common::run_harness`.
+
+mod common;
+
+use canvas_codegen::RustCodegen;
+use common::{
+    check_arithmetic, check_error_policies, check_samples, check_step_limit_and_stack_overflow, fixtures,
+    run_harness, Ran,
+};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::OnceLock;
+
+const HARNESS: &str = r#"
+use std::collections::VecDeque;
+use std::io::Read;
+
+struct Io {
+    input: VecDeque<i32>,
+    errors: Vec<String>,
+}
+
+macro_rules! programs {
+    ($($name:ident),*) => {
+        $(impl $name::PietIo for Io {
+            fn read_number(&mut self) -> Option<i32> {
+                self.input.pop_front()
+            }
+            fn read_char(&mut self) -> Option<i32> {
+                self.input.pop_front()
+            }
+            fn write_number(&mut self, value: i32) {
+                print!("{}", value);
+            }
+            fn write_char(&mut self, value: i32) {
+                if let Some(c) = char::from_u32(value as u32) {
+                    print!("{}", c);
+                }
+            }
+            fn on_error(&mut self, code: i32, state: usize) {
+                self.errors.push(format!("{}:{}", code, state));
+            }
+        })*
+
+        fn main() {
+            let name = std::env::args().nth(1).unwrap();
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input).unwrap();
+            let mut io = Io {
+                input: input.split_whitespace().map(|t| t.parse().unwrap()).collect(),
+                errors: Vec::new(),
+            };
+            let outcome = match name.as_str() {
+                $(stringify!($name) => match $name::run(&mut io) {
+                    Ok(status) => format!("exit {}", status as i32),
+                    Err(error) => format!("trap {} {}", error.code, error.state),
+                },)*
+                _ => panic!("unknown program {}", name),
+            };
+            eprintln!("{}", io.errors.join(" "));
+            eprintln!("{}", outcome);
+        }
+    };
+}
+"#;
+
+/// The test binary, built once
+fn binary() -> &'static PathBuf {
+    static BINARY: OnceLock<PathBuf> = OnceLock::new();
+    BINARY.get_or_init(|| {
+        let modules = fixtures();
+        let mut source = String::new();
+        for (name, program, options) in &modules {
+            source += &RustCodegen::with_options(options.clone()).module_name(name).generate(program).unwrap();
+        }
+        source += HARNESS;
+        let names: Vec<&str> = modules.iter().map(|(name, _, _)| name.as_str()).collect();
+        source += &format!("programs!({});\n", names.join(", "));
+
+        let dir = std::env::temp_dir().join(format!("canvas_codegen_rust_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.rs");
+        let exe = dir.join("programs");
+        std::fs::write(&main, &source).unwrap();
+        let result = Command::new(std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()))
+            .args(["--edition", "2021", "-D", "warnings", "-C", "opt-level=1", "-o"])
+            .arg(&exe)
+            .arg(&main)
+            .output()
+            .unwrap();
+        assert!(result.status.success(), "rustc failed:\n{}", String::from_utf8_lossy(&result.stderr));
+        exe
+    })
+}
+
+fn run(program: &str, input: &[i32]) -> Ran {
+    let mut command = Command::new(binary());
+    command.arg(program);
+    run_harness(command, input)
+}
+
+#[test]
+fn test_rust_samples_match_bytecode_vm() {
+    check_samples(run);
+}
+
+#[test]
+fn test_rust_arithmetic_and_roll_match_bytecode_vm() {
+    check_arithmetic(run);
+}
+
+#[test]
+fn test_rust_error_policies() {
+    check_error_policies(run);
+}
+
+#[test]
+fn test_rust_step_limit_and_stack_overflow() {
+    check_step_limit_and_stack_overflow(run);
+}
@@ -0,0 +1,569 @@
+//! Runs generated modules with an embedded WASM interpreter and compares
+//! them against `BytecodeVm`
+//!
+//! WASI modules also run under node's `node:wasi`, so their imports are
+//! checked against a real WASI implementation and not only the minimal one
+//! below. Those tests fail when node is missing; set `NODE` to use a node
+//! binary that is not on the path.
+
+mod common;
+
+use canvas_codegen::{
+    compile_to_wasm, compile_to_wasm_with_options, CodegenError, CodegenOptions, ErrorCode, ErrorPolicy,
+    ExitStatus, Target,
+};
+use canvas_vm::{Assembler, Compiler, Grid, Instruction};
+use common::{
+    assert_same_output, error_program, linear, load_grid, node, run_process, ERROR_PROGRAM_ERRORS, ERROR_PROGRAM_OUTPUT,
+    SAMPLES,
+};
+use std::collections::VecDeque;
+use std::process::Command;
+use wasmi::{Caller, Config, Engine, Linker, Module, Store};
+
+/// Output of a run and whether it stopped on its own (halt or missing
+/// input) rather than by running out of steps
+#[derive(Debug)]
+struct Run {
+    output: String,
+    finished: bool,
+    /// `env.on_error` calls as (code, state)
+    errors: Vec<(i32, i32)>,
+    trap: Option<String>,
+    /// Exported `status` and `fuel` globals after the run
+    status: Option<i32>,
+    fuel: Option<i64>,
+    /// `stack_pointer()` and the memory size in pages after the run
+    stack_pointer: Option<i32>,
+    memory_pages: u64,
+}
+
+#[derive(Default)]
+struct Host {
+    input: VecDeque<i32>,
+    output: String,
+    errors: Vec<(i32, i32)>,
+}
+
+fn read(caller: Caller<'_, Host>) -> Result<i32, wasmi::Error> {
+    let mut caller = caller;
+    caller.data_mut().input.pop_front().ok_or_else(|| wasmi::Error::new("input exhausted"))
+}
+
+fn run_wasm(wasm: &[u8], input: &[i32], fuel: u64) -> Run {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, wasm).expect("generated module should load");
+    let host = Host { input: input.iter().copied().collect(), ..Host::default() };
+    let mut store = Store::new(&engine, host);
+    store.set_fuel(fuel).unwrap();
+
+    let mut linker = <Linker<Host>>::new(&engine);
+    linker.func_wrap("env", "read_char", read).unwrap();
+    linker.func_wrap("env", "read_number", read).unwrap();
+    linker
+        .func_wrap("env", "write_char", |mut caller: Caller<'_, Host>, c: i32| {
+            if let Some(ch) = char::from_u32(c as u32) {
+                caller.data_mut().output.push(ch);
+            }
+        })
+        .unwrap();
+    linker
+        .func_wrap("env", "write_number", |mut caller: Caller<'_, Host>, n: i32| {
+            caller.data_mut().output.push_str(&n.to_string());
+        })
+        .unwrap();
+    linker
+        .func_wrap("env", "on_error", |mut caller: Caller<'_, Host>, code: i32, state: i32| {
+            caller.data_mut().errors.push((code, state));
+        })
+        .unwrap();
+
+    let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+    let main = instance.get_typed_func::<(), ()>(&store, "main").unwrap();
+    let result = main.call(&mut store, ());
+    let out_of_fuel = store.get_fuel().unwrap() == 0;
+    let status = instance.get_global(&store, "status").and_then(|g| g.get(&store).i32());
+    let fuel = instance.get_global(&store, "fuel").and_then(|g| g.get(&store).i64());
+    let stack_pointer = instance
+        .get_typed_func::<(), i32>(&store, "stack_pointer")
+        .ok()
+        .map(|f| f.call(&mut store, ()).unwrap());
+    let memory_pages = instance.get_memory(&store, "memory").unwrap().data(&store).len() as u64 / 65536;
+    let host = store.into_data();
+    Run {
+        output: host.output,
+        finished: result.is_ok() || !out_of_fuel,
+        errors: host.errors,
+        trap: result.err().map(|e| e.to_string()),
+        status,
+        fuel,
+        stack_pointer,
+        memory_pages,
+    }
+}
+
+/// Minimal `wasi_snapshot_preview1` for the functions WASI modules import
+#[derive(Default)]
+struct Wasi {
+    stdin: VecDeque<u8>,
+    stdout: Vec<u8>,
+}
+
+/// The `(buf, len)` pairs of an iovec array
+fn iovecs(memory: &[u8], iovs: i32, count: i32) -> Vec<(usize, usize)> {
+    let word = |at: usize| u32::from_le_bytes(memory[at..at + 4].try_into().unwrap()) as usize;
+    (0..count as usize).map(|i| (word(iovs as usize + 8 * i), word(iovs as usize + 8 * i + 4))).collect()
+}
+
+/// Run `_start` with `stdin`; also returns the `proc_exit` code, if any
+fn run_wasi(wasm: &[u8], stdin: &[u8], fuel: u64) -> (Run, Option<i32>) {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, wasm).expect("generated module should load");
+    let mut store = Store::new(&engine, Wasi { stdin: stdin.iter().copied().collect(), ..Wasi::default() });
+    store.set_fuel(fuel).unwrap();
+
+    let mut linker = <Linker<Wasi>>::new(&engine);
+    linker
+        .func_wrap(
+            "wasi_snapshot_preview1",
+            "fd_read",
+            |mut caller: Caller<'_, Wasi>, fd: i32, iovs: i32, count: i32, nread: i32| -> i32 {
+                assert_eq!(fd, 0);
+                let memory = caller.get_export("memory").and_then(|e| e.into_memory()).unwrap();
+                let (memory, wasi) = memory.data_and_store_mut(&mut caller);
+                let mut total = 0;
+                for (buf, len) in iovecs(memory, iovs, count) {
+                    for slot in &mut memory[buf..buf + len] {
+                        match wasi.stdin.pop_front() {
+                            Some(b) => *slot = b,
+                            None => break,
+                        }
+                        total += 1;
+                    }
+                }
+                memory[nread as usize..nread as usize + 4].copy_from_slice(&(total as u32).to_le_bytes());
+                0
+            },
+        )
+        .unwrap();
+    linker
+        .func_wrap(
+            "wasi_snapshot_preview1",
+            "fd_write",
+            |mut caller: Caller<'_, Wasi>, fd: i32, iovs: i32, count: i32, nwritten: i32| -> i32 {
+                assert_eq!(fd, 1);
+                let memory = caller.get_export("memory").and_then(|e| e.into_memory()).unwrap();
+                let (memory, wasi) = memory.data_and_store_mut(&mut caller);
+                let mut total = 0;
+                for (buf, len) in iovecs(memory, iovs, count) {
+                    wasi.stdout.extend_from_slice(&memory[buf..buf + len]);
+                    total += len;
+                }
+                memory[nwritten as usize..nwritten as usize + 4].copy_from_slice(&(total as u32).to_le_bytes());
+                0
+            },
+        )
+        .unwrap();
+    linker
+        .func_wrap("wasi_snapshot_preview1", "proc_exit", |code: i32| -> Result<(), wasmi::Error> {
+            Err(wasmi::Error::i32_exit(code))
+        })
+        .unwrap();
+
+    let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+    let start = instance.get_typed_func::<(), ()>(&store, "_start").unwrap();
+    let result = start.call(&mut store, ());
+    let exit_code = result.as_ref().err().and_then(|e| e.i32_exit_status());
+    let out_of_fuel = store.get_fuel().unwrap() == 0;
+    let status = instance.get_global(&store, "status").and_then(|g| g.get(&store).i32());
+    let fuel = instance.get_global(&store, "fuel").and_then(|g| g.get(&store).i64());
+    let stdout = String::from_utf8(store.into_data().stdout).expect("stdout should be UTF-8");
+    let run = Run {
+        output: stdout,
+        finished: result.is_ok() || exit_code.is_some() || !out_of_fuel,
+        errors: Vec::new(),
+        trap: result.err().filter(|e| e.i32_exit_status().is_none()).map(|e| e.to_string()),
+        status,
+        fuel,
+        stack_pointer: None,
+        memory_pages: 0,
+    };
+    (run, exit_code)
+}
+
+/// Instantiates the module given as the first argument and exits with its
+/// `proc_exit` code, or 0 when `_start` returns
+const NODE_WASI: &str = r#"
+const { WASI } = require("node:wasi");
+const wasi = new WASI({ version: "preview1", returnOnExit: true });
+const module = new WebAssembly.Module(require("fs").readFileSync(process.argv[1]));
+process.exitCode = wasi.start(new WebAssembly.Instance(module, wasi.getImportObject()));
+"#;
+
+/// Run a WASI module under node with `stdin`; returns stdout and the exit code
+fn run_node_wasi(name: &str, wasm: &[u8], stdin: &[u8]) -> (String, Option<i32>) {
+    let dir = std::env::temp_dir().join(format!("canvas_codegen_wasi_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.wasm", name));
+    std::fs::write(&path, wasm).unwrap();
+    let mut command = Command::new(node());
+    command.args(["--no-warnings", "-e", NODE_WASI]).arg(&path);
+    let result = run_process(command, stdin);
+    assert!(result.stderr.is_empty(), "{}: {}", name, String::from_utf8_lossy(&result.stderr));
+    (String::from_utf8(result.stdout).expect("stdout should be UTF-8"), result.status.code())
+}
+
+fn run_vm(grid: Grid, input: &[i32], max_steps: usize) -> Run {
+    let (output, finished) = common::run_vm(grid, input, max_steps);
+    Run { output, finished, errors: Vec::new(), trap: None, status: None, fuel: None, stack_pointer: None, memory_pages: 0 }
+}
+
+/// Both runs agree, up to where the one that was cut short stopped
+fn assert_same(name: &str, wasm: &Run, vm: &Run) {
+    assert_same_output(name, (&wasm.output, wasm.finished), (&vm.output, vm.finished));
+}
+
+#[test]
+fn test_samples_match_bytecode_vm() {
+    for name in SAMPLES {
+        let grid = load_grid(name);
+        let program = Compiler::new(grid.clone()).compile().expect("Failed to compile");
+        let wasm = compile_to_wasm(&program).expect("Failed to generate WASM");
+        wasmparser::validate(&wasm).expect("Generated WASM should be valid");
+        for input in [&[][..], &[5, 72, 105, 33, 10], &[12, 3]] {
+            let compiled = run_wasm(&wasm, input, 5_000_000);
+            let interpreted = run_vm(grid.clone(), input, 200_000);
+            assert_same(&format!("{} with input {:?}", name, input), &compiled, &interpreted);
+        }
+    }
+}
+
+#[test]
+fn test_loops_and_branches_match_bytecode_vm() {
+    let countdown = "
+            in(number)
+    loop:   dup
+            out(number)
+            push 1
+            subtract
+            dup
+            jnz loop
+    ";
+    let nested = "
+            push 3
+        outer:
+            dup
+            push 2
+        inner:
+            push '*'
+            outc
+            push 1
+            sub
+            dup
+            jnz inner
+            pop
+            pop
+            push 1
+            sub
+            dup
+            jnz outer
+    ";
+    for (source, input, expected) in [(countdown, &[4][..], "4321"), (countdown, &[1], "1"), (nested, &[], "******")] {
+        let grid = Assembler::parse(source).unwrap().assemble().unwrap();
+        let program = Compiler::new(grid.clone()).compile().unwrap();
+        let wasm = compile_to_wasm(&program).unwrap();
+        let compiled = run_wasm(&wasm, input, 1_000_000);
+        assert_eq!(compiled.output, expected);
+        assert_same(source, &compiled, &run_vm(grid, input, 100_000));
+    }
+}
+
+#[test]
+fn test_arithmetic_operand_order() {
+    use canvas_vm::Instruction::*;
+    let program = linear(&[
+        Push(7), Push(2), Subtract, OutNumber,
+        Push(-7), Push(3), Mod, OutNumber,
+        Push(7), Push(-3), Mod, OutNumber,
+        Push(-7), Push(2), Divide, OutNumber,
+        Push(3), Push(2), Greater, OutNumber,
+        Halt,
+    ]);
+    let wasm = compile_to_wasm(&program).unwrap();
+    assert_eq!(run_wasm(&wasm, &[], 10_000).output, "521-31");
+}
+
+/// Small xorshift generator so the random stacks are reproducible
+struct Rng(u64);
+
+impl Rng {
+    fn range(&mut self, lo: i32, hi: i32) -> i32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        lo + (self.0 % (hi - lo + 1) as u64) as i32
+    }
+}
+
+#[test]
+fn test_roll_matches_bytecode_vm_on_random_stacks() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for case in 0..150 {
+        let len = rng.range(0, 8);
+        let values: Vec<i32> = (0..len).map(|_| rng.range(-20, 20)).collect();
+        let depth = rng.range(-2, 10);
+        let times = rng.range(-12, 12);
+
+        // Push the stack, roll it and print it from the top
+        let mut source = String::new();
+        for v in &values {
+            source += &format!("push {}\n", v);
+        }
+        source += &format!("push {}\npush {}\nroll\n", depth, times);
+        for _ in 0..len {
+            source += "push ' '\noutc\noutn\n";
+        }
+
+        let grid = Assembler::parse(&source).unwrap().assemble().unwrap();
+        let program = Compiler::new(grid.clone()).compile().unwrap();
+        let wasm = compile_to_wasm(&program).unwrap();
+        let compiled = run_wasm(&wasm, &[], 1_000_000);
+        let interpreted = run_vm(grid, &[], 100_000);
+        assert!(compiled.finished && interpreted.finished);
+        assert_eq!(
+            compiled.output, interpreted.output,
+            "case {}: roll {:?} depth {} times {}",
+            case, values, depth, times
+        );
+    }
+}
+
+#[test]
+fn test_roll_rotates_in_place() {
+    use canvas_vm::Instruction::*;
+    let program = linear(&[Push(1), Push(2), Push(3), Push(4), Push(3), Push(-4), Roll, OutNumber, OutNumber, OutNumber, OutNumber, Halt]);
+    let wasm = compile_to_wasm(&program).unwrap();
+    // [1, 2, 3, 4] rolled -4 times at depth 3 is [1, 3, 4, 2]
+    assert_eq!(run_wasm(&wasm, &[], 10_000).output, "2431");
+}
+
+#[test]
+fn test_error_policies() {
+    let program = error_program();
+    let options = |on_error| CodegenOptions { on_error, ..CodegenOptions::default() };
+
+    let ignore = compile_to_wasm_with_options(&program, options(ErrorPolicy::Ignore)).unwrap();
+    let run = run_wasm(&ignore, &[], 10_000);
+    assert_eq!((run.output.as_str(), run.trap), (ERROR_PROGRAM_OUTPUT, None));
+
+    let report = compile_to_wasm_with_options(&program, options(ErrorPolicy::Report)).unwrap();
+    let run = run_wasm(&report, &[], 10_000);
+    assert_eq!(run.output, ERROR_PROGRAM_OUTPUT);
+    assert_eq!(run.errors, ERROR_PROGRAM_ERRORS.map(|(code, state)| (code as i32, state as i32)));
+
+    let trap = compile_to_wasm_with_options(&program, options(ErrorPolicy::Trap)).unwrap();
+    let run = run_wasm(&trap, &[], 10_000);
+    assert_eq!(run.output, "");
+    assert!(run.trap.is_some_and(|t| t.contains("unreachable")));
+}
+
+#[test]
+fn test_step_limit() {
+    use canvas_vm::{Instruction::*, Program};
+    let options = |max_steps| CodegenOptions { max_steps, ..CodegenOptions::default() };
+
+    // An endless loop: 0 -> 1 -> 0 -> ...
+    let mut endless = Program::new(0, 0);
+    endless.add_instruction(Push(1));
+    endless.add_instruction(OutNumber);
+    endless.set_successors(0, vec![Some(1)]);
+    endless.set_successors(1, vec![Some(0)]);
+    let wasm = compile_to_wasm_with_options(&endless, options(Some(7))).unwrap();
+    let run = run_wasm(&wasm, &[], 1_000_000);
+    assert!(run.finished && run.trap.is_none());
+    assert_eq!(run.output, "111");
+    assert_eq!((run.status, run.fuel), (Some(ExitStatus::OutOfFuel as i32), Some(0)));
+
+    // Three states: halting on the last unit of fuel is still a halt
+    let mut short = Program::new(0, 0);
+    for instr in [Push(4), OutNumber, Halt] {
+        short.add_instruction(instr);
+    }
+    short.set_successors(0, vec![Some(1)]);
+    short.set_successors(1, vec![Some(2)]);
+    for (max_steps, status, fuel) in [(3, ExitStatus::Halted, 0), (10, ExitStatus::Halted, 7), (2, ExitStatus::OutOfFuel, 0)] {
+        let wasm = compile_to_wasm_with_options(&short, options(Some(max_steps))).unwrap();
+        let run = run_wasm(&wasm, &[], 1_000_000);
+        assert_eq!((run.status, run.fuel), (Some(status as i32), Some(fuel)), "max_steps {}", max_steps);
+        assert_eq!(run.output, "4");
+    }
+
+    // Without a limit there is no fuel global
+    let run = run_wasm(&compile_to_wasm(&short).unwrap(), &[], 1_000_000);
+    assert_eq!((run.status, run.fuel), (Some(ExitStatus::Halted as i32), None));
+}
+
+#[test]
+fn test_wasi_samples_match_bytecode_vm() {
+    let options = CodegenOptions { target: Target::Wasi, ..CodegenOptions::default() };
+    for name in SAMPLES {
+        let grid = load_grid(name);
+        let program = Compiler::new(grid.clone()).compile().expect("Failed to compile");
+        let wasm = compile_to_wasm_with_options(&program, options.clone()).expect("Failed to generate WASM");
+        wasmparser::validate(&wasm).expect("Generated WASM should be valid");
+
+        // The same stdin reads as characters or as numbers
+        let stdin = "12 3\n";
+        let reads_chars = program.instructions.contains(&Instruction::InChar);
+        let reads_numbers = program.instructions.contains(&Instruction::InNumber);
+        let input: Vec<i32> = match (reads_chars, reads_numbers) {
+            (true, true) => continue,
+            (true, false) => stdin.chars().map(|c| c as i32).collect(),
+            (false, _) => vec![12, 3],
+        };
+        let (compiled, _) = run_wasi(&wasm, stdin.as_bytes(), 5_000_000);
+        assert_same(name, &compiled, &run_vm(grid, &input, 200_000));
+    }
+}
+
+#[test]
+fn test_wasi_text_and_numbers() {
+    let echo = "
+            in(char)
+            outc
+            in(char)
+            outc
+            in(char)
+            outc
+            in(number)
+            in(number)
+            add
+            out(number)
+            in(number)
+            out(number)
+            in(char)
+            outc
+    ";
+    let grid = Assembler::parse(echo).unwrap().assemble().unwrap();
+    let program = Compiler::new(grid).compile().unwrap();
+    let options = CodegenOptions { target: Target::Wasi, ..CodegenOptions::default() };
+    let wasm = compile_to_wasm_with_options(&program, options).unwrap();
+
+    // Multi-byte UTF-8, signs and whitespace, and the byte after a number
+    let (run, exit) = run_wasi(&wasm, "é€😀 -12\n+30 -2147483648!".as_bytes(), 1_000_000);
+    assert_eq!((run.output.as_str(), exit), ("é€😀18-2147483648!", None));
+    assert_eq!(run.status, Some(ExitStatus::Halted as i32));
+
+    // Not a number reads as 0; running out of input exits cleanly
+    let (run, exit) = run_wasi(&wasm, "abcx".as_bytes(), 1_000_000);
+    assert_eq!((run.output.as_str(), exit), ("abc", Some(0)));
+
+    // Malformed UTF-8 reads as U+FFFD
+    let (run, _) = run_wasi(&wasm, b"\xE2\x82A\xFF", 1_000_000);
+    assert_eq!(run.output, "\u{FFFD}A\u{FFFD}");
+}
+
+#[test]
+fn test_wasi_exit_status() {
+    use canvas_vm::{Instruction::*, Program};
+    let mut endless = Program::new(0, 0);
+    endless.add_instruction(Push(-7));
+    endless.add_instruction(OutNumber);
+    endless.set_successors(0, vec![Some(1)]);
+    endless.set_successors(1, vec![Some(0)]);
+    let options = CodegenOptions { target: Target::Wasi, max_steps: Some(4), ..CodegenOptions::default() };
+    let wasm = compile_to_wasm_with_options(&endless, options).unwrap();
+    let (run, exit) = run_wasi(&wasm, b"", 1_000_000);
+    assert_eq!((run.output.as_str(), exit), ("-7-7", Some(ExitStatus::OutOfFuel as i32)));
+
+    let options = CodegenOptions { target: Target::Wasi, on_error: ErrorPolicy::Report, ..CodegenOptions::default() };
+    assert!(matches!(compile_to_wasm_with_options(&endless, options), Err(CodegenError::Unsupported(_))));
+}
+
+#[test]
+fn test_wasi_runs_under_node() {
+    // Bounded, since node has no fuel to stop a sample that never halts
+    let options = CodegenOptions { target: Target::Wasi, max_steps: Some(200_000), ..CodegenOptions::default() };
+    for name in SAMPLES {
+        let grid = load_grid(name);
+        let program = Compiler::new(grid.clone()).compile().expect("Failed to compile");
+        if program.instructions.contains(&Instruction::InChar) && program.instructions.contains(&Instruction::InNumber) {
+            continue;
+        }
+        let wasm = compile_to_wasm_with_options(&program, options.clone()).expect("Failed to generate WASM");
+        let input = if program.instructions.contains(&Instruction::InChar) { vec![49, 50] } else { vec![12] };
+        let (output, exit) = run_node_wasi(name, &wasm, b"12");
+        let finished = exit != Some(ExitStatus::OutOfFuel as i32);
+        let vm = run_vm(grid, &input, 200_000);
+        assert_same_output(name, (&output, finished), (&vm.output, vm.finished));
+    }
+
+    let echo = "
+            in(char)
+            outc
+            in(number)
+            out(number)
+            in(char)
+            outc
+    ";
+    let grid = Assembler::parse(echo).unwrap().assemble().unwrap();
+    let program = Compiler::new(grid).compile().unwrap();
+    let options = CodegenOptions { target: Target::Wasi, ..CodegenOptions::default() };
+    let wasm = compile_to_wasm_with_options(&program, options).unwrap();
+    assert_eq!(run_node_wasi("echo", &wasm, "€ -12!".as_bytes()), ("€-12!".to_string(), Some(0)));
+    // Running out of input exits with 0 through proc_exit
+    assert_eq!(run_node_wasi("echo", &wasm, b"a"), ("a".to_string(), Some(0)));
+
+    let mut endless = canvas_vm::Program::new(0, 0);
+    endless.add_instruction(Instruction::Push(-7));
+    endless.add_instruction(Instruction::OutNumber);
+    endless.set_successors(0, vec![Some(1)]);
+    endless.set_successors(1, vec![Some(0)]);
+    let options = CodegenOptions { target: Target::Wasi, max_steps: Some(4), ..CodegenOptions::default() };
+    let wasm = compile_to_wasm_with_options(&endless, options).unwrap();
+    assert_eq!(run_node_wasi("endless", &wasm, b""), ("-7-7".to_string(), Some(ExitStatus::OutOfFuel as i32)));
+}
+
+#[test]
+fn test_stack_grows_until_max_memory_pages() {
+    use canvas_vm::{Instruction::*, Program};
+    // Pushes forever
+    let mut endless = Program::new(0, 0);
+    endless.add_instruction(Push(7));
+    endless.set_successors(0, vec![Some(0)]);
+    let options = |max_memory_pages, max_steps, on_error| CodegenOptions {
+        memory_pages: 1,
+        max_memory_pages,
+        max_steps,
+        on_error,
+        export_stack_pointer: true,
+        ..CodegenOptions::default()
+    };
+
+    // 20000 elements need a second page
+    let wasm = compile_to_wasm_with_options(&endless, options(Some(4), Some(20_000), ErrorPolicy::Ignore)).unwrap();
+    let run = run_wasm(&wasm, &[], 10_000_000);
+    assert_eq!(run.status, Some(ExitStatus::OutOfFuel as i32));
+    assert_eq!((run.stack_pointer, run.memory_pages), (Some(4 + 4 * 20_000), 2));
+
+    // Two pages hold 32767 elements, then the run stops
+    let wasm = compile_to_wasm_with_options(&endless, options(Some(2), None, ErrorPolicy::Report)).unwrap();
+    let run = run_wasm(&wasm, &[], 10_000_000);
+    assert!(run.finished && run.trap.is_none());
+    assert_eq!(run.status, Some(ExitStatus::StackOverflow as i32));
+    assert_eq!((run.stack_pointer, run.memory_pages), (Some(2 * 65536), 2));
+    assert_eq!(run.errors, vec![(ErrorCode::StackOverflow as i32, 0)]);
+
+    let wasm = compile_to_wasm_with_options(&endless, options(Some(1), None, ErrorPolicy::Trap)).unwrap();
+    let run = run_wasm(&wasm, &[], 10_000_000);
+    assert!(run.trap.is_some_and(|t| t.contains("unreachable")));
+    assert_eq!(run.stack_pointer, Some(65536));
+
+    // Not exported unless asked for
+    let run = run_wasm(&compile_to_wasm(&endless).unwrap(), &[], 1_000);
+    assert_eq!(run.stack_pointer, None);
+}
@@ -0,0 +1,746 @@
+//! Static stack-depth analysis of compiled programs
+//!
+//! A forward dataflow pass over the program states computes the range of
+//! stack depths each state can be reached with. Besides the depth, the
+//! values on top of the stack are tracked when they are known constants or
+//! booleans, so a `Pointer` fed by `not`/`greater` only follows the two
+//! rotations it can take instead of all four.
+//!
+//! Underflows are ignored at runtime (the instruction does nothing), which
+//! hides painting mistakes; [`StackAnalysis::warnings`] reports them.
+//! [`LoopAnalysis`] uses the same pass to find loops that can never be
+//! left, so they can be flagged before running into the step limit.
+
+use crate::bytecode::{Instruction, Program};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Known values tracked on top of the stack
+const TRACKED_VALUES: usize = 8;
+
+/// Updates of a state after which its maximum depth is widened to unbounded
+const WIDEN_AFTER: usize = 16;
+
+/// Range of stack depths a state can be reached with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DepthRange {
+    pub min: usize,
+    /// `None` when the depth can grow without bound (e.g. a pushing loop)
+    pub max: Option<usize>,
+}
+
+impl DepthRange {
+    fn exact(depth: usize) -> Self {
+        Self { min: depth, max: Some(depth) }
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self { min: self.min.min(other.min), max: self.max.zip(other.max).map(|(a, b)| a.max(b)) }
+    }
+
+    /// Whether `depth` is within the range
+    pub fn contains(&self, depth: usize) -> bool {
+        depth >= self.min && self.max.is_none_or(|max| depth <= max)
+    }
+}
+
+impl fmt::Display for DepthRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{}..{}", self.min, max),
+            None => write!(f, "{}..", self.min),
+        }
+    }
+}
+
+/// Abstract value of a stack entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Value {
+    Const(i32),
+    /// 0 or 1
+    Bool,
+    Any,
+}
+
+impl Value {
+    fn join(self, other: Value) -> Value {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Value::Const(0 | 1) | Value::Bool, Value::Const(0 | 1) | Value::Bool) => Value::Bool,
+            _ => Value::Any,
+        }
+    }
+}
+
+/// Abstract stack: its depth range and the known values on top
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AbstractStack {
+    pub(crate) depth: DepthRange,
+    /// Top of the stack last; never longer than `depth.min`
+    pub(crate) top: Vec<Value>,
+}
+
+impl AbstractStack {
+    pub(crate) fn empty() -> Self {
+        Self { depth: DepthRange::exact(0), top: Vec::new() }
+    }
+
+    pub(crate) fn join(&self, other: &Self) -> Self {
+        let len = self.top.len().min(other.top.len());
+        let top = self.top[self.top.len() - len..]
+            .iter()
+            .zip(&other.top[other.top.len() - len..])
+            .map(|(a, b)| a.join(*b))
+            .collect();
+        Self { depth: self.depth.union(other.depth), top }
+    }
+
+    /// Value `n` entries below the top (0 is the top)
+    pub(crate) fn peek(&self, n: usize) -> Value {
+        self.top.iter().rev().nth(n).copied().unwrap_or(Value::Any)
+    }
+
+    /// Only the stacks with at least `n` entries, if any
+    fn at_least(&self, n: usize) -> Option<Self> {
+        let depth = DepthRange { min: self.depth.min.max(n), max: self.depth.max };
+        depth.contains(depth.min).then(|| Self { depth, top: self.top.clone() })
+    }
+
+    /// Only the stacks with fewer than `n` entries, if any
+    fn below(&self, n: usize) -> Option<Self> {
+        (self.depth.min < n).then(|| {
+            let max = self.depth.max.map_or(n - 1, |max| max.min(n - 1));
+            Self { depth: DepthRange { min: self.depth.min, max: Some(max) }, top: self.top.clone() }
+        })
+    }
+
+    fn pop(&mut self) -> Value {
+        self.depth.min -= 1;
+        self.depth.max = self.depth.max.map(|max| max - 1);
+        self.top.pop().unwrap_or(Value::Any)
+    }
+
+    fn push(&mut self, value: Value) {
+        self.depth.min += 1;
+        self.depth.max = self.depth.max.map(|max| max + 1);
+        self.top.push(value);
+        if self.top.len() > TRACKED_VALUES {
+            self.top.remove(0);
+        }
+    }
+}
+
+/// Operands an instruction pops; with fewer the VM skips it
+pub(crate) fn operands(instr: &Instruction) -> usize {
+    match instr {
+        Instruction::Push(_) | Instruction::InNumber | Instruction::InChar | Instruction::Nop | Instruction::Halt => 0,
+        Instruction::Pop | Instruction::Not | Instruction::Duplicate | Instruction::Pointer | Instruction::Switch => 1,
+        Instruction::OutNumber | Instruction::OutChar => 1,
+        Instruction::Add
+        | Instruction::Subtract
+        | Instruction::Multiply
+        | Instruction::Divide
+        | Instruction::Mod
+        | Instruction::Greater
+        | Instruction::Roll => 2,
+        // The push always happens, so the fused operation never underflows as a whole
+        Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => 0,
+    }
+}
+
+/// Runs `instr` on `stack` (which holds enough operands), returning the
+/// resulting stacks with the successor index each one continues to
+fn execute(instr: &Instruction, mut stack: AbstractStack) -> Vec<(usize, AbstractStack)> {
+    let binary = |a: Value, b: Value, f: fn(i32, i32) -> i32| match (a, b) {
+        (Value::Const(a), Value::Const(b)) => Value::Const(f(a, b)),
+        _ => Value::Any,
+    };
+    match instr {
+        Instruction::Push(n) => stack.push(Value::Const(*n)),
+        Instruction::Pop | Instruction::OutNumber | Instruction::OutChar => {
+            stack.pop();
+        }
+        Instruction::Add | Instruction::Subtract | Instruction::Multiply => {
+            let b = stack.pop();
+            let a = stack.pop();
+            stack.push(match instr {
+                Instruction::Add => binary(a, b, i32::wrapping_add),
+                Instruction::Subtract => binary(a, b, i32::wrapping_sub),
+                _ => binary(a, b, i32::wrapping_mul),
+            });
+        }
+        Instruction::Divide | Instruction::Mod => {
+            // Division by zero leaves the stack as it was
+            let divisor = stack.peek(0);
+            if divisor == Value::Const(0) {
+                return vec![(0, stack)];
+            }
+            let unchanged = stack.clone();
+            let b = stack.pop();
+            let a = stack.pop();
+            stack.push(match (instr, b) {
+                (Instruction::Divide, Value::Const(b)) if b != 0 => binary(a, Value::Const(b), i32::wrapping_div),
+                (Instruction::Mod, Value::Const(b)) if b != 0 => binary(a, Value::Const(b), i32::wrapping_rem_euclid),
+                _ => Value::Any,
+            });
+            if matches!(divisor, Value::Any | Value::Bool) {
+                return vec![(0, stack.join(&unchanged))];
+            }
+        }
+        Instruction::Greater => {
+            let b = stack.pop();
+            let a = stack.pop();
+            stack.push(match (a, b) {
+                (Value::Const(a), Value::Const(b)) => Value::Const((a > b) as i32),
+                _ => Value::Bool,
+            });
+        }
+        Instruction::Not => {
+            let a = stack.pop();
+            stack.push(match a {
+                Value::Const(a) => Value::Const((a == 0) as i32),
+                _ => Value::Bool,
+            });
+        }
+        Instruction::Duplicate => {
+            let a = stack.peek(0);
+            stack.push(a);
+        }
+        Instruction::Roll => {
+            let times = stack.pop();
+            let depth = stack.pop();
+            match (depth, times) {
+                (Value::Const(depth), Value::Const(times)) if depth >= 0 && depth as usize <= stack.top.len() => {
+                    if depth > 0 {
+                        let start = stack.top.len() - depth as usize;
+                        stack.top[start..].rotate_right(times.rem_euclid(depth) as usize);
+                    }
+                }
+                (Value::Const(depth), _) if depth <= 0 => {}
+                // Entries within the rolled range may have moved
+                (Value::Const(depth), _) => {
+                    let keep = stack.top.len().saturating_sub(depth as usize);
+                    stack.top.truncate(keep);
+                }
+                _ => stack.top.clear(),
+            }
+        }
+        Instruction::InNumber | Instruction::InChar => stack.push(Value::Any),
+        Instruction::Nop => {}
+        Instruction::Halt => return Vec::new(),
+        // Split by `step` before getting here
+        Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => {}
+        Instruction::Pointer => {
+            return match stack.pop() {
+                Value::Const(n) => vec![(n.rem_euclid(4) as usize, stack)],
+                Value::Bool => vec![(0, stack.clone()), (1, stack)],
+                Value::Any => (0..4).map(|k| (k, stack.clone())).collect(),
+            };
+        }
+        Instruction::Switch => {
+            return match stack.pop() {
+                Value::Const(n) => vec![((n % 2 != 0) as usize, stack)],
+                _ => vec![(0, stack.clone()), (1, stack)],
+            };
+        }
+    }
+    vec![(0, stack)]
+}
+
+/// Stacks leaving state `i`, with the successor index each one takes
+fn outcomes(program: &Program, i: usize, stack: &AbstractStack) -> Vec<(usize, AbstractStack)> {
+    step(&program.instructions[i], stack)
+}
+
+/// Stacks after running `instr` from `stack`, superinstructions one part at a time
+pub(crate) fn step(instr: &Instruction, stack: &AbstractStack) -> Vec<(usize, AbstractStack)> {
+    if let Some([push, op]) = instr.unfused() {
+        return step(&push, stack).iter().flat_map(|(_, pushed)| step(&op, pushed)).collect();
+    }
+    let needed = operands(instr);
+    let mut outcomes = Vec::new();
+    // Too few operands: the instruction is skipped and DP/CC are kept
+    if let Some(short) = stack.below(needed) {
+        if *instr != Instruction::Halt {
+            outcomes.push((0, short));
+        }
+    }
+    if let Some(full) = stack.at_least(needed) {
+        outcomes.extend(execute(instr, full));
+    }
+    outcomes
+}
+
+/// Joins `incoming` into the stack on entry to a state, widening its depth
+/// once it has changed too often; returns whether it changed
+pub(crate) fn merge(slot: &mut Option<AbstractStack>, incoming: AbstractStack, updates: &mut usize) -> bool {
+    let mut joined = match slot {
+        Some(old) => old.join(&incoming),
+        None => incoming,
+    };
+    if slot.as_ref() == Some(&joined) {
+        return false;
+    }
+    *updates += 1;
+    if *updates > WIDEN_AFTER {
+        joined.depth.max = None;
+    }
+    *slot = Some(joined);
+    true
+}
+
+/// Abstract stacks on entry to every state; `None` for unreachable states
+pub(crate) fn abstract_stacks(program: &Program) -> Vec<Option<AbstractStack>> {
+    let n = program.len();
+    let mut states: Vec<Option<AbstractStack>> = vec![None; n];
+    let mut updates = vec![0usize; n];
+    if n == 0 {
+        return states;
+    }
+    states[0] = Some(AbstractStack::empty());
+    let mut work = vec![0usize];
+    let mut queued = vec![false; n];
+    queued[0] = true;
+
+    while let Some(i) = work.pop() {
+        queued[i] = false;
+        let Some(stack) = states[i].clone() else { continue };
+        for (branch, out) in outcomes(program, i, &stack) {
+            let Some(Some(next)) = program.get_successors(i).get(branch).copied() else { continue };
+            if next >= n {
+                continue;
+            }
+            if merge(&mut states[next], out, &mut updates[next]) && !queued[next] {
+                queued[next] = true;
+                work.push(next);
+            }
+        }
+    }
+    states
+}
+
+/// Successors each reachable state can actually take given `stacks`;
+/// `None` stands for stopping (a halt or a missing successor)
+pub(crate) fn feasible_edges(program: &Program, stacks: &[Option<AbstractStack>]) -> Vec<Vec<Option<usize>>> {
+    (0..program.len())
+        .map(|i| {
+            let Some(stack) = &stacks[i] else { return Vec::new() };
+            if program.instructions[i] == Instruction::Halt {
+                return vec![None];
+            }
+            let mut targets: Vec<Option<usize>> = outcomes(program, i, stack)
+                .into_iter()
+                .map(|(branch, _)| program.get_successors(i).get(branch).copied().flatten())
+                .map(|next| next.filter(|&next| next < program.len()))
+                .collect();
+            targets.sort();
+            targets.dedup();
+            targets
+        })
+        .collect()
+}
+
+/// Strongly connected component of every state (Kosaraju)
+pub(crate) fn components(successors: &[Vec<Option<usize>>]) -> Vec<usize> {
+    let n = successors.len();
+    let mut predecessors = vec![Vec::new(); n];
+    for (i, targets) in successors.iter().enumerate() {
+        for &t in targets.iter().flatten() {
+            predecessors[t].push(i);
+        }
+    }
+
+    // Post-order of a DFS over the successors
+    let mut visited = vec![false; n];
+    let mut finished = Vec::with_capacity(n);
+    for root in 0..n {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let mut stack = vec![(root, 0)];
+        while let Some((node, next)) = stack.last_mut() {
+            let node = *node;
+            if let Some(&target) = successors[node].get(*next) {
+                *next += 1;
+                if let Some(t) = target {
+                    if !std::mem::replace(&mut visited[t], true) {
+                        stack.push((t, 0));
+                    }
+                }
+            } else {
+                finished.push(node);
+                stack.pop();
+            }
+        }
+    }
+
+    // Components from the reversed graph in reverse post-order
+    let mut component = vec![usize::MAX; n];
+    let mut count = 0;
+    for &root in finished.iter().rev() {
+        if component[root] != usize::MAX {
+            continue;
+        }
+        let mut work = vec![root];
+        component[root] = count;
+        while let Some(node) = work.pop() {
+            for &p in &predecessors[node] {
+                if component[p] == usize::MAX {
+                    component[p] = count;
+                    work.push(p);
+                }
+            }
+        }
+        count += 1;
+    }
+    component
+}
+
+/// How sure an underflow is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Underflow {
+    /// Every path reaching the instruction underflows
+    Definite,
+    /// Some paths reaching the instruction underflow
+    Possible,
+}
+
+/// Instruction that can run with too few values on the stack
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnderflowWarning {
+    /// Instruction (state) index in the program
+    pub index: usize,
+    pub instruction: Instruction,
+    pub kind: Underflow,
+    /// Values the instruction pops
+    pub needed: usize,
+    /// Depths the instruction can be reached with
+    pub depth: DepthRange,
+    /// Source and destination codels, when compiled in debug mode
+    pub from_pos: Option<(usize, usize)>,
+    pub to_pos: Option<(usize, usize)>,
+}
+
+impl fmt::Display for UnderflowWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Underflow::Definite => "always underflows",
+            Underflow::Possible => "may underflow",
+        };
+        write!(f, "{} #{}", self.instruction, self.index)?;
+        if let (Some((fx, fy)), Some((tx, ty))) = (self.from_pos, self.to_pos) {
+            write!(f, " at ({}, {}) -> ({}, {})", fx, fy, tx, ty)?;
+        }
+        write!(f, " {}: needs {} but the stack holds {}", kind, self.needed, self.depth)
+    }
+}
+
+/// Stack depths and underflow warnings of a compiled [`Program`]
+///
+/// ```ignore
+/// for warning in StackAnalysis::new(&program).warnings() {
+///     println!("warning: {}", warning);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct StackAnalysis {
+    depths: Vec<Option<DepthRange>>,
+    warnings: Vec<UnderflowWarning>,
+}
+
+impl StackAnalysis {
+    pub fn new(program: &Program) -> Self {
+        let stacks = abstract_stacks(program);
+        let depths: Vec<Option<DepthRange>> = stacks.iter().map(|s| s.as_ref().map(|s| s.depth)).collect();
+
+        let mut warnings = Vec::new();
+        for (index, depth) in depths.iter().enumerate() {
+            let Some(depth) = *depth else { continue };
+            let instruction = &program.instructions[index];
+            let needed = operands(instruction);
+            if depth.min >= needed {
+                continue;
+            }
+            let kind = if depth.max.is_some_and(|max| max < needed) { Underflow::Definite } else { Underflow::Possible };
+            let debug = program.get_rich_instruction(index).and_then(|r| r.debug.as_ref());
+            warnings.push(UnderflowWarning {
+                index,
+                instruction: instruction.clone(),
+                kind,
+                needed,
+                depth,
+                from_pos: debug.map(|d| d.from_pos),
+                to_pos: debug.map(|d| d.to_pos),
+            });
+        }
+        Self { depths, warnings }
+    }
+
+    /// Depths state `index` can be reached with; `None` if unreachable
+    pub fn depth_at(&self, index: usize) -> Option<DepthRange> {
+        self.depths.get(index).copied().flatten()
+    }
+
+    /// Whether state `index` can be reached from the start
+    pub fn is_reachable(&self, index: usize) -> bool {
+        self.depth_at(index).is_some()
+    }
+
+    /// Largest depth any state can be reached with; `None` when unbounded
+    pub fn max_depth(&self) -> Option<usize> {
+        self.depths.iter().flatten().try_fold(0, |acc, d| d.max.map(|m| acc.max(m)))
+    }
+
+    /// Underflow warnings, by instruction index
+    pub fn warnings(&self) -> &[UnderflowWarning] {
+        &self.warnings
+    }
+}
+
+/// Why a loop never terminates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LoopKind {
+    /// No input, output or stack-shrinking operation: the loop spins
+    /// forever without any visible effect
+    Silent,
+    /// Every branch out of the loop is ruled out by constant conditions, or
+    /// there is none; the loop may still print or read
+    NoExit,
+}
+
+/// Reachable loop that can never be left
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LoopWarning {
+    pub kind: LoopKind,
+    /// Instruction (state) indices in the loop
+    pub states: Vec<usize>,
+    /// Codels of the blocks the loop passes through, when compiled in
+    /// debug mode
+    pub codels: Vec<(usize, usize)>,
+    /// The loop reads input, so it also stops when the input runs out
+    pub reads_input: bool,
+    pub writes_output: bool,
+    /// The stack keeps growing while the loop runs
+    pub grows_stack: bool,
+}
+
+impl fmt::Display for LoopWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            LoopKind::Silent => write!(f, "silent infinite loop")?,
+            LoopKind::NoExit => write!(f, "infinite loop")?,
+        }
+        write!(f, " through {} state(s) starting at #{}", self.states.len(), self.states[0])?;
+        if let Some((x, y)) = self.codels.first() {
+            write!(f, " at ({}, {})", x, y)?;
+        }
+        if self.reads_input {
+            write!(f, ", ends only when the input runs out")?;
+        }
+        if self.grows_stack {
+            write!(f, ", stack grows without bound")?;
+        }
+        Ok(())
+    }
+}
+
+/// Non-terminating loops of a compiled [`Program`]
+///
+/// Branches whose condition is a known constant (e.g. `push 1; pointer`)
+/// only follow the rotation they take, so loops closed by them are found
+/// too. Loops whose exit depends on computed values are not reported.
+#[derive(Debug, Clone)]
+pub struct LoopAnalysis {
+    warnings: Vec<LoopWarning>,
+}
+
+impl LoopAnalysis {
+    pub fn new(program: &Program) -> Self {
+        let stacks = abstract_stacks(program);
+        let edges = feasible_edges(program, &stacks);
+        let component = components(&edges);
+
+        let mut members: Vec<Vec<usize>> = Vec::new();
+        let mut index_of = HashMap::new();
+        for (i, &c) in component.iter().enumerate() {
+            if stacks[i].is_none() {
+                continue;
+            }
+            let slot = *index_of.entry(c).or_insert_with(|| {
+                members.push(Vec::new());
+                members.len() - 1
+            });
+            members[slot].push(i);
+        }
+
+        let mut warnings = Vec::new();
+        for states in members {
+            let first = states[0];
+            let is_cycle = states.len() > 1 || edges[first].contains(&Some(first));
+            let leaves = states
+                .iter()
+                .flat_map(|&i| &edges[i])
+                .any(|next| next.is_none_or(|next| component[next] != component[first]));
+            if !is_cycle || leaves {
+                continue;
+            }
+
+            let has = |pred: fn(&Instruction) -> bool| states.iter().any(|&i| pred(&program.instructions[i]));
+            let reads_input = has(|i| matches!(i, Instruction::InNumber | Instruction::InChar));
+            let writes_output = has(|i| matches!(i, Instruction::OutNumber | Instruction::OutChar));
+            let shrinks = has(|i| {
+                matches!(i, Instruction::Pop | Instruction::Pointer | Instruction::Switch | Instruction::Roll)
+                    || operands(i) == 2
+            });
+            let kind = if reads_input || writes_output || shrinks { LoopKind::NoExit } else { LoopKind::Silent };
+
+            let mut codels: Vec<(usize, usize)> = states
+                .iter()
+                .filter_map(|&i| program.get_rich_instruction(i).and_then(|r| r.debug.as_ref()))
+                .flat_map(|d| [d.from_pos, d.to_pos])
+                .collect();
+            codels.sort();
+            codels.dedup();
+            let grows_stack = states.iter().any(|&i| stacks[i].as_ref().is_some_and(|s| s.depth.max.is_none()));
+            warnings.push(LoopWarning { kind, states, codels, reads_input, writes_output, grows_stack });
+        }
+        Self { warnings }
+    }
+
+    /// Loops that never terminate, ordered by their first state
+    pub fn warnings(&self) -> &[LoopWarning] {
+        &self.warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::compiler::{CompileMode, Compiler};
+    use crate::grid::Grid;
+
+    fn analysis_program(source: &str) -> Program {
+        let grid = Assembler::parse(source).unwrap().assemble().unwrap();
+        Compiler::new(grid).with_mode(CompileMode::Debug).compile().unwrap()
+    }
+
+    fn analyze(source: &str) -> (Program, StackAnalysis) {
+        let program = analysis_program(source);
+        let analysis = StackAnalysis::new(&program);
+        (program, analysis)
+    }
+
+    #[test]
+    fn test_straight_line_depths() {
+        let (program, analysis) = analyze("push 3\npush 4\nadd\ndup\nout(number)\nout(number)\n");
+        assert!(analysis.warnings().is_empty(), "{:?}", analysis.warnings());
+        let add = program.instructions.iter().position(|i| *i == Instruction::Add).unwrap();
+        assert_eq!(analysis.depth_at(add), Some(DepthRange::exact(2)));
+        assert_eq!(analysis.max_depth(), Some(2));
+    }
+
+    #[test]
+    fn test_definite_underflow_has_position() {
+        let (_, analysis) = analyze("push 3\nadd\nout(number)\n");
+        let warning = &analysis.warnings()[0];
+        assert_eq!(warning.instruction, Instruction::Add);
+        assert_eq!(warning.kind, Underflow::Definite);
+        assert_eq!(warning.depth, DepthRange::exact(1));
+        assert!(warning.from_pos.is_some() && warning.to_pos.is_some());
+        assert!(warning.to_string().contains("always underflows"), "{}", warning);
+        // The skipped add leaves the 3 for out(number)
+        assert_eq!(analysis.warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_paths_are_tracked_separately() {
+        // The taken jz path reaches both prints with an empty stack; the
+        // fall-through one only underflows on the second print
+        let (_, analysis) = analyze("in(number)\njz skip\npush 5\nskip:\nout(number)\nout(number)\n");
+        let kinds: Vec<(Instruction, Underflow)> =
+            analysis.warnings().iter().map(|w| (w.instruction.clone(), w.kind)).collect();
+        assert_eq!(kinds, vec![(Instruction::OutNumber, Underflow::Definite); 3]);
+    }
+
+    #[test]
+    fn test_possible_underflow_in_loop() {
+        // Each iteration prints one of the three values, then prints from an
+        // empty stack; the constant condition never leaves the loop
+        let (_, analysis) = analyze("push 1\npush 2\npush 3\nloop:\nout(number)\npush 1\njnz loop\n");
+        // The loop body is compiled twice, once per CC it is entered with
+        let warnings = analysis.warnings();
+        assert!(!warnings.is_empty());
+        for warning in warnings {
+            assert_eq!((&warning.instruction, warning.kind), (&Instruction::OutNumber, Underflow::Possible));
+            assert_eq!(warning.depth.min, 0);
+            assert!(warning.depth.max.is_some_and(|max| max <= 3), "{}", warning);
+        }
+    }
+
+    #[test]
+    fn test_constant_min_mod_minus_one_wraps() {
+        // i32::MIN % -1 overflows unless wrapped, like the VM does
+        let (program, analysis) = analyze("push -2147483648\npush -1\nmod\nout(number)\n");
+        assert!(analysis.warnings().is_empty(), "{:?}", analysis.warnings());
+        let out = program.instructions.iter().position(|i| *i == Instruction::OutNumber).unwrap();
+        assert_eq!(analysis.depth_at(out), Some(DepthRange::exact(1)));
+    }
+
+    #[test]
+    fn test_loops_converge() {
+        // A counting loop keeps its depth; a pushing loop grows without bound
+        let (_, counting) = analyze("push 5\nloop:\ndup\nout(number)\npush 1\nsub\ndup\njnz loop\n");
+        assert!(counting.warnings().is_empty(), "{:?}", counting.warnings());
+        assert_eq!(counting.max_depth(), Some(2));
+
+        let (_, growing) = analyze("loop:\npush 1\njmp loop\n");
+        assert_eq!(growing.max_depth(), None);
+    }
+
+    #[test]
+    fn test_silent_loop() {
+        let program = analysis_program("push 1\nloop:\ndup\nnot\njmp loop\n");
+        let warnings = LoopAnalysis::new(&program).warnings().to_vec();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        let warning = &warnings[0];
+        assert_eq!(warning.kind, LoopKind::Silent);
+        assert!(warning.grows_stack && !warning.codels.is_empty());
+        assert!(warning.to_string().starts_with("silent infinite loop"), "{}", warning);
+    }
+
+    #[test]
+    fn test_constant_condition_loop() {
+        // jnz on a constant 1 always jumps back
+        let program = analysis_program("loop:\nin(number)\nout(number)\npush 1\njnz loop\n");
+        let warnings = LoopAnalysis::new(&program).warnings().to_vec();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert_eq!(warnings[0].kind, LoopKind::NoExit);
+        assert!(warnings[0].reads_input && warnings[0].writes_output && !warnings[0].grows_stack);
+    }
+
+    #[test]
+    fn test_terminating_loops_are_not_reported() {
+        for source in [
+            "push 5\nloop:\ndup\nout(number)\npush 1\nsub\ndup\njnz loop\n",
+            "loop:\nin(number)\ndup\njnz loop\n",
+            "push 3\npush 4\nadd\nout(number)\n",
+        ] {
+            let warnings = LoopAnalysis::new(&analysis_program(source)).warnings().to_vec();
+            assert!(warnings.is_empty(), "{}: {:?}", source, warnings);
+        }
+    }
+
+    #[test]
+    fn test_hand_painted_grid() {
+        // pop on an empty stack, then push 3 / out(number)
+        let grid = Grid::from_text("lR dR K\nK  lR K").unwrap();
+        let program = Compiler::new(grid).compile().unwrap();
+        let analysis = StackAnalysis::new(&program);
+        assert!(analysis.warnings().iter().all(|w| w.from_pos.is_none()));
+        assert!(analysis.warnings().iter().any(|w| w.kind == Underflow::Definite));
+    }
+}
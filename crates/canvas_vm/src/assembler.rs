@@ -0,0 +1,748 @@
+//! Piet assembly: a small textual language and a layout engine that paints it
+//!
+//! ```text
+//! ; countdown from the number read
+//!         in(number)
+//! loop:   dup
+//!         out(number)
+//!         push 1
+//!         subtract
+//!         dup
+//!         jnz loop
+//!         halt
+//! ```
+//!
+//! One statement per line; `;` or `#` start a comment. Statements are the
+//! Piet operations by their [`Instruction::mnemonic`] (plus the short forms
+//! `sub`, `mul`, `div`, `gt`, `dup`, `inn`, `inc`, `outn`, `outc`), `push`
+//! with an integer or a character literal such as `'A'`, labels (`name:`),
+//! `jmp label`, `jz label` / `jnz label` (pop, then branch if zero /
+//! non-zero) and `halt`. `pointer` is rejected because it would steer the
+//! painted layout; branches are lowered to `pointer` by the assembler.
+//!
+//! Layout: every basic block becomes a row of color blocks read left to
+//! right, each sized for the push it performs. Rows end in a white corridor
+//! that turns down a channel on the right, runs left along the bottom and
+//! comes back up a lane on the left into the target row. Turns are single
+//! codels against black walls, so they never execute an operation.
+
+use crate::bytecode::Instruction;
+use crate::error::VmError;
+use crate::grid::Grid;
+use crate::ops::PietColor;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// Largest push painted as a single block; bigger values are built with
+/// multiply/add from smaller pushes
+const MAX_PUSH_BLOCK: i32 = 32;
+
+/// Color of the single-codel turns in corridors
+const TURN_COLOR: PietColor = PietColor::Blue;
+/// Color of the block exiting into a conditional branch
+const BRANCH_COLOR: PietColor = PietColor::LightGreen;
+/// Color of the enclosed block that stops execution
+const HALT_COLOR: PietColor = PietColor::DarkBlue;
+/// Color of the first block of every row
+const ROW_COLOR: PietColor = PietColor::LightRed;
+
+/// One statement of Piet assembly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmStmt {
+    /// A plain operation (`push`, `add`, `out(char)`, ...)
+    Op(Instruction),
+    /// Jump target
+    Label(String),
+    /// Unconditional jump
+    Jump(String),
+    /// Pops a value and jumps if it is zero
+    JumpIfZero(String),
+    /// Pops a value and jumps if it is not zero
+    JumpIfNotZero(String),
+    /// Stops execution
+    Halt,
+}
+
+impl fmt::Display for AsmStmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmStmt::Op(instr) => write!(f, "    {}", instr),
+            AsmStmt::Label(name) => write!(f, "{}:", name),
+            AsmStmt::Jump(name) => write!(f, "    jmp {}", name),
+            AsmStmt::JumpIfZero(name) => write!(f, "    jz {}", name),
+            AsmStmt::JumpIfNotZero(name) => write!(f, "    jnz {}", name),
+            AsmStmt::Halt => write!(f, "    halt"),
+        }
+    }
+}
+
+/// Assembler from Piet assembly to a painted [`Grid`]
+///
+/// ```ignore
+/// let grid = Assembler::parse("push 7\nout(number)\n")?.assemble()?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Assembler {
+    statements: Vec<AsmStmt>,
+}
+
+/// Where execution continues after a row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RowExit {
+    Goto(usize),
+    /// Pops 0/1 and takes `taken` on 1, `fall` on 0
+    Branch { taken: usize, fall: usize },
+    Halt,
+}
+
+/// A basic block laid out as one row
+#[derive(Debug, Clone)]
+struct Row {
+    ops: Vec<Instruction>,
+    exit: RowExit,
+}
+
+/// Jump target before labels are resolved
+enum Target {
+    Next,
+    Label(String, usize),
+}
+
+impl Assembler {
+    pub fn new(statements: Vec<AsmStmt>) -> Self {
+        Self { statements }
+    }
+
+    /// Parses assembly source, reporting errors with 1-based line numbers
+    pub fn parse(source: &str) -> Result<Self, VmError> {
+        let mut statements = Vec::new();
+        let mut defined = HashMap::new();
+        let mut references = Vec::new();
+
+        for (i, raw) in source.lines().enumerate() {
+            let line = i + 1;
+            let error = |message: String| VmError::Parse { line, message };
+            let mut text = strip_comment(raw).trim();
+
+            if let Some((head, rest)) = text.split_once(':') {
+                let head = head.trim();
+                if is_identifier(head) {
+                    if defined.insert(head.to_string(), line).is_some() {
+                        return Err(error(format!("duplicate label '{}'", head)));
+                    }
+                    statements.push(AsmStmt::Label(head.to_string()));
+                    text = rest.trim();
+                }
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let (mnemonic, operand) = match text.split_once(char::is_whitespace) {
+                Some((m, rest)) => (m, rest.trim()),
+                None => (text, ""),
+            };
+            let mnemonic = mnemonic.to_ascii_lowercase();
+
+            let stmt = match mnemonic.as_str() {
+                "push" => {
+                    if operand.is_empty() {
+                        return Err(error("push needs a value".to_string()));
+                    }
+                    AsmStmt::Op(Instruction::Push(parse_value(operand).map_err(error)?))
+                }
+                "jmp" | "jump" | "jz" | "jnz" => {
+                    if !is_identifier(operand) {
+                        return Err(error(format!("{} needs a label", mnemonic)));
+                    }
+                    references.push((line, operand.to_string()));
+                    match mnemonic.as_str() {
+                        "jz" => AsmStmt::JumpIfZero(operand.to_string()),
+                        "jnz" => AsmStmt::JumpIfNotZero(operand.to_string()),
+                        _ => AsmStmt::Jump(operand.to_string()),
+                    }
+                }
+                "pointer" => {
+                    return Err(error("pointer would steer the layout; use jmp, jz or jnz".to_string()));
+                }
+                _ => {
+                    let instr = match mnemonic.as_str() {
+                        "pop" => Instruction::Pop,
+                        "add" => Instruction::Add,
+                        "subtract" | "sub" => Instruction::Subtract,
+                        "multiply" | "mul" => Instruction::Multiply,
+                        "divide" | "div" => Instruction::Divide,
+                        "mod" => Instruction::Mod,
+                        "not" => Instruction::Not,
+                        "greater" | "gt" => Instruction::Greater,
+                        "switch" => Instruction::Switch,
+                        "duplicate" | "dup" => Instruction::Duplicate,
+                        "roll" => Instruction::Roll,
+                        "in(number)" | "inn" => Instruction::InNumber,
+                        "in(char)" | "inc" => Instruction::InChar,
+                        "out(number)" | "outn" => Instruction::OutNumber,
+                        "out(char)" | "outc" => Instruction::OutChar,
+                        "nop" => Instruction::Nop,
+                        "halt" => Instruction::Halt,
+                        _ => return Err(error(format!("unknown instruction '{}'", mnemonic))),
+                    };
+                    if !operand.is_empty() {
+                        return Err(error(format!("{} takes no operand", mnemonic)));
+                    }
+                    match instr {
+                        Instruction::Halt => AsmStmt::Halt,
+                        other => AsmStmt::Op(other),
+                    }
+                }
+            };
+            statements.push(stmt);
+        }
+
+        if let Some((line, name)) = references.iter().find(|(_, name)| !defined.contains_key(name)) {
+            return Err(VmError::Parse { line: *line, message: format!("undefined label '{}'", name) });
+        }
+        Ok(Self { statements })
+    }
+
+    pub fn statements(&self) -> &[AsmStmt] {
+        &self.statements
+    }
+
+    /// Paints the program into a grid that starts at (0,0) heading right
+    ///
+    /// Errors on statements built in code report the 1-based statement
+    /// index as the line.
+    pub fn assemble(&self) -> Result<Grid, VmError> {
+        let rows = self.rows()?;
+        Ok(Layout::new(&rows).paint())
+    }
+
+    /// Splits the statements into rows (basic blocks) and resolves labels,
+    /// dropping rows that can never run
+    fn rows(&self) -> Result<Vec<Row>, VmError> {
+        let mut rows: Vec<(Vec<Instruction>, Option<Target>, Option<Target>)> = Vec::new();
+        let mut labels = HashMap::new();
+        let mut ops = Vec::new();
+
+        for (i, stmt) in self.statements.iter().enumerate() {
+            let line = i + 1;
+            let target = |name: &String| Some(Target::Label(name.clone(), line));
+            match stmt {
+                AsmStmt::Op(Instruction::Pointer) => {
+                    return Err(VmError::Parse {
+                        line,
+                        message: "pointer would steer the layout; use jmp, jz or jnz".to_string(),
+                    });
+                }
+                AsmStmt::Op(Instruction::Halt) | AsmStmt::Halt => {
+                    rows.push((std::mem::take(&mut ops), None, None));
+                }
+                AsmStmt::Op(Instruction::Nop) => {}
+                AsmStmt::Op(Instruction::Push(n)) => lower_push(*n, &mut ops),
+                AsmStmt::Op(instr) => match instr.unfused() {
+                    Some([Instruction::Push(n), op]) => {
+                        lower_push(n, &mut ops);
+                        ops.push(op);
+                    }
+                    _ => ops.push(instr.clone()),
+                },
+                AsmStmt::Label(name) => {
+                    if !ops.is_empty() {
+                        rows.push((std::mem::take(&mut ops), Some(Target::Next), None));
+                    }
+                    if labels.insert(name.clone(), rows.len()).is_some() {
+                        return Err(VmError::Parse { line, message: format!("duplicate label '{}'", name) });
+                    }
+                }
+                AsmStmt::Jump(name) => {
+                    rows.push((std::mem::take(&mut ops), target(name), None));
+                }
+                AsmStmt::JumpIfZero(name) => {
+                    ops.push(Instruction::Not);
+                    rows.push((std::mem::take(&mut ops), Some(Target::Next), target(name)));
+                }
+                AsmStmt::JumpIfNotZero(name) => {
+                    ops.extend([Instruction::Not, Instruction::Not]);
+                    rows.push((std::mem::take(&mut ops), Some(Target::Next), target(name)));
+                }
+            }
+        }
+        // Falling off the end halts
+        rows.push((ops, None, None));
+
+        let resolve = |target: &Target, row: usize| match target {
+            Target::Next => Ok(row + 1),
+            Target::Label(name, line) => labels.get(name).copied().ok_or_else(|| VmError::Parse {
+                line: *line,
+                message: format!("undefined label '{}'", name),
+            }),
+        };
+        let mut resolved = Vec::with_capacity(rows.len());
+        for (i, (ops, fall, taken)) in rows.into_iter().enumerate() {
+            let exit = match (fall, taken) {
+                (Some(fall), Some(taken)) => RowExit::Branch { taken: resolve(&taken, i)?, fall: resolve(&fall, i)? },
+                (Some(next), None) => RowExit::Goto(resolve(&next, i)?),
+                _ => RowExit::Halt,
+            };
+            resolved.push(Row { ops, exit });
+        }
+
+        // Keep only the rows reachable from the first one, in source order
+        let mut reachable = vec![false; resolved.len()];
+        let mut queue = VecDeque::from([0]);
+        reachable[0] = true;
+        while let Some(i) = queue.pop_front() {
+            let next: &[usize] = match &resolved[i].exit {
+                RowExit::Goto(t) => &[*t],
+                RowExit::Branch { taken, fall } => &[*taken, *fall],
+                RowExit::Halt => &[],
+            };
+            for &t in next {
+                if !reachable[t] {
+                    reachable[t] = true;
+                    queue.push_back(t);
+                }
+            }
+        }
+        let mut index = vec![0; resolved.len()];
+        let mut kept = 0;
+        for (i, &r) in reachable.iter().enumerate() {
+            index[i] = kept;
+            kept += usize::from(r);
+        }
+        Ok(resolved
+            .into_iter()
+            .zip(reachable)
+            .filter(|(_, r)| *r)
+            .map(|(mut row, _)| {
+                row.exit = match row.exit {
+                    RowExit::Goto(t) => RowExit::Goto(index[t]),
+                    RowExit::Branch { taken, fall } => RowExit::Branch { taken: index[taken], fall: index[fall] },
+                    RowExit::Halt => RowExit::Halt,
+                };
+                row
+            })
+            .collect())
+    }
+}
+
+impl fmt::Display for Assembler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stmt in &self.statements {
+            writeln!(f, "{}", stmt)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cuts a `;` or `#` comment, ignoring those inside character literals
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            ';' | '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_identifier(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with(|c: char| c.is_ascii_digit())
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Parses an integer or a character literal (`'a'`, `'\n'`, `' '`)
+fn parse_value(operand: &str) -> Result<i32, String> {
+    if let Some(inner) = operand.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+        let c = match inner {
+            "\\n" => '\n',
+            "\\t" => '\t',
+            "\\r" => '\r',
+            "\\0" => '\0',
+            "\\\\" => '\\',
+            "\\'" => '\'',
+            _ => {
+                let mut chars = inner.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => c,
+                    _ => return Err(format!("invalid character literal {}", operand)),
+                }
+            }
+        };
+        return Ok(c as i32);
+    }
+    operand.parse().map_err(|_| format!("invalid push value '{}'", operand))
+}
+
+/// Expands `push n` into pushes of block sizes 1..=MAX_PUSH_BLOCK
+fn lower_push(n: i32, ops: &mut Vec<Instruction>) {
+    match n {
+        0 => ops.extend([Instruction::Push(1), Instruction::Not]),
+        1..=MAX_PUSH_BLOCK => ops.push(Instruction::Push(n)),
+        n if n > 0 => {
+            lower_push(n / MAX_PUSH_BLOCK, ops);
+            ops.extend([Instruction::Push(MAX_PUSH_BLOCK), Instruction::Multiply]);
+            if n % MAX_PUSH_BLOCK != 0 {
+                ops.extend([Instruction::Push(n % MAX_PUSH_BLOCK), Instruction::Add]);
+            }
+        }
+        // 0 - |n| computed as (1 - 1) - |n| so i32::MIN still works
+        _ => {
+            lower_push(0, ops);
+            lower_push(-(n + 1), ops);
+            ops.extend([Instruction::Subtract, Instruction::Push(1), Instruction::Subtract]);
+        }
+    }
+}
+
+/// Hue and lightness steps that encode an operation
+fn color_steps(instr: &Instruction) -> (u8, u8) {
+    match instr {
+        Instruction::Push(_) => (1, 0),
+        Instruction::Pop => (2, 0),
+        Instruction::Add => (3, 0),
+        Instruction::Subtract => (4, 0),
+        Instruction::Multiply => (5, 0),
+        Instruction::Divide => (0, 1),
+        Instruction::Mod => (1, 1),
+        Instruction::Not => (2, 1),
+        Instruction::Greater => (3, 1),
+        Instruction::Pointer => (4, 1),
+        Instruction::Switch => (5, 1),
+        Instruction::Duplicate => (0, 2),
+        Instruction::Roll => (1, 2),
+        Instruction::InNumber => (2, 2),
+        Instruction::InChar => (3, 2),
+        Instruction::OutNumber => (4, 2),
+        Instruction::OutChar => (5, 2),
+        Instruction::Nop | Instruction::Halt => (0, 0),
+        // Superinstructions are lowered before painting
+        Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => (0, 0),
+    }
+}
+
+/// Size of the block that performs `instr` when exited
+pub(crate) fn block_size(instr: &Instruction) -> usize {
+    match instr {
+        Instruction::Push(n) => *n as usize,
+        _ => 1,
+    }
+}
+
+/// Geometry of the painted program
+///
+/// Rows are stacked `pitch` codels apart. Each control edge `e` owns a lane
+/// column `1 + 2e` on the left, a channel column `channel_x + 2e` on the
+/// right and a corridor row `bottom_y + 2e` below the rows, so corridors
+/// only ever cross in white and every turn has its own black wall.
+struct Layout<'a> {
+    rows: &'a [Row],
+    /// (source row, target row) of every edge
+    edges: Vec<(usize, usize)>,
+    /// (down edge, right edge) leaving each row
+    row_edges: Vec<(Option<usize>, Option<usize>)>,
+    /// Rows a push block may hang into
+    height: usize,
+    pitch: usize,
+    code_x: usize,
+    channel_x: usize,
+    bottom_y: usize,
+}
+
+impl<'a> Layout<'a> {
+    fn new(rows: &'a [Row]) -> Self {
+        let mut edges = Vec::new();
+        let mut row_edges = Vec::new();
+        for (i, row) in rows.iter().enumerate() {
+            let mut edge = |target| {
+                edges.push((i, target));
+                Some(edges.len() - 1)
+            };
+            row_edges.push(match row.exit {
+                RowExit::Goto(t) => (None, edge(t)),
+                // The down edge must get the nearer channel
+                RowExit::Branch { taken, fall } => (edge(taken), edge(fall)),
+                RowExit::Halt => (None, None),
+            });
+        }
+
+        let largest = rows.iter().flat_map(|r| &r.ops).map(block_size).max().unwrap_or(1);
+        let height = (1..).find(|h| h * h >= largest).unwrap_or(1).max(2);
+        let code_width = rows
+            .iter()
+            .map(|row| {
+                let blocks: usize = row.ops.iter().map(|op| comb_width(block_size(op), height)).sum::<usize>() + 1;
+                blocks + if row.exit == RowExit::Halt { 4 } else { 0 }
+            })
+            .max()
+            .unwrap_or(1);
+
+        let code_x = 2 * edges.len() + 1;
+        let pitch = height + 1;
+        Self {
+            rows,
+            channel_x: code_x + code_width + 2,
+            bottom_y: rows.len() * pitch,
+            edges,
+            row_edges,
+            height,
+            pitch,
+            code_x,
+        }
+    }
+
+    fn lane(&self, edge: usize) -> usize {
+        1 + 2 * edge
+    }
+
+    fn channel(&self, edge: usize) -> usize {
+        self.channel_x + 2 * edge
+    }
+
+    fn corridor(&self, edge: usize) -> usize {
+        self.bottom_y + 2 * edge
+    }
+
+    fn paint(&self) -> Grid {
+        let width = self.channel_x + 2 * self.edges.len();
+        let height = self.bottom_y + 2 * self.edges.len();
+        let mut canvas = Canvas { width, cells: vec![PietColor::Black; width * height] };
+
+        // White corridors first; colored codels never sit on a crossing
+        for (i, _) in self.rows.iter().enumerate() {
+            let y = i * self.pitch;
+            let start = self
+                .edges
+                .iter()
+                .enumerate()
+                .filter(|(_, &(_, target))| target == i)
+                .map(|(e, _)| self.lane(e))
+                .min()
+                .unwrap_or(self.code_x);
+            let start = if i == 0 { 0 } else { start };
+            canvas.hline(start, self.code_x, y, PietColor::White);
+        }
+        for (e, &(source, target)) in self.edges.iter().enumerate() {
+            let (x, lane, y) = (self.channel(e), self.lane(e), self.corridor(e));
+            canvas.vline(x, source * self.pitch + 1, y, PietColor::White);
+            canvas.hline(lane + 1, x, y, PietColor::White);
+            canvas.vline(lane, target * self.pitch + 1, y, PietColor::White);
+            canvas.set(x, y, TURN_COLOR);
+            canvas.set(lane, y, TURN_COLOR);
+            canvas.set(lane, target * self.pitch, TURN_COLOR);
+        }
+
+        for (i, row) in self.rows.iter().enumerate() {
+            let y = i * self.pitch;
+            let mut x = self.code_x;
+            let mut color = ROW_COLOR;
+            for op in &row.ops {
+                x = canvas.comb(x, y, block_size(op), self.height, color);
+                let (hue, lightness) = color_steps(op);
+                color = color.shifted(hue, lightness).unwrap_or(color);
+            }
+            x = canvas.comb(x, y, 1, self.height, color);
+
+            match self.row_edges[i] {
+                (None, Some(right)) => {
+                    canvas.hline(x, self.channel(right), y, PietColor::White);
+                    canvas.set(self.channel(right), y, TURN_COLOR);
+                }
+                (Some(down), Some(right)) => {
+                    // Pops 0/1 into pointer: 1 turns down the nearer channel
+                    let branch_x = self.channel(down);
+                    canvas.hline(x, branch_x - 1, y, PietColor::White);
+                    canvas.set(branch_x - 1, y, BRANCH_COLOR);
+                    let (hue, lightness) = color_steps(&Instruction::Pointer);
+                    canvas.set(branch_x, y, BRANCH_COLOR.shifted(hue, lightness).unwrap_or(TURN_COLOR));
+                    canvas.hline(branch_x + 1, self.channel(right), y, PietColor::White);
+                    canvas.set(self.channel(right), y, TURN_COLOR);
+                }
+                _ => {
+                    // Entered through its top row, which is neither end of its
+                    // left edge, so every exit of this block is walled off
+                    canvas.set(x, y, PietColor::White);
+                    canvas.hline(x + 1, x + 4, y, HALT_COLOR);
+                    canvas.hline(x, x + 4, y + 1, HALT_COLOR);
+                }
+            }
+        }
+
+        Grid::new(width, height, canvas.cells).expect("layout dimensions match its cells")
+    }
+}
+
+/// Width of a block of `size` codels whose last column is only its top codel
+pub(crate) fn comb_width(size: usize, height: usize) -> usize {
+    if size <= 1 {
+        1
+    } else {
+        (size - 1).div_ceil(height) + 1
+    }
+}
+
+struct Canvas {
+    width: usize,
+    cells: Vec<PietColor>,
+}
+
+impl Canvas {
+    fn set(&mut self, x: usize, y: usize, color: PietColor) {
+        self.cells[y * self.width + x] = color;
+    }
+
+    /// Paints `[x0, x1)` on row `y`
+    fn hline(&mut self, x0: usize, x1: usize, y: usize, color: PietColor) {
+        for x in x0..x1 {
+            self.set(x, y, color);
+        }
+    }
+
+    /// Paints `[y0, y1)` on column `x`
+    fn vline(&mut self, x: usize, y0: usize, y1: usize, color: PietColor) {
+        for y in y0..y1 {
+            self.set(x, y, color);
+        }
+    }
+
+    /// Paints a block of `size` codels hanging from row `y`, whose rightmost
+    /// column is a single codel on row `y` so its exit does not depend on
+    /// CC; returns the column after it
+    fn comb(&mut self, x: usize, y: usize, size: usize, height: usize, color: PietColor) -> usize {
+        let width = comb_width(size, height);
+        self.hline(x, x + width, y, color);
+        let mut left = size - width;
+        for column in x..x + width - 1 {
+            let hang = left.min(height - 1);
+            self.vline(column, y + 1, y + 1 + hang, color);
+            left -= hang;
+        }
+        x + width
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::testutil::{run_grid, run_program};
+
+    fn assemble(source: &str) -> Grid {
+        Assembler::parse(source).unwrap().assemble().unwrap()
+    }
+
+    /// Runs the compiled program, so the compiler's view of the image is checked too
+    fn run_compiled(source: &str, input: &[i32]) -> String {
+        run_program(&Compiler::new(assemble(source)).compile().unwrap(), input)
+    }
+
+    const COUNTDOWN: &str = "
+        ; countdown from the number read
+                in(number)
+        loop:   dup
+                out(number)
+                push 1
+                subtract
+                dup
+                jnz loop
+                halt
+    ";
+
+    #[test]
+    fn test_straight_line() {
+        assert_eq!(run_grid(assemble("push 3\npush 4\nadd\nout(number)\n"), &[]), "7");
+        assert_eq!(run_grid(assemble("push 'H'\noutc\npush 'i'\noutc\n"), &[]), "Hi");
+    }
+
+    #[test]
+    fn test_push_lowering() {
+        for n in [0, 1, 31, 32, 33, 64, 1000, 123_456, -1, -5, -1000, i32::MAX, i32::MIN] {
+            let source = format!("push {}\nout(number)\n", n);
+            assert_eq!(run_grid(assemble(&source), &[]), n.to_string(), "push {}", n);
+        }
+    }
+
+    #[test]
+    fn test_loops_and_branches() {
+        assert_eq!(run_grid(assemble(COUNTDOWN), &[5]), "54321");
+        assert_eq!(run_grid(assemble(COUNTDOWN), &[1]), "1");
+
+        // Parity with a forward jz and a jmp over the else branch
+        let parity = "
+                in(number)
+                push 2
+                mod
+                jz even
+                push 'o'
+                jmp done
+            even:
+                push 'e'
+            done:
+                out(char)
+        ";
+        assert_eq!(run_grid(assemble(parity), &[7]), "o");
+        assert_eq!(run_grid(assemble(parity), &[10]), "e");
+    }
+
+    #[test]
+    fn test_compiled_program_matches_vm() {
+        assert_eq!(run_compiled(COUNTDOWN, &[4]), "4321");
+
+        // Division truncates and mod is Euclidean, like the VM
+        let negative = "push -7\npush 2\ndiv\nout(number)\npush -7\npush 3\nmod\nout(number)\n";
+        assert_eq!(run_grid(assemble(negative), &[]), "-32");
+        assert_eq!(run_compiled(negative, &[]), "-32");
+        let nested = "
+                push 3
+            outer:
+                dup
+                push 2
+            inner:
+                push '*'
+                outc
+                push 1
+                sub
+                dup
+                jnz inner
+                pop
+                pop
+                push 1
+                sub
+                dup
+                jnz outer
+        ";
+        assert_eq!(run_grid(assemble(nested), &[]), "******");
+        assert_eq!(run_compiled(nested, &[]), "******");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let line_of = |source: &str| match Assembler::parse(source) {
+            Err(VmError::Parse { line, .. }) => line,
+            other => panic!("expected parse error, got {:?}", other),
+        };
+        assert_eq!(line_of("push 1\nfrobnicate\n"), 2);
+        assert_eq!(line_of("push\n"), 1);
+        assert_eq!(line_of("push 1\n\njmp nowhere\n"), 3);
+        assert_eq!(line_of("a:\na:\n"), 2);
+        assert_eq!(line_of("pointer\n"), 1);
+        assert_eq!(line_of("add 3\n"), 1);
+
+        let statements = vec![AsmStmt::Jump("missing".to_string())];
+        assert!(matches!(Assembler::new(statements).assemble(), Err(VmError::Parse { line: 1, .. })));
+    }
+
+    #[test]
+    fn test_display_roundtrip() {
+        let asm = Assembler::parse(COUNTDOWN).unwrap();
+        let again = Assembler::parse(&asm.to_string()).unwrap();
+        assert_eq!(asm.statements(), again.statements());
+        assert!(Assembler::parse("push ';' ; comment\n").is_ok());
+    }
+}
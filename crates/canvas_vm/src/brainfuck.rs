@@ -0,0 +1,235 @@
+//! Brainfuck to Piet transpiler
+//!
+//! The tape is a ring of `tape_size` cells kept on the stack with the
+//! current cell on top and its right neighbour just below, so `>` and `<`
+//! are a single `roll` over the whole tape. Cells are 8-bit and wrap.
+//! Loops become a `jz` over the body and a `jnz` back to its start.
+//! Reading past the end of the input stops the VM like any `in(char)`.
+
+use crate::assembler::{AsmStmt, Assembler};
+use crate::bytecode::Instruction;
+use crate::error::VmError;
+use crate::grid::Grid;
+use crate::textgen::NumberBuilder;
+
+/// Transpiler from Brainfuck source to Piet assembly and grids
+#[derive(Debug, Clone)]
+pub struct BrainfuckTranspiler {
+    tape_size: usize,
+}
+
+impl Default for BrainfuckTranspiler {
+    fn default() -> Self {
+        Self { tape_size: 64 }
+    }
+}
+
+impl BrainfuckTranspiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of cells in the (circular) tape
+    pub fn tape_size(mut self, cells: usize) -> Self {
+        self.tape_size = cells.max(1);
+        self
+    }
+
+    /// Lowers `source` to assembly statements; characters other than the
+    /// eight commands are comments
+    pub fn transpile(&self, source: &str) -> Result<Vec<AsmStmt>, VmError> {
+        let mut numbers = NumberBuilder::new();
+        let mut out = Vec::new();
+        let mut ops = Vec::new();
+        let emit = |out: &mut Vec<AsmStmt>, ops: &mut Vec<Instruction>| {
+            out.extend(ops.drain(..).map(AsmStmt::Op));
+        };
+
+        // Zeroed tape
+        ops.extend([Instruction::Push(1), Instruction::Not]);
+        ops.extend(vec![Instruction::Duplicate; self.tape_size - 1]);
+
+        let commands: Vec<(char, usize)> = source
+            .lines()
+            .enumerate()
+            .flat_map(|(i, line)| line.chars().filter(|c| "+-<>.,[]".contains(*c)).map(move |c| (c, i + 1)))
+            .collect();
+        let mut open: Vec<(usize, usize)> = Vec::new();
+        let mut loops = 0;
+        let mut at = 0;
+
+        while at < commands.len() {
+            let (command, line) = commands[at];
+            // Length of the run of identical commands starting here
+            let run = commands[at..].iter().take_while(|(c, _)| *c == command).count();
+            match command {
+                '+' | '-' => {
+                    // Net change of a whole +/- run, then wrap to 0..=255
+                    let mut delta: i32 = 0;
+                    while let Some(&(c @ ('+' | '-'), _)) = commands.get(at) {
+                        delta += if c == '+' { 1 } else { -1 };
+                        at += 1;
+                    }
+                    let delta = delta.rem_euclid(256);
+                    if delta != 0 {
+                        numbers.push(delta, &mut ops);
+                        ops.push(Instruction::Add);
+                        numbers.push(256, &mut ops);
+                        ops.push(Instruction::Mod);
+                    }
+                    continue;
+                }
+                '>' | '<' => {
+                    let mut shift: i64 = 0;
+                    while let Some(&(c @ ('>' | '<'), _)) = commands.get(at) {
+                        shift += if c == '>' { 1 } else { -1 };
+                        at += 1;
+                    }
+                    let times = shift.rem_euclid(self.tape_size as i64) as i32;
+                    if times != 0 {
+                        numbers.push(self.tape_size as i32, &mut ops);
+                        numbers.push(times, &mut ops);
+                        ops.push(Instruction::Roll);
+                    }
+                    continue;
+                }
+                '.' => {
+                    for _ in 0..run {
+                        ops.extend([Instruction::Duplicate, Instruction::OutChar]);
+                    }
+                }
+                ',' => {
+                    for _ in 0..run {
+                        ops.extend([Instruction::Pop, Instruction::InChar]);
+                    }
+                }
+                '[' => {
+                    // [-] and [+] just clear the cell
+                    if let [(_, _), ('-' | '+', _), (']', _), ..] = commands[at..] {
+                        ops.extend([Instruction::Pop, Instruction::Push(1), Instruction::Not]);
+                        at += 3;
+                        continue;
+                    }
+                    loops += 1;
+                    open.push((loops, line));
+                    ops.push(Instruction::Duplicate);
+                    emit(&mut out, &mut ops);
+                    out.push(AsmStmt::JumpIfZero(format!("end{}", loops)));
+                    out.push(AsmStmt::Label(format!("body{}", loops)));
+                    at += 1;
+                    continue;
+                }
+                _ => {
+                    let Some((id, _)) = open.pop() else {
+                        return Err(VmError::Parse { line, message: "unmatched ']'".to_string() });
+                    };
+                    ops.push(Instruction::Duplicate);
+                    emit(&mut out, &mut ops);
+                    out.push(AsmStmt::JumpIfNotZero(format!("body{}", id)));
+                    out.push(AsmStmt::Label(format!("end{}", id)));
+                    at += 1;
+                    continue;
+                }
+            }
+            at += run;
+        }
+
+        if let Some(&(_, line)) = open.last() {
+            return Err(VmError::Parse { line, message: "unmatched '['".to_string() });
+        }
+        emit(&mut out, &mut ops);
+        out.push(AsmStmt::Halt);
+        Ok(out)
+    }
+
+    /// Transpiles and paints `source`
+    pub fn to_grid(&self, source: &str) -> Result<Grid, VmError> {
+        Assembler::new(self.transpile(source)?).assemble()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::run_grid;
+
+    /// Reference interpreter: 8-bit wrapping cells on a circular tape
+    fn interpret(source: &str, tape_size: usize, input: &str) -> String {
+        let code: Vec<char> = source.chars().filter(|c| "+-<>.,[]".contains(*c)).collect();
+        let mut jumps = vec![0; code.len()];
+        let mut stack = Vec::new();
+        for (i, &c) in code.iter().enumerate() {
+            if c == '[' {
+                stack.push(i);
+            } else if c == ']' {
+                let j = stack.pop().unwrap();
+                jumps[i] = j;
+                jumps[j] = i;
+            }
+        }
+        let mut tape = vec![0u8; tape_size];
+        let mut input = input.chars();
+        let (mut ptr, mut pc) = (0, 0);
+        let mut out = String::new();
+        while pc < code.len() {
+            match code[pc] {
+                '+' => tape[ptr] = tape[ptr].wrapping_add(1),
+                '-' => tape[ptr] = tape[ptr].wrapping_sub(1),
+                '>' => ptr = (ptr + 1) % tape_size,
+                '<' => ptr = (ptr + tape_size - 1) % tape_size,
+                '.' => out.push(tape[ptr] as char),
+                ',' => tape[ptr] = input.next().unwrap() as u8,
+                '[' if tape[ptr] == 0 => pc = jumps[pc],
+                ']' if tape[ptr] != 0 => pc = jumps[pc],
+                _ => {}
+            }
+            pc += 1;
+        }
+        out
+    }
+
+    fn run(source: &str, tape_size: usize, input: &str) -> String {
+        let grid = BrainfuckTranspiler::new().tape_size(tape_size).to_grid(source).unwrap();
+        let input: Vec<i32> = input.chars().map(|c| c as i32).collect();
+        run_grid(grid, &input)
+    }
+
+    fn check(source: &str, tape_size: usize, input: &str) {
+        let expected = interpret(source, tape_size, input);
+        assert_eq!(run(source, tape_size, input), expected, "program {}", source);
+    }
+
+    #[test]
+    fn test_hello_world() {
+        let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!(interpret(hello, 16, ""), "Hello World!\n");
+        check(hello, 16, "");
+    }
+
+    #[test]
+    fn test_input_and_wrapping() {
+        // Reverse the input up to a newline
+        check(">,----------[++++++++++>,----------]<[.<]", 16, "piet\n");
+        // Underflow wraps to 255, then counts back up to 0
+        check("-[>+<-]>[-<+>]<.", 8, "");
+        // Left from cell 0 lands on the last cell of the ring
+        check("<+++++[>++++++++++<-]>+++.", 4, "");
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        // Prints the digits 0-9 from nested counters
+        let digits = "++++++++++[>+++++<-]>--<++++++++++[>.+<-]";
+        check(digits, 8, "");
+        // Multiply 6 * 7 and print it as '*'
+        check("++++++[>+++++++<-]>.", 8, "");
+        check("+++[>+++[>+++[>+<-]<-]<-]>>>+++++++++++++++.", 8, "");
+    }
+
+    #[test]
+    fn test_unmatched_brackets() {
+        let transpiler = BrainfuckTranspiler::new();
+        assert!(matches!(transpiler.transpile("+\n+]"), Err(VmError::Parse { line: 2, .. })));
+        assert!(matches!(transpiler.transpile("[\n[]"), Err(VmError::Parse { line: 1, .. })));
+    }
+}
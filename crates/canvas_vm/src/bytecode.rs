@@ -1,9 +1,10 @@
 /// Intermediate bytecode for optimized Piet execution
 use crate::exits::{CodelChooser, Direction};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 /// Metadata about the compiled program
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ProgramMetadata {
     /// Codel size used during compilation (pixels per codel)
     pub codel_size: usize,
@@ -30,7 +31,7 @@ impl Default for ProgramMetadata {
 }
 
 /// Debug information for an instruction
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct InstructionDebugInfo {
     /// Position of the source codel (x, y)
     pub from_pos: (usize, usize),
@@ -63,28 +64,33 @@ impl Default for InstructionDebugInfo {
 }
 
 /// Rich instruction with debug information
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RichInstruction {
     /// The actual operation
     pub op: Instruction,
     /// Debug information (optional, can be stripped for production)
     pub debug: Option<InstructionDebugInfo>,
+    /// Debug information of every original state this instruction stands
+    /// for, in execution order (only set by the optimizer; the debugger
+    /// reports it in each `ExecutionStep`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged: Vec<InstructionDebugInfo>,
 }
 
 impl RichInstruction {
     /// Creates a new rich instruction with debug info
     pub fn new(op: Instruction, debug: InstructionDebugInfo) -> Self {
-        Self { op, debug: Some(debug) }
+        Self { op, debug: Some(debug), merged: Vec::new() }
     }
     
     /// Creates a rich instruction without debug info
     pub fn simple(op: Instruction) -> Self {
-        Self { op, debug: None }
+        Self { op, debug: None, merged: Vec::new() }
     }
 }
 
 /// Bytecode instruction
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Instruction {
     /// Push: pushes block size to stack
     Push(i32),
@@ -124,10 +130,68 @@ pub enum Instruction {
     Nop,
     /// Halt: halts the VM
     Halt,
+    /// Superinstruction for `push n` followed by `add`
+    PushAdd(i32),
+    /// Superinstruction for `push n` followed by `subtract`
+    PushSubtract(i32),
+    /// Superinstruction for `push n` followed by `multiply`
+    PushMultiply(i32),
+}
+
+impl Instruction {
+    /// Lowercase Piet name of the operation (without operand)
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Push(_) => "push",
+            Instruction::Pop => "pop",
+            Instruction::Add => "add",
+            Instruction::Subtract => "subtract",
+            Instruction::Multiply => "multiply",
+            Instruction::Divide => "divide",
+            Instruction::Mod => "mod",
+            Instruction::Not => "not",
+            Instruction::Greater => "greater",
+            Instruction::Pointer => "pointer",
+            Instruction::Switch => "switch",
+            Instruction::Duplicate => "duplicate",
+            Instruction::Roll => "roll",
+            Instruction::InNumber => "in(number)",
+            Instruction::InChar => "in(char)",
+            Instruction::OutNumber => "out(number)",
+            Instruction::OutChar => "out(char)",
+            Instruction::Nop => "nop",
+            Instruction::Halt => "halt",
+            Instruction::PushAdd(_) => "push+add",
+            Instruction::PushSubtract(_) => "push+subtract",
+            Instruction::PushMultiply(_) => "push+multiply",
+        }
+    }
+
+    /// The two plain instructions a superinstruction stands for
+    pub fn unfused(&self) -> Option<[Instruction; 2]> {
+        match self {
+            Instruction::PushAdd(n) => Some([Instruction::Push(*n), Instruction::Add]),
+            Instruction::PushSubtract(n) => Some([Instruction::Push(*n), Instruction::Subtract]),
+            Instruction::PushMultiply(n) => Some([Instruction::Push(*n), Instruction::Multiply]),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Push(n) => write!(f, "push {}", n),
+            Instruction::PushAdd(n) | Instruction::PushSubtract(n) | Instruction::PushMultiply(n) => {
+                write!(f, "{} {}", self.mnemonic(), n)
+            }
+            other => f.write_str(other.mnemonic()),
+        }
+    }
 }
 
 /// Compiled program (bytecode + metadata)
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Program {
     /// Program metadata (codel size, dimensions, etc.)
     pub metadata: ProgramMetadata,
@@ -145,6 +209,15 @@ pub struct Program {
     pub width: usize,
     /// Original grid height (in codels)
     pub height: usize,
+    /// Successor instructions of each instruction, one entry per branch
+    ///
+    /// `Pointer` has 4 entries (0..3 clockwise rotations), `Switch` has 2
+    /// (CC kept, CC toggled), `Halt` has none and every other instruction
+    /// has 1. `None` means execution stops after taking that branch, or
+    /// that the compiler proved the branch is never taken.
+    /// Execution starts at instruction 0.
+    #[serde(default)]
+    pub successors: Vec<Vec<Option<usize>>>,
 }
 
 impl Program {
@@ -164,6 +237,7 @@ impl Program {
             next_position: vec![vec![None; width]; height],
             width,
             height,
+            successors: Vec::new(),
         }
     }
     
@@ -179,6 +253,7 @@ impl Program {
             next_position: vec![vec![None; width]; height],
             width,
             height,
+            successors: Vec::new(),
         }
     }
 
@@ -187,6 +262,7 @@ impl Program {
         let idx = self.instructions.len();
         self.instructions.push(instr.clone());
         self.rich_instructions.push(RichInstruction::simple(instr));
+        self.successors.push(Vec::new());
         idx
     }
     
@@ -195,59 +271,59 @@ impl Program {
         let idx = self.instructions.len();
         self.instructions.push(instr.clone());
         self.rich_instructions.push(RichInstruction::new(instr, debug));
+        self.successors.push(Vec::new());
         idx
     }
 
+    /// Sets the successors of an instruction (see [`Program::successors`])
+    pub fn set_successors(&mut self, idx: usize, successors: Vec<Option<usize>>) {
+        if self.successors.len() < self.instructions.len() {
+            self.successors.resize(self.instructions.len(), Vec::new());
+        }
+        if let Some(entry) = self.successors.get_mut(idx) {
+            *entry = successors;
+        }
+    }
+
+    /// Gets the successors of an instruction (empty if unknown)
+    pub fn get_successors(&self, idx: usize) -> &[Option<usize>] {
+        self.successors.get(idx).map(Vec::as_slice).unwrap_or(&[])
+    }
+
     /// Associates a position with an instruction
     pub fn map_position(&mut self, x: usize, y: usize, instr_idx: usize) {
-        if y < self.height && x < self.width {
-            self.position_map[y][x] = Some(instr_idx);
+        if let Some(cell) = self.position_map.get_mut(y).and_then(|row| row.get_mut(x)) {
+            *cell = Some(instr_idx);
         }
     }
 
     /// Associates a position with its next position
     pub fn map_next_position(&mut self, x: usize, y: usize, next_x: usize, next_y: usize) {
-        if y < self.height && x < self.width {
-            self.next_position[y][x] = Some((next_x, next_y));
+        if let Some(cell) = self.next_position.get_mut(y).and_then(|row| row.get_mut(x)) {
+            *cell = Some((next_x, next_y));
         }
     }
 
     /// Gets the next position from current position
     pub fn get_next_position(&self, x: usize, y: usize) -> Option<(usize, usize)> {
-        if y < self.height && x < self.width {
-            self.next_position[y][x]
-        } else {
-            None
-        }
+        self.next_position.get(y).and_then(|row| row.get(x)).copied().flatten()
     }
 
     /// Gets the instruction at a position
     pub fn get_instruction_at(&self, x: usize, y: usize) -> Option<&Instruction> {
-        if y < self.height && x < self.width {
-            if let Some(idx) = self.position_map[y][x] {
-                return self.instructions.get(idx);
-            }
-        }
-        None
+        self.get_instruction_index_at(x, y).and_then(|idx| self.instructions.get(idx))
     }
     
     /// Gets the rich instruction at a position
     pub fn get_rich_instruction_at(&self, x: usize, y: usize) -> Option<&RichInstruction> {
-        if y < self.height && x < self.width {
-            if let Some(idx) = self.position_map[y][x] {
-                return self.rich_instructions.get(idx);
-            }
-        }
-        None
+        self.get_instruction_index_at(x, y).and_then(|idx| self.rich_instructions.get(idx))
     }
     
     /// Gets the instruction index at a position
+    ///
+    /// Maps may be empty (e.g. loaded from a stripped `.pbc` file).
     pub fn get_instruction_index_at(&self, x: usize, y: usize) -> Option<usize> {
-        if y < self.height && x < self.width {
-            self.position_map[y][x]
-        } else {
-            None
-        }
+        self.position_map.get(y).and_then(|row| row.get(x)).copied().flatten()
     }
     
     /// Gets a rich instruction by index
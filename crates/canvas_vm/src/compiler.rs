@@ -1,9 +1,11 @@
 /// Compilador de Piet: Imagen → Bytecode
+use crate::analysis::{merge, step, AbstractStack};
 use crate::bytecode::{Instruction, InstructionDebugInfo, Program, ProgramMetadata};
 use crate::error::VmError;
 use crate::exits::{CodelChooser, Direction, Position};
 use crate::grid::Grid;
 use crate::ops::{get_operation, Operation, PietColor};
+use serde::Serialize;
 use std::collections::{HashMap, HashSet, VecDeque};
 
 /// Compilation mode - similar to modern language compilers
@@ -16,7 +18,180 @@ pub enum CompileMode {
     Debug,
 }
 
+/// Estado de exploración: posición, DP y CC
+type State = (Position, Direction, CodelChooser);
+
+/// Where a single state leads, independent of the BFS order
+#[derive(Debug, Clone)]
+enum Explored {
+    /// Nothing to emit (e.g. a white codel with no way out)
+    Skip,
+    /// Execution stops at this state
+    Halt {
+        dp: Direction,
+        cc: CodelChooser,
+        block_size: usize,
+        to_color: &'static str,
+    },
+    /// White codel sliding to the next colored codel
+    Slide { next: Position },
+    /// Color transition out of the state's block
+    Transition {
+        instr: Instruction,
+        next: Position,
+        dp: Direction,
+        cc: CodelChooser,
+        block_size: usize,
+    },
+}
+
+/// Summary of a compilation (see [`Compiler::compile_with_report`])
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CompileReport {
+    /// Instructions in the compiled program
+    pub instructions: usize,
+    /// Pointer/Switch branches never taken because their argument is known
+    pub pruned_branches: usize,
+    /// Instructions the program would have without pruning
+    pub pruned_states: usize,
+}
+
+/// Abstract stacks flowing through the BFS, used to prune the branches of
+/// Pointer/Switch whose argument is a known constant
+#[derive(Default)]
+struct Pruning {
+    /// Stack on entry to each instruction
+    stacks: Vec<Option<AbstractStack>>,
+    updates: Vec<usize>,
+    /// Stacks reaching states that have no instruction yet
+    incoming: HashMap<State, AbstractStack>,
+    /// Instructions whose entry stack changed
+    dirty: VecDeque<usize>,
+    queued: HashSet<usize>,
+    /// (instruction, branch) pairs some stack takes
+    taken: HashSet<(usize, usize)>,
+}
+
+impl Pruning {
+    /// `state` was compiled to instruction `idx`
+    fn arrive(&mut self, state: State, idx: usize) {
+        if let Some(stack) = self.incoming.remove(&state) {
+            self.flow_into(idx, stack);
+        }
+    }
+
+    /// `stack` reaches `state`, which has no instruction yet
+    fn pend(&mut self, state: State, stack: AbstractStack) {
+        let joined = match self.incoming.remove(&state) {
+            Some(old) => old.join(&stack),
+            None => stack,
+        };
+        self.incoming.insert(state, joined);
+    }
+
+    fn flow_into(&mut self, idx: usize, stack: AbstractStack) {
+        if self.stacks.len() <= idx {
+            self.stacks.resize(idx + 1, None);
+            self.updates.resize(idx + 1, 0);
+        }
+        if merge(&mut self.stacks[idx], stack, &mut self.updates[idx]) && self.queued.insert(idx) {
+            self.dirty.push_back(idx);
+        }
+    }
+}
+
+/// Exploration results kept between compiles of an edited grid
+///
+/// Pass the same cache to [`Compiler::compile_incremental`] after every
+/// edit; states unaffected by the changed codels are not explored again.
+#[derive(Debug, Clone, Default)]
+pub struct CompileCache {
+    /// Grid the cached results were computed from
+    grid: Option<Grid>,
+    /// Outcome of each explored state and the codels it read
+    states: HashMap<State, (Explored, Vec<Position>)>,
+    /// States taken from the cache during the last compile
+    reused: usize,
+    /// States explored again during the last compile
+    recomputed: usize,
+}
+
+impl CompileCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of states reused by the last compile
+    pub fn reused_states(&self) -> usize {
+        self.reused
+    }
+
+    /// Number of states explored by the last compile
+    pub fn recomputed_states(&self) -> usize {
+        self.recomputed
+    }
+
+    /// Number of cached states
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Drops every cached state that an edit from the cached grid to
+    /// `grid` may have changed
+    fn invalidate(&mut self, grid: &Grid) {
+        let Some(old) = self.grid.as_ref() else {
+            self.states.clear();
+            return;
+        };
+        if old.width() != grid.width() || old.height() != grid.height() {
+            self.states.clear();
+            return;
+        }
+
+        let edited: HashSet<Position> = old.cells().iter()
+            .zip(grid.cells())
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| Position::new(i % grid.width(), i / grid.width()))
+            .collect();
+        if edited.is_empty() {
+            return;
+        }
+
+        // Blocks that lost a codel (old grid) or gained one (new grid)
+        let old_blocks: HashSet<_> = edited.iter().filter_map(|&p| old.get_block_id(p)).collect();
+        let mut grown = HashSet::new();
+        for &p in &edited {
+            if let Some(info) = grid.get_block_id(p).and_then(|id| grid.get_block_info(id)) {
+                grown.extend(info.positions.iter().copied());
+            }
+        }
+
+        self.states.retain(|&(pos, _, _), (_, probes)| {
+            let chromatic = old.get(pos).is_some_and(|c| !c.is_white() && !c.is_black());
+            let block_changed = chromatic
+                && (old.get_block_id(pos).is_some_and(|id| old_blocks.contains(&id)) || grown.contains(&pos));
+            !block_changed && !probes.iter().any(|p| edited.contains(p))
+        });
+    }
+}
+
 /// Compilador que transforma una grilla de Piet en bytecode
+///
+/// Las salidas de cada bloque se resuelven con las mismas reglas que
+/// `BytecodeVm::stroke`, así que el programa compilado hace lo mismo que
+/// interpretar la imagen:
+///
+/// - negro y los bordes bloquean una salida, y se reintenta alternando CC
+///   y DP hasta 8 veces (luego el programa termina);
+/// - un deslizamiento por blanco que vuelve al mismo bloque también
+///   bloquea, y cruzar blanco no ejecuta ninguna operación (`Nop`);
+/// - Pointer y Switch giran a partir del DP/CC con el que se entró al
+///   bloque, no del que encontró la búsqueda.
 pub struct Compiler {
     grid: Grid,
     /// Image dimensions (in pixels, before codel reduction)
@@ -26,6 +201,8 @@ pub struct Compiler {
     codel_size: usize,
     /// Compilation mode
     mode: CompileMode,
+    /// Only explore the Pointer/Switch branches a constant argument allows
+    prune: bool,
 }
 
 impl Compiler {
@@ -44,6 +221,7 @@ impl Compiler {
             image_width: iw,
             image_height: ih,
             mode: CompileMode::Release,
+            prune: true,
         }
     }
     
@@ -53,6 +231,17 @@ impl Compiler {
         self
     }
     
+    /// Enables or disables branch pruning (enabled by default)
+    ///
+    /// With pruning, the stack values are tracked abstractly during the
+    /// BFS and a Pointer or Switch whose argument is provably constant only
+    /// leads to the state it actually selects. The other branches get no
+    /// successor, since execution can never take them.
+    pub fn with_pruning(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+
     /// Returns whether debug info should be included
     fn include_debug_info(&self) -> bool {
         self.mode == CompileMode::Debug
@@ -79,6 +268,68 @@ impl Compiler {
     /// 2. Para cada transición de color, genera la instrucción correspondiente
     /// 3. Mapea cada posición a su instrucción
     pub fn compile(&self) -> Result<Program, VmError> {
+        let (program, _) = self.build_program(|state| {
+            let mut probes = Vec::new();
+            self.explore(state, &mut probes)
+        })?;
+        Ok(program)
+    }
+
+    /// Compiles and reports how much pruning saved
+    ///
+    /// Counting the pruned states means compiling a second time without
+    /// pruning, so this costs about twice as much as `compile`.
+    pub fn compile_with_report(&self) -> Result<(Program, CompileReport), VmError> {
+        let (program, pruned_branches) = self.build_program(|state| {
+            let mut probes = Vec::new();
+            self.explore(state, &mut probes)
+        })?;
+        let pruned_states = if pruned_branches > 0 {
+            let unpruned_compiler = Self { grid: self.grid.clone(), prune: false, ..*self };
+            let (unpruned, _) = unpruned_compiler.build_program(|state| {
+                let mut probes = Vec::new();
+                self.explore(state, &mut probes)
+            })?;
+            unpruned.len().saturating_sub(program.len())
+        } else {
+            0
+        };
+        let report = CompileReport { instructions: program.len(), pruned_branches, pruned_states };
+        Ok((program, report))
+    }
+
+    /// Compiles reusing the exploration results stored in `cache`
+    ///
+    /// Only the states whose block changed, or whose exit/slide path crosses
+    /// an edited codel, are explored again. The resulting program is
+    /// identical to the one `compile` would produce for the same grid.
+    pub fn compile_incremental(&self, cache: &mut CompileCache) -> Result<Program, VmError> {
+        cache.invalidate(&self.grid);
+        cache.reused = 0;
+        cache.recomputed = 0;
+
+        let program = self.build_program(|state| {
+            if let Some((outcome, _)) = cache.states.get(&state) {
+                cache.reused += 1;
+                return outcome.clone();
+            }
+            let mut probes = Vec::new();
+            let outcome = self.explore(state, &mut probes);
+            cache.states.insert(state, (outcome.clone(), probes));
+            cache.recomputed += 1;
+            outcome
+        });
+
+        cache.grid = Some(self.grid.clone());
+        program.map(|(program, _)| program)
+    }
+
+    /// Runs the BFS over (position, DP, CC) states and emits the program
+    ///
+    /// `explore` resolves where a state leads; everything that depends on
+    /// the BFS order (instruction indices, block reuse, pruning) is handled
+    /// here. Also returns the number of pruned branches.
+    fn build_program(&self, mut explore: impl FnMut(State) -> Explored) -> Result<(Program, usize), VmError> {
         let width = self.grid.width();
         let height = self.grid.height();
         
@@ -107,43 +358,97 @@ impl Compiler {
         queue.push_back((start_pos, start_dp, start_cc));
         visited.insert((start_pos, start_dp, start_cc));
         
-        while let Some((pos, dp, cc)) = queue.pop_front() {
-            // eprintln!("DEBUG compile: processing pos=({},{}) dp={:?} cc={:?}", pos.x, pos.y, dp, cc);
-            
-            let current_color = match self.grid.get(pos) {
-                Some(color) => color,
-                None => {
-                    // eprintln!("DEBUG compile: no color at pos, skipping");
+        // Instrucción de cada estado y estados sucesores de cada instrucción
+        let mut state_instr: HashMap<State, usize> = HashMap::new();
+        let mut pending_successors: HashMap<usize, Vec<State>> = HashMap::new();
+        let mut pruning = Pruning::default();
+        if self.prune {
+            pruning.incoming.insert((start_pos, start_dp, start_cc), AbstractStack::empty());
+        }
+        
+        loop {
+            let Some((pos, dp, cc)) = queue.pop_front() else {
+                // Sin estados nuevos: propagar las pilas, que pueden habilitar ramas
+                let Some(idx) = pruning.dirty.pop_front() else { break };
+                pruning.queued.remove(&idx);
+                let (Some(stack), Some(next_states)) = (pruning.stacks[idx].clone(), pending_successors.get(&idx)) else {
                     continue;
+                };
+                for (branch, out) in step(&program.instructions[idx], &stack) {
+                    let Some(&next_state) = next_states.get(branch) else { continue };
+                    pruning.taken.insert((idx, branch));
+                    if let Some(&next_idx) = state_instr.get(&next_state) {
+                        pruning.flow_into(next_idx, out);
+                        continue;
+                    }
+                    pruning.pend(next_state, out);
+                    if visited.insert(next_state) {
+                        queue.push_back(next_state);
+                    }
                 }
+                continue;
+            };
+            let current_color = match self.grid.get(pos) {
+                Some(color) => color,
+                None => continue,
             };
             
-            // eprintln!("DEBUG compile: color={:?}", current_color);
-            
-            // Negro = halt
-            if current_color.is_black() {
-                let idx = self.emit_instruction(&mut program, Instruction::Halt, || {
-                    InstructionDebugInfo {
-                        from_pos: (pos.x, pos.y),
-                        to_pos: (pos.x, pos.y),
-                        dp,
-                        cc,
-                        block_size: 1,
-                        from_color: "Black".to_string(),
-                        to_color: "Black".to_string(),
+            // Los bloques de color reutilizan la instrucción ya compilada
+            let mut block = None;
+            if !current_color.is_black() && !current_color.is_white() {
+                let block_id = match self.grid.get_block_id(pos) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let block_info = match self.grid.get_block_info(block_id) {
+                    Some(info) => info,
+                    None => continue,
+                };
+                
+                // Verificar si ya compilamos esta transición
+                let key = (block_id, dp, cc);
+                if let Some(&instr_idx) = block_instr_map.get(&key) {
+                    // Ya existe, solo mapear la posición
+                    state_instr.insert((pos, dp, cc), instr_idx);
+                    pruning.arrive((pos, dp, cc), instr_idx);
+                    program.map_position(pos.x, pos.y, instr_idx);
+                    // Intentar mapear la siguiente posición también
+                    if let Some(next_pos) = self.grid.get_exit(block_id, dp, cc) {
+                        program.map_next_position(pos.x, pos.y, next_pos.x, next_pos.y);
                     }
-                });
-                program.map_position(pos.x, pos.y, idx);
-                continue;
+                    continue;
+                }
+                block = Some((key, block_info));
             }
             
-            // Blanco = deslizamiento (slide)
-            // En Piet, los bloques blancos se "atraviesan" hasta encontrar color
-            if current_color.is_white() {
-                // Buscar la siguiente posición no-blanca
-                if let Some(next_pos) = self.slide_preview(pos, dp) {
-                    let next_color_name = self.grid.get(next_pos).map(|c| Self::color_name(c)).unwrap_or_default();
-                    // Crear un NOP que apunta directamente al destino
+            match explore((pos, dp, cc)) {
+                Explored::Skip => {}
+                Explored::Halt { dp: halt_dp, cc: halt_cc, block_size, to_color } => {
+                    let idx = self.emit_instruction(&mut program, Instruction::Halt, || {
+                        InstructionDebugInfo {
+                            from_pos: (pos.x, pos.y),
+                            to_pos: (pos.x, pos.y),
+                            dp: halt_dp,
+                            cc: halt_cc,
+                            block_size,
+                            from_color: Self::color_name(current_color),
+                            to_color: to_color.to_string(),
+                        }
+                    });
+                    state_instr.insert((pos, dp, cc), idx);
+                    pruning.arrive((pos, dp, cc), idx);
+                    match block {
+                        Some((_, block_info)) => {
+                            for &block_pos in &block_info.positions {
+                                program.map_position(block_pos.x, block_pos.y, idx);
+                            }
+                        }
+                        None => program.map_position(pos.x, pos.y, idx),
+                    }
+                }
+                Explored::Slide { next: next_pos } => {
+                    // Blanco: un NOP que apunta directamente al destino
+                    let next_color_name = self.grid.get(next_pos).map(Self::color_name).unwrap_or_default();
                     let idx = self.emit_instruction(&mut program, Instruction::Nop, || {
                         InstructionDebugInfo {
                             from_pos: (pos.x, pos.y),
@@ -152,118 +457,25 @@ impl Compiler {
                             cc,
                             block_size: 1,
                             from_color: "White".to_string(),
-                            to_color: next_color_name.clone(),
+                            to_color: next_color_name,
                         }
                     });
                     program.map_position(pos.x, pos.y, idx);
                     program.map_next_position(pos.x, pos.y, next_pos.x, next_pos.y);
                     
                     let next_state = (next_pos, dp, cc);
-                    if !visited.contains(&next_state) {
-                        visited.insert(next_state);
+                    state_instr.insert((pos, dp, cc), idx);
+                    pruning.arrive((pos, dp, cc), idx);
+                    pending_successors.insert(idx, vec![next_state]);
+                    if visited.insert(next_state) {
                         queue.push_back(next_state);
                     }
                 }
-                // Si no hay siguiente posición válida, simplemente no agregamos nada
-                // (el programa terminará cuando llegue aquí)
-                continue;
-            }
-            
-            // Color cromático: obtener bloque y calcular transición
-            let block_id = match self.grid.get_block_id(pos) {
-                Some(id) => id,
-                None => continue,
-            };
-            
-            let block_info = match self.grid.get_block_info(block_id) {
-                Some(info) => info,
-                None => continue,
-            };
-            
-            // eprintln!("DEBUG compile: block_id={} block_size={}", block_id, block_info.size);
-            
-            // Verificar si ya compilamos esta transición
-            let key = (block_id, dp, cc);
-            if let Some(&instr_idx) = block_instr_map.get(&key) {
-                // eprintln!("DEBUG compile: reusing existing instruction at index {}", instr_idx);
-                // Ya existe, solo mapear la posición
-                program.map_position(pos.x, pos.y, instr_idx);
-                // Intentar mapear la siguiente posición también
-                if let Some(next_pos) = self.grid.get_exit(block_id, dp, cc) {
-                    program.map_next_position(pos.x, pos.y, next_pos.x, next_pos.y);
-                }
-                continue;
-            }
-            
-            // Intentar encontrar una salida válida (con reintentos como Piet real)
-            let exit_result = self.find_valid_exit(block_id, dp, cc);
-            // eprintln!("DEBUG compile: find_valid_exit result={:?}", exit_result);
-            
-            if let Some((next_pos, exit_dp, exit_cc)) = exit_result {
-                if let Some(next_color) = self.grid.get(next_pos) {
-                    // Si la salida lleva a blanco, deslizarse hasta el siguiente color
-                    let (final_pos, final_color) = if next_color.is_white() {
-                        if let Some(slide_pos) = self.slide_preview(next_pos, exit_dp) {
-                            // Nota: volver al mismo bloque después de slide ES válido en loops de Piet
-                            // Solo es halt si slide_preview no encuentra ningún destino
-                            if let Some(c) = self.grid.get(slide_pos) {
-                                (slide_pos, c)
-                            } else {
-                                // No hay destino válido después del slide = halt
-                                let from_color = Self::color_name(current_color);
-                                let block_size = block_info.size;
-                                let idx = self.emit_instruction(&mut program, Instruction::Halt, || {
-                                    InstructionDebugInfo {
-                                        from_pos: (pos.x, pos.y),
-                                        to_pos: (pos.x, pos.y),
-                                        dp: exit_dp,
-                                        cc: exit_cc,
-                                        block_size,
-                                        from_color: from_color.clone(),
-                                        to_color: "None".to_string(),
-                                    }
-                                });
-                                for &block_pos in &block_info.positions {
-                                    program.map_position(block_pos.x, block_pos.y, idx);
-                                }
-                                continue;
-                            }
-                        } else {
-                            // Slide no encontró destino = halt
-                            let from_color = Self::color_name(current_color);
-                            let block_size = block_info.size;
-                            let idx = self.emit_instruction(&mut program, Instruction::Halt, || {
-                                InstructionDebugInfo {
-                                    from_pos: (pos.x, pos.y),
-                                    to_pos: (pos.x, pos.y),
-                                    dp: exit_dp,
-                                    cc: exit_cc,
-                                    block_size,
-                                    from_color: from_color.clone(),
-                                    to_color: "None".to_string(),
-                                }
-                            });
-                            for &block_pos in &block_info.positions {
-                                program.map_position(block_pos.x, block_pos.y, idx);
-                            }
-                            continue;
-                        }
-                    } else {
-                        (next_pos, next_color)
+                Explored::Transition { instr, next: final_pos, dp: exit_dp, cc: exit_cc, block_size } => {
+                    let Some((key, block_info)) = block else {
+                        continue;
                     };
-                    
-                    // Calcular la operación basada en el cambio de color
-                    let instr = self.color_transition_to_instruction(
-                        current_color,
-                        final_color,
-                        block_info.size,
-                    );
-                    
-                    // Build debug info (captured for closure)
-                    let from_color = Self::color_name(current_color);
-                    let to_color = Self::color_name(final_color);
-                    let block_size = block_info.size;
-                    
+                    let final_color = self.grid.get(final_pos).unwrap_or(PietColor::Black);
                     let idx = self.emit_instruction(&mut program, instr.clone(), || {
                         InstructionDebugInfo {
                             from_pos: (pos.x, pos.y),
@@ -271,8 +483,8 @@ impl Compiler {
                             dp: exit_dp,
                             cc: exit_cc,
                             block_size,
-                            from_color,
-                            to_color,
+                            from_color: Self::color_name(current_color),
+                            to_color: Self::color_name(final_color),
                         }
                     });
                     
@@ -282,65 +494,164 @@ impl Compiler {
                         program.map_next_position(block_pos.x, block_pos.y, final_pos.x, final_pos.y);
                     }
                     block_instr_map.insert(key, idx);
+                    state_instr.insert((pos, dp, cc), idx);
+                    pruning.arrive((pos, dp, cc), idx);
                     
-                    // Para Switch y Pointer, explorar AMBAS ramas posibles
-                    if matches!(instr, Instruction::Switch) {
-                        // Rama 1: CC no cambia (valor par en stack)
-                        let next_state_unchanged = (final_pos, exit_dp, exit_cc);
-                        if !visited.contains(&next_state_unchanged) {
-                            visited.insert(next_state_unchanged);
-                            queue.push_back(next_state_unchanged);
-                        }
-                        // Rama 2: CC cambia (valor impar en stack)
-                        let toggled_cc = exit_cc.toggle();
-                        let next_state_toggled = (final_pos, exit_dp, toggled_cc);
-                        if !visited.contains(&next_state_toggled) {
-                            visited.insert(next_state_toggled);
-                            queue.push_back(next_state_toggled);
-                        }
-                    } else if matches!(instr, Instruction::Pointer) {
-                        // Pointer puede rotar 0, 1, 2, o 3 veces
-                        for rotation in 0..4 {
-                            let rotated_dp = exit_dp.rotate_clockwise(rotation);
-                            let next_state = (final_pos, rotated_dp, exit_cc);
-                            if !visited.contains(&next_state) {
-                                visited.insert(next_state);
+                    // Como en BytecodeVm, Pointer y Switch parten del DP/CC con
+                    // el que se entró al bloque, no del resultante de la búsqueda
+                    let (next_dp, next_cc) = match instr {
+                        Instruction::Pointer | Instruction::Switch => (dp, cc),
+                        _ => (exit_dp, exit_cc),
+                    };
+                    let next_states = Self::successor_states(&instr, final_pos, next_dp, next_cc);
+                    // Al podar, las ramas se encolan cuando la pila de entrada las habilita
+                    let branches = matches!(instr, Instruction::Pointer | Instruction::Switch);
+                    if !(self.prune && branches) {
+                        for &next_state in &next_states {
+                            if visited.insert(next_state) {
                                 queue.push_back(next_state);
                             }
                         }
-                    } else {
-                        // Otras instrucciones: un solo siguiente estado
-                        let next_state = (final_pos, exit_dp, exit_cc);
-                        if !visited.contains(&next_state) {
-                            visited.insert(next_state);
-                            queue.push_back(next_state);
-                        }
-                    }
-                }
-            } else {
-                // No hay salida después de 8 intentos = halt
-                let from_color = Self::color_name(current_color);
-                let block_size = block_info.size;
-                let idx = self.emit_instruction(&mut program, Instruction::Halt, || {
-                    InstructionDebugInfo {
-                        from_pos: (pos.x, pos.y),
-                        to_pos: (pos.x, pos.y),
-                        dp,
-                        cc,
-                        block_size,
-                        from_color,
-                        to_color: "Blocked".to_string(),
                     }
-                });
-                for &block_pos in &block_info.positions {
-                    program.map_position(block_pos.x, block_pos.y, idx);
+                    pending_successors.insert(idx, next_states);
                 }
             }
         }
         
-        Ok(program)
+        // Todos los sucesores ya fueron visitados: resolver sus índices
+        let mut pruned = 0;
+        for (idx, next_states) in pending_successors {
+            let branches = matches!(program.instructions[idx], Instruction::Pointer | Instruction::Switch);
+            let successors = next_states
+                .iter()
+                .enumerate()
+                .map(|(branch, state)| {
+                    if self.prune && branches && !pruning.taken.contains(&(idx, branch)) {
+                        pruned += 1;
+                        return None;
+                    }
+                    state_instr.get(state).copied()
+                })
+                .collect();
+            program.set_successors(idx, successors);
+        }
+        
+        Ok((program, pruned))
+    }
+
+    /// States the BFS must visit after executing `instr`
+    ///
+    /// Para Switch y Pointer, se exploran TODAS las ramas posibles
+    fn successor_states(instr: &Instruction, pos: Position, dp: Direction, cc: CodelChooser) -> Vec<State> {
+        match instr {
+            // Rama 1: CC no cambia (valor par); rama 2: CC cambia (valor impar)
+            Instruction::Switch => vec![(pos, dp, cc), (pos, dp, cc.toggle())],
+            // Pointer puede rotar 0, 1, 2, o 3 veces
+            Instruction::Pointer => (0..4).map(|rotation| (pos, dp.rotate_clockwise(rotation), cc)).collect(),
+            // Otras instrucciones: un solo siguiente estado
+            _ => vec![(pos, dp, cc)],
+        }
+    }
+
+    /// Resolves where a single state leads, recording every codel read
+    ///
+    /// The result only depends on the block containing the state's codel
+    /// and on the colors at `probes`, which is what lets
+    /// `compile_incremental` reuse it after unrelated edits.
+    fn explore(&self, (pos, dp, cc): State, probes: &mut Vec<Position>) -> Explored {
+        probes.push(pos);
+        let current_color = match self.grid.get(pos) {
+            Some(color) => color,
+            None => return Explored::Skip,
+        };
+        
+        // Negro = halt
+        if current_color.is_black() {
+            return Explored::Halt { dp, cc, block_size: 1, to_color: "Black" };
+        }
+        
+        // Blanco = deslizamiento (slide)
+        // En Piet, los bloques blancos se "atraviesan" hasta encontrar color.
+        // Si no hay siguiente posición válida, simplemente no agregamos nada
+        // (el programa terminará cuando llegue aquí)
+        if current_color.is_white() {
+            return match self.slide_preview(pos, dp, probes) {
+                Some(next) => Explored::Slide { next },
+                None => Explored::Skip,
+            };
+        }
+        
+        // Color cromático: obtener bloque y calcular transición
+        let Some(block_id) = self.grid.get_block_id(pos) else {
+            return Explored::Skip;
+        };
+        let Some(block_info) = self.grid.get_block_info(block_id) else {
+            return Explored::Skip;
+        };
+        let block_size = block_info.size;
+        
+        // No hay salida después de 8 intentos = halt
+        let Some((final_pos, crossed_white, exit_dp, exit_cc)) = self.find_exit(block_id, dp, cc, probes) else {
+            return Explored::Halt { dp, cc, block_size, to_color: "Blocked" };
+        };
+        let Some(final_color) = self.grid.get(final_pos) else {
+            return Explored::Skip;
+        };
+        
+        // Cruzar blanco no ejecuta ninguna operación
+        let instr = if crossed_white {
+            Instruction::Nop
+        } else {
+            self.color_transition_to_instruction(current_color, final_color, block_size)
+        };
+        Explored::Transition { instr, next: final_pos, dp: exit_dp, cc: exit_cc, block_size }
+    }
+
+    /// Si el programa se detiene al entrar en el bloque: ningún DP/CC
+    /// encuentra salida, el mismo caso en que `explore` emite un halt
+    pub(crate) fn is_trapped(&self, block_id: usize) -> bool {
+        self.find_exit(block_id, Direction::Right, CodelChooser::Left, &mut Vec::new()).is_none()
     }
     
+    /// Busca la salida de un bloque con las mismas reglas que
+    /// BytecodeVm::stroke: negro y bordes bloquean, y un deslizamiento por
+    /// blanco que vuelve al mismo bloque también cuenta como bloqueo
+    ///
+    /// Every codel read is appended to `probes`, including those of the
+    /// attempts that were blocked. Returns the exit codel, whether white was
+    /// crossed to reach it, and the DP/CC it was found with.
+    fn find_exit(
+        &self,
+        block_id: usize,
+        mut dp: Direction,
+        mut cc: CodelChooser,
+        probes: &mut Vec<Position>,
+    ) -> Option<(Position, bool, Direction, CodelChooser)> {
+        // Piet intenta 8 veces: alterna entre rotar CC y rotar DP
+        for attempt in 0..8 {
+            if let Some(exit_pos) = self.grid.get_exit(block_id, dp, cc) {
+                probes.push(exit_pos);
+                match self.grid.get(exit_pos) {
+                    Some(color) if color.is_black() => {}
+                    Some(color) if color.is_white() => {
+                        let slide = self.slide_preview(exit_pos, dp, probes);
+                        if let Some(slide_pos) = slide.filter(|&p| self.grid.get_block_id(p) != Some(block_id)) {
+                            return Some((slide_pos, true, dp, cc));
+                        }
+                    }
+                    Some(_) => return Some((exit_pos, false, dp, cc)),
+                    None => {}
+                }
+            }
+            if attempt % 2 == 0 {
+                cc = cc.toggle();
+            } else {
+                dp = dp.rotate_clockwise(1);
+            }
+        }
+        None
+    }
+
     /// Converts a PietColor to a human-readable name
     fn color_name(color: PietColor) -> String {
         match color {
@@ -417,36 +728,10 @@ impl Compiler {
         }
     }
     
-    /// Busca una salida válida desde un bloque, rotando CC y DP como Piet real
-    /// Retorna (next_pos, final_dp, final_cc) o None si no hay salida válida
-    fn find_valid_exit(
-        &self, 
-        block_id: usize, 
-        mut dp: Direction, 
-        mut cc: CodelChooser
-    ) -> Option<(Position, Direction, CodelChooser)> {
-        // Piet intenta 8 veces: alterna entre rotar CC y rotar DP
-        for attempt in 0..8 {
-            if let Some(next_pos) = self.grid.get_exit(block_id, dp, cc) {
-                return Some((next_pos, dp, cc));
-            }
-            
-            // No hay salida, intentar otra dirección
-            if attempt % 2 == 0 {
-                // Intentos pares: rotar CC
-                cc = cc.toggle();
-            } else {
-                // Intentos impares: rotar DP en sentido horario
-                dp = dp.rotate_clockwise(1);
-            }
-        }
-        
-        // Después de 8 intentos, no hay salida = programa termina
-        None
-    }
-    
     /// Vista previa de deslizamiento por blancos (sin modificar estado)
-    fn slide_preview(&self, start_pos: Position, start_dp: Direction) -> Option<Position> {
+    ///
+    /// Every codel inspected along the way is appended to `probes`.
+    fn slide_preview(&self, start_pos: Position, start_dp: Direction, probes: &mut Vec<Position>) -> Option<Position> {
         let mut pos = start_pos;
         let mut dp = start_dp;
         let mut attempts = 0;
@@ -464,6 +749,7 @@ impl Compiler {
             }
             
             if let Some(next_pos) = pos.step(dp, self.grid.width(), self.grid.height()) {
+                probes.push(next_pos);
                 if let Some(color) = self.grid.get(next_pos) {
                     if color.is_white() {
                         // Continuar deslizándose por el blanco
@@ -512,4 +798,219 @@ mod tests {
         // Debe tener al menos una instrucción Add
         assert!(program.instructions.iter().any(|i| matches!(i, Instruction::Add)));
     }
+
+    /// Small xorshift generator so the property tests are reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    const PALETTE: [PietColor; 8] = [
+        PietColor::LightRed, PietColor::Red, PietColor::Yellow, PietColor::DarkGreen,
+        PietColor::Cyan, PietColor::Blue, PietColor::White, PietColor::Black,
+    ];
+
+    #[test]
+    fn test_incremental_matches_full_compile() {
+        for seed in 1..=20u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+            let (width, height) = (8, 6);
+            let cells = (0..width * height).map(|_| PALETTE[rng.below(PALETTE.len())]).collect();
+            let mut grid = Grid::new(width, height, cells).unwrap();
+            let mut cache = CompileCache::new();
+
+            for _ in 0..15 {
+                let pos = Position::new(rng.below(width), rng.below(height));
+                let color = PALETTE[rng.below(PALETTE.len())];
+                if rng.below(4) == 0 {
+                    grid.fill_rect(pos, 1 + rng.below(width - pos.x), 1, color).unwrap();
+                } else {
+                    grid.set(pos, color).unwrap();
+                }
+
+                let compiler = Compiler::new(grid.clone()).with_mode(CompileMode::Debug);
+                let full = compiler.compile().unwrap();
+                let incremental = compiler.compile_incremental(&mut cache).unwrap();
+                assert_eq!(incremental, full, "seed {} diverged", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_reuses_unaffected_states() {
+        // Fila superior: LR R Y W W W, el resto blanco con una esquina lejana
+        let mut cells = vec![PietColor::White; 6 * 4];
+        cells[0] = PietColor::LightRed;
+        cells[1] = PietColor::Red;
+        cells[2] = PietColor::Yellow;
+        cells[5] = PietColor::Black;
+        let mut grid = Grid::new(6, 4, cells).unwrap();
+        let mut cache = CompileCache::new();
+
+        Compiler::new(grid.clone()).compile_incremental(&mut cache).unwrap();
+        assert_eq!(cache.reused_states(), 0);
+        let explored = cache.recomputed_states();
+        assert!(explored > 0);
+
+        // Sin cambios: todo se reutiliza
+        Compiler::new(grid.clone()).compile_incremental(&mut cache).unwrap();
+        assert_eq!(cache.recomputed_states(), 0);
+        assert_eq!(cache.reused_states(), explored);
+
+        // Cambiar el último bloque solo invalida los estados que lo tocan
+        grid.set(Position::new(2, 0), PietColor::Green).unwrap();
+        let compiler = Compiler::new(grid.clone());
+        let program = compiler.compile_incremental(&mut cache).unwrap();
+        assert!(cache.reused_states() > 0);
+        assert!(cache.recomputed_states() < explored);
+        assert_eq!(program, compiler.compile().unwrap());
+    }
+
+    #[test]
+    fn test_incremental_sees_retried_exits() {
+        // La salida de lR a la derecha choca con negro y se reintenta hacia
+        // abajo hasta lY: lY solo se lee en un reintento
+        let mut grid = Grid::from_text("lR lR lR K\nK K lY K\nK K dM K").unwrap();
+        let mut cache = CompileCache::new();
+        Compiler::new(grid.clone()).compile_incremental(&mut cache).unwrap();
+
+        for (pos, color) in [
+            (Position::new(2, 1), PietColor::Green),
+            (Position::new(3, 0), PietColor::Yellow),
+        ] {
+            grid.set(pos, color).unwrap();
+            let compiler = Compiler::new(grid.clone()).with_mode(CompileMode::Debug);
+            assert_eq!(compiler.compile_incremental(&mut cache).unwrap(), compiler.compile().unwrap(), "{:?}", pos);
+        }
+    }
+
+    fn assemble(source: &str) -> Grid {
+        crate::assembler::Assembler::parse(source).unwrap().assemble().unwrap()
+    }
+
+    #[test]
+    fn test_constant_branches_are_pruned() {
+        // El salto siempre se toma: la rama que imprime 5 nunca se ejecuta
+        let grid = assemble("push 1\njnz skip\npush 5\nout(number)\nskip:\npush 2\nout(number)\n");
+        let (pruned, report) = Compiler::new(grid.clone()).compile_with_report().unwrap();
+        let unpruned = Compiler::new(grid).with_pruning(false).compile().unwrap();
+        assert!(report.pruned_branches > 0, "{:?}", report);
+        assert_eq!(report.instructions, pruned.len());
+        assert_eq!(report.pruned_states, unpruned.len() - pruned.len());
+        assert!(report.pruned_states > 0, "{:?}", report);
+        assert!(!pruned.instructions.contains(&Instruction::Push(5)));
+        crate::optimize::Optimizer::new(&unpruned).verify(&pruned, &[], 1000).unwrap();
+
+        // Una condición leída de la entrada conserva las dos ramas (solo se
+        // podan los giros constantes que el ensamblador usa para el trazado)
+        let grid = assemble("in(number)\njnz skip\npush 5\nout(number)\nskip:\npush 2\nout(number)\n");
+        let pruned = Compiler::new(grid.clone()).compile().unwrap();
+        let unpruned = Compiler::new(grid).with_pruning(false).compile().unwrap();
+        assert!(pruned.instructions.contains(&Instruction::Push(5)));
+        for input in [0, 1] {
+            crate::optimize::Optimizer::new(&unpruned).verify(&pruned, &[input], 1000).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_pruning_folds_min_mod_minus_one() {
+        // El plegado de constantes envuelve i32::MIN % -1 como la VM
+        let grid = assemble("push -2147483648\npush -1\nmod\nout(number)\n");
+        let pruned = Compiler::new(grid.clone()).with_pruning(true).compile().unwrap();
+        let unpruned = Compiler::new(grid.clone()).with_pruning(false).compile().unwrap();
+        crate::optimize::Optimizer::new(&unpruned).verify(&pruned, &[], 1000).unwrap();
+
+        let mut vm = crate::vm::BytecodeVm::from_grid(grid).unwrap();
+        while vm.stroke().is_ok() {}
+        assert_eq!(vm.ink_string(), "0");
+    }
+
+    #[test]
+    fn test_pruning_keeps_loops_reachable() {
+        // El contador cambia en cada vuelta, así que el salto no es constante
+        let grid = assemble("push 3\nloop:\ndup\nout(number)\npush 1\nsubtract\ndup\njnz loop\n");
+        let pruned = Compiler::new(grid.clone()).compile().unwrap();
+        let unpruned = Compiler::new(grid).with_pruning(false).compile().unwrap();
+        for input in [&[][..], &[4]] {
+            crate::optimize::Optimizer::new(&unpruned).verify(&pruned, input, 10_000).unwrap();
+        }
+    }
+
+    /// Reglas de salida de bloques, comparadas con BytecodeVm
+    mod exit_rules {
+        use super::*;
+        use crate::testutil::{grid_output, program_output};
+
+        /// Compila sin poda y compara con la VM
+        fn assert_matches_vm(grid: Grid, input: &[i32]) -> (Program, String) {
+            let expected = grid_output(&grid, input, 1000);
+            let program = Compiler::new(grid).with_pruning(false).compile().unwrap();
+            assert_eq!(program_output(&program, input, 1000), expected);
+            (program, expected)
+        }
+
+        #[test]
+        fn test_black_exit_is_retried() {
+            // La salida a la derecha choca con negro: se rota hasta bajar a lY
+            // (push 3) y luego a dM (out(number)). Antes el compilador tomaba
+            // la primera salida aunque fuera negra
+            let grid = Grid::from_text("lR lR lR K\nK K lY K\nK K dM K").unwrap();
+            let (_, output) = assert_matches_vm(grid, &[]);
+            assert!(output.starts_with('3'), "{:?}", output);
+        }
+
+        #[test]
+        fn test_white_crossing_is_nop() {
+            // lY -> blanco -> lC no ejecuta pop: cruzar blanco es un Nop, así
+            // que se imprimen los dos 1 (antes se compilaba lY -> lC)
+            let grid = Grid::from_text("lR lY W lC lB dG R").unwrap();
+            let (program, output) = assert_matches_vm(grid, &[]);
+            assert!(output.starts_with("11"), "{:?}", output);
+            assert!(program.instructions.contains(&Instruction::Nop));
+            assert!(!program.instructions.contains(&Instruction::Pop));
+        }
+
+        #[test]
+        fn test_white_slide_back_to_same_block_is_blocked() {
+            // El blanco de la derecha devuelve a lR: cuenta como bloqueo y se
+            // sale hacia abajo (push 1, out(number))
+            let grid = Grid::from_text("lR W K\nlY K K\ndM K K").unwrap();
+            let (_, output) = assert_matches_vm(grid, &[]);
+            assert!(output.starts_with('1'), "{:?}", output);
+        }
+
+        #[test]
+        fn test_pointer_turns_from_entry_direction() {
+            // Se entra en lY hacia la derecha y la salida se encuentra rotando
+            // hacia abajo, hasta M (pointer con 1). El giro parte del DP de
+            // entrada (derecha), así que se sigue hacia abajo: push 1 y
+            // out(number). Desde el DP de salida se giraría hacia la izquierda
+            let grid = Grid::from_text("lR lY K\nK M K\nK R K\nK lB K").unwrap();
+            let (_, output) = assert_matches_vm(grid, &[]);
+            assert!(output.starts_with('1'), "{:?}", output);
+        }
+
+        #[test]
+        fn test_random_grids_match_vm() {
+            for seed in 1..=40u64 {
+                let mut rng = Rng(seed.wrapping_mul(0x2545_F491_4F6C_DD1D));
+                let (width, height) = (6, 5);
+                let mut cells: Vec<PietColor> = (0..width * height).map(|_| PALETTE[rng.below(PALETTE.len())]).collect();
+                // Arrancar siempre en un bloque de color
+                cells[0] = PietColor::LightRed;
+                let grid = Grid::new(width, height, cells).unwrap();
+                let expected = grid_output(&grid, &[3, 5, 7], 200);
+                let program = Compiler::new(grid).with_pruning(false).compile().unwrap();
+                let output = program_output(&program, &[3, 5, 7], 200);
+                let (short, long) = if output.len() <= expected.len() { (&output, &expected) } else { (&expected, &output) };
+                assert!(long.starts_with(short.as_str()), "seed {}: compiled {:?} vs vm {:?}", seed, output, expected);
+            }
+        }
+    }
 }
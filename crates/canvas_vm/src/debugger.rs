@@ -31,6 +31,10 @@ pub struct ExecutionStep {
     pub instruction: Instruction,
     /// Debug info for this instruction (if compiled in debug mode)
     pub debug_info: Option<InstructionDebugInfo>,
+    /// Debug info of every original state an optimized instruction stands
+    /// for, in execution order (empty for unoptimized programs)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged: Vec<InstructionDebugInfo>,
     /// Stack before execution
     pub stack_before: Vec<i32>,
     /// Stack after execution
@@ -386,6 +390,7 @@ impl Debugger {
         let rich_instr = &self.program.rich_instructions[self.ip];
         let instruction = rich_instr.op.clone();
         let debug_info = rich_instr.debug.clone();
+        let merged = rich_instr.merged.clone();
 
         // Check if this instruction needs input and we don't have any
         match &instruction {
@@ -552,6 +557,16 @@ impl Debugger {
             Instruction::Halt => {
                 self.halted = true;
             }
+            Instruction::PushAdd(n) | Instruction::PushSubtract(n) | Instruction::PushMultiply(n) => {
+                // push n, then the operation unless n is the only value
+                let result = match (self.stack.pop(), &instruction) {
+                    (None, _) => *n,
+                    (Some(b), Instruction::PushAdd(_)) => b.wrapping_add(*n),
+                    (Some(b), Instruction::PushSubtract(_)) => b.wrapping_sub(*n),
+                    (Some(b), _) => b.wrapping_mul(*n),
+                };
+                self.stack.push(result);
+            }
         }
 
         // Update position to destination if available
@@ -567,6 +582,7 @@ impl Debugger {
             step: self.steps - 1,
             instruction,
             debug_info,
+            merged,
             stack_before,
             stack_after: self.stack.clone(),
             position: position_before,
@@ -762,4 +778,21 @@ mod tests {
         assert!(trace.steps.len() > 0);
         assert!(trace.completed);
     }
+
+    #[test]
+    fn test_step_reports_merged_states() {
+        let grid = crate::assembler::Assembler::parse("push 2\npush 5\nadd\nout(number)\n")
+            .unwrap()
+            .assemble()
+            .unwrap();
+        let program = Compiler::new(grid.clone()).with_mode(CompileMode::Debug).compile().unwrap();
+        let optimized = crate::optimize::Optimizer::new(&program).optimize();
+        let mut debugger = Debugger::from_program(optimized, grid, 1);
+
+        let step = debugger.step().unwrap().unwrap();
+        assert_eq!(step.instruction, Instruction::Push(7));
+        // push 2, push 5 and add were folded into this step
+        assert!(step.merged.len() >= 3, "{:?}", step.merged);
+        assert_eq!(step.debug_info.unwrap().from_pos, step.merged[0].from_pos);
+    }
 }
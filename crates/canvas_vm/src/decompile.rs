@@ -0,0 +1,1062 @@
+//! Decompiler from compiled programs to structured pseudocode
+//!
+//! Straight-line runs of states are grouped into basic blocks and evaluated
+//! on a symbolic stack. The stack slots live at a block entry are named
+//! `v0`, `v1`, ... from the bottom, so this needs every block to be entered
+//! with the same stack depth on all paths. Loops are found from DFS back
+//! edges and if/else regions from the `Pointer`/`Switch` branch points and
+//! their post-dominators. Division by a computed value is assumed not to
+//! divide by zero. Programs that do not fit this model (non-constant `roll`,
+//! inconsistent depths, four-way pointers, ...) fall back to the annotated
+//! disassembly.
+
+use crate::analysis::components;
+use crate::bytecode::{Instruction, Program};
+use crate::disasm::Disassembler;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+
+/// Upper bound on emitted statements, guarding against exponential
+/// duplication of shared regions
+const MAX_STATEMENTS: usize = 20_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Var {
+    Slot(usize),
+    Temp(usize),
+    /// Slot value after the block's write-back, only used while building
+    /// the branch condition
+    Next(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Const(i32),
+    Var(Var),
+    Not(Box<Expr>),
+    Bin(&'static str, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn bin(op: &'static str, a: Expr, b: Expr) -> Expr {
+        match (op, &a, &b) {
+            ("+", Expr::Const(x), Expr::Const(y)) => Expr::Const(x.wrapping_add(*y)),
+            ("-", Expr::Const(x), Expr::Const(y)) => Expr::Const(x.wrapping_sub(*y)),
+            ("*", Expr::Const(x), Expr::Const(y)) => Expr::Const(x.wrapping_mul(*y)),
+            (">", Expr::Const(x), Expr::Const(y)) => Expr::Const((x > y) as i32),
+            ("/", Expr::Const(x), Expr::Const(y)) if *y != 0 => Expr::Const(x.wrapping_div(*y)),
+            ("%", Expr::Const(x), Expr::Const(y)) if *y != 0 => Expr::Const(x.wrapping_rem_euclid(*y)),
+            ("+" | "-", _, Expr::Const(0)) | ("*" | "/", _, Expr::Const(1)) => a,
+            ("+", Expr::Const(0), _) | ("*", Expr::Const(1), _) => b,
+            _ => Expr::Bin(op, Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn not(a: Expr) -> Expr {
+        match a {
+            Expr::Const(x) => Expr::Const((x == 0) as i32),
+            other => Expr::Not(Box::new(other)),
+        }
+    }
+
+    /// Whether the value is always 0 or 1
+    fn is_boolean(&self) -> bool {
+        matches!(self, Expr::Not(_) | Expr::Bin(">", _, _) | Expr::Const(0 | 1))
+    }
+
+    /// Same truth value, with double negations removed
+    fn truthy(self) -> Expr {
+        match self {
+            Expr::Not(inner) => match *inner {
+                Expr::Not(x) => x.truthy(),
+                other => Expr::Not(Box::new(other)),
+            },
+            other => other,
+        }
+    }
+
+    fn uses(&self, var: &Var) -> bool {
+        match self {
+            Expr::Const(_) => false,
+            Expr::Var(v) => v == var,
+            Expr::Not(a) => a.uses(var),
+            Expr::Bin(_, a, b) => a.uses(var) || b.uses(var),
+        }
+    }
+
+    fn render(&self) -> String {
+        let atom = |e: &Expr| match e {
+            Expr::Bin(..) => format!("({})", e.render()),
+            _ => e.render(),
+        };
+        match self {
+            Expr::Const(n) => n.to_string(),
+            Expr::Var(Var::Slot(i) | Var::Next(i)) => format!("v{}", i),
+            Expr::Var(Var::Temp(i)) => format!("t{}", i),
+            Expr::Not(a) => match &**a {
+                Expr::Bin(">", x, y) => format!("{} <= {}", atom(x), atom(y)),
+                _ => format!("!{}", atom(a)),
+            },
+            Expr::Bin(op, a, b) => format!("{} {} {}", atom(a), op, atom(b)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Stmt {
+    Assign(Var, Expr),
+    Input(Var, bool),
+    Print(Expr, bool),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    Loop(Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    DoWhile(Vec<Stmt>, Expr),
+    Break,
+    Continue,
+    Halt,
+}
+
+/// Block successor; `None` stops execution
+type Node = Option<usize>;
+
+#[derive(Debug, Clone)]
+enum Term {
+    Goto(Node),
+    /// Truthy condition takes `then`
+    Branch(Expr, Node, Node),
+    Halt,
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    /// Program instruction indices in execution order
+    instrs: Vec<usize>,
+    /// Statements with side effects, in order
+    body: Vec<Stmt>,
+    term: Term,
+    /// Stack depth when the block is entered
+    depth: usize,
+}
+
+/// Program with `Nop`s skipped and equivalent states merged
+///
+/// The compiler creates one state per (block, DP, CC), so a loop body is
+/// often present several times, once per direction it is entered with.
+/// Merging states that behave identically (same instruction, equivalent
+/// successors) folds those copies back together.
+struct Graph {
+    instructions: Vec<Instruction>,
+    successors: Vec<Vec<Option<usize>>>,
+    /// A program instruction each state stands for
+    origin: Vec<usize>,
+}
+
+impl Graph {
+    fn minimized(program: &Program) -> Self {
+        let n = program.len();
+        // First non-Nop state reached from `i`; a cycle of Nops is kept
+        let resolve = |i: usize| -> Option<usize> {
+            let mut at = i;
+            for _ in 0..=n {
+                if at >= n {
+                    return None;
+                }
+                match (&program.instructions[at], program.get_successors(at)) {
+                    (Instruction::Nop, [next]) => at = (*next)?,
+                    _ => return Some(at),
+                }
+            }
+            Some(i)
+        };
+
+        // Reachable states in discovery order, entry first
+        let mut order = Vec::new();
+        let mut index = HashMap::new();
+        let mut successors: Vec<Vec<Option<usize>>> = Vec::new();
+        let mut work: Vec<usize> = resolve(0).into_iter().collect();
+        while let Some(i) = work.pop() {
+            if index.contains_key(&i) {
+                continue;
+            }
+            index.insert(i, order.len());
+            order.push(i);
+            let targets: Vec<Option<usize>> = program.get_successors(i).iter().map(|s| s.and_then(resolve)).collect();
+            work.extend(targets.iter().rev().flatten());
+            successors.push(targets);
+        }
+        let successors: Vec<Vec<Option<usize>>> =
+            successors.into_iter().map(|t| t.into_iter().map(|s| s.map(|s| index[&s])).collect()).collect();
+
+        // Moore-style partition refinement
+        let mut class: Vec<usize> = Vec::with_capacity(order.len());
+        let mut keys = HashMap::new();
+        for (k, &i) in order.iter().enumerate() {
+            let key = (program.instructions[i].clone(), successors[k].len());
+            let next = keys.len();
+            class.push(*keys.entry(key).or_insert(next));
+        }
+        let mut count = keys.len();
+        loop {
+            let mut signatures = HashMap::new();
+            let refined: Vec<usize> = (0..order.len())
+                .map(|k| {
+                    let signature: (usize, Vec<Option<usize>>) =
+                        (class[k], successors[k].iter().map(|s| s.map(|s| class[s])).collect());
+                    let next = signatures.len();
+                    *signatures.entry(signature).or_insert(next)
+                })
+                .collect();
+            class = refined;
+            if signatures.len() == count {
+                break;
+            }
+            count = signatures.len();
+        }
+
+        // Only copies within one strongly connected component are merged:
+        // those are the per-direction copies of a loop, while merging
+        // common tails of straight-line code would only add join points
+        let component = components(&successors);
+        let mut merged = HashMap::new();
+        for k in 0..order.len() {
+            let next = merged.len();
+            class[k] = *merged.entry((class[k], component[k])).or_insert(next);
+        }
+        let count = merged.len();
+
+        // Classes are numbered by first appearance, so the entry is state 0
+        let mut graph = Graph { instructions: Vec::new(), successors: Vec::new(), origin: Vec::new() };
+        let mut seen = vec![false; count];
+        for (k, &i) in order.iter().enumerate() {
+            if !std::mem::replace(&mut seen[class[k]], true) {
+                graph.instructions.push(program.instructions[i].clone());
+                graph.successors.push(successors[k].iter().map(|s| s.map(|s| class[s])).collect());
+                graph.origin.push(i);
+            }
+        }
+        if graph.instructions.is_empty() {
+            graph.instructions.push(Instruction::Halt);
+            graph.successors.push(Vec::new());
+            graph.origin.push(0);
+        }
+        graph
+    }
+
+    fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    fn get_successors(&self, idx: usize) -> &[Option<usize>] {
+        &self.successors[idx]
+    }
+}
+
+/// Decompiler for a compiled [`Program`]
+///
+/// ```ignore
+/// println!("{}", Decompiler::new(&program).decompile());
+/// ```
+pub struct Decompiler<'a> {
+    program: &'a Program,
+}
+
+impl<'a> Decompiler<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self { program }
+    }
+
+    /// Structured pseudocode, or the annotated disassembly (prefixed by the
+    /// reason) when the structure cannot be recovered
+    pub fn decompile(&self) -> String {
+        match self.structured() {
+            Ok(code) => code,
+            Err(reason) => format!(
+                "; could not decompile: {}\n{}",
+                reason,
+                Disassembler::new(self.program).annotated(true)
+            ),
+        }
+    }
+
+    /// Structured pseudocode, or why it could not be recovered
+    pub fn structured(&self) -> Result<String, String> {
+        if self.program.is_empty() {
+            return Ok("halt;\n".to_string());
+        }
+        let blocks = self.blocks()?;
+        let mut structurer = Structurer::new(&blocks);
+        let mut code = Vec::new();
+        structurer.region(Some(0), None, &mut code, true)?;
+        let code = tidy(code);
+
+        let mut out = String::new();
+        render(&code, 0, &mut out);
+        Ok(out)
+    }
+
+    /// Splits the program into basic blocks and evaluates each one
+    ///
+    /// Edges that evaluation shows are never taken (the other rotations of
+    /// a `Pointer` on a boolean) can make blocks look like join points, so
+    /// the split is redone with the taken edges until it is stable.
+    fn blocks(&self) -> Result<Vec<Block>, String> {
+        let graph = Graph::minimized(self.program);
+        let mut taken: Option<HashSet<(usize, usize)>> = None;
+        for _ in 0..8 {
+            let (blocks, edges) = split(&graph, taken.as_ref())?;
+            if taken.as_ref() == Some(&edges) {
+                return Ok(blocks);
+            }
+            taken = Some(edges);
+        }
+        split(&graph, taken.as_ref()).map(|(blocks, _)| blocks)
+    }
+}
+
+/// Basic blocks over the edges in `taken` (all edges when `None`), and the
+/// edges evaluation took
+type Split = (Vec<Block>, HashSet<(usize, usize)>);
+
+fn split(program: &Graph, taken: Option<&HashSet<(usize, usize)>>) -> Result<Split, String> {
+    let n = program.len();
+    let successors = |i: usize| -> Vec<usize> {
+        program
+            .get_successors(i)
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&s| taken.is_none_or(|t| t.contains(&(i, s))))
+            .collect()
+    };
+    let is_branch = |i: usize| matches!(program.instructions[i], Instruction::Pointer | Instruction::Switch);
+
+    let mut predecessors = vec![0usize; n];
+    for i in 0..n {
+        for s in successors(i) {
+            predecessors[s] += 1;
+        }
+    }
+
+    // Leaders: the entry, join points and targets of branches
+    let mut leader = vec![false; n];
+    leader[0] = true;
+    for i in 0..n {
+        if predecessors[i] != 1 {
+            leader[i] = true;
+        }
+        if is_branch(i) {
+            for s in successors(i) {
+                leader[s] = true;
+            }
+        }
+    }
+
+    let mut block_of = HashMap::new();
+    let mut raw: Vec<Vec<usize>> = Vec::new();
+    for start in (0..n).filter(|&i| leader[i]) {
+        let mut instrs = vec![start];
+        let mut at = start;
+        while !is_branch(at) {
+            match program.get_successors(at) {
+                [Some(next)] if !leader[*next] => {
+                    at = *next;
+                    instrs.push(at);
+                }
+                _ => break,
+            }
+        }
+        block_of.insert(start, raw.len());
+        raw.push(instrs);
+    }
+
+    // Depths propagate from the entry; every block is evaluated once
+    let mut blocks: Vec<Option<Block>> = vec![None; raw.len()];
+    let mut edges = HashSet::new();
+    let mut pending = vec![(0usize, 0usize)];
+    let mut temps = 0;
+    while let Some((id, depth)) = pending.pop() {
+        if let Some(block) = &blocks[id] {
+            if block.depth != depth {
+                return Err(format!(
+                    "stack depth at instruction {} is {} or {} depending on the path",
+                    block.instrs[0], block.depth, depth
+                ));
+            }
+            continue;
+        }
+        let (block, exit_depth) = evaluate(program, &raw[id], depth, &block_of, &mut temps)?;
+        let targets: Vec<Node> = match &block.term {
+            Term::Goto(t) => vec![*t],
+            Term::Branch(_, t, f) => vec![*t, *f],
+            Term::Halt => vec![],
+        };
+        blocks[id] = Some(block);
+        edges.extend(raw[id].windows(2).map(|pair| (pair[0], pair[1])));
+        let last = raw[id][raw[id].len() - 1];
+        for target in targets.into_iter().flatten() {
+            edges.insert((last, raw[target][0]));
+            pending.push((target, exit_depth));
+        }
+    }
+
+    // Unreachable blocks are never emitted; keep indices stable
+    let blocks = blocks
+        .into_iter()
+        .map(|b| b.unwrap_or(Block { instrs: vec![], body: vec![], term: Term::Halt, depth: 0 }))
+        .collect();
+    Ok((blocks, edges))
+}
+
+/// Runs a block on a symbolic stack whose entry slots are `v0..depth`
+fn evaluate(
+    program: &Graph,
+    instrs: &[usize],
+    depth: usize,
+    block_of: &HashMap<usize, usize>,
+    temps: &mut usize,
+) -> Result<(Block, usize), String> {
+    let mut stack: Vec<Expr> = (0..depth).map(|i| Expr::Var(Var::Slot(i))).collect();
+    let mut body = Vec::new();
+    let node = |target: Option<&Option<usize>>| -> Node {
+        target.copied().flatten().and_then(|t| block_of.get(&t).copied())
+    };
+    let mut term = Term::Halt;
+
+    for &idx in instrs {
+        let successors = program.get_successors(idx);
+        let target = |i: usize| node(successors.get(i));
+        let instr = &program.instructions[idx];
+        let parts = instr.unfused().map_or_else(|| vec![instr.clone()], Vec::from);
+        for instr in &parts {
+            // Like the VM, an instruction without enough operands does nothing
+            let operands = match instr {
+                Instruction::Push(_) | Instruction::InNumber | Instruction::InChar => 0,
+                Instruction::Nop | Instruction::Halt => 0,
+                Instruction::Pop | Instruction::Not | Instruction::Duplicate => 1,
+                Instruction::Pointer | Instruction::Switch => 1,
+                Instruction::OutNumber | Instruction::OutChar => 1,
+                _ => 2,
+            };
+            if stack.len() < operands {
+                if matches!(instr, Instruction::Pointer | Instruction::Switch) {
+                    term = Term::Goto(target(0));
+                }
+                continue;
+            }
+            let pop = |stack: &mut Vec<Expr>| stack.pop().expect("operands were checked");
+            match instr {
+                Instruction::Push(v) => stack.push(Expr::Const(*v)),
+                Instruction::Pop => {
+                    pop(&mut stack);
+                }
+                Instruction::Divide | Instruction::Mod if stack.last() == Some(&Expr::Const(0)) => {
+                    // Division by zero leaves the stack as it was
+                }
+                Instruction::Add
+                | Instruction::Subtract
+                | Instruction::Multiply
+                | Instruction::Divide
+                | Instruction::Mod
+                | Instruction::Greater => {
+                    let b = pop(&mut stack);
+                    let a = pop(&mut stack);
+                    let op = match instr {
+                        Instruction::Add => "+",
+                        Instruction::Subtract => "-",
+                        Instruction::Multiply => "*",
+                        Instruction::Divide => "/",
+                        Instruction::Mod => "%",
+                        _ => ">",
+                    };
+                    stack.push(Expr::bin(op, a, b));
+                }
+                Instruction::Not => {
+                    let a = pop(&mut stack);
+                    stack.push(Expr::not(a));
+                }
+                Instruction::Duplicate => {
+                    let a = stack[stack.len() - 1].clone();
+                    stack.push(a);
+                }
+                Instruction::Roll => {
+                    let times = pop(&mut stack);
+                    let roll_depth = pop(&mut stack);
+                    let (Expr::Const(times), Expr::Const(roll_depth)) = (times, roll_depth) else {
+                        return Err(format!("roll with a computed depth at instruction {}", program.origin[idx]));
+                    };
+                    if roll_depth > 0 && roll_depth as usize <= stack.len() {
+                        let start = stack.len() - roll_depth as usize;
+                        let times = times.rem_euclid(roll_depth) as usize;
+                        stack[start..].rotate_right(times);
+                    }
+                }
+                Instruction::InNumber | Instruction::InChar => {
+                    let var = Var::Temp(*temps);
+                    *temps += 1;
+                    body.push(Stmt::Input(var.clone(), *instr == Instruction::InChar));
+                    stack.push(Expr::Var(var));
+                }
+                Instruction::OutNumber | Instruction::OutChar => {
+                    let value = pop(&mut stack);
+                    body.push(Stmt::Print(value, *instr == Instruction::OutChar));
+                }
+                Instruction::Nop => {}
+                Instruction::Halt => {}
+                Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => {
+                    unreachable!("superinstructions are split above")
+                }
+                Instruction::Pointer | Instruction::Switch => {
+                    let cond = pop(&mut stack);
+                    let is_pointer = *instr == Instruction::Pointer;
+                    term = match cond {
+                        Expr::Const(n) if is_pointer => Term::Goto(target(n.rem_euclid(4) as usize)),
+                        Expr::Const(n) => Term::Goto(target((n % 2 != 0) as usize)),
+                        cond if cond.is_boolean() => Term::Branch(cond, target(1), target(0)),
+                        cond if !is_pointer => Term::Branch(Expr::bin("%", cond, Expr::Const(2)), target(1), target(0)),
+                        cond if target(1) == target(2) && target(2) == target(3) => {
+                            Term::Branch(Expr::bin("%", cond, Expr::Const(4)), target(1), target(0))
+                        }
+                        _ => {
+                            return Err(format!(
+                                "pointer at instruction {} branches more than two ways",
+                                program.origin[idx]
+                            ))
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    let last = *instrs.last().expect("blocks are never empty");
+    match term {
+        Term::Branch(_, t, f) if t == f => term = Term::Goto(t),
+        Term::Branch(..) => {}
+        _ if program.instructions[last] == Instruction::Halt => {}
+        _ => term = Term::Goto(node(program.get_successors(last).first())),
+    }
+
+    // Write back the slots that changed, unless execution stops here
+    let stops = matches!(term, Term::Halt | Term::Goto(None));
+    let mut changed: Vec<(usize, Expr)> = stack
+        .iter()
+        .enumerate()
+        .filter(|(i, e)| !stops && **e != Expr::Var(Var::Slot(*i)))
+        .map(|(i, e)| (i, e.clone()))
+        .collect();
+    // The condition sees the stack after the block: values that were
+    // written back are read from their slot again
+    if let Term::Branch(c, ..) = &mut term {
+        for (i, e) in &changed {
+            if !matches!(e, Expr::Const(_)) {
+                replace(c, e, &Expr::Var(Var::Next(*i)));
+            }
+        }
+    }
+    // Old values still needed after their slot is overwritten are saved
+    for n in 0..changed.len() {
+        let old = Var::Slot(changed[n].0);
+        let later = changed[n + 1..].iter().any(|(_, e)| e.uses(&old));
+        let in_condition = matches!(&term, Term::Branch(c, ..) if c.uses(&old));
+        if later || in_condition {
+            let saved = Expr::Var(Var::Temp(*temps));
+            *temps += 1;
+            body.push(Stmt::Assign(Var::Temp(*temps - 1), Expr::Var(old.clone())));
+            for (_, e) in changed.iter_mut().skip(n + 1) {
+                replace(e, &Expr::Var(old.clone()), &saved);
+            }
+            if let Term::Branch(c, ..) = &mut term {
+                replace(c, &Expr::Var(old.clone()), &saved);
+            }
+        }
+    }
+    body.extend(changed.into_iter().map(|(i, e)| Stmt::Assign(Var::Slot(i), e)));
+    if let Term::Branch(c, ..) = &mut term {
+        for i in 0..stack.len() {
+            replace(c, &Expr::Var(Var::Next(i)), &Expr::Var(Var::Slot(i)));
+        }
+    }
+    if let Term::Branch(c, ..) = &mut term {
+        *c = c.clone().truthy();
+    }
+
+    let exit_depth = stack.len();
+    let instrs = instrs.iter().map(|&i| program.origin[i]).collect();
+    let body = inline_inputs(body, &mut term);
+    Ok((Block { instrs, body, term, depth }, exit_depth))
+}
+
+/// Replaces every occurrence of `from` inside `expr`
+fn replace(expr: &mut Expr, from: &Expr, to: &Expr) {
+    if expr == from {
+        *expr = to.clone();
+        return;
+    }
+    match expr {
+        Expr::Not(a) => replace(a, from, to),
+        Expr::Bin(_, a, b) => {
+            replace(a, from, to);
+            replace(b, from, to);
+        }
+        _ => {}
+    }
+}
+
+/// Turns `tN = input(); ...; vK = tN;` into `vK = input();` when `tN` has
+/// no other use in the body and `vK` is not read in between
+fn inline_inputs(mut body: Vec<Stmt>, term: &mut Term) -> Vec<Stmt> {
+    let reads = |stmt: &Stmt, var: &Var| match stmt {
+        Stmt::Assign(_, e) | Stmt::Print(e, _) => e.uses(var),
+        _ => false,
+    };
+    let mut i = 0;
+    while i < body.len() {
+        if let Stmt::Input(temp, is_char) = body[i].clone() {
+            let uses: Vec<usize> = (i + 1..body.len()).filter(|&j| reads(&body[j], &temp)).collect();
+            if let [j] = uses[..] {
+                if let Stmt::Assign(slot @ Var::Slot(_), Expr::Var(v)) = &body[j] {
+                    if *v == temp && !(i + 1..j).any(|k| reads(&body[k], slot)) {
+                        // The branch condition sees the value after the body
+                        if let Term::Branch(c, ..) = term {
+                            replace(c, &Expr::Var(temp.clone()), &Expr::Var(slot.clone()));
+                        }
+                        body[i] = Stmt::Input(slot.clone(), is_char);
+                        body.remove(j);
+                        continue;
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+    body
+}
+
+/// Loop being emitted: its header, its single exit and its body blocks
+struct LoopCtx {
+    header: usize,
+    exit: Node,
+    body: HashSet<usize>,
+}
+
+struct Structurer<'b> {
+    blocks: &'b [Block],
+    /// Immediate post-dominator (None for the virtual exit)
+    ipdom: Vec<Node>,
+    /// Post-dominator sets; index `blocks.len()` is the virtual exit
+    pdom: Vec<BTreeSet<usize>>,
+    /// Back-edge sources of each loop header
+    latches: HashMap<usize, Vec<usize>>,
+    loops: Vec<LoopCtx>,
+    emitted: usize,
+}
+
+impl<'b> Structurer<'b> {
+    fn new(blocks: &'b [Block]) -> Self {
+        let n = blocks.len();
+        let succ = |b: &Block| -> Vec<Node> {
+            match &b.term {
+                Term::Goto(t) => vec![*t],
+                Term::Branch(_, t, f) => vec![*t, *f],
+                Term::Halt => vec![None],
+            }
+        };
+
+        // Post-dominator sets; index n is the virtual exit
+        let all: BTreeSet<usize> = (0..=n).collect();
+        let mut pdom: Vec<BTreeSet<usize>> = vec![all; n + 1];
+        pdom[n] = BTreeSet::from([n]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..n).rev() {
+                let mut set: Option<BTreeSet<usize>> = None;
+                for s in succ(&blocks[i]) {
+                    let other = &pdom[s.unwrap_or(n)];
+                    set = Some(match set {
+                        None => other.clone(),
+                        Some(acc) => acc.intersection(other).copied().collect(),
+                    });
+                }
+                let mut set = set.unwrap_or_default();
+                set.insert(i);
+                if set != pdom[i] {
+                    pdom[i] = set;
+                    changed = true;
+                }
+            }
+        }
+        let ipdom = (0..n)
+            .map(|i| {
+                if pdom[i].len() == n + 1 {
+                    return None;
+                }
+                pdom[i]
+                    .iter()
+                    .copied()
+                    .filter(|&d| d != i)
+                    .find(|&d| pdom[d].len() + 1 == pdom[i].len())
+                    .filter(|&d| d != n)
+            })
+            .collect();
+
+        // Back edges from an iterative DFS
+        let mut latches: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut state = vec![0u8; n];
+        let mut stack = vec![(0usize, 0usize)];
+        if n > 0 {
+            state[0] = 1;
+        }
+        while let Some(&mut (node, ref mut next)) = stack.last_mut() {
+            let targets = succ(&blocks[node]);
+            if *next < targets.len() {
+                let target = targets[*next];
+                *next += 1;
+                if let Some(t) = target {
+                    match state[t] {
+                        0 => {
+                            state[t] = 1;
+                            stack.push((t, 0));
+                        }
+                        1 => latches.entry(t).or_default().push(node),
+                        _ => {}
+                    }
+                }
+            } else {
+                state[node] = 2;
+                stack.pop();
+            }
+        }
+
+        Self { blocks, ipdom, pdom, latches, loops: Vec::new(), emitted: 0 }
+    }
+
+    fn successors(&self, id: usize) -> Vec<Node> {
+        match &self.blocks[id].term {
+            Term::Goto(t) => vec![*t],
+            Term::Branch(_, t, f) => vec![*t, *f],
+            Term::Halt => vec![None],
+        }
+    }
+
+    /// Natural loop of `header`: blocks reaching a latch without it
+    fn loop_body(&self, header: usize) -> HashSet<usize> {
+        let mut body = HashSet::from([header]);
+        let mut work: Vec<usize> = self.latches[&header].clone();
+        while let Some(b) = work.pop() {
+            if body.insert(b) {
+                for p in 0..self.blocks.len() {
+                    if self.successors(p).contains(&Some(b)) {
+                        work.push(p);
+                    }
+                }
+            }
+        }
+        body
+    }
+
+    /// Emits code from `node` until `stop`, a loop edge or a halt
+    fn region(&mut self, mut node: Node, stop: Node, out: &mut Vec<Stmt>, mut entering: bool) -> Result<(), String> {
+        loop {
+            self.emitted += 1;
+            if self.emitted > MAX_STATEMENTS {
+                return Err("control flow too tangled to structure".to_string());
+            }
+            let Some(id) = node else {
+                out.push(Stmt::Halt);
+                return Ok(());
+            };
+            if node == stop && !entering {
+                return Ok(());
+            }
+            if !entering {
+                for (depth, ctx) in self.loops.iter().rev().enumerate() {
+                    let edge = if ctx.header == id {
+                        Some(Stmt::Continue)
+                    } else if ctx.exit == Some(id) {
+                        Some(Stmt::Break)
+                    } else {
+                        None
+                    };
+                    if let Some(edge) = edge {
+                        if depth > 0 {
+                            return Err("jump out of a nested loop".to_string());
+                        }
+                        out.push(edge);
+                        return Ok(());
+                    }
+                }
+            }
+
+            if self.latches.contains_key(&id) && !self.loops.iter().any(|l| l.header == id) {
+                let body = self.loop_body(id);
+                let mut exits: Vec<Node> = body
+                    .iter()
+                    .flat_map(|&b| self.successors(b))
+                    .filter(|s| s.is_some_and(|t| !body.contains(&t)))
+                    .collect();
+                exits.sort();
+                exits.dedup();
+                // With several exits the loop is left where they all meet;
+                // exits that only meet at the end halt inside the loop
+                let virtual_exit = self.blocks.len();
+                let common = exits
+                    .iter()
+                    .flatten()
+                    .map(|&e| self.pdom[e].clone())
+                    .reduce(|a, b| a.intersection(&b).copied().collect())
+                    .unwrap_or_default();
+                let exit = common
+                    .into_iter()
+                    .max_by_key(|&d| self.pdom[d].len())
+                    .filter(|&d| d != virtual_exit && !body.contains(&d));
+                self.loops.push(LoopCtx { header: id, exit, body });
+                let mut code = Vec::new();
+                let result = self.region(Some(id), None, &mut code, true);
+                self.loops.pop();
+                result?;
+                out.push(Stmt::Loop(code));
+                match exit {
+                    Some(_) => {
+                        node = exit;
+                        entering = false;
+                        continue;
+                    }
+                    None => return Ok(()),
+                }
+            }
+            entering = false;
+
+            let block = &self.blocks[id];
+            out.extend(block.body.iter().cloned());
+            match block.term.clone() {
+                Term::Halt => {
+                    out.push(Stmt::Halt);
+                    return Ok(());
+                }
+                Term::Goto(next) => node = next,
+                Term::Branch(cond, then, otherwise) => {
+                    let mut join = self.ipdom[id];
+                    // A join outside the current loop is reached via break
+                    if let (Some(ctx), Some(j)) = (self.loops.last(), join) {
+                        if !ctx.body.contains(&j) {
+                            join = None;
+                        }
+                    }
+                    let mut then_code = Vec::new();
+                    let mut else_code = Vec::new();
+                    if then != join || join.is_none() {
+                        self.region(then, join.or(stop), &mut then_code, false)?;
+                    }
+                    if otherwise != join || join.is_none() {
+                        self.region(otherwise, join.or(stop), &mut else_code, false)?;
+                    }
+                    out.push(Stmt::If(cond, then_code, else_code));
+                    match join {
+                        Some(_) => node = join,
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rewrites generic loops into while/do-while and cleans up branches
+fn tidy(code: Vec<Stmt>) -> Vec<Stmt> {
+    let mut out = Vec::new();
+    for stmt in code {
+        match stmt {
+            Stmt::Loop(body) => {
+                let mut body = tidy(body);
+                // loop { ...; if (c) { continue; } break; } => do { ... } while (c)
+                if let [.., Stmt::If(c, then, otherwise), Stmt::Break] = &body[..] {
+                    if then == &[Stmt::Continue] && otherwise.is_empty() {
+                        let cond = c.clone();
+                        body.truncate(body.len() - 2);
+                        out.push(Stmt::DoWhile(body, cond));
+                        continue;
+                    }
+                }
+                // loop { if (c) { ...; continue; } break; } => while (c) { ... }
+                if let [Stmt::If(c, then, otherwise), Stmt::Break] = &body[..] {
+                    if then.last() == Some(&Stmt::Continue) && otherwise.is_empty() {
+                        let mut then = then.clone();
+                        then.pop();
+                        out.push(Stmt::While(c.clone(), then));
+                        continue;
+                    }
+                }
+                if body.last() == Some(&Stmt::Continue) {
+                    body.pop();
+                }
+                // loop { if (c) { break; } ... } => while (!c) { ... }
+                if let [Stmt::If(c, then, otherwise), ..] = &body[..] {
+                    if then == &[Stmt::Break] && otherwise.is_empty() {
+                        let cond = Expr::not(c.clone()).truthy();
+                        out.push(Stmt::While(cond, body.split_off(1)));
+                        continue;
+                    }
+                }
+                out.push(Stmt::Loop(body));
+            }
+            Stmt::If(c, then, otherwise) => {
+                let (mut c, mut then, mut otherwise) = (c, tidy(then), tidy(otherwise));
+                // Branches that both end in the same jump (usually a copy of
+                // the halt) share their common tail after the if
+                let mut shared = Vec::new();
+                while let (Some(a), Some(b)) = (then.last(), otherwise.last()) {
+                    if a != b || (shared.is_empty() && !ends_in_jump(&then)) {
+                        break;
+                    }
+                    shared.push(then.pop().expect("then is not empty"));
+                    otherwise.pop();
+                }
+                shared.reverse();
+                if then.is_empty() && otherwise.is_empty() {
+                    out.extend(shared);
+                    continue;
+                }
+                // Prefer the positive condition, and a jump in the then-branch
+                let negated = matches!(c, Expr::Not(_)) && !otherwise.is_empty();
+                if negated || then.is_empty() || (ends_in_jump(&otherwise) && !ends_in_jump(&then)) {
+                    c = Expr::not(c).truthy();
+                    std::mem::swap(&mut then, &mut otherwise);
+                }
+                // if (c) { ...; break; } else { b } => if (c) { ...; break; } b
+                if ends_in_jump(&then) {
+                    out.push(Stmt::If(c, then, Vec::new()));
+                    out.extend(otherwise);
+                } else {
+                    out.push(Stmt::If(c, then, otherwise));
+                }
+                out.extend(shared);
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Whether control never falls off the end of `code`
+fn ends_in_jump(code: &[Stmt]) -> bool {
+    matches!(code.last(), Some(Stmt::Break | Stmt::Continue | Stmt::Halt))
+}
+
+fn render(code: &[Stmt], indent: usize, out: &mut String) {
+    let pad = "    ".repeat(indent);
+    let var = |v: &Var| Expr::Var(v.clone()).render();
+    for stmt in code {
+        match stmt {
+            Stmt::Assign(v, e) => {
+                let _ = writeln!(out, "{}{} = {};", pad, var(v), e.render());
+            }
+            Stmt::Input(v, is_char) => {
+                let func = if *is_char { "input_char" } else { "input" };
+                let _ = writeln!(out, "{}{} = {}();", pad, var(v), func);
+            }
+            Stmt::Print(e, is_char) => {
+                let func = if *is_char { "print_char" } else { "print" };
+                let _ = writeln!(out, "{}{}({});", pad, func, e.render());
+            }
+            Stmt::If(c, then, otherwise) => {
+                let _ = writeln!(out, "{}if ({}) {{", pad, c.render());
+                render(then, indent + 1, out);
+                if !otherwise.is_empty() {
+                    let _ = writeln!(out, "{}}} else {{", pad);
+                    render(otherwise, indent + 1, out);
+                }
+                let _ = writeln!(out, "{}}}", pad);
+            }
+            Stmt::Loop(body) => {
+                let _ = writeln!(out, "{}loop {{", pad);
+                render(body, indent + 1, out);
+                let _ = writeln!(out, "{}}}", pad);
+            }
+            Stmt::While(c, body) => {
+                let _ = writeln!(out, "{}while ({}) {{", pad, c.render());
+                render(body, indent + 1, out);
+                let _ = writeln!(out, "{}}}", pad);
+            }
+            Stmt::DoWhile(body, c) => {
+                let _ = writeln!(out, "{}do {{", pad);
+                render(body, indent + 1, out);
+                let _ = writeln!(out, "{}}} while ({});", pad, c.render());
+            }
+            Stmt::Break => {
+                let _ = writeln!(out, "{}break;", pad);
+            }
+            Stmt::Continue => {
+                let _ = writeln!(out, "{}continue;", pad);
+            }
+            Stmt::Halt => {
+                let _ = writeln!(out, "{}halt;", pad);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::compiler::Compiler;
+    use crate::script::Script;
+
+    fn decompile_script(source: &str) -> String {
+        let grid = Script::compile(source).unwrap().to_grid().unwrap();
+        let program = Compiler::new(grid).compile().unwrap();
+        Decompiler::new(&program).structured().unwrap()
+    }
+
+    #[test]
+    fn test_countdown_while_loop() {
+        let code = decompile_script("var x; read x; while (x > 0) { print x; x = x - 1; }");
+        assert_eq!(
+            code,
+            "v0 = input();\nwhile (v0 > 0) {\n    print(v0);\n    v0 = v0 - 1;\n}\nhalt;\n",
+            "{}",
+            code
+        );
+    }
+
+    #[test]
+    fn test_if_else() {
+        let code = decompile_script("var x; read x; if (x % 2 == 0) { print 0; } else { print 1; }");
+        assert!(code.contains("if (") && code.contains("} else {"), "{}", code);
+        assert!(code.contains("print(0);") && code.contains("print(1);"), "{}", code);
+    }
+
+    #[test]
+    fn test_do_while_from_assembly() {
+        let source = "in(number)\nloop: dup\nout(number)\npush 1\nsub\ndup\njnz loop\n";
+        let grid = Assembler::parse(source).unwrap().assemble().unwrap();
+        let program = Compiler::new(grid).compile().unwrap();
+        let code = Decompiler::new(&program).structured().unwrap();
+        assert!(code.contains("do {") && code.contains("} while (v0"), "{}", code);
+        assert!(code.contains("print(v0);"), "{}", code);
+    }
+
+    #[test]
+    fn test_constant_min_mod_minus_one() {
+        // Folding wraps like the VM instead of overflowing
+        let source = "push -2147483648\npush -1\nmod\nout(number)\n";
+        let grid = Assembler::parse(source).unwrap().assemble().unwrap();
+        let program = Compiler::new(grid).with_pruning(false).compile().unwrap();
+        let code = Decompiler::new(&program).decompile();
+        assert!(code.contains("print(0);"), "{}", code);
+    }
+
+    #[test]
+    fn test_fallback_to_disassembly() {
+        // The roll depth comes from the input
+        let source = "in(number)\nin(number)\nroll\nout(number)\n";
+        let grid = Assembler::parse(source).unwrap().assemble().unwrap();
+        let program = Compiler::new(grid).compile().unwrap();
+        let decompiler = Decompiler::new(&program);
+        assert!(decompiler.structured().is_err());
+        let text = decompiler.decompile();
+        assert!(text.starts_with("; could not decompile: roll with a computed depth"), "{}", text);
+        assert!(text.contains("roll"));
+    }
+}
@@ -0,0 +1,234 @@
+//! Human-readable disassembly of compiled programs
+//!
+//! Prints one line per instruction (program state) with its operand, source
+//! codel, DP/CC, colors, block size and successor indices. The annotated
+//! mode also labels loop heads and halt states.
+
+use crate::bytecode::{Instruction, Program};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Disassembler for a compiled [`Program`]
+///
+/// ```ignore
+/// let listing = Disassembler::new(&program).annotated(true).to_string();
+/// ```
+pub struct Disassembler<'a> {
+    program: &'a Program,
+    annotated: bool,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self { program, annotated: false }
+    }
+
+    /// Enables loop-head labels and halt markers
+    pub fn annotated(mut self, annotated: bool) -> Self {
+        self.annotated = annotated;
+        self
+    }
+
+    /// Formats a single instruction line (without annotations)
+    pub fn line(&self, idx: usize) -> String {
+        let Some(rich) = self.program.get_rich_instruction(idx) else {
+            return String::new();
+        };
+        let cs = self.program.metadata.codel_size.max(1);
+
+        let (codel, pixel, dp, cc, colors, size) = match &rich.debug {
+            Some(d) => (
+                format!("({},{})", d.from_pos.0, d.from_pos.1),
+                format!("({},{})", d.from_pos.0 * cs, d.from_pos.1 * cs),
+                format!("{:?}", d.dp),
+                format!("{:?}", d.cc),
+                format!("{} -> {}", d.from_color, d.to_color),
+                d.block_size.to_string(),
+            ),
+            None => ("-".into(), "-".into(), "-".into(), "-".into(), "-".into(), "-".into()),
+        };
+
+        format!(
+            "{:04}  {:<13} {:<9} {:<11} {:<5} {:<5}  {:<26} {:>5}  {}",
+            idx, rich.op.to_string(), codel, pixel, dp, cc, colors, size, self.successors(idx)
+        )
+    }
+
+    /// Formats the successor list of an instruction
+    fn successors(&self, idx: usize) -> String {
+        let successors = self.program.get_successors(idx);
+        if successors.is_empty() {
+            return "-".to_string();
+        }
+        successors.iter()
+            .map(|s| s.map(|i| i.to_string()).unwrap_or_else(|| "end".to_string()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl fmt::Display for Disassembler<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let program = self.program;
+        let loops = if self.annotated { loop_heads(program) } else { BTreeMap::new() };
+
+        writeln!(
+            f,
+            "; {} instructions, grid {}x{} codels, codel size {}",
+            program.len(), program.width, program.height, program.metadata.codel_size
+        )?;
+        writeln!(
+            f,
+            "{:<4}  {:<13} {:<9} {:<11} {:<5} {:<5}  {:<26} {:>5}  next",
+            "idx", "instruction", "codel", "pixel", "dp", "cc", "from -> to", "size"
+        )?;
+
+        for idx in 0..program.len() {
+            if let Some(sources) = loops.get(&idx) {
+                let sources: Vec<String> = sources.iter().map(|s| format!("{:04}", s)).collect();
+                writeln!(f, "loop_{:04}:  ; back edge from {}", idx, sources.join(", "))?;
+            }
+            let line = self.line(idx);
+            if self.annotated && is_halt_state(program, idx) {
+                writeln!(f, "{}  ; halt", line)?;
+            } else {
+                writeln!(f, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether execution always stops after this instruction
+pub(crate) fn is_halt_state(program: &Program, idx: usize) -> bool {
+    let successors = program.get_successors(idx);
+    matches!(program.instructions.get(idx), Some(Instruction::Halt))
+        || (!successors.is_empty() && successors.iter().all(Option::is_none))
+}
+
+/// Finds loop heads: targets of back edges in a DFS from the entry
+///
+/// Returns each loop head with the instructions that jump back to it.
+pub(crate) fn loop_heads(program: &Program) -> BTreeMap<usize, Vec<usize>> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mark {
+        New,
+        OnStack,
+        Done,
+    }
+
+    let mut heads: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    if program.is_empty() {
+        return heads;
+    }
+
+    let mut marks = vec![Mark::New; program.len()];
+    let mut stack = vec![(0usize, 0usize)];
+    marks[0] = Mark::OnStack;
+
+    while let Some(&mut (node, ref mut next)) = stack.last_mut() {
+        let successors = program.get_successors(node);
+        if *next < successors.len() {
+            let target = successors[*next];
+            *next += 1;
+            let Some(target) = target.filter(|&t| t < program.len()) else {
+                continue;
+            };
+            match marks[target] {
+                Mark::New => {
+                    marks[target] = Mark::OnStack;
+                    stack.push((target, 0));
+                }
+                Mark::OnStack => {
+                    let sources = heads.entry(target).or_default();
+                    if !sources.contains(&node) {
+                        sources.push(node);
+                    }
+                }
+                Mark::Done => {}
+            }
+        } else {
+            marks[node] = Mark::Done;
+            stack.pop();
+        }
+    }
+
+    heads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::InstructionDebugInfo;
+    use crate::compiler::{CompileMode, Compiler};
+    use crate::exits::{CodelChooser, Direction};
+    use crate::grid::Grid;
+
+    fn debug_info(from: (usize, usize), from_color: &str, to_color: &str, size: usize) -> InstructionDebugInfo {
+        InstructionDebugInfo {
+            from_pos: from,
+            to_pos: from,
+            dp: Direction::Right,
+            cc: CodelChooser::Left,
+            block_size: size,
+            from_color: from_color.to_string(),
+            to_color: to_color.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_line_format() {
+        let mut program = Program::new(4, 2);
+        program.metadata.codel_size = 10;
+        let push = program.add_rich_instruction(Instruction::Push(3), debug_info((1, 0), "LightRed", "LightYellow", 3));
+        let halt = program.add_instruction(Instruction::Halt);
+        program.set_successors(push, vec![Some(halt)]);
+
+        let disasm = Disassembler::new(&program);
+        let line = disasm.line(push);
+        assert!(line.starts_with("0000  push 3 "));
+        assert!(line.contains("(1,0)"));
+        assert!(line.contains("(10,0)"));
+        assert!(line.contains("Right Left"));
+        assert!(line.contains("LightRed -> LightYellow"));
+        assert!(line.ends_with("3  1"));
+
+        // Sin debug info, las columnas quedan vacías
+        let line = disasm.line(halt);
+        assert!(line.starts_with("0001  halt "));
+        assert!(line.ends_with("-  -"));
+    }
+
+    #[test]
+    fn test_annotated_labels_loops_and_halts() {
+        let mut program = Program::new(1, 1);
+        let a = program.add_instruction(Instruction::Push(1));
+        let b = program.add_instruction(Instruction::Switch);
+        let c = program.add_instruction(Instruction::Halt);
+        program.set_successors(a, vec![Some(b)]);
+        program.set_successors(b, vec![Some(a), Some(c)]);
+
+        assert_eq!(loop_heads(&program).get(&a), Some(&vec![b]));
+
+        let plain = Disassembler::new(&program).to_string();
+        assert!(!plain.contains("loop_"));
+        assert!(!plain.contains("; halt"));
+
+        let listing = Disassembler::new(&program).annotated(true).to_string();
+        assert!(listing.contains("loop_0000:  ; back edge from 0001"));
+        assert!(listing.lines().any(|l| l.starts_with("0001  switch") && l.contains("0, 2")));
+        assert!(listing.lines().any(|l| l.starts_with("0002  halt") && l.ends_with("; halt")));
+    }
+
+    #[test]
+    fn test_disassemble_compiled_loop() {
+        // Dos bloques que rebotan entre sí para siempre
+        let grid = Grid::from_text("lR lY\n").unwrap();
+        let program = Compiler::new(grid).with_mode(CompileMode::Debug).compile().unwrap();
+        let listing = Disassembler::new(&program).annotated(true).to_string();
+
+        assert_eq!(listing.lines().filter(|l| l.starts_with(char::is_numeric)).count(), program.len());
+        assert!(listing.contains("push 1"));
+        assert!(listing.contains("loop_"));
+    }
+}
@@ -11,6 +11,10 @@ pub enum VmError {
     Halted,
     /// Watchdog timeout - programa excedió el límite de pasos
     ExecutionTimeout(usize),
+    /// Malformed text input (grid text, source code), with its 1-based line
+    Parse { line: usize, message: String },
+    /// Malformed or unsupported binary program file
+    InvalidProgramFile(String),
 }
 
 impl fmt::Display for VmError {
@@ -24,6 +28,8 @@ impl fmt::Display for VmError {
             VmError::OutOfBounds => write!(f, "Position out of bounds"),
             VmError::Halted => write!(f, "VM is halted"),
             VmError::ExecutionTimeout(steps) => write!(f, "Execution timeout after {} steps", steps),
+            VmError::Parse { line, message } => write!(f, "Parse error on line {}: {}", line, message),
+            VmError::InvalidProgramFile(message) => write!(f, "Invalid program file: {}", message),
         }
     }
 }
@@ -14,6 +14,14 @@ pub struct BlockInfo {
     pub positions: HashSet<Position>,
 }
 
+/// A single codel change, kept in the edit history for undo/redo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodelChange {
+    pub pos: Position,
+    pub old: PietColor,
+    pub new: PietColor,
+}
+
 /// Key for precomputed exits
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct ExitKey {
@@ -32,6 +40,11 @@ pub struct Grid {
     block_ids: Vec<BlockId>,              // blockId[x][y]
     blocks: HashMap<BlockId, BlockInfo>,   // blockSize[block]
     exits: HashMap<ExitKey, Option<Position>>, // exit[block][dp][cc]
+    /// Next unused block ID (IDs of removed blocks are never reused)
+    next_block_id: BlockId,
+    // Edit history
+    undo_stack: Vec<Vec<CodelChange>>,
+    redo_stack: Vec<Vec<CodelChange>>,
 }
 
 impl Grid {
@@ -48,6 +61,9 @@ impl Grid {
             block_ids: vec![0; width * height],
             blocks: HashMap::new(),
             exits: HashMap::new(),
+            next_block_id: 0,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
         };
         
         grid.precompute_blocks();
@@ -61,6 +77,59 @@ impl Grid {
         Self::from_rgba_with_codel_size(width, height, rgba_data, None)
     }
     
+    /// Parses a grid from the text format, one color token per codel
+    ///
+    /// Codels are separated by whitespace and rows by newlines (see
+    /// [`PietColor::token`]). Blank lines and `#` comments are ignored.
+    ///
+    /// ```text
+    /// # push 3, out(number), halt
+    /// lR lR lR lY dM K
+    /// K  K  K  K  dM K
+    /// ```
+    pub fn from_text(text: &str) -> Result<Self, VmError> {
+        let mut width = None;
+        let mut cells = Vec::new();
+        let mut height = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            let parse_error = |message: String| VmError::Parse { line: i + 1, message };
+
+            match width {
+                None => width = Some(tokens.len()),
+                Some(w) if w != tokens.len() => {
+                    return Err(parse_error(format!("expected {} codels, found {}", w, tokens.len())));
+                }
+                Some(_) => {}
+            }
+            for token in tokens {
+                let color = PietColor::from_token(token)
+                    .ok_or_else(|| parse_error(format!("unknown color token '{}'", token)))?;
+                cells.push(color);
+            }
+            height += 1;
+        }
+
+        let width = width.ok_or(VmError::Parse { line: 1, message: "empty grid".to_string() })?;
+        Self::new(width, height, cells)
+    }
+
+    /// Prints the grid in the format read by [`Grid::from_text`]
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for row in self.cells.chunks(self.width.max(1)) {
+            let line: Vec<String> = row.iter().map(|c| format!("{:<2}", c.token())).collect();
+            text.push_str(line.join(" ").trim_end());
+            text.push('\n');
+        }
+        text
+    }
+    
     /// Detects the codel size from RGBA data without creating the grid
     /// Returns 1 if detection is uncertain
     pub fn detect_codel_size_from_rgba(width: usize, height: usize, rgba_data: &[u8]) -> usize {
@@ -253,6 +322,11 @@ impl Grid {
         self.height
     }
 
+    /// Returns all codel colors in row-major order
+    pub fn cells(&self) -> &[PietColor] {
+        &self.cells
+    }
+
     pub fn get(&self, pos: Position) -> Option<PietColor> {
         if pos.x < self.width && pos.y < self.height {
             Some(self.cells[pos.y * self.width + pos.x])
@@ -281,10 +355,157 @@ impl Grid {
         self.exits.get(&key).copied().flatten()
     }
 
+    // === Editing ===
+
+    /// Paints a single codel
+    ///
+    /// Returns the IDs of the blocks removed or created by the edit.
+    pub fn set(&mut self, pos: Position, color: PietColor) -> Result<HashSet<BlockId>, VmError> {
+        if pos.x >= self.width || pos.y >= self.height {
+            return Err(VmError::OutOfBounds);
+        }
+        Ok(self.edit(&[(pos, color)]))
+    }
+
+    /// Paints every codel of the rectangle starting at `top_left`
+    pub fn fill_rect(
+        &mut self,
+        top_left: Position,
+        width: usize,
+        height: usize,
+        color: PietColor,
+    ) -> Result<HashSet<BlockId>, VmError> {
+        if top_left.x + width > self.width || top_left.y + height > self.height {
+            return Err(VmError::OutOfBounds);
+        }
+        let mut targets = Vec::with_capacity(width * height);
+        for y in top_left.y..top_left.y + height {
+            for x in top_left.x..top_left.x + width {
+                targets.push((Position::new(x, y), color));
+            }
+        }
+        Ok(self.edit(&targets))
+    }
+
+    /// Recolors the whole block containing `pos` (paint bucket)
+    pub fn flood_recolor(&mut self, pos: Position, color: PietColor) -> Result<HashSet<BlockId>, VmError> {
+        let block_id = self.get_block_id(pos).ok_or(VmError::OutOfBounds)?;
+        let targets: Vec<_> = self.blocks[&block_id].positions.iter()
+            .map(|&p| (p, color))
+            .collect();
+        Ok(self.edit(&targets))
+    }
+
+    /// Reverts the last edit, returning the changed block IDs
+    pub fn undo(&mut self) -> Option<HashSet<BlockId>> {
+        let changes = self.undo_stack.pop()?;
+        let targets: Vec<_> = changes.iter().map(|c| (c.pos, c.old)).collect();
+        let changed = self.apply_changes(&targets);
+        self.redo_stack.push(changes);
+        Some(changed)
+    }
+
+    /// Re-applies the last undone edit, returning the changed block IDs
+    pub fn redo(&mut self) -> Option<HashSet<BlockId>> {
+        let changes = self.redo_stack.pop()?;
+        let targets: Vec<_> = changes.iter().map(|c| (c.pos, c.new)).collect();
+        let changed = self.apply_changes(&targets);
+        self.undo_stack.push(changes);
+        Some(changed)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Applies a user edit and records it in the undo history
+    fn edit(&mut self, targets: &[(Position, PietColor)]) -> HashSet<BlockId> {
+        let changes: Vec<CodelChange> = targets.iter()
+            .filter_map(|&(pos, new)| {
+                let old = self.cells[pos.y * self.width + pos.x];
+                (old != new).then_some(CodelChange { pos, old, new })
+            })
+            .collect();
+        if changes.is_empty() {
+            return HashSet::new();
+        }
+
+        let changed = self.apply_changes(targets);
+        self.undo_stack.push(changes);
+        self.redo_stack.clear();
+        changed
+    }
+
+    /// Writes the new colors and recomputes only the blocks they touch
+    ///
+    /// The affected region is every old block containing an edited codel,
+    /// plus every neighbouring block with the codel's new color (it may
+    /// merge). No block can grow past that region, so re-running the
+    /// flood-fill inside it is enough.
+    fn apply_changes(&mut self, targets: &[(Position, PietColor)]) -> HashSet<BlockId> {
+        let edited: HashMap<Position, PietColor> = targets.iter()
+            .copied()
+            .filter(|&(pos, color)| self.cells[pos.y * self.width + pos.x] != color)
+            .collect();
+        let mut changed = HashSet::new();
+        if edited.is_empty() {
+            return changed;
+        }
+
+        // 1. Collect the old blocks that the edit can split, shrink or merge
+        let mut affected = HashSet::new();
+        for (&pos, &color) in &edited {
+            affected.insert(self.block_ids[pos.y * self.width + pos.x]);
+            for dir in [Direction::Right, Direction::Down, Direction::Left, Direction::Up] {
+                if let Some(next) = pos.step(dir, self.width, self.height) {
+                    let idx = next.y * self.width + next.x;
+                    if !edited.contains_key(&next) && self.cells[idx] == color {
+                        affected.insert(self.block_ids[idx]);
+                    }
+                }
+            }
+        }
+
+        // 2. Drop them (and their exits) and paint the new colors
+        let mut region = HashSet::new();
+        for block_id in &affected {
+            if let Some(info) = self.blocks.remove(block_id) {
+                region.extend(info.positions.iter().map(|p| p.y * self.width + p.x));
+            }
+            for dp in [Direction::Right, Direction::Down, Direction::Left, Direction::Up] {
+                for cc in [CodelChooser::Left, CodelChooser::Right] {
+                    self.exits.remove(&ExitKey { block_id: *block_id, dp, cc });
+                }
+            }
+        }
+        for (&pos, &color) in &edited {
+            self.cells[pos.y * self.width + pos.x] = color;
+        }
+        changed.extend(affected);
+
+        // 3. Rebuild blocks inside the region with fresh IDs, in scan order
+        let mut starts: Vec<usize> = region.iter().copied().collect();
+        starts.sort_unstable();
+        for idx in starts {
+            if !region.contains(&idx) {
+                continue;
+            }
+            let pos = Position::new(idx % self.width, idx / self.width);
+            let block_id = self.add_block(pos, |i| region.remove(&i));
+            self.compute_exits_for(block_id);
+            changed.insert(block_id);
+        }
+
+        changed
+    }
+
     /// Precomputes all blocks using flood-fill
     fn precompute_blocks(&mut self) {
         let mut visited = vec![false; self.width * self.height];
-        let mut next_block_id: BlockId = 0;
         
         for y in 0..self.height {
             for x in 0..self.width {
@@ -293,52 +514,53 @@ impl Grid {
                     continue;
                 }
                 
-                let pos = Position::new(x, y);
-                let color = self.cells[idx];
-                
-                // Flood-fill to find the block
-                let positions = self.flood_fill(pos, color, &mut visited);
-                let size = positions.len();
-                
-                // Assign block_id to all positions
-                for &p in &positions {
-                    self.block_ids[p.y * self.width + p.x] = next_block_id;
-                }
-                
-                // Save block information
-                self.blocks.insert(next_block_id, BlockInfo {
-                    size,
-                    color,
-                    positions,
-                });
-                
-                next_block_id += 1;
+                self.add_block(Position::new(x, y), |i| !std::mem::replace(&mut visited[i], true));
             }
         }
     }
+
+    /// Flood-fills the block at `pos` and registers it under a new ID
+    ///
+    /// `claim(idx)` marks a codel as taken, returning false if it already was.
+    fn add_block(&mut self, pos: Position, claim: impl FnMut(usize) -> bool) -> BlockId {
+        let color = self.cells[pos.y * self.width + pos.x];
+        let positions = self.flood_fill(pos, color, claim);
+        let size = positions.len();
+        let block_id = self.next_block_id;
+        
+        // Assign block_id to all positions
+        for &p in &positions {
+            self.block_ids[p.y * self.width + p.x] = block_id;
+        }
+        
+        // Save block information
+        self.blocks.insert(block_id, BlockInfo {
+            size,
+            color,
+            positions,
+        });
+        
+        self.next_block_id += 1;
+        block_id
+    }
     
     /// Flood-fill to find contiguous blocks
-    fn flood_fill(&self, start: Position, color: PietColor, visited: &mut [bool]) -> HashSet<Position> {
+    fn flood_fill(&self, start: Position, color: PietColor, mut claim: impl FnMut(usize) -> bool) -> HashSet<Position> {
         let mut block = HashSet::new();
         let mut to_visit = vec![start];
         
         while let Some(pos) = to_visit.pop() {
             let idx = pos.y * self.width + pos.x;
-            if visited[idx] {
+            if self.cells[idx] != color || !claim(idx) {
                 continue;
             }
+            block.insert(pos);
             
-            if self.get(pos) == Some(color) {
-                visited[idx] = true;
-                block.insert(pos);
-                
-                // Add neighbors (4-connectivity)
-                for dir in [Direction::Right, Direction::Down, Direction::Left, Direction::Up] {
-                    if let Some(next) = pos.step(dir, self.width, self.height) {
-                        let next_idx = next.y * self.width + next.x;
-                        if !visited[next_idx] {
-                            to_visit.push(next);
-                        }
+            // Add neighbors (4-connectivity)
+            for dir in [Direction::Right, Direction::Down, Direction::Left, Direction::Up] {
+                if let Some(next) = pos.step(dir, self.width, self.height) {
+                    if self.cells[next.y * self.width + next.x] == color {
+                        to_visit.push(next);
                     }
                 }
             }
@@ -349,15 +571,24 @@ impl Grid {
     
     /// Precomputes all possible exits
     fn precompute_exits(&mut self) {
-        for (&block_id, block_info) in &self.blocks {
-            for dp in [Direction::Right, Direction::Down, Direction::Left, Direction::Up] {
-                for cc in [CodelChooser::Left, CodelChooser::Right] {
-                    let exit = self.find_exit_for_block(block_info, dp, cc);
-                    let key = ExitKey { block_id, dp, cc };
-                    self.exits.insert(key, exit);
-                }
+        let block_ids: Vec<BlockId> = self.blocks.keys().copied().collect();
+        for block_id in block_ids {
+            self.compute_exits_for(block_id);
+        }
+    }
+
+    /// Computes the 8 DP/CC exits of one block
+    fn compute_exits_for(&mut self, block_id: BlockId) {
+        let Some(block_info) = self.blocks.get(&block_id) else {
+            return;
+        };
+        let mut exits = Vec::with_capacity(8);
+        for dp in [Direction::Right, Direction::Down, Direction::Left, Direction::Up] {
+            for cc in [CodelChooser::Left, CodelChooser::Right] {
+                exits.push((ExitKey { block_id, dp, cc }, self.find_exit_for_block(block_info, dp, cc)));
             }
         }
+        self.exits.extend(exits);
     }
     
     /// Finds the exit codel of a block (used internally for precomputation)
@@ -590,6 +821,171 @@ mod tests {
         assert_eq!(exit, Some(Position::new(1, 0)));
     }
 
+    /// Checks that an edited grid has the same blocks and exits as a fresh one
+    fn assert_matches_fresh(grid: &Grid) {
+        let fresh = Grid::new(grid.width(), grid.height(), grid.cells().to_vec()).unwrap();
+        assert_eq!(grid.blocks.len(), fresh.blocks.len());
+        for y in 0..grid.height() {
+            for x in 0..grid.width() {
+                let pos = Position::new(x, y);
+                let info = grid.get_block_info(grid.get_block_id(pos).unwrap()).unwrap();
+                let fresh_id = fresh.get_block_id(pos).unwrap();
+                let fresh_info = fresh.get_block_info(fresh_id).unwrap();
+                assert_eq!(info.positions, fresh_info.positions, "block at {:?}", pos);
+                assert_eq!(info.color, fresh_info.color);
+                for dp in [Direction::Right, Direction::Down, Direction::Left, Direction::Up] {
+                    for cc in [CodelChooser::Left, CodelChooser::Right] {
+                        assert_eq!(
+                            grid.get_exit(grid.get_block_id(pos).unwrap(), dp, cc),
+                            fresh.get_exit(fresh_id, dp, cc),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_splits_and_merges_blocks() {
+        let mut grid = Grid::new(3, 1, vec![PietColor::Red; 3]).unwrap();
+        let old_id = grid.get_block_id(Position::new(0, 0)).unwrap();
+
+        // Painting the middle codel splits the red block in two
+        let changed = grid.set(Position::new(1, 0), PietColor::Blue).unwrap();
+        assert!(changed.contains(&old_id));
+        assert_eq!(changed.len(), 4); // 1 removed + 3 created
+        assert_eq!(grid.get_block_info(grid.get_block_id(Position::new(0, 0)).unwrap()).unwrap().size, 1);
+        assert_matches_fresh(&grid);
+
+        // Painting it back merges them again
+        let changed = grid.set(Position::new(1, 0), PietColor::Red).unwrap();
+        assert_eq!(changed.len(), 4); // 3 removed + 1 created
+        assert_eq!(grid.get_block_info(grid.get_block_id(Position::new(0, 0)).unwrap()).unwrap().size, 3);
+        assert_matches_fresh(&grid);
+    }
+
+    #[test]
+    fn test_set_only_touches_affected_blocks() {
+        let cells = vec![
+            PietColor::Red, PietColor::Red, PietColor::White, PietColor::Blue,
+            PietColor::Red, PietColor::Red, PietColor::White, PietColor::Blue,
+        ];
+        let mut grid = Grid::new(4, 2, cells).unwrap();
+        let blue_id = grid.get_block_id(Position::new(3, 0)).unwrap();
+        let white_id = grid.get_block_id(Position::new(2, 0)).unwrap();
+
+        let changed = grid.set(Position::new(0, 0), PietColor::Green).unwrap();
+        assert!(!changed.contains(&blue_id));
+        assert!(!changed.contains(&white_id));
+        assert_eq!(grid.get_block_id(Position::new(3, 1)), Some(blue_id));
+
+        // Painting with the same color is a no-op
+        assert!(grid.set(Position::new(0, 0), PietColor::Green).unwrap().is_empty());
+        assert!(grid.set(Position::new(4, 0), PietColor::Green).is_err());
+    }
+
+    #[test]
+    fn test_fill_rect_and_flood_recolor() {
+        let mut grid = Grid::new(4, 4, vec![PietColor::White; 16]).unwrap();
+        grid.fill_rect(Position::new(1, 1), 2, 2, PietColor::Red).unwrap();
+        let red_id = grid.get_block_id(Position::new(1, 1)).unwrap();
+        assert_eq!(grid.get_block_info(red_id).unwrap().size, 4);
+        assert_matches_fresh(&grid);
+
+        grid.flood_recolor(Position::new(2, 2), PietColor::Blue).unwrap();
+        assert_eq!(grid.get(Position::new(1, 1)), Some(PietColor::Blue));
+        assert_eq!(grid.get(Position::new(0, 0)), Some(PietColor::White));
+        assert_matches_fresh(&grid);
+
+        assert!(grid.fill_rect(Position::new(3, 3), 2, 1, PietColor::Red).is_err());
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let original = vec![PietColor::Red, PietColor::Red, PietColor::Green, PietColor::Green];
+        let mut grid = Grid::new(2, 2, original.clone()).unwrap();
+        assert!(!grid.can_undo());
+
+        grid.set(Position::new(0, 0), PietColor::Green).unwrap();
+        grid.fill_rect(Position::new(0, 1), 2, 1, PietColor::Blue).unwrap();
+        let edited = grid.cells().to_vec();
+
+        assert!(grid.undo().is_some());
+        assert!(grid.undo().is_some());
+        assert!(grid.undo().is_none());
+        assert_eq!(grid.cells(), &original[..]);
+        assert_matches_fresh(&grid);
+
+        assert!(grid.redo().is_some());
+        assert!(grid.redo().is_some());
+        assert!(!grid.can_redo());
+        assert_eq!(grid.cells(), &edited[..]);
+        assert_matches_fresh(&grid);
+
+        // A new edit discards the redo history
+        grid.undo();
+        grid.set(Position::new(1, 1), PietColor::Black).unwrap();
+        assert!(!grid.can_redo());
+    }
+
+    #[test]
+    fn test_random_edits_match_fresh_grid() {
+        let palette = [PietColor::Red, PietColor::Blue, PietColor::White, PietColor::Black];
+        let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+        let mut next = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+
+        let cells = (0..36).map(|_| palette[next(4)]).collect();
+        let mut grid = Grid::new(6, 6, cells).unwrap();
+        for _ in 0..200 {
+            let pos = Position::new(next(6), next(6));
+            let color = palette[next(4)];
+            match next(3) {
+                0 => { grid.set(pos, color).unwrap(); }
+                1 => { grid.flood_recolor(pos, color).unwrap(); }
+                _ => { grid.fill_rect(pos, next(6 - pos.x) + 1, next(6 - pos.y) + 1, color).unwrap(); }
+            }
+            assert_matches_fresh(&grid);
+        }
+    }
+
+    #[test]
+    fn test_text_roundtrip() {
+        let text = "lR R  dR W\nK  lM M  dM\n";
+        let grid = Grid::from_text(text).unwrap();
+        assert_eq!(grid.width(), 4);
+        assert_eq!(grid.height(), 2);
+        assert_eq!(grid.get(Position::new(2, 0)), Some(PietColor::DarkRed));
+        assert_eq!(grid.get(Position::new(0, 1)), Some(PietColor::Black));
+        assert_eq!(grid.to_text(), text);
+    }
+
+    #[test]
+    fn test_text_comments_and_blank_lines() {
+        let grid = Grid::from_text("# header\n\n  lY   Y # trailing\n\n dY  W\n").unwrap();
+        assert_eq!(grid.cells(), &[PietColor::LightYellow, PietColor::Yellow, PietColor::DarkYellow, PietColor::White]);
+    }
+
+    #[test]
+    fn test_text_errors() {
+        match Grid::from_text("R R\nR\n") {
+            Err(VmError::Parse { line, .. }) => assert_eq!(line, 2),
+            other => panic!("expected parse error, got {:?}", other),
+        }
+        match Grid::from_text("R R\n\nR Q\n") {
+            Err(VmError::Parse { line, message }) => {
+                assert_eq!(line, 3);
+                assert!(message.contains("'Q'"));
+            }
+            other => panic!("expected parse error, got {:?}", other),
+        }
+        assert!(Grid::from_text("# nothing\n").is_err());
+    }
+
     #[test]
     fn test_detect_codel_size_1px() {
         // 3x3 imagen con codel size 1 (cada pixel es un codel)
@@ -1,21 +1,45 @@
+mod analysis;
+mod assembler;
+mod brainfuck;
 mod bytecode;
 mod compiler;
 mod debugger;
+mod decompile;
+mod disasm;
 mod error;
 mod exits;
 mod grid;
 mod io;
+mod lint;
 mod ops;
+mod optimize;
+mod pbc;
+mod program_vm;
+mod script;
+mod textgen;
+#[cfg(test)]
+mod testutil;
 mod vm;
 
+pub use analysis::{DepthRange, LoopAnalysis, LoopKind, LoopWarning, StackAnalysis, Underflow, UnderflowWarning};
+pub use assembler::{AsmStmt, Assembler};
+pub use brainfuck::BrainfuckTranspiler;
 pub use bytecode::{Instruction, InstructionDebugInfo, Program, ProgramMetadata, RichInstruction};
-pub use compiler::{CompileMode, Compiler};
+pub use compiler::{CompileCache, CompileMode, CompileReport, Compiler};
 pub use debugger::{Debugger, DebuggerState, ExecutionMode, ExecutionStep, ExecutionTrace, InputRequest};
+pub use decompile::Decompiler;
+pub use disasm::Disassembler;
 pub use error::VmError;
 pub use exits::{CodelChooser, Direction, Position};
-pub use grid::{BlockId, BlockInfo, Grid};
+pub use grid::{BlockId, BlockInfo, CodelChange, Grid};
 pub use io::{Input, Output};
+pub use lint::{Diagnostic, LintKind, Linter, Severity};
 pub use ops::PietColor;
+pub use optimize::Optimizer;
+pub use pbc::{PbcFile, PbcWriter, PBC_MAGIC, PBC_VERSION};
+pub use program_vm::ProgramVm;
+pub use script::Script;
+pub use textgen::{text_grid, text_program};
 pub use vm::BytecodeVm;
 
 #[cfg(test)]
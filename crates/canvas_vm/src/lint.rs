@@ -0,0 +1,425 @@
+//! Lints for common Piet painting mistakes
+//!
+//! Combines the grid (colors and blocks) with the compiled program (which
+//! blocks execution actually visits) to point at codels that are probably
+//! not painted the way the author meant.
+
+use crate::analysis::{abstract_stacks, Value};
+use crate::bytecode::{Instruction, Program};
+use crate::compiler::{CompileMode, Compiler};
+use crate::exits::Position;
+use crate::grid::Grid;
+use crate::ops::PietColor;
+use crate::textgen::NumberBuilder;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Default block size above which a `push` is reported
+const DEFAULT_MAX_PUSH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub enum LintKind {
+    /// Colored block no execution path enters
+    UnreachableBlock,
+    /// Block with no way out in any DP/CC, where execution stops
+    TrappedBlock,
+    /// Single codel touching a same-colored block only by a corner
+    LoneCodel,
+    /// Pixel whose color is not in the Piet palette (black if it is the
+    /// top-left pixel of its codel, ignored otherwise)
+    OffPalette,
+    /// `push` of a very large block
+    HugePush,
+    /// `divide`/`mod` whose divisor is always 0
+    DivisionByZero,
+}
+
+impl LintKind {
+    fn code(&self) -> &'static str {
+        match self {
+            LintKind::UnreachableBlock => "unreachable-block",
+            LintKind::TrappedBlock => "trapped-block",
+            LintKind::LoneCodel => "lone-codel",
+            LintKind::OffPalette => "off-palette",
+            LintKind::HugePush => "huge-push",
+            LintKind::DivisionByZero => "division-by-zero",
+        }
+    }
+}
+
+/// A single lint finding
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: LintKind,
+    /// Codel the diagnostic is about
+    pub position: (usize, usize),
+    pub message: String,
+    pub suggestion: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(
+            f,
+            "{}[{}] ({}, {}): {}\n  fix: {}",
+            severity,
+            self.kind.code(),
+            self.position.0,
+            self.position.1,
+            self.message,
+            self.suggestion
+        )
+    }
+}
+
+/// Source image of the grid, for the checks that need the raw pixels
+struct Pixels<'a> {
+    width: usize,
+    height: usize,
+    rgba: &'a [u8],
+}
+
+/// Lint pass over a grid and its compiled program
+///
+/// ```ignore
+/// let program = Compiler::new(grid.clone()).compile()?;
+/// for diagnostic in Linter::new(&grid, &program).pixels(w, h, &rgba).run() {
+///     println!("{}", diagnostic);
+/// }
+/// ```
+pub struct Linter<'a> {
+    grid: &'a Grid,
+    /// The program, recompiled in debug mode if it has no codel positions
+    program: Cow<'a, Program>,
+    pixels: Option<Pixels<'a>>,
+    max_push: usize,
+}
+
+impl<'a> Linter<'a> {
+    pub fn new(grid: &'a Grid, program: &'a Program) -> Self {
+        let has_positions = program.rich_instructions.iter().any(|r| r.debug.is_some());
+        let program = if has_positions || program.is_empty() {
+            Cow::Borrowed(program)
+        } else {
+            let m = &program.metadata;
+            Compiler::with_codel_size(grid.clone(), m.codel_size.max(1), m.image_width, m.image_height)
+                .with_mode(CompileMode::Debug)
+                .compile()
+                .map(Cow::Owned)
+                .unwrap_or(Cow::Borrowed(program))
+        };
+        Self { grid, program, pixels: None, max_push: DEFAULT_MAX_PUSH }
+    }
+
+    /// RGBA data the grid was read from, enabling the off-palette check
+    pub fn pixels(mut self, width: usize, height: usize, rgba: &'a [u8]) -> Self {
+        self.pixels = Some(Pixels { width, height, rgba });
+        self
+    }
+
+    /// Block size above which `push` is reported (default 1024)
+    pub fn max_push(mut self, size: usize) -> Self {
+        self.max_push = size;
+        self
+    }
+
+    /// Runs every check; diagnostics are ordered by position
+    pub fn run(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        self.check_blocks(&mut diagnostics);
+        self.check_lone_codels(&mut diagnostics);
+        self.check_pixels(&mut diagnostics);
+        self.check_instructions(&mut diagnostics);
+
+        let mut seen = HashSet::new();
+        diagnostics.retain(|d| seen.insert((d.kind, d.position)));
+        diagnostics.sort_by_key(|d| (d.position.1, d.position.0, d.kind));
+        diagnostics
+    }
+
+    fn block_of(&self, (x, y): (usize, usize)) -> Option<usize> {
+        self.grid.get_block_id(Position::new(x, y))
+    }
+
+    /// Top-left codel of every colored block, by block id
+    fn colored_blocks(&self) -> Vec<(usize, Position)> {
+        let mut seen = HashSet::new();
+        let mut blocks = Vec::new();
+        for y in 0..self.grid.height() {
+            for x in 0..self.grid.width() {
+                let pos = Position::new(x, y);
+                let colored = self.grid.get(pos).is_some_and(|c| !c.is_white() && !c.is_black());
+                if let Some(id) = self.grid.get_block_id(pos).filter(|_| colored) {
+                    if seen.insert(id) {
+                        blocks.push((id, pos));
+                    }
+                }
+            }
+        }
+        blocks
+    }
+
+    fn check_blocks(&self, out: &mut Vec<Diagnostic>) {
+        let mut visited = HashSet::new();
+        for rich in &self.program.rich_instructions {
+            let Some(debug) = &rich.debug else { continue };
+            visited.extend(self.block_of(debug.from_pos));
+            visited.extend(self.block_of(debug.to_pos));
+        }
+        if visited.is_empty() {
+            return;
+        }
+        let compiler = Compiler::new(self.grid.clone());
+
+        for (id, pos) in self.colored_blocks() {
+            let size = self.grid.get_block_info(id).map_or(1, |b| b.size);
+            if !visited.contains(&id) {
+                out.push(Diagnostic {
+                    severity: Severity::Info,
+                    kind: LintKind::UnreachableBlock,
+                    position: (pos.x, pos.y),
+                    message: format!("block of {} codel(s) is never executed", size),
+                    suggestion: "remove it, or connect it to the program's path".to_string(),
+                });
+            } else if compiler.is_trapped(id) {
+                out.push(Diagnostic {
+                    severity: Severity::Info,
+                    kind: LintKind::TrappedBlock,
+                    position: (pos.x, pos.y),
+                    message: "no exit in any DP/CC: the program stops in this block".to_string(),
+                    suggestion: "if execution should continue, open a path out of the block".to_string(),
+                });
+            }
+        }
+    }
+
+    fn check_lone_codels(&self, out: &mut Vec<Diagnostic>) {
+        let (width, height) = (self.grid.width(), self.grid.height());
+        for (id, pos) in self.colored_blocks() {
+            if self.grid.get_block_info(id).is_none_or(|b| b.size != 1) {
+                continue;
+            }
+            let color = self.grid.get(pos);
+            for (dx, dy) in [(-1, -1), (1, -1), (-1, 1), (1, 1)] {
+                let (nx, ny) = (pos.x as i64 + dx, pos.y as i64 + dy);
+                if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                    continue;
+                }
+                let corner = Position::new(nx as usize, ny as usize);
+                if self.grid.get(corner) != color {
+                    continue;
+                }
+                out.push(Diagnostic {
+                    severity: Severity::Warning,
+                    kind: LintKind::LoneCodel,
+                    position: (pos.x, pos.y),
+                    message: format!(
+                        "single codel touches a same-colored block at ({}, {}) only by a corner",
+                        corner.x, corner.y
+                    ),
+                    suggestion: format!(
+                        "paint ({}, {}) or ({}, {}) the same color to join them",
+                        corner.x, pos.y, pos.x, corner.y
+                    ),
+                });
+                break;
+            }
+        }
+    }
+
+    fn check_pixels(&self, out: &mut Vec<Diagnostic>) {
+        let Some(pixels) = &self.pixels else { return };
+        if pixels.rgba.len() < pixels.width * pixels.height * 4 {
+            return;
+        }
+        let codel = self.program.metadata.codel_size.max(1);
+        let mut reported = HashSet::new();
+        for py in 0..pixels.height {
+            for px in 0..pixels.width {
+                let i = (py * pixels.width + px) * 4;
+                let (r, g, b) = (pixels.rgba[i], pixels.rgba[i + 1], pixels.rgba[i + 2]);
+                if PietColor::ALL.iter().any(|c| c.rgb() == (r, g, b)) {
+                    continue;
+                }
+                let position = (px / codel, py / codel);
+                if !reported.insert(position) {
+                    continue;
+                }
+                // Grid only samples the top-left pixel of each codel
+                let effect = if px % codel == 0 && py % codel == 0 {
+                    "it is read as black"
+                } else {
+                    "it is ignored, since a codel takes the color of its top-left pixel"
+                };
+                let nearest = nearest_color(r, g, b);
+                let (nr, ng, nb) = nearest.rgb();
+                out.push(Diagnostic {
+                    severity: Severity::Warning,
+                    kind: LintKind::OffPalette,
+                    position,
+                    message: format!(
+                        "pixel ({}, {}) is #{:02X}{:02X}{:02X}, not a Piet color; {}",
+                        px, py, r, g, b, effect
+                    ),
+                    suggestion: format!("repaint it as {:?} (#{:02X}{:02X}{:02X})", nearest, nr, ng, nb),
+                });
+            }
+        }
+    }
+
+    fn check_instructions(&self, out: &mut Vec<Diagnostic>) {
+        let program = &self.program;
+        let stacks = abstract_stacks(program);
+        let mut numbers = NumberBuilder::new();
+        for (i, instr) in program.instructions.iter().enumerate() {
+            let Some(debug) = program.get_rich_instruction(i).and_then(|r| r.debug.as_ref()) else { continue };
+            match instr {
+                Instruction::Push(n) if *n as usize > self.max_push => {
+                    let mut ops = Vec::new();
+                    numbers.push(*n, &mut ops);
+                    let ops: Vec<String> = ops.iter().map(|op| op.to_string()).collect();
+                    out.push(Diagnostic {
+                        severity: Severity::Warning,
+                        kind: LintKind::HugePush,
+                        position: debug.from_pos,
+                        message: format!("pushes {}, the size of the whole block", n),
+                        suggestion: format!("build the number from small blocks: {}", ops.join(", ")),
+                    });
+                }
+                Instruction::Divide | Instruction::Mod => {
+                    let zero = stacks[i]
+                        .as_ref()
+                        .is_some_and(|s| s.depth.min >= 2 && s.peek(0) == Value::Const(0));
+                    if zero {
+                        out.push(Diagnostic {
+                            severity: Severity::Error,
+                            kind: LintKind::DivisionByZero,
+                            position: debug.from_pos,
+                            message: format!("{} by a divisor that is always 0; the VM skips it", instr),
+                            suggestion: "check the value pushed before it, or remove the operation".to_string(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Palette color closest to an arbitrary RGB value
+fn nearest_color(r: u8, g: u8, b: u8) -> PietColor {
+    let distance = |c: &PietColor| {
+        let (cr, cg, cb) = c.rgb();
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(r, cr) + d(g, cg) + d(b, cb)
+    };
+    *PietColor::ALL.iter().min_by_key(|c| distance(c)).expect("the palette is not empty")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(text: &str) -> Vec<Diagnostic> {
+        let grid = Grid::from_text(text).unwrap();
+        let program = Compiler::new(grid.clone()).compile().unwrap();
+        Linter::new(&grid, &program).run()
+    }
+
+    fn kinds(diagnostics: &[Diagnostic]) -> Vec<(LintKind, (usize, usize))> {
+        diagnostics.iter().map(|d| (d.kind, d.position)).collect()
+    }
+
+    #[test]
+    fn test_clean_program_only_reports_its_halt() {
+        // a straight path that ends in the enclosed dR block
+        let diagnostics = lint(
+            "
+            lR lR lR lY dM K  K
+            K  K  K  K  dM K  K
+            K  K  K  dR dR dR K
+            K  K  K  dR dR dR K
+            K  K  K  dR dR dR K
+            ",
+        );
+        assert_eq!(kinds(&diagnostics), vec![(LintKind::TrappedBlock, (3, 2))]);
+        assert_eq!(diagnostics[0].severity, Severity::Info);
+    }
+
+    #[test]
+    fn test_unreachable_and_lone_codels() {
+        let diagnostics = lint(
+            "
+            lR lR dR K  K
+            K  K  dR K  G
+            K  K  K  G  K
+            ",
+        );
+        let found = kinds(&diagnostics);
+        assert!(found.contains(&(LintKind::UnreachableBlock, (4, 1))), "{:?}", found);
+        assert!(found.contains(&(LintKind::LoneCodel, (4, 1))), "{:?}", found);
+        assert!(found.contains(&(LintKind::LoneCodel, (3, 2))), "{:?}", found);
+        let lone = diagnostics.iter().find(|d| d.kind == LintKind::LoneCodel).unwrap();
+        assert!(lone.suggestion.contains("(3, 1)") && lone.suggestion.contains("(4, 2)"), "{}", lone);
+    }
+
+    #[test]
+    fn test_huge_push_and_division_by_zero() {
+        let lint_source = |source: &str, max_push: usize| {
+            let grid = crate::assembler::Assembler::parse(source).unwrap().assemble().unwrap();
+            let program = Compiler::new(grid.clone()).compile().unwrap();
+            Linter::new(&grid, &program).max_push(max_push).run()
+        };
+        let diagnostics = lint_source("push 20\nout(number)\n", 16);
+        let huge = diagnostics.iter().find(|d| d.kind == LintKind::HugePush).expect("huge push");
+        assert_eq!(huge.position, (1, 0));
+        assert!(huge.suggestion.contains("multiply"), "{}", huge);
+        assert!(lint_source("push 20\nout(number)\n", 20).iter().all(|d| d.kind != LintKind::HugePush));
+
+        // the zero comes from `push 1, not`
+        let diagnostics = lint_source("push 7\npush 1\nnot\ndiv\nout(number)\n", 1024);
+        let zero: Vec<&Diagnostic> = diagnostics.iter().filter(|d| d.kind == LintKind::DivisionByZero).collect();
+        assert_eq!(zero.len(), 1, "{:?}", diagnostics);
+        assert_eq!(zero[0].severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_off_palette_pixels() {
+        // 2x1 codels of 2x2 pixels; one pixel of the second codel is off
+        let red = [0xFF, 0x00, 0x00, 0xFF];
+        let black = [0x00, 0x00, 0x00, 0xFF];
+        // Only the top-left pixel of a codel decides its color
+        for (off_pixel, effect) in [((3, 1), "ignored"), ((2, 0), "read as black")] {
+            let mut rgba = Vec::new();
+            for y in 0..2 {
+                for x in 0..4 {
+                    let pixel = if (x, y) == off_pixel { [0xF0, 0x10, 0x08, 0xFF] } else if x < 2 { red } else { black };
+                    rgba.extend(pixel);
+                }
+            }
+            let grid = Grid::from_rgba_with_codel_size(4, 2, &rgba, Some(2)).unwrap();
+            let program = Compiler::with_codel_size(grid.clone(), 2, 4, 2).compile().unwrap();
+            let diagnostics = Linter::new(&grid, &program).pixels(4, 2, &rgba).run();
+            let off: Vec<&Diagnostic> = diagnostics.iter().filter(|d| d.kind == LintKind::OffPalette).collect();
+            assert_eq!(off.len(), 1, "{:?}", diagnostics);
+            assert_eq!(off[0].position, (1, 0));
+            assert!(off[0].message.contains(effect), "{}", off[0]);
+            assert!(off[0].suggestion.contains("Red (#FF0000)"), "{}", off[0]);
+        }
+    }
+}
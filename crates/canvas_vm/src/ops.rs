@@ -89,6 +89,59 @@ impl PietColor {
         }
     }
 
+    /// All 20 colors, chromatic ones ordered by hue then lightness
+    pub const ALL: [PietColor; 20] = [
+        PietColor::LightRed, PietColor::Red, PietColor::DarkRed,
+        PietColor::LightYellow, PietColor::Yellow, PietColor::DarkYellow,
+        PietColor::LightGreen, PietColor::Green, PietColor::DarkGreen,
+        PietColor::LightCyan, PietColor::Cyan, PietColor::DarkCyan,
+        PietColor::LightBlue, PietColor::Blue, PietColor::DarkBlue,
+        PietColor::LightMagenta, PietColor::Magenta, PietColor::DarkMagenta,
+        PietColor::White, PietColor::Black,
+    ];
+
+    /// Short text token used by the grid text format
+    ///
+    /// Hue letter (R, Y, G, C, B, M) with an `l`/`d` prefix for light/dark,
+    /// `W` for white and `K` for black.
+    pub fn token(&self) -> &'static str {
+        match self {
+            PietColor::LightRed => "lR",
+            PietColor::Red => "R",
+            PietColor::DarkRed => "dR",
+            PietColor::LightYellow => "lY",
+            PietColor::Yellow => "Y",
+            PietColor::DarkYellow => "dY",
+            PietColor::LightGreen => "lG",
+            PietColor::Green => "G",
+            PietColor::DarkGreen => "dG",
+            PietColor::LightCyan => "lC",
+            PietColor::Cyan => "C",
+            PietColor::DarkCyan => "dC",
+            PietColor::LightBlue => "lB",
+            PietColor::Blue => "B",
+            PietColor::DarkBlue => "dB",
+            PietColor::LightMagenta => "lM",
+            PietColor::Magenta => "M",
+            PietColor::DarkMagenta => "dM",
+            PietColor::White => "W",
+            PietColor::Black => "K",
+        }
+    }
+
+    /// Parses a token produced by [`PietColor::token`]
+    pub fn from_token(token: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.token() == token)
+    }
+
+    /// Color reached by moving `hue_steps` along the hue cycle and
+    /// `lightness_steps` along the lightness cycle, None for white/black
+    pub fn shifted(&self, hue_steps: u8, lightness_steps: u8) -> Option<Self> {
+        let hue = (self.hue()? + hue_steps) % 6;
+        let lightness = (self.lightness()? + lightness_steps) % 3;
+        Some(Self::ALL[(hue * 3 + lightness) as usize])
+    }
+
     /// Gets the hue of the color (0-5), None for white/black
     pub fn hue(&self) -> Option<u8> {
         match self {
@@ -118,6 +171,18 @@ impl PietColor {
         }
     }
 
+    /// Palette RGB value, the inverse of [`PietColor::from_rgb`]
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        let (Some(hue), Some(lightness)) = (self.hue(), self.lightness()) else {
+            return if self.is_white() { (0xFF, 0xFF, 0xFF) } else { (0x00, 0x00, 0x00) };
+        };
+        // Channels lit by each hue: red, yellow, green, cyan, blue, magenta
+        let lit = [(1, 0, 0), (1, 1, 0), (0, 1, 0), (0, 1, 1), (0, 0, 1), (1, 0, 1)][hue as usize];
+        let (on, off) = [(0xFF, 0xC0), (0xFF, 0x00), (0xC0, 0x00)][lightness as usize];
+        let channel = |bit: u8| if bit == 1 { on } else { off };
+        (channel(lit.0), channel(lit.1), channel(lit.2))
+    }
+
     pub fn is_white(&self) -> bool {
         matches!(self, PietColor::White)
     }
@@ -224,6 +289,20 @@ mod tests {
         assert_eq!(PietColor::from_rgb(0x00, 0x00, 0xFF).unwrap(), PietColor::Blue);
         assert_eq!(PietColor::from_rgb(0xFF, 0xFF, 0xFF).unwrap(), PietColor::White);
         assert_eq!(PietColor::from_rgb(0x00, 0x00, 0x00).unwrap(), PietColor::Black);
+        for color in PietColor::ALL {
+            let (r, g, b) = color.rgb();
+            assert_eq!(PietColor::from_rgb(r, g, b).unwrap(), color);
+        }
+    }
+
+    #[test]
+    fn test_color_tokens() {
+        for color in PietColor::ALL {
+            assert_eq!(PietColor::from_token(color.token()), Some(color));
+        }
+        assert_eq!(PietColor::from_token("dR"), Some(PietColor::DarkRed));
+        assert_eq!(PietColor::from_token("X"), None);
+        assert_eq!(PietColor::from_token("r"), None);
     }
 
     #[test]
@@ -0,0 +1,533 @@
+//! Peephole optimizer for compiled programs
+//!
+//! Works on the straight-line chains of the state graph: runs of states
+//! where each one is the only way into the next. Within a chain it drops
+//! `nop`s, cancels `push`/`pop` and `duplicate`/`pop` pairs, folds
+//! operations on constants and fuses `push n` with the arithmetic that
+//! follows into a superinstruction. Every instruction of the result keeps
+//! the debug info of all the states it replaced in `merged`.
+
+use crate::bytecode::{Instruction, InstructionDebugInfo, Program, RichInstruction};
+use crate::error::VmError;
+use crate::program_vm::ProgramVm;
+use std::collections::{HashMap, HashSet};
+
+/// Instruction being rewritten, with the original states it stands for
+#[derive(Debug, Clone)]
+struct Op {
+    instr: Instruction,
+    origins: Vec<usize>,
+}
+
+/// How a run of a program ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum End {
+    Halted,
+    /// An input instruction found no input left
+    Waiting,
+    OutOfSteps,
+}
+
+/// Observable result of running a program on [`ProgramVm`]
+#[derive(Debug, Clone)]
+struct Run {
+    output: Vec<i32>,
+    /// The output as text, which tells numbers from characters
+    text: String,
+    stack: Vec<i32>,
+    end: End,
+}
+
+/// Peephole optimizer over a compiled [`Program`]
+///
+/// ```ignore
+/// let optimizer = Optimizer::new(&program);
+/// let optimized = optimizer.optimize();
+/// optimizer.verify(&optimized, &[5], 10_000)?;
+/// ```
+pub struct Optimizer<'a> {
+    program: &'a Program,
+    superinstructions: bool,
+}
+
+impl<'a> Optimizer<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self { program, superinstructions: true }
+    }
+
+    /// Whether to fuse `push n` with a following add/subtract/multiply (default true)
+    pub fn superinstructions(mut self, enabled: bool) -> Self {
+        self.superinstructions = enabled;
+        self
+    }
+
+    /// Returns the optimized program; instruction 0 is still the entry
+    ///
+    /// Programs without successor edges are returned unchanged.
+    pub fn optimize(&self) -> Program {
+        let program = self.program;
+        let n = program.len();
+        if n == 0 || program.successors.len() != n {
+            return program.clone();
+        }
+
+        // A pointer or switch whose branches all lead to the same state only pops
+        let mut instrs = program.instructions.clone();
+        let mut succs: Vec<Vec<Option<usize>>> = program
+            .successors
+            .iter()
+            .map(|targets| targets.iter().map(|t| t.filter(|&t| t < n)).collect())
+            .collect();
+        for i in 0..n {
+            let branches = matches!(instrs[i], Instruction::Pointer | Instruction::Switch);
+            if branches && !succs[i].is_empty() && succs[i].iter().all(|s| *s == succs[i][0]) {
+                instrs[i] = Instruction::Pop;
+                succs[i].truncate(1);
+            }
+        }
+
+        let chains = chains(&succs);
+        let mut ops: HashMap<usize, Vec<Op>> = HashMap::new();
+        let mut exits: HashMap<usize, Vec<Option<usize>>> = HashMap::new();
+        for chain in &chains {
+            let last = *chain.last().expect("chains are never empty");
+            ops.insert(chain[0], self.rewrite(chain, &instrs));
+            exits.insert(chain[0], succs[last].clone());
+        }
+
+        // Chains that vanished forward to their exit; a cycle of them keeps a nop
+        for chain in &chains {
+            let mut seen = HashSet::new();
+            let mut head = Some(chain[0]);
+            while let Some(h) = head.filter(|h| ops[h].is_empty()) {
+                if !seen.insert(h) {
+                    ops.insert(h, vec![Op { instr: Instruction::Nop, origins: vec![h] }]);
+                    break;
+                }
+                head = exits[&h].first().copied().flatten();
+            }
+        }
+        let resolve = |mut head: Option<usize>| {
+            while let Some(h) = head.filter(|h| ops[h].is_empty()) {
+                head = exits[&h].first().copied().flatten();
+            }
+            head
+        };
+
+        // Entry chain first, then the others in their original order
+        let entry = resolve(Some(0));
+        let mut order: Vec<usize> = chains.iter().map(|c| c[0]).filter(|h| !ops[h].is_empty()).collect();
+        order.sort_by_key(|&h| (Some(h) != entry, h));
+        let mut base = HashMap::new();
+        let mut next = 0;
+        for &h in &order {
+            base.insert(h, next);
+            next += ops[&h].len();
+        }
+
+        let mut optimized = Program::with_metadata(program.metadata.clone());
+        optimized.width = program.width;
+        optimized.height = program.height;
+        optimized.next_position = program.next_position.clone();
+        let mut new_index: Vec<Option<usize>> = vec![None; n];
+
+        if entry.is_none() {
+            // Nothing observable ever happens
+            let debug = program.rich_instructions.first().and_then(|r| r.debug.clone());
+            optimized.instructions.push(Instruction::Halt);
+            optimized.rich_instructions.push(RichInstruction { op: Instruction::Halt, debug, merged: Vec::new() });
+            optimized.successors.push(Vec::new());
+        }
+        for &h in &order {
+            let chain_ops = &ops[&h];
+            for (k, op) in chain_ops.iter().enumerate() {
+                let idx = base[&h] + k;
+                for &origin in &op.origins {
+                    new_index[origin] = Some(idx);
+                }
+                let successors = if k + 1 < chain_ops.len() {
+                    vec![Some(idx + 1)]
+                } else {
+                    exits[&h].iter().map(|t| resolve(*t).map(|t| base[&t])).collect()
+                };
+                optimized.instructions.push(op.instr.clone());
+                optimized.rich_instructions.push(self.rich(op));
+                optimized.successors.push(successors);
+            }
+        }
+        // States of vanished chains map to where execution continues
+        for chain in &chains {
+            if ops[&chain[0]].is_empty() {
+                let target = resolve(Some(chain[0])).map(|t| base[&t]).or(Some(0));
+                for &i in chain {
+                    new_index[i] = target;
+                }
+            }
+        }
+
+        optimized.position_map = program
+            .position_map
+            .iter()
+            .map(|row| row.iter().map(|idx| idx.and_then(|i| new_index.get(i).copied().flatten())).collect())
+            .collect();
+        optimized
+    }
+
+    /// Runs the original and the optimized program on `input` with
+    /// [`ProgramVm`] and compares what they print and the stack they end with
+    ///
+    /// Runs cut short by `max_steps` only need one output to be a prefix
+    /// of the other, since the optimized program needs fewer steps.
+    pub fn verify(&self, optimized: &Program, input: &[i32], max_steps: usize) -> Result<(), String> {
+        let before = run(self.program, input, max_steps);
+        let after = run(optimized, input, max_steps);
+        if before.end == End::OutOfSteps || after.end == End::OutOfSteps {
+            let (short, long) = if before.output.len() <= after.output.len() {
+                (&before, &after)
+            } else {
+                (&after, &before)
+            };
+            if long.output.starts_with(&short.output) && long.text.starts_with(&short.text) {
+                return Ok(());
+            }
+            return Err(format!("outputs diverge: {:?} vs {:?}", before.output, after.output));
+        }
+        if before.end != after.end {
+            return Err(format!("original ended {:?} but optimized ended {:?}", before.end, after.end));
+        }
+        if before.output != after.output || before.text != after.text {
+            return Err(format!("outputs differ: {:?} vs {:?}", before.text, after.text));
+        }
+        if before.stack != after.stack {
+            return Err(format!("final stacks differ: {:?} vs {:?}", before.stack, after.stack));
+        }
+        Ok(())
+    }
+
+    /// Peephole rewrite of one chain of states
+    fn rewrite(&self, chain: &[usize], instrs: &[Instruction]) -> Vec<Op> {
+        let mut out: Vec<Op> = Vec::new();
+        // States removed so far, credited to the next instruction kept
+        let mut pending = Vec::new();
+        for &i in chain {
+            let mut origins = std::mem::take(&mut pending);
+            origins.push(i);
+            out.push(Op { instr: instrs[i].clone(), origins });
+            while let Some(removed) = self.reduce(&mut out) {
+                pending.extend(removed);
+            }
+        }
+        if let Some(last) = out.last_mut() {
+            last.origins.append(&mut pending);
+        }
+        out
+    }
+
+    /// Applies one rule at the end of `out`, returning the states it removed
+    fn reduce(&self, out: &mut Vec<Op>) -> Option<Vec<usize>> {
+        let len = out.len();
+        let tail = |k: usize| (len >= k).then(|| &out[len - k].instr);
+
+        // Instructions that cancel out entirely
+        let dropped = match (tail(3), tail(2), tail(1)) {
+            (_, _, Some(Instruction::Nop)) => 1,
+            (_, Some(Instruction::Push(_) | Instruction::Duplicate), Some(Instruction::Pop)) => 2,
+            // Rolling at most one value does nothing
+            (Some(Instruction::Push(depth)), Some(Instruction::Push(_)), Some(Instruction::Roll)) if *depth <= 1 => 3,
+            _ => 0,
+        };
+        let replacement = match (tail(3), tail(2), tail(1)) {
+            (Some(Instruction::Push(a)), Some(Instruction::Push(b)), Some(op)) if fold(op, *a, *b).is_some() => {
+                Some((3, vec![Instruction::Push(fold(op, *a, *b)?)]))
+            }
+            (_, Some(Instruction::Push(a)), Some(Instruction::Not)) => Some((2, vec![Instruction::Push((*a == 0) as i32)])),
+            (_, Some(Instruction::Push(a)), Some(Instruction::Duplicate)) => {
+                Some((2, vec![Instruction::Push(*a), Instruction::Push(*a)]))
+            }
+            (_, Some(Instruction::Push(n)), Some(op)) if self.superinstructions => match op {
+                Instruction::Add => Some((2, vec![Instruction::PushAdd(*n)])),
+                Instruction::Subtract => Some((2, vec![Instruction::PushSubtract(*n)])),
+                Instruction::Multiply => Some((2, vec![Instruction::PushMultiply(*n)])),
+                _ => None,
+            },
+            // On an empty stack the first one only pushes, which the second then adds to
+            (_, Some(Instruction::PushAdd(a)), Some(Instruction::PushAdd(b))) => {
+                Some((2, vec![Instruction::PushAdd(a.wrapping_add(*b))]))
+            }
+            (_, Some(Instruction::PushMultiply(a)), Some(Instruction::PushMultiply(b))) => {
+                Some((2, vec![Instruction::PushMultiply(a.wrapping_mul(*b))]))
+            }
+            _ => None,
+        };
+        if dropped > 0 {
+            return Some(out.drain(len - dropped..).flat_map(|op| op.origins).collect());
+        }
+        let (replaced, instrs) = replacement?;
+        let old: Vec<Op> = out.drain(len - replaced..).collect();
+        if instrs.len() == 1 {
+            let origins = old.into_iter().flat_map(|op| op.origins).collect();
+            out.push(Op { instr: instrs[0].clone(), origins });
+        } else {
+            // One instruction for each of the old ones
+            out.extend(old.into_iter().zip(instrs).map(|(op, instr)| Op { instr, origins: op.origins }));
+        }
+        Some(Vec::new())
+    }
+
+    /// Rich instruction for `op`: entered like its first state, left like its last
+    fn rich(&self, op: &Op) -> RichInstruction {
+        let infos: Vec<InstructionDebugInfo> = op
+            .origins
+            .iter()
+            .filter_map(|&i| self.program.rich_instructions.get(i).and_then(|r| r.debug.clone()))
+            .collect();
+        let debug = infos.first().map(|first| {
+            let last = infos.last().unwrap_or(first);
+            InstructionDebugInfo { to_pos: last.to_pos, to_color: last.to_color.clone(), ..first.clone() }
+        });
+        let merged = if op.origins.len() > 1 { infos } else { Vec::new() };
+        RichInstruction { op: op.instr.clone(), debug, merged }
+    }
+}
+
+/// Result of `a op b` on constants, when the VM would not skip it
+fn fold(op: &Instruction, a: i32, b: i32) -> Option<i32> {
+    match op {
+        Instruction::Add => Some(a.wrapping_add(b)),
+        Instruction::Subtract => Some(a.wrapping_sub(b)),
+        Instruction::Multiply => Some(a.wrapping_mul(b)),
+        Instruction::Divide if b != 0 => Some(a.wrapping_div(b)),
+        Instruction::Mod if b != 0 => Some(a.wrapping_rem_euclid(b)),
+        Instruction::Greater => Some((a > b) as i32),
+        _ => None,
+    }
+}
+
+/// Straight-line chains of the states reachable from the entry
+///
+/// A state continues its predecessor's chain when that predecessor is its
+/// only way in and has no other successor; every other state starts one.
+fn chains(succs: &[Vec<Option<usize>>]) -> Vec<Vec<usize>> {
+    let n = succs.len();
+    let mut reachable = vec![false; n];
+    let mut work = vec![0];
+    reachable[0] = true;
+    while let Some(i) = work.pop() {
+        for &j in succs[i].iter().flatten() {
+            if j < n && !reachable[j] {
+                reachable[j] = true;
+                work.push(j);
+            }
+        }
+    }
+
+    let mut preds = vec![0usize; n];
+    let mut pred = vec![None; n];
+    preds[0] += 1;
+    for i in (0..n).filter(|&i| reachable[i]) {
+        for &j in succs[i].iter().flatten().filter(|&&j| j < n) {
+            preds[j] += 1;
+            pred[j] = Some(i);
+        }
+    }
+    let is_head = |j: usize| match pred[j] {
+        Some(p) if preds[j] == 1 => p == j || succs[p].len() != 1,
+        _ => true,
+    };
+
+    (0..n)
+        .filter(|&h| reachable[h] && is_head(h))
+        .map(|h| {
+            let mut chain = vec![h];
+            let mut current = h;
+            while let [Some(next)] = succs[current][..] {
+                if next >= n || is_head(next) {
+                    break;
+                }
+                chain.push(next);
+                current = next;
+            }
+            chain
+        })
+        .collect()
+}
+
+fn run(program: &Program, input: &[i32], max_steps: usize) -> Run {
+    let mut vm = ProgramVm::new(program.clone());
+    vm.load_input_number_vec(input);
+    vm.set_max_steps(Some(max_steps));
+    let end = loop {
+        match vm.stroke() {
+            Ok(()) => {}
+            Err(VmError::InvalidInput) => break End::Waiting,
+            Err(VmError::ExecutionTimeout(_)) => break End::OutOfSteps,
+            Err(_) => break End::Halted,
+        }
+    };
+    Run { output: vm.ink(), text: vm.ink_string(), stack: vm.stack().to_vec(), end }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::compiler::{CompileMode, Compiler};
+    use crate::testutil::run_program;
+
+    fn compile(source: &str, mode: CompileMode) -> Program {
+        let grid = Assembler::parse(source).unwrap().assemble().unwrap();
+        Compiler::new(grid).with_mode(mode).compile().unwrap()
+    }
+
+    const COUNTDOWN: &str = "
+                in(number)
+        loop:   dup
+                out(number)
+                push 1
+                subtract
+                dup
+                jnz loop
+                halt
+    ";
+
+    #[test]
+    fn test_folds_constants_and_drops_nops() {
+        let program = compile("push 3\npush 4\nadd\npush 2\nmultiply\nout(number)\n", CompileMode::Release);
+        let optimizer = Optimizer::new(&program);
+        let optimized = optimizer.optimize();
+        assert!(optimized.instructions.contains(&Instruction::Push(14)), "{:?}", optimized.instructions);
+        assert!(!optimized.instructions.contains(&Instruction::Nop));
+        assert!(optimized.len() < program.len());
+        optimizer.verify(&optimized, &[], 1000).unwrap();
+    }
+
+    #[test]
+    fn test_loops_keep_their_behavior() {
+        let program = compile(COUNTDOWN, CompileMode::Release);
+        let optimizer = Optimizer::new(&program);
+        let optimized = optimizer.optimize();
+        assert!(optimized.instructions.contains(&Instruction::PushSubtract(1)), "{:?}", optimized.instructions);
+        for n in [1, 5, 0, -3] {
+            optimizer.verify(&optimized, &[n], 10_000).unwrap();
+        }
+        assert_eq!(run_program(&optimized, &[3]), "321");
+
+        let plain = Optimizer::new(&program).superinstructions(false).optimize();
+        assert!(plain.instructions.iter().all(|i| i.unfused().is_none()));
+    }
+
+    #[test]
+    fn test_keeps_debug_info_of_every_state() {
+        let program = compile("push 2\npush 5\nadd\nout(number)\n", CompileMode::Debug);
+        let optimized = Optimizer::new(&program).optimize();
+        let merged: usize = optimized.rich_instructions.iter().map(|r| r.merged.len().max(1)).sum();
+        // Every state reachable in the original is accounted for
+        let reachable = chains(&program.successors).iter().map(Vec::len).sum::<usize>();
+        assert_eq!(merged, reachable);
+        let push = optimized.rich_instructions.iter().find(|r| r.op == Instruction::Push(7)).unwrap();
+        let debug = push.debug.as_ref().unwrap();
+        assert_eq!(debug.from_pos, push.merged[0].from_pos);
+        assert_eq!(debug.to_pos, push.merged.last().unwrap().to_pos);
+        for row in &optimized.position_map {
+            assert!(row.iter().flatten().all(|&i| i < optimized.len()));
+        }
+    }
+
+    #[test]
+    fn test_underflow_semantics_are_preserved() {
+        // Hand-built chain: the folds must agree with skipped operations
+        let instrs = vec![
+            Instruction::Pop,
+            Instruction::Push(4),
+            Instruction::Add,
+            Instruction::Duplicate,
+            Instruction::Pop,
+            Instruction::Push(0),
+            Instruction::Divide,
+            Instruction::Push(2),
+            Instruction::Multiply,
+            Instruction::OutNumber,
+            Instruction::Halt,
+        ];
+        let mut program = Program::new(1, 1);
+        for (i, instr) in instrs.iter().enumerate() {
+            program.add_instruction(instr.clone());
+            let next = (i + 1 < instrs.len()).then_some(i + 1);
+            program.set_successors(i, if *instr == Instruction::Halt { vec![] } else { vec![next] });
+        }
+        let optimizer = Optimizer::new(&program);
+        let optimized = optimizer.optimize();
+        optimizer.verify(&optimized, &[], 100).unwrap();
+        assert_eq!(run_program(&optimized, &[]), "0");
+    }
+
+    /// Small xorshift generator so the random programs are reproducible
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn test_random_programs_match() {
+        let pool = [
+            Instruction::Push(0),
+            Instruction::Push(1),
+            Instruction::Push(2),
+            Instruction::Push(3),
+            Instruction::Pop,
+            Instruction::Add,
+            Instruction::Subtract,
+            Instruction::Multiply,
+            Instruction::Divide,
+            Instruction::Mod,
+            Instruction::Not,
+            Instruction::Greater,
+            Instruction::Duplicate,
+            Instruction::Roll,
+            Instruction::Nop,
+            Instruction::Nop,
+            Instruction::InNumber,
+            Instruction::OutNumber,
+            Instruction::Pointer,
+            Instruction::Switch,
+            Instruction::Halt,
+        ];
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..500 {
+            let n = 2 + rng.below(30);
+            let mut program = Program::new(1, 1);
+            for i in 0..n {
+                let instr = pool[rng.below(pool.len())].clone();
+                let branches = match instr {
+                    Instruction::Halt => 0,
+                    Instruction::Pointer => 4,
+                    Instruction::Switch => 2,
+                    _ => 1,
+                };
+                program.add_instruction(instr);
+                // Mostly fall through, sometimes jump anywhere or stop
+                let successors = (0..branches)
+                    .map(|_| match rng.below(8) {
+                        0 => None,
+                        1 | 2 => Some(rng.below(n)),
+                        _ => (i + 1 < n).then_some(i + 1),
+                    })
+                    .collect();
+                program.set_successors(i, successors);
+            }
+            let optimizer = Optimizer::new(&program);
+            let optimized = optimizer.optimize();
+            for input in [&[][..], &[1, 2, 3], &[0, -7, 5, 9]] {
+                if let Err(e) = optimizer.verify(&optimized, input, 500) {
+                    panic!("{}\n{:?}\n{:?}\n{:?}", e, program.instructions, program.successors, optimized.instructions);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,656 @@
+//! Compact binary program files (`.pbc`)
+//!
+//! A `.pbc` file holds a compiled [`Program`] so it can be distributed and
+//! run without the source image: [`PbcFile::into_program_vm`] executes the
+//! stored instructions and successors, while [`PbcFile::into_vm`] needs the
+//! optional grid section. All integers are little-endian; counts and
+//! coordinates are LEB128 varints and operands are zigzag varints.
+//!
+//! ```text
+//! magic     "PBC\0"
+//! version   u16
+//! flags     u8          bit 0: debug section, bit 1: grid section
+//! metadata  codel_size image_width image_height grid_width grid_height
+//!           width height
+//! code      count, then per instruction:
+//!           opcode u8, [operand], successor count u8, successors
+//!           (0 = none, k + 1 = instruction k)
+//! debug     per instruction: has_debug u8, [info], merged count, infos;
+//!           then the codel -> instruction map as runs of (length, value)
+//! grid      width height, then runs of (length, color index)
+//! checksum  u32         FNV-1a of every preceding byte
+//! ```
+//!
+//! `next_position` is not stored; loaded programs leave it empty. Without
+//! the debug section `position_map` is empty too, so a file only gets a
+//! map as large as the runs it actually contains.
+
+use crate::bytecode::{Instruction, InstructionDebugInfo, Program, ProgramMetadata, RichInstruction};
+use crate::error::VmError;
+use crate::exits::{CodelChooser, Direction};
+use crate::grid::Grid;
+use crate::ops::PietColor;
+use crate::program_vm::ProgramVm;
+use crate::vm::BytecodeVm;
+
+/// File signature
+pub const PBC_MAGIC: [u8; 4] = *b"PBC\0";
+/// Current format version (the only one the loader accepts)
+pub const PBC_VERSION: u16 = 1;
+
+const FLAG_DEBUG: u8 = 1;
+const FLAG_GRID: u8 = 2;
+/// Upper bound on codels per map (a 2048x2048 grid). Maps are only built
+/// from runs read from the file, and this caps what those runs can cover.
+const MAX_CELLS: usize = 1 << 22;
+
+/// Serializes a [`Program`] (and optionally its [`Grid`]) to `.pbc` bytes
+///
+/// ```ignore
+/// let bytes = PbcWriter::new(&program).grid(&grid).to_bytes();
+/// ```
+pub struct PbcWriter<'a> {
+    program: &'a Program,
+    grid: Option<&'a Grid>,
+    debug: bool,
+}
+
+impl<'a> PbcWriter<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self { program, grid: None, debug: true }
+    }
+
+    /// Embeds the grid, which [`PbcFile::into_vm`] needs to run the program
+    /// on the image; [`PbcFile::into_program_vm`] runs without it
+    pub fn grid(mut self, grid: &'a Grid) -> Self {
+        self.grid = Some(grid);
+        self
+    }
+
+    /// Writes debug info and the codel map (default: on)
+    pub fn debug_info(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Encodes the file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let program = self.program;
+        let mut out = Vec::new();
+        out.extend_from_slice(&PBC_MAGIC);
+        out.extend_from_slice(&PBC_VERSION.to_le_bytes());
+        let mut flags = 0;
+        if self.debug {
+            flags |= FLAG_DEBUG;
+        }
+        if self.grid.is_some() {
+            flags |= FLAG_GRID;
+        }
+        out.push(flags);
+
+        let meta = &program.metadata;
+        for value in [
+            meta.codel_size,
+            meta.image_width,
+            meta.image_height,
+            meta.grid_width,
+            meta.grid_height,
+            program.width,
+            program.height,
+        ] {
+            put_varint(&mut out, value as u64);
+        }
+
+        put_varint(&mut out, program.instructions.len() as u64);
+        for (idx, instr) in program.instructions.iter().enumerate() {
+            let (opcode, operand) = encode_instruction(instr);
+            out.push(opcode);
+            if let Some(n) = operand {
+                put_signed(&mut out, n);
+            }
+            let successors = program.get_successors(idx);
+            out.push(successors.len() as u8);
+            for succ in successors {
+                put_varint(&mut out, succ.map_or(0, |s| s as u64 + 1));
+            }
+        }
+
+        if self.debug {
+            for idx in 0..program.instructions.len() {
+                let rich = program.get_rich_instruction(idx);
+                match rich.and_then(|r| r.debug.as_ref()) {
+                    Some(info) => {
+                        out.push(1);
+                        put_debug(&mut out, info);
+                    }
+                    None => out.push(0),
+                }
+                let merged = rich.map(|r| r.merged.as_slice()).unwrap_or(&[]);
+                put_varint(&mut out, merged.len() as u64);
+                for info in merged {
+                    put_debug(&mut out, info);
+                }
+            }
+            let cells = (0..program.height).flat_map(|y| {
+                (0..program.width).map(move |x| program.get_instruction_index_at(x, y).map_or(0, |i| i as u64 + 1))
+            });
+            put_runs(&mut out, cells, put_varint);
+        }
+
+        if let Some(grid) = self.grid {
+            put_varint(&mut out, grid.width() as u64);
+            put_varint(&mut out, grid.height() as u64);
+            let cells = grid.cells().iter().map(|c| color_index(*c));
+            put_runs(&mut out, cells, |out, c| out.push(c));
+        }
+
+        let checksum = fnv1a(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+}
+
+/// A loaded and validated `.pbc` file
+#[derive(Debug, Clone)]
+pub struct PbcFile {
+    pub program: Program,
+    pub grid: Option<Grid>,
+}
+
+impl PbcFile {
+    /// Decodes and validates a `.pbc` file
+    ///
+    /// Rejects a wrong magic, other versions, a bad checksum, truncated or
+    /// trailing data, unknown opcodes and out-of-range successors, codel map
+    /// entries or colors.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VmError> {
+        if bytes.len() < PBC_MAGIC.len() || bytes[..PBC_MAGIC.len()] != PBC_MAGIC {
+            return Err(invalid("not a .pbc file (bad magic)"));
+        }
+        let mut r = Reader { bytes, pos: PBC_MAGIC.len() };
+        let version = u16::from_le_bytes([r.byte()?, r.byte()?]);
+        if version != PBC_VERSION {
+            return Err(invalid(format!("unsupported version {} (expected {})", version, PBC_VERSION)));
+        }
+        if bytes.len() < r.pos + 4 {
+            return Err(invalid("unexpected end of file"));
+        }
+        let (body, trailer) = bytes.split_at(bytes.len() - 4);
+        let stored = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        if fnv1a(body) != stored {
+            return Err(invalid("checksum mismatch"));
+        }
+        r.bytes = body;
+
+        let flags = r.byte()?;
+        if flags & !(FLAG_DEBUG | FLAG_GRID) != 0 {
+            return Err(invalid(format!("unknown flags {:#04x}", flags)));
+        }
+
+        let metadata = ProgramMetadata {
+            codel_size: r.usize()?,
+            image_width: r.usize()?,
+            image_height: r.usize()?,
+            grid_width: r.usize()?,
+            grid_height: r.usize()?,
+        };
+        let width = r.usize()?;
+        let height = r.usize()?;
+        let cells = area(width, height)?;
+
+        // Every instruction takes at least two bytes
+        let count = r.usize()?;
+        if count > r.remaining() / 2 {
+            return Err(invalid("instruction count exceeds file size"));
+        }
+        let mut instructions = Vec::with_capacity(count);
+        let mut successors = Vec::with_capacity(count);
+        for idx in 0..count {
+            instructions.push(r.instruction()?);
+            let len = r.byte()?;
+            if len > 4 {
+                return Err(invalid(format!("instruction {} has {} successors", idx, len)));
+            }
+            let mut succ = Vec::with_capacity(len as usize);
+            for _ in 0..len {
+                succ.push(r.index(count, "successor")?);
+            }
+            successors.push(succ);
+        }
+
+        let mut rich_instructions: Vec<RichInstruction> =
+            instructions.iter().cloned().map(RichInstruction::simple).collect();
+        let mut position_map = Vec::new();
+        if flags & FLAG_DEBUG != 0 {
+            for rich in &mut rich_instructions {
+                if r.byte()? != 0 {
+                    rich.debug = Some(r.debug()?);
+                }
+                let merged = r.usize()?;
+                if merged > r.remaining() {
+                    return Err(invalid("merged debug count exceeds file size"));
+                }
+                for _ in 0..merged {
+                    rich.merged.push(r.debug()?);
+                }
+            }
+            // Validate every run before allocating the map
+            let mut runs = Vec::new();
+            r.runs(cells, |(len, value)| {
+                runs.push((len, map_value(value, count, "codel map entry")?));
+                Ok(())
+            })?;
+            if cells > 0 {
+                let flat: Vec<Option<usize>> =
+                    runs.into_iter().flat_map(|(len, value)| std::iter::repeat_n(value, len)).collect();
+                position_map = flat.chunks(width).map(<[_]>::to_vec).collect();
+            }
+        }
+
+        let grid = if flags & FLAG_GRID != 0 {
+            let grid_width = r.usize()?;
+            let grid_height = r.usize()?;
+            let mut colors = Vec::new();
+            r.runs(area(grid_width, grid_height)?, |(len, index)| {
+                let color = PietColor::ALL
+                    .get(index as usize)
+                    .copied()
+                    .ok_or_else(|| invalid(format!("unknown color index {}", index)))?;
+                colors.extend(std::iter::repeat_n(color, len));
+                Ok(())
+            })?;
+            if (grid_width, grid_height) != (width, height) {
+                return Err(invalid(format!(
+                    "grid is {}x{} but the program expects {}x{}",
+                    grid_width, grid_height, width, height
+                )));
+            }
+            Some(Grid::new(grid_width, grid_height, colors)?)
+        } else {
+            None
+        };
+
+        if r.remaining() != 0 {
+            return Err(invalid(format!("{} trailing bytes", r.remaining())));
+        }
+
+        let program = Program {
+            metadata,
+            rich_instructions,
+            instructions,
+            position_map,
+            next_position: Vec::new(),
+            width,
+            height,
+            successors,
+        };
+        Ok(Self { program, grid })
+    }
+
+    /// Builds a VM for the program; fails if the file has no grid
+    pub fn into_vm(self) -> Result<BytecodeVm, VmError> {
+        let grid = self.grid.ok_or_else(|| invalid("file has no grid section, cannot run it"))?;
+        Ok(BytecodeVm::new(self.program, grid))
+    }
+
+    /// Builds a VM that executes the stored instructions; needs no grid
+    pub fn into_program_vm(self) -> ProgramVm {
+        ProgramVm::new(self.program)
+    }
+}
+
+fn invalid(message: impl Into<String>) -> VmError {
+    VmError::InvalidProgramFile(message.into())
+}
+
+fn area(width: usize, height: usize) -> Result<usize, VmError> {
+    width
+        .checked_mul(height)
+        .filter(|&cells| cells <= MAX_CELLS)
+        .ok_or_else(|| invalid(format!("{}x{} map is too large", width, height)))
+}
+
+/// Decodes an instruction reference (0 = none, k + 1 = instruction k)
+fn map_value(value: u64, count: usize, what: &str) -> Result<Option<usize>, VmError> {
+    match value {
+        0 => Ok(None),
+        v if v - 1 < count as u64 => Ok(Some((v - 1) as usize)),
+        v => Err(invalid(format!("{} {} out of range ({} instructions)", what, v - 1, count))),
+    }
+}
+
+fn encode_instruction(instr: &Instruction) -> (u8, Option<i32>) {
+    match instr {
+        Instruction::Push(n) => (0, Some(*n)),
+        Instruction::Pop => (1, None),
+        Instruction::Add => (2, None),
+        Instruction::Subtract => (3, None),
+        Instruction::Multiply => (4, None),
+        Instruction::Divide => (5, None),
+        Instruction::Mod => (6, None),
+        Instruction::Not => (7, None),
+        Instruction::Greater => (8, None),
+        Instruction::Pointer => (9, None),
+        Instruction::Switch => (10, None),
+        Instruction::Duplicate => (11, None),
+        Instruction::Roll => (12, None),
+        Instruction::InNumber => (13, None),
+        Instruction::InChar => (14, None),
+        Instruction::OutNumber => (15, None),
+        Instruction::OutChar => (16, None),
+        Instruction::Nop => (17, None),
+        Instruction::Halt => (18, None),
+        Instruction::PushAdd(n) => (19, Some(*n)),
+        Instruction::PushSubtract(n) => (20, Some(*n)),
+        Instruction::PushMultiply(n) => (21, Some(*n)),
+    }
+}
+
+fn color_index(color: PietColor) -> u8 {
+    PietColor::ALL.iter().position(|c| *c == color).unwrap_or(0) as u8
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_signed(out: &mut Vec<u8>, n: i32) {
+    put_varint(out, ((n << 1) ^ (n >> 31)) as u32 as u64);
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_varint(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn put_debug(out: &mut Vec<u8>, info: &InstructionDebugInfo) {
+    for value in [info.from_pos.0, info.from_pos.1, info.to_pos.0, info.to_pos.1] {
+        put_varint(out, value as u64);
+    }
+    out.push(info.dp as u8);
+    out.push(info.cc as u8);
+    put_varint(out, info.block_size as u64);
+    put_str(out, &info.from_color);
+    put_str(out, &info.to_color);
+}
+
+/// Writes `values` as (run length, value) pairs
+fn put_runs<T: PartialEq + Copy>(
+    out: &mut Vec<u8>,
+    values: impl Iterator<Item = T>,
+    put: impl Fn(&mut Vec<u8>, T),
+) {
+    let mut current: Option<(T, usize)> = None;
+    for value in values {
+        match &mut current {
+            Some((v, len)) if *v == value => *len += 1,
+            _ => {
+                if let Some((v, len)) = current.take() {
+                    put_varint(out, len as u64);
+                    put(out, v);
+                }
+                current = Some((value, 1));
+            }
+        }
+    }
+    if let Some((v, len)) = current {
+        put_varint(out, len as u64);
+        put(out, v);
+    }
+}
+
+/// FNV-1a, 32 bits
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn remaining(&self) -> usize {
+        self.bytes.len().saturating_sub(self.pos)
+    }
+
+    fn byte(&mut self) -> Result<u8, VmError> {
+        let b = *self.bytes.get(self.pos).ok_or_else(|| invalid("unexpected end of file"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64, VmError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    fn usize(&mut self) -> Result<usize, VmError> {
+        usize::try_from(self.varint()?).map_err(|_| invalid("value out of range"))
+    }
+
+    fn signed(&mut self) -> Result<i32, VmError> {
+        let raw = u32::try_from(self.varint()?).map_err(|_| invalid("operand out of range"))?;
+        Ok((raw >> 1) as i32 ^ -((raw & 1) as i32))
+    }
+
+    fn index(&mut self, count: usize, what: &str) -> Result<Option<usize>, VmError> {
+        map_value(self.varint()?, count, what)
+    }
+
+    fn str(&mut self) -> Result<String, VmError> {
+        let len = self.usize()?;
+        if len > self.remaining() {
+            return Err(invalid("unexpected end of file"));
+        }
+        let s = std::str::from_utf8(&self.bytes[self.pos..self.pos + len])
+            .map_err(|_| invalid("color name is not UTF-8"))?;
+        self.pos += len;
+        Ok(s.to_string())
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, VmError> {
+        let opcode = self.byte()?;
+        Ok(match opcode {
+            0 => Instruction::Push(self.signed()?),
+            1 => Instruction::Pop,
+            2 => Instruction::Add,
+            3 => Instruction::Subtract,
+            4 => Instruction::Multiply,
+            5 => Instruction::Divide,
+            6 => Instruction::Mod,
+            7 => Instruction::Not,
+            8 => Instruction::Greater,
+            9 => Instruction::Pointer,
+            10 => Instruction::Switch,
+            11 => Instruction::Duplicate,
+            12 => Instruction::Roll,
+            13 => Instruction::InNumber,
+            14 => Instruction::InChar,
+            15 => Instruction::OutNumber,
+            16 => Instruction::OutChar,
+            17 => Instruction::Nop,
+            18 => Instruction::Halt,
+            19 => Instruction::PushAdd(self.signed()?),
+            20 => Instruction::PushSubtract(self.signed()?),
+            21 => Instruction::PushMultiply(self.signed()?),
+            other => return Err(invalid(format!("unknown opcode {}", other))),
+        })
+    }
+
+    fn debug(&mut self) -> Result<InstructionDebugInfo, VmError> {
+        let from_pos = (self.usize()?, self.usize()?);
+        let to_pos = (self.usize()?, self.usize()?);
+        let dp = match self.byte()? {
+            0 => Direction::Right,
+            1 => Direction::Down,
+            2 => Direction::Left,
+            3 => Direction::Up,
+            other => return Err(invalid(format!("invalid direction {}", other))),
+        };
+        let cc = match self.byte()? {
+            0 => CodelChooser::Left,
+            1 => CodelChooser::Right,
+            other => return Err(invalid(format!("invalid codel chooser {}", other))),
+        };
+        Ok(InstructionDebugInfo {
+            from_pos,
+            to_pos,
+            dp,
+            cc,
+            block_size: self.usize()?,
+            from_color: self.str()?,
+            to_color: self.str()?,
+        })
+    }
+
+    /// Reads (run length, value) pairs until they cover exactly `total` cells
+    fn runs(&mut self, total: usize, mut each: impl FnMut((usize, u64)) -> Result<(), VmError>) -> Result<(), VmError> {
+        let mut covered = 0usize;
+        while covered < total {
+            let len = self.usize()?;
+            if len == 0 || len > total - covered {
+                return Err(invalid("run lengths do not match the map size"));
+            }
+            let value = self.varint()?;
+            each((len, value))?;
+            covered += len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::compiler::{CompileMode, Compiler};
+
+    fn sample() -> (Program, Grid) {
+        let grid = Assembler::parse("push 3\npush 4\nadd\ndup\noutn\noutn\n")
+            .and_then(|asm| asm.assemble())
+            .unwrap();
+        let program = Compiler::new(grid.clone()).with_mode(CompileMode::Debug).compile().unwrap();
+        (program, grid)
+    }
+
+    #[test]
+    fn test_round_trip_with_debug_and_grid() {
+        let (program, grid) = sample();
+        let bytes = PbcWriter::new(&program).grid(&grid).to_bytes();
+        let file = PbcFile::from_bytes(&bytes).unwrap();
+
+        let loaded = file.program.clone();
+        assert_eq!(loaded.metadata, program.metadata);
+        assert_eq!(loaded.rich_instructions, program.rich_instructions);
+        assert_eq!(loaded.instructions, program.instructions);
+        assert_eq!(loaded.successors, program.successors);
+        assert_eq!(loaded.position_map, program.position_map);
+        assert_eq!(file.grid.as_ref().unwrap().cells(), grid.cells());
+
+        let mut vm = file.into_vm().unwrap();
+        while vm.stroke().is_ok() {}
+        assert_eq!(vm.ink_string(), "77");
+    }
+
+    #[test]
+    fn test_stripped_file_is_smaller() {
+        let (program, _) = sample();
+        let full = PbcWriter::new(&program).to_bytes();
+        let stripped = PbcWriter::new(&program).debug_info(false).to_bytes();
+        assert!(stripped.len() < full.len());
+
+        let file = PbcFile::from_bytes(&stripped).unwrap();
+        assert!(file.grid.is_none());
+        assert!(file.program.rich_instructions.iter().all(|r| r.debug.is_none()));
+        assert_eq!(file.program.instructions, program.instructions);
+        assert!(file.into_vm().is_err());
+    }
+
+    #[test]
+    fn test_superinstructions_and_operands_round_trip() {
+        let mut program = Program::new(0, 0);
+        for instr in [
+            Instruction::Push(-7),
+            Instruction::PushAdd(i32::MAX),
+            Instruction::PushSubtract(i32::MIN),
+            Instruction::PushMultiply(300),
+            Instruction::Halt,
+        ] {
+            program.add_instruction(instr);
+        }
+        for idx in 0..4 {
+            program.set_successors(idx, vec![Some(idx + 1)]);
+        }
+        let file = PbcFile::from_bytes(&PbcWriter::new(&program).to_bytes()).unwrap();
+        assert_eq!(file.program.instructions, program.instructions);
+        assert_eq!(file.program.successors, program.successors);
+    }
+
+    #[test]
+    fn test_rejects_corrupt_files() {
+        let (program, grid) = sample();
+        let bytes = PbcWriter::new(&program).grid(&grid).to_bytes();
+
+        for len in 0..bytes.len() {
+            assert!(PbcFile::from_bytes(&bytes[..len]).is_err(), "prefix of {} bytes accepted", len);
+        }
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert!(matches!(PbcFile::from_bytes(&magic), Err(VmError::InvalidProgramFile(m)) if m.contains("magic")));
+
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert!(matches!(PbcFile::from_bytes(&version), Err(VmError::InvalidProgramFile(m)) if m.contains("version")));
+
+        let mut flipped = bytes.clone();
+        flipped[12] ^= 0x40;
+        assert!(matches!(PbcFile::from_bytes(&flipped), Err(VmError::InvalidProgramFile(m)) if m.contains("checksum")));
+    }
+
+    /// A header-only file: no instructions and no sections
+    fn header_only(width: usize, height: usize) -> Vec<u8> {
+        let mut bytes = PBC_MAGIC.to_vec();
+        bytes.extend_from_slice(&PBC_VERSION.to_le_bytes());
+        bytes.push(0);
+        for value in [1, width, height, width, height, width, height, 0] {
+            put_varint(&mut bytes, value as u64);
+        }
+        let checksum = fnv1a(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_rejects_oversized_header() {
+        let err = PbcFile::from_bytes(&header_only(8192, 8192)).unwrap_err();
+        assert!(err.to_string().contains("8192x8192 map is too large"), "{}", err);
+
+        // Within the cap, a file without sections allocates no maps
+        let file = PbcFile::from_bytes(&header_only(2048, 2048)).unwrap();
+        assert!(file.program.position_map.is_empty() && file.program.next_position.is_empty());
+        assert_eq!(file.program.get_instruction_index_at(5, 5), None);
+        assert_eq!(file.program.get_next_position(5, 5), None);
+    }
+
+    #[test]
+    fn test_rejects_out_of_range_successor() {
+        let mut program = Program::new(0, 0);
+        program.add_instruction(Instruction::Nop);
+        program.set_successors(0, vec![Some(5)]);
+        let err = PbcFile::from_bytes(&PbcWriter::new(&program).to_bytes()).unwrap_err();
+        assert!(err.to_string().contains("successor 5 out of range"), "{}", err);
+    }
+}
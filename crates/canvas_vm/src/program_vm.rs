@@ -0,0 +1,239 @@
+//! Runs a compiled program without its image
+//!
+//! [`BytecodeVm`] walks the grid and only uses its program for lookups.
+//! `ProgramVm` executes the instructions themselves along their successor
+//! edges, so it can run a program loaded from a `.pbc` file that has no
+//! grid section. Both follow the same rules: arithmetic wraps, and
+//! operations without enough operands or dividing by zero are skipped.
+
+use crate::bytecode::{Instruction, Program};
+use crate::error::VmError;
+use crate::io::{Input, Output};
+use crate::vm::BytecodeVm;
+
+/// Interpreter over a [`Program`]'s instructions and successor edges
+///
+/// A program without successor information runs its instructions in
+/// sequence. The API mirrors [`BytecodeVm`].
+///
+/// ```ignore
+/// let mut vm = ProgramVm::new(PbcFile::from_bytes(&bytes)?.program);
+/// vm.load_input_number_vec(&[5]);
+/// while vm.stroke().is_ok() {}
+/// println!("{}", vm.ink_string());
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProgramVm {
+    program: Program,
+    /// Next instruction; `None` once the program has ended
+    state: Option<usize>,
+    stack: Vec<i32>,
+    input: Input,
+    output: Output,
+    steps: usize,
+    max_steps: Option<usize>,
+}
+
+impl ProgramVm {
+    pub fn new(program: Program) -> Self {
+        let state = (!program.is_empty()).then_some(0);
+        Self {
+            program,
+            state,
+            stack: Vec::new(),
+            input: Input::new(),
+            output: Output::new(),
+            steps: 0,
+            max_steps: Some(BytecodeVm::DEFAULT_MAX_STEPS),
+        }
+    }
+
+    /// Sets the step limit (`None` = no limit)
+    pub fn set_max_steps(&mut self, max: Option<usize>) {
+        self.max_steps = max;
+    }
+
+    /// Executes one instruction
+    ///
+    /// Fails with `Halted` once the program has ended, `ExecutionTimeout`
+    /// at the step limit, and `InvalidInput` when an input instruction
+    /// finds no input; that instruction runs again on the next call.
+    pub fn stroke(&mut self) -> Result<(), VmError> {
+        let Some(idx) = self.state else {
+            return Err(VmError::Halted);
+        };
+        if self.max_steps.is_some_and(|max| self.steps >= max) {
+            self.state = None;
+            return Err(VmError::ExecutionTimeout(self.steps));
+        }
+
+        let instr = self.program.instructions[idx].clone();
+        let parts = instr.unfused().map_or_else(|| vec![instr.clone()], Vec::from);
+        let mut branch = 0;
+        for part in &parts {
+            branch = self.execute(part)?;
+        }
+        self.steps += 1;
+
+        let len = self.program.len();
+        self.state = if instr == Instruction::Halt {
+            None
+        } else if self.program.successors.is_empty() {
+            Some(idx + 1).filter(|&j| j < len)
+        } else {
+            self.program.get_successors(idx).get(branch).copied().flatten().filter(|&j| j < len)
+        };
+        Ok(())
+    }
+
+    /// Executes one plain instruction and returns the branch taken
+    fn execute(&mut self, instr: &Instruction) -> Result<usize, VmError> {
+        let stack = &mut self.stack;
+        let len = stack.len();
+        if len < crate::analysis::operands(instr) {
+            return Ok(0);
+        }
+        match instr {
+            Instruction::Push(n) => stack.push(*n),
+            Instruction::Pop => drop(stack.pop()),
+            Instruction::Divide | Instruction::Mod if stack[len - 1] == 0 => {}
+            Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Mod
+            | Instruction::Greater => {
+                let (a, b) = (stack[len - 2], stack[len - 1]);
+                stack.truncate(len - 2);
+                stack.push(match instr {
+                    Instruction::Add => a.wrapping_add(b),
+                    Instruction::Subtract => a.wrapping_sub(b),
+                    Instruction::Multiply => a.wrapping_mul(b),
+                    Instruction::Divide => a.wrapping_div(b),
+                    Instruction::Mod => a.wrapping_rem_euclid(b),
+                    _ => (a > b) as i32,
+                });
+            }
+            Instruction::Not => stack[len - 1] = (stack[len - 1] == 0) as i32,
+            Instruction::Duplicate => stack.push(stack[len - 1]),
+            Instruction::Roll => {
+                let times = stack[len - 1];
+                let depth = stack[len - 2];
+                stack.truncate(len - 2);
+                if depth > 0 && depth as usize <= stack.len() {
+                    let start = stack.len() - depth as usize;
+                    stack[start..].rotate_right(times.rem_euclid(depth) as usize);
+                }
+            }
+            Instruction::InNumber => stack.push(self.input.read_number()?),
+            Instruction::InChar => stack.push(self.input.read_char()?),
+            Instruction::OutNumber => self.output.write_number(stack.pop().expect("operands were checked")),
+            Instruction::OutChar => self.output.write_char(stack.pop().expect("operands were checked")),
+            Instruction::Pointer => return Ok(stack.pop().expect("operands were checked").rem_euclid(4) as usize),
+            Instruction::Switch => return Ok((stack.pop().expect("operands were checked") % 2 != 0) as usize),
+            Instruction::Nop | Instruction::Halt => {}
+            Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => {
+                unreachable!("superinstructions are run one part at a time")
+            }
+        }
+        Ok(0)
+    }
+
+    /// Instruction that runs next, if the program has not ended
+    pub fn state(&self) -> Option<usize> {
+        self.state
+    }
+
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    pub fn ink(&self) -> Vec<i32> {
+        self.output.read()
+    }
+
+    pub fn ink_string(&self) -> String {
+        self.output.read_string()
+    }
+
+    pub fn input(&mut self, value: i32) {
+        self.input.write(value);
+    }
+
+    pub fn load_input_text(&mut self, text: &str) {
+        self.input.load_text(text);
+    }
+
+    pub fn load_input_number_vec(&mut self, numbers: &[i32]) {
+        self.input.load_number_vec(numbers);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.state.is_none()
+    }
+
+    pub fn get_steps(&self) -> usize {
+        self.steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::compiler::Compiler;
+    use crate::pbc::{PbcFile, PbcWriter};
+    use crate::testutil::{run_grid, run_program};
+
+    #[test]
+    fn test_runs_pbc_without_grid() {
+        let grid = Assembler::parse("in(number)\ndup\nmul\nout(number)\npush 10\noutc\n")
+            .unwrap()
+            .assemble()
+            .unwrap();
+        let program = Compiler::new(grid.clone()).compile().unwrap();
+        let file = PbcFile::from_bytes(&PbcWriter::new(&program).to_bytes()).unwrap();
+        assert!(file.grid.is_none());
+        assert_eq!(run_grid(grid, &[-7]), "49\n");
+
+        let mut vm = file.into_program_vm();
+        vm.input(-7);
+        while vm.stroke().is_ok() {}
+        assert!(vm.is_halted());
+        assert_eq!(vm.ink_string(), "49\n");
+    }
+
+    #[test]
+    fn test_matches_bytecode_vm() {
+        let sources = [
+            // Wrapping, Euclidean mod, division by zero and roll
+            "push 7\npush -3\nmod\noutn\npush -7\npush 2\ndiv\noutn\npush 3\npush 0\ndiv\noutn\noutn\n\
+             push 1\npush 2\npush 3\npush 3\npush -4\nroll\noutn\noutn\noutn\n",
+            // Branches and loops
+            "in(number)\nloop:\ndup\noutn\npush 1\nsub\ndup\njnz loop\n",
+            "in(number)\npush 2\nmod\njz even\npush 1\noutn\njmp end\neven:\npush 0\noutn\nend:\n",
+        ];
+        for source in sources {
+            let grid = Assembler::parse(source).unwrap().assemble().unwrap();
+            let program = Compiler::new(grid.clone()).compile().unwrap();
+            for input in [&[4][..], &[7]] {
+                assert_eq!(run_program(&program, input), run_grid(grid.clone(), input), "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_waits_for_input() {
+        let mut program = Program::new(0, 0);
+        program.add_instruction(Instruction::InNumber);
+        program.add_instruction(Instruction::OutNumber);
+        program.set_successors(0, vec![Some(1)]);
+        let mut vm = ProgramVm::new(program);
+        assert!(matches!(vm.stroke(), Err(VmError::InvalidInput)));
+        vm.input(5);
+        vm.stroke().unwrap();
+        vm.stroke().unwrap();
+        assert!(matches!(vm.stroke(), Err(VmError::Halted)));
+        assert_eq!(vm.ink_string(), "5");
+    }
+}
@@ -0,0 +1,689 @@
+//! Structured mini-language compiled to Piet
+//!
+//! ```text
+//! // FizzBuzz
+//! var i = 1;
+//! while (i <= 15) {
+//!     if (i % 15 == 0) { print "FizzBuzz"; }
+//!     else if (i % 3 == 0) { print "Fizz"; }
+//!     else if (i % 5 == 0) { print "Buzz"; }
+//!     else { print i; }
+//!     putc '\n';
+//!     i = i + 1;
+//! }
+//! ```
+//!
+//! Statements: `var x = expr;`, `x = expr;`, `if (...) {...} else {...}`,
+//! `while (...) {...}`, `print item, ...;` (strings are printed as text,
+//! expressions as numbers), `putc expr;`, `read x;` (number) and
+//! `readc x;` (character). Expressions use integer arithmetic (`+ - * / %`),
+//! comparisons, `&& || !` and parentheses. `/` truncates and `%` is never
+//! negative, as in the VM; both yield 0 when the divisor is 0.
+//!
+//! Every variable lives at a fixed slot at the bottom of the stack. Reads
+//! and writes reach it with `roll`, accounting for the temporaries of the
+//! expression being evaluated. Programs are lowered to assembly statements
+//! and painted by the [`Assembler`].
+
+use crate::assembler::{AsmStmt, Assembler};
+use crate::bytecode::Instruction;
+use crate::error::VmError;
+use crate::grid::Grid;
+use crate::textgen::{text_program, NumberBuilder};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i32),
+    Str(String),
+    Symbol(&'static str),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i32),
+    Var(usize),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum PrintItem {
+    Text(String),
+    Number(Expr),
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Assign(usize, Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Print(Vec<PrintItem>),
+    PutChar(Expr),
+    Read(usize, Instruction),
+}
+
+/// A mini-language program lowered to Piet assembly
+#[derive(Debug, Clone)]
+pub struct Script {
+    statements: Vec<AsmStmt>,
+}
+
+impl Script {
+    /// Parses and lowers `source`; errors carry 1-based line numbers
+    pub fn compile(source: &str) -> Result<Self, VmError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0, vars: HashMap::new() };
+        let mut program = Vec::new();
+        while parser.pos < parser.tokens.len() {
+            program.push(parser.statement()?);
+        }
+
+        let mut codegen = Codegen {
+            out: Vec::new(),
+            numbers: NumberBuilder::new(),
+            slots: parser.vars.len(),
+            temps: 0,
+            labels: 0,
+        };
+        if codegen.slots > 0 {
+            codegen.ops([Instruction::Push(1), Instruction::Not]);
+            codegen.ops(vec![Instruction::Duplicate; codegen.slots - 1]);
+        }
+        codegen.block(&program);
+        codegen.out.push(AsmStmt::Halt);
+        Ok(Self { statements: codegen.out })
+    }
+
+    /// The lowered program as assembly statements
+    pub fn statements(&self) -> &[AsmStmt] {
+        &self.statements
+    }
+
+    /// Plain instruction list, without labels or control flow
+    pub fn instructions(&self) -> Vec<Instruction> {
+        self.statements
+            .iter()
+            .filter_map(|stmt| match stmt {
+                AsmStmt::Op(instr) => Some(instr.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Paints the program
+    pub fn to_grid(&self) -> Result<Grid, VmError> {
+        Assembler::new(self.statements.clone()).assemble()
+    }
+}
+
+/// Operators and punctuation, two-character ones first
+const SYMBOLS: [&str; 21] = [
+    "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "=", "(", ")", "{", "}", ";", ",",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, VmError> {
+    let mut tokens = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let error = |message: String| VmError::Parse { line, message };
+        let chars: Vec<char> = text.chars().collect();
+        let mut at = 0;
+        while at < chars.len() {
+            let c = chars[at];
+            let rest = &chars[at..];
+            if c.is_whitespace() {
+                at += 1;
+            } else if rest.starts_with(&['/', '/']) {
+                break;
+            } else if c.is_ascii_digit() {
+                let digits: String = chars[at..].iter().take_while(|c| c.is_ascii_digit()).collect();
+                at += digits.len();
+                let value = digits.parse().map_err(|_| error(format!("number too large: {}", digits)))?;
+                tokens.push((Token::Number(value), line));
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let word: String = chars[at..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == '_').collect();
+                at += word.len();
+                tokens.push((Token::Ident(word), line));
+            } else if c == '"' || c == '\'' {
+                let mut value = String::new();
+                at += 1;
+                loop {
+                    match chars.get(at) {
+                        None => return Err(error("unterminated literal".to_string())),
+                        Some(&q) if q == c => break,
+                        Some('\\') => {
+                            value.push(match chars.get(at + 1) {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some('0') => '\0',
+                                Some(&other) => other,
+                                None => return Err(error("unterminated literal".to_string())),
+                            });
+                            at += 2;
+                        }
+                        Some(&other) => {
+                            value.push(other);
+                            at += 1;
+                        }
+                    }
+                }
+                at += 1;
+                if c == '"' {
+                    tokens.push((Token::Str(value), line));
+                } else {
+                    let mut it = value.chars();
+                    match (it.next(), it.next()) {
+                        (Some(ch), None) => tokens.push((Token::Number(ch as i32), line)),
+                        _ => return Err(error(format!("invalid character literal '{}'", value))),
+                    }
+                }
+            } else if let Some(symbol) = SYMBOLS.iter().find(|s| rest.iter().take(s.len()).copied().eq(s.chars())) {
+                at += symbol.len();
+                tokens.push((Token::Symbol(symbol), line));
+            } else {
+                return Err(error(format!("unexpected character '{}'", c)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    /// Slot of every declared variable
+    vars: HashMap<String, usize>,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map(|(_, line)| *line)
+            .unwrap_or(1)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, VmError> {
+        Err(VmError::Parse { line: self.line(), message: message.into() })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn eat(&mut self, symbol: &'static str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), VmError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            self.error(format!("expected '{}'", symbol))
+        }
+    }
+
+    fn keyword(&mut self, word: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(w)) if w == word) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, VmError> {
+        match self.peek().cloned() {
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => self.error("expected a name"),
+        }
+    }
+
+    fn variable(&mut self) -> Result<usize, VmError> {
+        let name = self.ident()?;
+        match self.vars.get(&name) {
+            Some(&slot) => Ok(slot),
+            None => {
+                self.pos -= 1;
+                self.error(format!("undefined variable '{}'", name))
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, VmError> {
+        self.expect("{")?;
+        let mut body = Vec::new();
+        while !self.eat("}") {
+            if self.peek().is_none() {
+                return self.error("expected '}'");
+            }
+            body.push(self.statement()?);
+        }
+        Ok(body)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, VmError> {
+        let stmt = if self.keyword("var") {
+            let name = self.ident()?;
+            if self.vars.contains_key(&name) {
+                self.pos -= 1;
+                return self.error(format!("variable '{}' already declared", name));
+            }
+            let value = if self.eat("=") { self.expr()? } else { Expr::Number(0) };
+            let slot = self.vars.len();
+            self.vars.insert(name, slot);
+            Stmt::Assign(slot, value)
+        } else if self.keyword("if") {
+            self.expect("(")?;
+            let cond = self.expr()?;
+            self.expect(")")?;
+            let then = self.block()?;
+            let otherwise = if !self.keyword("else") {
+                Vec::new()
+            } else if matches!(self.peek(), Some(Token::Ident(w)) if w == "if") {
+                vec![self.statement()?]
+            } else {
+                self.block()?
+            };
+            return Ok(Stmt::If(cond, then, otherwise));
+        } else if self.keyword("while") {
+            self.expect("(")?;
+            let cond = self.expr()?;
+            self.expect(")")?;
+            return Ok(Stmt::While(cond, self.block()?));
+        } else if self.keyword("print") {
+            let mut items = Vec::new();
+            loop {
+                if let Some(Token::Str(text)) = self.peek().cloned() {
+                    self.pos += 1;
+                    items.push(PrintItem::Text(text));
+                } else {
+                    items.push(PrintItem::Number(self.expr()?));
+                }
+                if !self.eat(",") {
+                    break;
+                }
+            }
+            Stmt::Print(items)
+        } else if self.keyword("putc") {
+            Stmt::PutChar(self.expr()?)
+        } else if self.keyword("read") {
+            Stmt::Read(self.variable()?, Instruction::InNumber)
+        } else if self.keyword("readc") {
+            Stmt::Read(self.variable()?, Instruction::InChar)
+        } else {
+            let slot = self.variable()?;
+            self.expect("=")?;
+            Stmt::Assign(slot, self.expr()?)
+        };
+        self.expect(";")?;
+        Ok(stmt)
+    }
+
+    fn expr(&mut self) -> Result<Expr, VmError> {
+        self.binary(0)
+    }
+
+    /// Precedence climbing over the binary operator levels
+    fn binary(&mut self, level: usize) -> Result<Expr, VmError> {
+        const LEVELS: [&[&str]; 6] =
+            [&["||"], &["&&"], &["==", "!="], &["<", "<=", ">", ">="], &["+", "-"], &["*", "/", "%"]];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'outer: loop {
+            for &op in LEVELS[level] {
+                if self.eat(op) {
+                    let right = self.binary(level + 1)?;
+                    left = Expr::Binary(op, Box::new(left), Box::new(right));
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, VmError> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let inner = self.expr()?;
+            self.expect(")")?;
+            return Ok(inner);
+        }
+        match self.peek() {
+            Some(Token::Number(n)) => {
+                let n = *n;
+                self.pos += 1;
+                Ok(Expr::Number(n))
+            }
+            Some(Token::Ident(_)) => Ok(Expr::Var(self.variable()?)),
+            _ => self.error("expected an expression"),
+        }
+    }
+}
+
+struct Codegen {
+    out: Vec<AsmStmt>,
+    numbers: NumberBuilder,
+    /// Number of variable slots below the temporaries
+    slots: usize,
+    /// Temporaries currently above the variable slots
+    temps: usize,
+    labels: usize,
+}
+
+impl Codegen {
+    fn ops(&mut self, ops: impl IntoIterator<Item = Instruction>) {
+        self.out.extend(ops.into_iter().map(AsmStmt::Op));
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("L{}", self.labels)
+    }
+
+    /// Pushes a constant without counting it as a temporary
+    fn constant(&mut self, n: i32) {
+        let mut ops = Vec::new();
+        self.numbers.push(n, &mut ops);
+        self.ops(ops);
+    }
+
+    /// `roll` with constant depth and times
+    fn roll(&mut self, depth: usize, times: usize) {
+        if depth > 1 && !times.is_multiple_of(depth) {
+            self.constant(depth as i32);
+            self.constant(times as i32);
+            self.ops([Instruction::Roll]);
+        }
+    }
+
+    /// Depth of a slot's value counted from the top of the stack
+    fn depth(&self, slot: usize) -> usize {
+        self.temps + (self.slots - 1 - slot)
+    }
+
+    /// Copies a variable onto the top of the stack
+    fn load(&mut self, slot: usize) {
+        let d = self.depth(slot);
+        self.temps += 1;
+        if d == 0 {
+            self.ops([Instruction::Duplicate]);
+            return;
+        }
+        // Bring it up, duplicate, and bury the original back in place
+        self.roll(d + 1, d);
+        self.ops([Instruction::Duplicate]);
+        self.roll(d + 2, 1);
+    }
+
+    /// Moves the value on top of the stack into a variable
+    fn store(&mut self, slot: usize) {
+        let d = self.depth(slot);
+        self.roll(d + 1, d);
+        self.ops([Instruction::Pop]);
+        self.roll(d, 1);
+        self.temps -= 1;
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Number(n) => {
+                self.constant(*n);
+                self.temps += 1;
+            }
+            Expr::Var(slot) => self.load(*slot),
+            Expr::Not(inner) => {
+                self.expr(inner);
+                self.ops([Instruction::Not]);
+            }
+            Expr::Neg(inner) => {
+                self.constant(0);
+                self.temps += 1;
+                self.expr(inner);
+                self.ops([Instruction::Subtract]);
+                self.temps -= 1;
+            }
+            Expr::Binary(op, left, right) => {
+                // a < b and a <= b are evaluated as b > a and !(a > b)
+                let (first, second) = if matches!(*op, "<" | ">=") { (right, left) } else { (left, right) };
+                self.expr(first);
+                if matches!(*op, "&&" | "||") {
+                    self.ops([Instruction::Not, Instruction::Not]);
+                }
+                self.expr(second);
+                if matches!(*op, "&&" | "||") {
+                    self.ops([Instruction::Not, Instruction::Not]);
+                }
+                if matches!(*op, "/" | "%") {
+                    self.divide(if *op == "/" { Instruction::Divide } else { Instruction::Mod });
+                    self.temps -= 1;
+                    return;
+                }
+                self.ops(match *op {
+                    "+" => vec![Instruction::Add],
+                    "-" => vec![Instruction::Subtract],
+                    "*" => vec![Instruction::Multiply],
+                    ">" | "<" => vec![Instruction::Greater],
+                    "<=" => vec![Instruction::Greater, Instruction::Not],
+                    ">=" => vec![Instruction::Greater, Instruction::Not],
+                    "==" => vec![Instruction::Subtract, Instruction::Not],
+                    "!=" => vec![Instruction::Subtract, Instruction::Not, Instruction::Not],
+                    "&&" => vec![Instruction::Multiply],
+                    "||" => vec![Instruction::Add, Instruction::Not, Instruction::Not],
+                    _ => unreachable!("parser only produces known operators"),
+                });
+                self.temps -= 1;
+            }
+        }
+    }
+
+    /// `/` or `%`, replacing both operands with 0 when the divisor is 0
+    ///
+    /// The VM skips a division by zero and leaves both operands on the
+    /// stack, which would throw off the depth of every variable.
+    fn divide(&mut self, op: Instruction) {
+        let (zero, end) = (self.label(), self.label());
+        self.ops([Instruction::Duplicate]);
+        self.out.push(AsmStmt::JumpIfZero(zero.clone()));
+        self.ops([op]);
+        self.out.push(AsmStmt::Jump(end.clone()));
+        self.out.push(AsmStmt::Label(zero));
+        self.ops([Instruction::Pop, Instruction::Pop]);
+        self.constant(0);
+        self.out.push(AsmStmt::Label(end));
+    }
+
+    fn block(&mut self, body: &[Stmt]) {
+        for stmt in body {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Assign(slot, value) => {
+                self.expr(value);
+                self.store(*slot);
+            }
+            Stmt::If(cond, then, otherwise) => {
+                let (else_label, end_label) = (self.label(), self.label());
+                self.expr(cond);
+                self.out.push(AsmStmt::JumpIfZero(else_label.clone()));
+                self.temps -= 1;
+                self.block(then);
+                self.out.push(AsmStmt::Jump(end_label.clone()));
+                self.out.push(AsmStmt::Label(else_label));
+                self.block(otherwise);
+                self.out.push(AsmStmt::Label(end_label));
+            }
+            Stmt::While(cond, body) => {
+                let (top, end) = (self.label(), self.label());
+                self.out.push(AsmStmt::Label(top.clone()));
+                self.expr(cond);
+                self.out.push(AsmStmt::JumpIfZero(end.clone()));
+                self.temps -= 1;
+                self.block(body);
+                self.out.push(AsmStmt::Jump(top));
+                self.out.push(AsmStmt::Label(end));
+            }
+            Stmt::Print(items) => {
+                for item in items {
+                    match item {
+                        PrintItem::Text(text) if text.is_empty() => {}
+                        PrintItem::Text(text) => {
+                            // text_program leaves the last character on the stack
+                            self.ops(text_program(text));
+                            self.ops([Instruction::Pop]);
+                        }
+                        PrintItem::Number(expr) => {
+                            self.expr(expr);
+                            self.ops([Instruction::OutNumber]);
+                            self.temps -= 1;
+                        }
+                    }
+                }
+            }
+            Stmt::PutChar(expr) => {
+                self.expr(expr);
+                self.ops([Instruction::OutChar]);
+                self.temps -= 1;
+            }
+            Stmt::Read(slot, instr) => {
+                self.ops([instr.clone()]);
+                self.temps += 1;
+                self.store(*slot);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::run_grid;
+
+    fn run(source: &str, input: &[i32]) -> String {
+        run_grid(Script::compile(source).unwrap().to_grid().unwrap(), input)
+    }
+
+    #[test]
+    fn test_fizzbuzz() {
+        let source = r#"
+            // FizzBuzz up to the number read
+            var n;
+            read n;
+            var i = 1;
+            while (i <= n) {
+                if (i % 15 == 0) { print "FizzBuzz"; }
+                else if (i % 3 == 0) { print "Fizz"; }
+                else if (i % 5 == 0) { print "Buzz"; }
+                else { print i; }
+                putc '\n';
+                i = i + 1;
+            }
+        "#;
+        let expected: String = (1..=20)
+            .map(|i| match (i % 3, i % 5) {
+                (0, 0) => "FizzBuzz\n".to_string(),
+                (0, _) => "Fizz\n".to_string(),
+                (_, 0) => "Buzz\n".to_string(),
+                _ => format!("{}\n", i),
+            })
+            .collect();
+        assert_eq!(run(source, &[20]), expected);
+    }
+
+    #[test]
+    fn test_primes() {
+        let source = r#"
+            var n = 2;
+            while (n < 40) {
+                var d = 2;
+                var prime = 1;
+                while (d * d <= n && prime) {
+                    if (n % d == 0) { prime = 0; }
+                    d = d + 1;
+                }
+                if (prime) { print n, " "; }
+                n = n + 1;
+            }
+        "#;
+        assert_eq!(run(source, &[]), "2 3 5 7 11 13 17 19 23 29 31 37 ");
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = r#"
+            var a; var b;
+            read a; read b;
+            print a + b, ",", a - b, ",", a * b, ",", a / b, ",", a % b, ",", -a, ",";
+            print a < b, a <= b, a > b, a >= b, a == b, a != b, !a, a && b, a || 0, ",";
+            var c = (a + 1) * (b - 1) - 2 * (a - b);
+            print c;
+        "#;
+        let (a, b) = (17, 5);
+        let expected = format!("22,12,85,3,2,-17,001101011,{}", (a + 1) * (b - 1) - 2 * (a - b));
+        assert_eq!(run(source, &[a, b]), expected);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let source = r#"
+            var a = 7; var b; var c = 9;
+            read b;
+            var q = a / b;
+            var r = a % b;
+            print q, ",", r, ",", a, ",", b, ",", c;
+        "#;
+        assert_eq!(run(source, &[0]), "0,0,7,0,9");
+        assert_eq!(run(source, &[-2]), "-3,1,7,-2,9");
+    }
+
+    #[test]
+    fn test_read_chars() {
+        let source = "var c; readc c; while (c != '.') { putc c - 32; readc c; }";
+        let input: Vec<i32> = "piet.".chars().map(|c| c as i32).collect();
+        assert_eq!(run(source, &input), "PIET");
+    }
+
+    #[test]
+    fn test_errors() {
+        let line_of = |source: &str| match Script::compile(source) {
+            Err(VmError::Parse { line, .. }) => line,
+            other => panic!("expected parse error, got {:?}", other.map(|s| s.statements().len())),
+        };
+        assert_eq!(line_of("var x = 1;\nx = y;\n"), 2);
+        assert_eq!(line_of("var x;\nvar x;\n"), 2);
+        assert_eq!(line_of("var x\n= 1\n"), 2);
+        assert_eq!(line_of("if (1) {\n print 1;\n"), 2);
+        assert_eq!(line_of("print \"open\n"), 1);
+        assert_eq!(line_of("\n\nvar $;\n"), 3);
+    }
+
+    #[test]
+    fn test_long_line() {
+        // Each token only looks at the characters it needs
+        let tokens = tokenize(&"x<=10;".repeat(100_000)).unwrap();
+        assert_eq!(tokens.len(), 400_000);
+        assert_eq!(tokens[1], (Token::Symbol("<="), 1));
+    }
+}
@@ -0,0 +1,64 @@
+//! Runners shared by the unit tests
+//!
+//! Grids run on [`BytecodeVm`], which walks the image itself; programs run
+//! on [`ProgramVm`], which follows the compiled successor edges. Comparing
+//! the two checks the compiler's view of an image against the VM.
+
+use crate::bytecode::Program;
+use crate::error::VmError;
+use crate::grid::Grid;
+use crate::program_vm::ProgramVm;
+use crate::vm::BytecodeVm;
+
+/// Step limit for [`run_grid`] and [`run_program`]
+const MAX_STEPS: usize = 5_000_000;
+
+/// Runs a grid until it halts and returns what it printed
+///
+/// Panics on any other error, including running out of steps or input.
+pub(crate) fn run_grid(grid: Grid, input: &[i32]) -> String {
+    let mut vm = BytecodeVm::from_grid(grid).unwrap();
+    vm.load_input_number_vec(input);
+    vm.set_max_steps(Some(MAX_STEPS));
+    while !vm.is_halted() {
+        match vm.stroke() {
+            Ok(()) | Err(VmError::Halted) => {}
+            Err(e) => panic!("vm error: {}", e),
+        }
+    }
+    vm.ink_string()
+}
+
+/// Runs a program's instructions until it halts and returns what it printed
+///
+/// Panics on any other error, including running out of steps or input.
+pub(crate) fn run_program(program: &Program, input: &[i32]) -> String {
+    let mut vm = ProgramVm::new(program.clone());
+    vm.load_input_number_vec(input);
+    vm.set_max_steps(Some(MAX_STEPS));
+    while !vm.is_halted() {
+        match vm.stroke() {
+            Ok(()) | Err(VmError::Halted) => {}
+            Err(e) => panic!("program vm error: {}", e),
+        }
+    }
+    vm.ink_string()
+}
+
+/// What a grid prints in at most `max_steps` steps, stopping at the first error
+pub(crate) fn grid_output(grid: &Grid, input: &[i32], max_steps: usize) -> String {
+    let mut vm = BytecodeVm::from_grid(grid.clone()).unwrap();
+    vm.load_input_number_vec(input);
+    vm.set_max_steps(Some(max_steps));
+    while vm.stroke().is_ok() {}
+    vm.ink_string()
+}
+
+/// What a program prints in at most `max_steps` steps, stopping at the first error
+pub(crate) fn program_output(program: &Program, input: &[i32], max_steps: usize) -> String {
+    let mut vm = ProgramVm::new(program.clone());
+    vm.load_input_number_vec(input);
+    vm.set_max_steps(Some(max_steps));
+    while vm.stroke().is_ok() {}
+    vm.ink_string()
+}
@@ -0,0 +1,214 @@
+//! Generator of Piet programs that print a fixed text
+//!
+//! Each character is reached from the previous one (kept on the stack with
+//! `duplicate`) by adding or subtracting the difference, or pushed fresh
+//! when that is cheaper. Numbers are built from small blocks with
+//! multiply/add/subtract, so no block is larger than a handful of codels.
+
+use crate::assembler::{block_size, comb_width, AsmStmt, Assembler};
+use crate::bytecode::Instruction;
+use crate::grid::Grid;
+use std::collections::HashMap;
+
+/// Largest push emitted as a single block
+const MAX_BLOCK: i32 = 16;
+
+/// Builds cheap instruction sequences for constants, memoized by value
+#[derive(Debug, Default)]
+pub(crate) struct NumberBuilder {
+    /// Codel cost and instructions of every value built so far
+    cache: HashMap<i32, (usize, Vec<Instruction>)>,
+}
+
+impl NumberBuilder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Codels needed to push `n`: block sizes plus one per other operation
+    pub(crate) fn cost(&mut self, n: i32) -> usize {
+        self.build(n).0
+    }
+
+    /// Appends instructions that push `n` (any value) onto the stack
+    pub(crate) fn push(&mut self, n: i32, ops: &mut Vec<Instruction>) {
+        ops.extend(self.build(n).1);
+    }
+
+    fn build(&mut self, n: i32) -> (usize, Vec<Instruction>) {
+        if let Some(found) = self.cache.get(&n) {
+            return found.clone();
+        }
+
+        let best = if n == 0 {
+            (2, vec![Instruction::Push(1), Instruction::Not])
+        } else if n < 0 {
+            // 0 - |n|; |i32::MIN| does not fit, so it subtracts i32::MAX and 1
+            let mut parts = vec![0, n.checked_neg().unwrap_or(i32::MAX)];
+            if n == i32::MIN {
+                parts.push(1);
+            }
+            let mut cost = 0;
+            let mut ops = Vec::new();
+            for (i, part) in parts.into_iter().enumerate() {
+                let (c, built) = self.build(part);
+                cost += c;
+                ops.extend(built);
+                if i > 0 {
+                    ops.push(Instruction::Subtract);
+                    cost += 1;
+                }
+            }
+            (cost, ops)
+        } else {
+            let mut best = if n <= MAX_BLOCK {
+                (n as usize, vec![Instruction::Push(n)])
+            } else {
+                (usize::MAX, Vec::new())
+            };
+
+            let root = (n as f64).sqrt() as i32;
+            if root > 1 && root * root == n {
+                let (c, mut ops) = self.build(root);
+                if c + 2 < best.0 {
+                    ops.extend([Instruction::Duplicate, Instruction::Multiply]);
+                    best = (c + 2, ops);
+                }
+            }
+
+            // n = q * a + r  or  n = (q + 1) * a - (a - r)
+            for a in 2..=MAX_BLOCK.min(n / 2) {
+                let (q, r) = (n / a, n % a);
+                let (ca, _) = self.build(a);
+                let (cq, _) = self.build(q);
+                let mut cost = cq + ca + 1;
+                if r > 0 {
+                    cost += self.build(r).0 + 1;
+                }
+                if cost < best.0 {
+                    let mut ops = self.build(q).1;
+                    ops.push(Instruction::Push(a));
+                    ops.push(Instruction::Multiply);
+                    if r > 0 {
+                        ops.extend(self.build(r).1);
+                        ops.push(Instruction::Add);
+                    }
+                    best = (cost, ops);
+                }
+                if r > 0 && (q + 1).checked_mul(a).is_some() {
+                    let cost = self.build(q + 1).0 + ca + 1 + self.build(a - r).0 + 1;
+                    if cost < best.0 {
+                        let mut ops = self.build(q + 1).1;
+                        ops.push(Instruction::Push(a));
+                        ops.push(Instruction::Multiply);
+                        ops.extend(self.build(a - r).1);
+                        ops.push(Instruction::Subtract);
+                        best = (cost, ops);
+                    }
+                }
+            }
+            best
+        };
+
+        self.cache.insert(n, best.clone());
+        best
+    }
+}
+
+/// Instructions that print `text` with `out(char)`
+pub fn text_program(text: &str) -> Vec<Instruction> {
+    let mut numbers = NumberBuilder::new();
+    let mut ops = Vec::new();
+    let mut previous: Option<i32> = None;
+
+    for c in text.chars() {
+        let value = c as i32;
+        match previous {
+            None => numbers.push(value, &mut ops),
+            Some(prev) if prev == value => {}
+            Some(prev) => {
+                let delta = (value - prev).abs();
+                // Both choices cost one extra operation (add/subtract vs pop)
+                if numbers.cost(delta) <= numbers.cost(value) {
+                    numbers.push(delta, &mut ops);
+                    ops.push(if value > prev { Instruction::Add } else { Instruction::Subtract });
+                } else {
+                    ops.push(Instruction::Pop);
+                    numbers.push(value, &mut ops);
+                }
+            }
+        }
+        // Keep a copy of the character to derive the next one from
+        ops.extend([Instruction::Duplicate, Instruction::OutChar]);
+        previous = Some(value);
+    }
+    ops
+}
+
+/// Paints a program that prints `text` and halts
+///
+/// Long texts are wrapped into several rows so the image stays roughly
+/// square instead of growing into a single long strip.
+pub fn text_grid(text: &str) -> Grid {
+    let ops = text_program(text);
+    let width = |op: &Instruction| comb_width(block_size(op), 4);
+    let total: usize = ops.iter().map(width).sum();
+    let row_width = ((total * 8) as f64).sqrt() as usize;
+    let row_width = row_width.max(32);
+
+    let mut statements = Vec::with_capacity(ops.len());
+    let mut used = 0;
+    for op in ops {
+        used += width(&op);
+        if used > row_width {
+            statements.push(AsmStmt::Label(format!("row{}", statements.len())));
+            used = width(&op);
+        }
+        statements.push(AsmStmt::Op(op));
+    }
+    Assembler::new(statements)
+        .assemble()
+        .expect("generated statements only use defined labels")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Program;
+    use crate::program_vm::ProgramVm;
+    use crate::testutil::run_grid;
+
+    #[test]
+    fn test_prints_exact_text() {
+        for text in ["", "A", "Hello, World!\n", "aaaa", "zyx  \t~", "Piet 🎨 ünïcødé ✓"] {
+            assert_eq!(run_grid(text_grid(text), &[]), text, "text {:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_long_text_is_wrapped_and_compact() {
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(12);
+        let grid = text_grid(&text);
+        assert_eq!(run_grid(grid.clone(), &[]), text);
+        assert!(grid.width() < 4 * grid.height(), "{}x{}", grid.width(), grid.height());
+    }
+
+    #[test]
+    fn test_numbers_use_small_blocks() {
+        let mut numbers = NumberBuilder::new();
+        for n in [0, 1, 16, 17, 72, 1000, 65_535, 0x1F3A8, i32::MAX, -7, i32::MIN] {
+            let mut ops = Vec::new();
+            numbers.push(n, &mut ops);
+            assert!(ops.iter().all(|op| block_size(op) <= MAX_BLOCK as usize), "{}: {:?}", n, ops);
+
+            // Without successor edges the program runs in sequence
+            let mut program = Program::new(0, 0);
+            program.instructions = ops;
+            let mut vm = ProgramVm::new(program);
+            while vm.stroke().is_ok() {}
+            assert_eq!(vm.stack(), [n]);
+        }
+        // 'H' = 72 = 8 * 9 is far cheaper than a 72-codel block
+        assert!(numbers.cost(72) < 20);
+    }
+}
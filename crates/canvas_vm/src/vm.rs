@@ -561,7 +561,8 @@ impl BytecodeVm {
                     return Err(VmError::DivisionByZero);
                 }
                 let a = self.pop()?;
-                self.stack.push(a / b);
+                // i32::MIN / -1 da la vuelta en lugar de abortar
+                self.stack.push(a.wrapping_div(b));
             }
             Instruction::Mod => {
                 self.check_stack(2)?;
@@ -572,7 +573,7 @@ impl BytecodeVm {
                     return Err(VmError::DivisionByZero);
                 }
                 let a = self.pop()?;
-                self.stack.push(a.rem_euclid(b));
+                self.stack.push(a.wrapping_rem_euclid(b));
             }
             Instruction::Not => {
                 self.check_stack(1)?;
@@ -654,6 +655,13 @@ impl BytecodeVm {
             Instruction::Halt => {
                 self.halted = true;
             }
+            Instruction::PushAdd(_) | Instruction::PushSubtract(_) | Instruction::PushMultiply(_) => {
+                // Igual que el push seguido de la operación (que se ignora si el stack estaba vacío)
+                if let Some([push, op]) = instr.unfused() {
+                    self.execute_instruction(&push)?;
+                    self.execute_instruction(&op)?;
+                }
+            }
         }
         Ok(())
     }
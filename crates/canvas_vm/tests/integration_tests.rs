@@ -1,5 +1,8 @@
 /// Integration tests usando ejemplos PNG de Piet
-use canvas_vm::{BytecodeVm, Grid};
+use canvas_vm::{
+    BytecodeVm, CompileCache, CompileMode, Compiler, Decompiler, Grid, Optimizer, PbcFile, PbcWriter, PietColor, Position,
+    ProgramVm,
+};
 use image::ImageReader;
 use std::path::PathBuf;
 
@@ -13,8 +16,8 @@ fn workspace_root() -> PathBuf {
         .to_path_buf()
 }
 
-/// Helper para cargar una imagen PNG y crear una VM
-fn load_piet_image(relative_path: &str) -> BytecodeVm {
+/// Helper para cargar una imagen PNG como Grid
+fn load_piet_grid(relative_path: &str) -> Grid {
     let path = workspace_root().join(relative_path);
     
     let img = ImageReader::open(&path)
@@ -26,10 +29,13 @@ fn load_piet_image(relative_path: &str) -> BytecodeVm {
     let (width, height) = img.dimensions();
     let rgba_data: Vec<u8> = img.into_raw();
 
-    let grid = Grid::from_rgba(width as usize, height as usize, &rgba_data)
-        .expect("Failed to create grid");
+    Grid::from_rgba(width as usize, height as usize, &rgba_data)
+        .expect("Failed to create grid")
+}
 
-    BytecodeVm::from_grid(grid).expect("Failed to create VM")
+/// Helper para cargar una imagen PNG y crear una VM
+fn load_piet_image(relative_path: &str) -> BytecodeVm {
+    BytecodeVm::from_grid(load_piet_grid(relative_path)).expect("Failed to create VM")
 }
 
 #[test]
@@ -353,3 +359,172 @@ fn trace_echo4_linear_execution() {
     println!("\nOutput: '{}'", vm.ink_string());
     println!("Halted: {}", vm.is_halted());
 }
+
+#[test]
+fn test_incremental_compile_after_random_edits() {
+    // Editar codels al azar y comparar con una compilación completa
+    let palette = [
+        PietColor::Red, PietColor::DarkYellow, PietColor::LightBlue,
+        PietColor::Magenta, PietColor::White, PietColor::Black,
+    ];
+    let mut seed: u64 = 0xC0FF_EE12_3456_789B;
+    let mut next = |n: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % n as u64) as usize
+    };
+
+    for example in ["tools/fixtures/samples/PrimeGenerator.png", "tools/fixtures/samples/Piet.png"] {
+        let mut grid = load_piet_grid(example);
+        let mut cache = CompileCache::new();
+        Compiler::new(grid.clone()).compile_incremental(&mut cache).unwrap();
+
+        for _ in 0..10 {
+            let pos = Position::new(next(grid.width()), next(grid.height()));
+            grid.set(pos, palette[next(palette.len())]).unwrap();
+
+            let compiler = Compiler::new(grid.clone()).with_mode(CompileMode::Debug);
+            let incremental = compiler.compile_incremental(&mut cache).unwrap();
+            assert_eq!(incremental, compiler.compile().unwrap(), "{} diverged after editing {:?}", example, pos);
+        }
+        println!("✓ {} recompiles incrementally", example);
+    }
+}
+
+#[test]
+fn test_text_fixture_execution() {
+    // push(3) → out(number) → bloque dR de 3x3 rodeado de negro = halt
+    let grid = Grid::from_text("
+        lR lR lR lY dM K  K
+        K  K  K  K  dM K  K
+        K  K  K  dR dR dR K
+        K  K  K  dR dR dR K
+        K  K  K  dR dR dR K
+    ").expect("Failed to parse grid");
+    let mut vm = BytecodeVm::from_grid(grid).expect("Failed to create VM");
+
+    let mut steps = 0;
+    while vm.stroke().is_ok() && steps < 100 {
+        steps += 1;
+    }
+
+    assert!(vm.is_halted(), "Program should halt");
+    assert_eq!(vm.ink_string(), "3");
+}
+
+#[test]
+fn test_decompile_all_examples() {
+    let hello2 = format!("{}loop {{\n}}\n", "print_char(1);\n".repeat(6));
+    let examples = [
+        ("HelloWorld.png", "halt;\n"),
+        ("HelloWorld2.png", hello2.as_str()),
+        ("PI.png", "halt;\n"),
+        ("Piet.png", "loop {\n}\n"),
+        ("PrimeGenerator.png", "halt;\n"),
+    ];
+    for (example, expected) in examples {
+        let grid = load_piet_grid(&format!("tools/fixtures/samples/{}", example));
+        let program = Compiler::new(grid).compile().expect("Failed to compile");
+        let decompiler = Decompiler::new(&program);
+        let code = decompiler.structured().unwrap_or_else(|reason| panic!("{}: {}", example, reason));
+        assert_eq!(code, expected, "{}", example);
+        assert_eq!(decompiler.decompile(), code, "{}", example);
+    }
+}
+
+#[test]
+fn test_decompile_falls_back_to_disassembly() {
+    // HelloWorld3 hace roll con una profundidad calculada, que no se puede estructurar
+    let grid = load_piet_grid("tools/fixtures/samples/HelloWorld3.png");
+    let program = Compiler::new(grid).compile().expect("Failed to compile");
+    let decompiler = Decompiler::new(&program);
+    let reason = decompiler.structured().expect_err("HelloWorld3 should not be structurable");
+    assert_eq!(reason, "roll with a computed depth at instruction 7");
+    // La salida de respaldo es el desensamblado anotado
+    let text = decompiler.decompile();
+    assert!(text.contains(&reason) && text.contains("idx"));
+}
+
+#[test]
+fn test_decompile_text_fixture() {
+    let grid = Grid::from_text("
+        lR lR lR lY dM K  K
+        K  K  K  K  dM K  K
+        K  K  K  dR dR dR K
+        K  K  K  dR dR dR K
+        K  K  K  dR dR dR K
+    ").expect("Failed to parse grid");
+    let program = Compiler::new(grid).compile().expect("Failed to compile");
+    assert_eq!(Decompiler::new(&program).decompile(), "print(3);\nhalt;\n");
+}
+
+#[test]
+fn test_optimizer_differential_all_examples() {
+    let examples = ["HelloWorld.png", "HelloWorld2.png", "HelloWorld3.png", "PI.png", "Piet.png", "PrimeGenerator.png"];
+    for example in examples {
+        let grid = load_piet_grid(&format!("tools/fixtures/samples/{}", example));
+        let program = Compiler::new(grid.clone()).with_mode(CompileMode::Debug).compile().expect("Failed to compile");
+        let optimizer = Optimizer::new(&program);
+        let optimized = optimizer.optimize();
+        assert!(optimized.len() <= program.len(), "{}", example);
+        // Se compara la ejecución con y sin optimizar, con varias entradas
+        for input in [&[][..], &[7], &[20, 3]] {
+            if let Err(e) = optimizer.verify(&optimized, input, 200_000) {
+                panic!("{} con entrada {:?}: {}", example, input, e);
+            }
+            // Y el programa optimizado contra la VM que recorre la imagen
+            let mut vm = BytecodeVm::from_grid(grid.clone()).expect("Failed to create VM");
+            vm.load_input_number_vec(input);
+            vm.set_max_steps(Some(200_000));
+            while vm.stroke().is_ok() {}
+            let mut fast = ProgramVm::new(optimized.clone());
+            fast.load_input_number_vec(input);
+            fast.set_max_steps(Some(200_000));
+            while fast.stroke().is_ok() {}
+            let (expected, actual) = (vm.ink_string(), fast.ink_string());
+            let (short, long) = if actual.len() <= expected.len() { (&actual, &expected) } else { (&expected, &actual) };
+            assert!(long.starts_with(short.as_str()), "{}: {:?} vs {:?}", example, actual, expected);
+        }
+    }
+}
+
+#[test]
+fn test_pruning_preserves_all_examples() {
+    let examples = ["HelloWorld.png", "HelloWorld2.png", "HelloWorld3.png", "PI.png", "Piet.png", "PrimeGenerator.png"];
+    for example in examples {
+        let grid = load_piet_grid(&format!("tools/fixtures/samples/{}", example));
+        let (pruned, report) = Compiler::new(grid.clone()).compile_with_report().expect("Failed to compile");
+        let unpruned = Compiler::new(grid).with_pruning(false).compile().expect("Failed to compile");
+        assert_eq!(report.instructions + report.pruned_states, unpruned.len(), "{}", example);
+        // El programa podado se comporta igual que el completo
+        for input in [&[][..], &[7], &[20, 3]] {
+            if let Err(e) = Optimizer::new(&unpruned).verify(&pruned, input, 200_000) {
+                panic!("{} con entrada {:?}: {}", example, input, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_pbc_round_trip_all_examples() {
+    let examples = ["HelloWorld.png", "HelloWorld2.png", "HelloWorld3.png", "PI.png", "Piet.png", "PrimeGenerator.png"];
+    for example in examples {
+        let grid = load_piet_grid(&format!("tools/fixtures/samples/{}", example));
+        let program = Compiler::new(grid.clone()).with_mode(CompileMode::Debug).compile().expect("Failed to compile");
+        let bytes = PbcWriter::new(&program).grid(&grid).to_bytes();
+        let json = serde_json::to_vec(&program).unwrap();
+        assert!(bytes.len() < json.len() / 4, "{}: {} bytes vs {} de JSON", example, bytes.len(), json.len());
+
+        // Ejecutar desde el archivo, sin la imagen original
+        let file = PbcFile::from_bytes(&bytes).expect("Failed to load .pbc");
+        assert_eq!(file.program.rich_instructions, program.rich_instructions, "{}", example);
+        let mut loaded = file.into_vm().unwrap();
+        let mut original = BytecodeVm::new(program, grid);
+        for vm in [&mut loaded, &mut original] {
+            vm.set_max_steps(Some(20_000));
+            while vm.stroke().is_ok() {}
+        }
+        assert_eq!(loaded.ink(), original.ink(), "{}", example);
+    }
+}
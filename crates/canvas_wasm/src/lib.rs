@@ -1,7 +1,8 @@
 use wasm_bindgen::prelude::*;
 use canvas_vm::{
     Grid, BytecodeVm, CompileMode, Compiler, Instruction, Program,
-    Debugger, DebuggerState, ExecutionStep, RichInstruction,
+    Debugger, DebuggerState, Disassembler, ExecutionStep, RichInstruction, StackAnalysis,
+    LoopAnalysis, LoopWarning, Position, Linter, PbcFile, PbcWriter, BlockId,
 };
 use serde::{Deserialize, Serialize};
 
@@ -61,6 +62,15 @@ pub struct JsInstructionDebugInfo {
     pub to_color: String,
 }
 
+/// Loop infinito detectado, con las celdas de los bloques involucrados
+#[derive(Serialize)]
+pub struct JsLoopWarning {
+    pub message: String,
+    #[serde(flatten)]
+    pub warning: LoopWarning,
+    pub cells: Vec<(usize, usize)>,
+}
+
 /// Instrucción de bytecode enriquecida serializable para JavaScript
 #[derive(Serialize, Deserialize)]
 pub struct BytecodeInstruction {
@@ -166,6 +176,9 @@ fn instruction_to_opcode(instr: &Instruction) -> (String, Option<i32>) {
         Instruction::OutChar => ("OutChar".to_string(), None),
         Instruction::Nop => ("Nop".to_string(), None),
         Instruction::Halt => ("Halt".to_string(), None),
+        Instruction::PushAdd(v) => ("PushAdd".to_string(), Some(*v)),
+        Instruction::PushSubtract(v) => ("PushSubtract".to_string(), Some(*v)),
+        Instruction::PushMultiply(v) => ("PushMultiply".to_string(), Some(*v)),
     }
 }
 
@@ -1093,6 +1106,9 @@ impl Canvas {
                     Instruction::OutChar => "OutChar",
                     Instruction::Nop => "Nop",
                     Instruction::Halt => "Halt",
+                    Instruction::PushAdd(_) => "PushAdd",
+                    Instruction::PushSubtract(_) => "PushSubtract",
+                    Instruction::PushMultiply(_) => "PushMultiply",
                 };
                 Ok(JsValue::from_str(opcode))
             }
@@ -1185,6 +1201,131 @@ impl Canvas {
             .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))
     }
 
+    /// Retorna el listado desensamblado del programa cargado
+    /// disassemble(annotated: boolean): string
+    #[wasm_bindgen]
+    pub fn disassemble(&self, annotated: bool) -> Result<String, JsValue> {
+        let program = self.program.as_ref()
+            .ok_or_else(|| JsValue::from_str("No image loaded. Call paint() first"))?;
+        Ok(Disassembler::new(program).annotated(annotated).to_string())
+    }
+
+    /// Exporta el programa compilado en formato binario .pbc
+    /// export_pbc(include_grid: boolean, debug_info: boolean): Uint8Array
+    #[wasm_bindgen]
+    pub fn export_pbc(&self, include_grid: bool, debug_info: bool) -> Result<Vec<u8>, JsValue> {
+        let program = self.program.as_ref()
+            .ok_or_else(|| JsValue::from_str("No image loaded. Call paint() first"))?;
+        let mut writer = PbcWriter::new(program).debug_info(debug_info);
+        if include_grid {
+            if let Some(grid) = self.grid.as_ref() {
+                writer = writer.grid(grid);
+            }
+        }
+        Ok(writer.to_bytes())
+    }
+
+    /// Carga un programa .pbc (debe incluir la grid para poder ejecutarse)
+    /// load_pbc(bytes: Uint8Array): void
+    #[wasm_bindgen]
+    pub fn load_pbc(&mut self, bytes: &[u8]) -> Result<(), JsValue> {
+        let file = PbcFile::from_bytes(bytes)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let grid = file.grid.clone()
+            .ok_or_else(|| JsValue::from_str("The .pbc file has no grid section"))?;
+        let meta = &file.program.metadata;
+        self.width = meta.image_width;
+        self.height = meta.image_height;
+        self.codel_size = meta.codel_size;
+        self.grid = Some(grid);
+        self.program = Some(file.program.clone());
+        let mut vm = file.into_vm()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        vm.set_max_steps(self.max_steps);
+        self.vm = Some(vm);
+        Ok(())
+    }
+
+    /// Retorna el resumen de la compilación, con las ramas y estados podados
+    /// compile_report(): CompileReport
+    #[wasm_bindgen]
+    pub fn compile_report(&self) -> Result<JsValue, JsValue> {
+        let grid = self.grid.as_ref()
+            .ok_or_else(|| JsValue::from_str("No image loaded. Call paint() first"))?;
+        let (_, report) = Compiler::new(grid.clone())
+            .compile_with_report()
+            .map_err(|e| JsValue::from_str(&format!("Compilation error: {}", e)))?;
+        serde_wasm_bindgen::to_value(&report)
+            .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))
+    }
+
+    /// Retorna los posibles stack underflows del programa, con sus codels
+    /// stack_warnings(): UnderflowWarning[]
+    #[wasm_bindgen]
+    pub fn stack_warnings(&self) -> Result<JsValue, JsValue> {
+        let grid = self.grid.as_ref()
+            .ok_or_else(|| JsValue::from_str("No image loaded. Call paint() first"))?;
+        // Debug mode para tener las posiciones de cada instrucción
+        let program = Compiler::new(grid.clone())
+            .with_mode(CompileMode::Debug)
+            .compile()
+            .map_err(|e| JsValue::from_str(&format!("Compilation error: {}", e)))?;
+        let analysis = StackAnalysis::new(&program);
+        serde_wasm_bindgen::to_value(analysis.warnings())
+            .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))
+    }
+
+    /// Retorna los loops que nunca terminan, con las celdas de sus bloques
+    /// loop_warnings(): LoopWarning[]
+    #[wasm_bindgen]
+    pub fn loop_warnings(&self) -> Result<JsValue, JsValue> {
+        let grid = self.grid.as_ref()
+            .ok_or_else(|| JsValue::from_str("No image loaded. Call paint() first"))?;
+        let program = Compiler::new(grid.clone())
+            .with_mode(CompileMode::Debug)
+            .compile()
+            .map_err(|e| JsValue::from_str(&format!("Compilation error: {}", e)))?;
+
+        // Expande los codels de cada loop a sus bloques completos para resaltarlos
+        let warnings: Vec<JsLoopWarning> = LoopAnalysis::new(&program)
+            .warnings()
+            .iter()
+            .map(|warning| {
+                let mut blocks: Vec<BlockId> = warning.codels
+                    .iter()
+                    .filter_map(|&(x, y)| grid.get_block_id(Position::new(x, y)))
+                    .collect();
+                blocks.sort();
+                blocks.dedup();
+                let mut cells: Vec<(usize, usize)> = blocks
+                    .iter()
+                    .filter_map(|&id| grid.get_block_info(id))
+                    .flat_map(|block| block.positions.iter().map(|pos| (pos.x, pos.y)))
+                    .collect();
+                cells.sort();
+                JsLoopWarning { message: warning.to_string(), warning: warning.clone(), cells }
+            })
+            .collect();
+        serde_wasm_bindgen::to_value(&warnings)
+            .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))
+    }
+
+    /// Retorna los diagnósticos del linter; con los píxeles originales revisa también colores fuera de paleta
+    /// lint(rgbaData?: Uint8Array, width?: number, height?: number): Diagnostic[]
+    #[wasm_bindgen]
+    pub fn lint(&self, rgba_data: Option<Vec<u8>>, width: Option<usize>, height: Option<usize>) -> Result<JsValue, JsValue> {
+        let grid = self.grid.as_ref()
+            .ok_or_else(|| JsValue::from_str("No image loaded. Call paint() first"))?;
+        let program = self.program.as_ref()
+            .ok_or_else(|| JsValue::from_str("No image loaded. Call paint() first"))?;
+        let mut linter = Linter::new(grid, program);
+        if let (Some(rgba), Some(w), Some(h)) = (rgba_data.as_deref(), width, height) {
+            linter = linter.pixels(w, h, rgba);
+        }
+        serde_wasm_bindgen::to_value(&linter.run())
+            .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))
+    }
+
     /// Compila la grilla actual a bytecode y retorna las instrucciones
     /// compile_to_bytecode(): BytecodeInstruction[]
     #[wasm_bindgen]
@@ -1227,6 +1368,9 @@ impl Canvas {
                     Instruction::OutChar => ("OutChar".to_string(), String::new()),
                     Instruction::Nop => ("Nop".to_string(), String::new()),
                     Instruction::Halt => ("Halt".to_string(), String::new()),
+                    Instruction::PushAdd(v) => ("PushAdd".to_string(), format!("{}", v)),
+                    Instruction::PushSubtract(v) => ("PushSubtract".to_string(), format!("{}", v)),
+                    Instruction::PushMultiply(v) => ("PushMultiply".to_string(), format!("{}", v)),
                 };
                 
                 // Convert debug info if present